@@ -8,6 +8,9 @@ import {
     FindByStatus,
     FindByTag,
     Pet,
+    PetSearchQuery,
+    PetSearchTagMatch,
+    PetUpdate,
     Status,
     Tag,
     Get,
@@ -17,6 +20,7 @@ import {
     ExamplePromiseClient,
 } from "../clients/grpc-web/api_grpc_web_pb";
 import { HttpBody } from "../clients/grpc-web/google/api/httpbody_pb";
+import { FieldMask } from "google-protobuf/google/protobuf/field_mask_pb";
 
 window["ExampleHttpClientClass"] = ExampleApi;
 window["exampleHttpClient"] = new ExampleApi(
@@ -85,12 +89,16 @@ window["petshopGrpcClient"] = new PetshopPromiseClient(
 
 window["Empty"] = Empty;
 window["Struct"] = Struct;
+window["FieldMask"] = FieldMask;
 window["HttpBody"] = HttpBody;
 
 window["Category"] = Category;
 window["FindByStatus"] = FindByStatus;
 window["FindByTag"] = FindByTag;
 window["Pet"] = Pet;
+window["PetSearchQuery"] = PetSearchQuery;
+window["PetSearchTagMatch"] = PetSearchTagMatch;
+window["PetUpdate"] = PetUpdate;
 window["Status"] = Status;
 window["Tag"] = Tag;
 window["Echo"] = Echo;
@@ -1,33 +1,321 @@
+//! Generates gRPC code with tonic, and `validator::Validate` implementations for `api`
+//! messages from field annotations in the proto files
+//!
+//! - `google.api.field_behavior = REQUIRED` on strings, repeated fields, messages and numbers
+//! - `validate.rules` options defined in `proto/validate.proto`
+//! - Enum values are defined
+//! - Nested messages in package are validated
+//!
+//! Validator derive macros don't work with prost field types, so implementations are
+//! generated here using the wrapper functions in the `prost_validator` module
+//!
+//! <https://cheatsheetseries.owasp.org/cheatsheets/Input_Validation_Cheat_Sheet.html>
+//! <https://cheatsheetseries.owasp.org/cheatsheets/REST_Security_Cheat_Sheet.html#input-validation>
+use prost::Message;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const PROTOS: &[&str] = &[
+    "proto/api.proto",
+    "proto/google/rpc/status.proto",
+    "proto/google/rpc/error_details.proto",
+];
+const INCLUDES: &[&str] = &["proto"];
+
+/// Package which validation is generated for
+const VALIDATE_PACKAGE: &str = "api";
+
+const TYPE_BOOL: i32 = 8;
+const TYPE_STRING: i32 = 9;
+const TYPE_MESSAGE: i32 = 11;
+const TYPE_BYTES: i32 = 12;
+const TYPE_ENUM: i32 = 14;
+const LABEL_REPEATED: i32 = 3;
+const FIELD_BEHAVIOR_REQUIRED: i32 = 2;
+
 fn main() {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR not set"));
+
+    let descriptor_set = file_descriptor_set(&out_dir);
+    let validate = validate_impls(&descriptor_set);
+    std::fs::write(out_dir.join("api.validate.rs"), validate).expect("write validate failed");
+
     tonic_build::configure()
-        // FIXME: Derive Validate trait and add validation to fields here
-        //
-        // It would be nicer to define these somewhere in the proto file, but that seems unlikely to be
-        // supported in the near future
-        // <https://github.com/danburkert/prost/issues/338>
-        //
-        // Validator functions have to be wrapped to make them usable with the prost types, an alternative
-        // way of doing this might be to implement the Validate trait manually on structs in lib.rs
-        //
-        // <https://cheatsheetseries.owasp.org/cheatsheets/Input_Validation_Cheat_Sheet.html>
-        // <https://cheatsheetseries.owasp.org/cheatsheets/REST_Security_Cheat_Sheet.html#input-validation>
-        .type_attribute("api.User", "#[derive(Validate)]")
-        .field_attribute(
-            "api.User.email",
-            "#[validate(custom = \"prost_validator::email\")]",
-        )
-        .field_attribute(
-            "api.User.name",
-            "#[validate(custom = \"prost_validator::user_name\")]",
-        )
-        .type_attribute("api.Get", "#[derive(Validate)]")
-        .field_attribute(
-            "api.Get.url",
-            "#[validate(custom = \"prost_validator::url\")]",
-        )
-        .compile(
-            &["proto/api.proto", "proto/google/rpc/status.proto"],
-            &["proto"],
-        )
+        .compile(PROTOS, INCLUDES)
         .expect("tonic_build failed");
 }
+
+/// Run protoc to get descriptors of proto files and their imports
+fn file_descriptor_set(out_dir: &Path) -> FileDescriptorSet {
+    let path = out_dir.join("api.descriptor.bin");
+    let mut cmd = Command::new(prost_build::protoc());
+    cmd.arg("--include_imports")
+        .arg("-o")
+        .arg(&path)
+        .arg("-I")
+        .arg(prost_build::protoc_include());
+    for include in INCLUDES {
+        cmd.arg("-I").arg(include);
+    }
+    cmd.args(PROTOS);
+
+    let status = cmd.status().expect("protoc failed");
+    assert!(status.success(), "protoc failed: {}", status);
+    let buf = std::fs::read(&path).expect("read descriptor failed");
+    FileDescriptorSet::decode(buf.as_slice()).expect("decode descriptor failed")
+}
+
+/// Returns source of `Validate` trait implementations for messages in package
+fn validate_impls(descriptor_set: &FileDescriptorSet) -> String {
+    let mut out = String::new();
+    for file in descriptor_set.file.iter() {
+        if file.package.as_deref() != Some(VALIDATE_PACKAGE) {
+            continue;
+        }
+        for message in file.message_type.iter() {
+            validate_impl(&mut out, message);
+        }
+    }
+    out
+}
+
+fn validate_impl(out: &mut String, message: &DescriptorProto) {
+    let name = message.name.as_deref().unwrap_or_default();
+    writeln!(out, "impl ::validator::Validate for {} {{", name).unwrap();
+    writeln!(
+        out,
+        "    fn validate(&self) -> ::std::result::Result<(), ::validator::ValidationErrors> {{"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "        #[allow(unused_mut)]\n        let mut errors = ::validator::ValidationErrors::new();"
+    )
+    .unwrap();
+
+    let mut nested = vec![];
+    for field in message.field.iter() {
+        let field_name = field.name.as_deref().unwrap_or_default();
+        let self_field = format!("self.{}", field_ident(field_name));
+        for check in field_checks(field) {
+            writeln!(
+                out,
+                "        prost_validator::add(&mut errors, {:?}, {});",
+                field_name,
+                check.replace("{}", &self_field)
+            )
+            .unwrap();
+        }
+        if let Some(check) = field_nested(field) {
+            nested.push(
+                check
+                    .replace("{name}", &format!("{:?}", field_name))
+                    .replace("{}", &self_field),
+            );
+        }
+    }
+
+    writeln!(
+        out,
+        "        #[allow(unused_mut)]\n        let mut result = if errors.is_empty() {{ Ok(()) }} else {{ Err(errors) }};"
+    )
+    .unwrap();
+    for check in nested {
+        writeln!(out, "        {}", check).unwrap();
+    }
+    writeln!(out, "        result\n    }}\n}}\n").unwrap();
+}
+
+/// Returns validator function calls for field, where `{}` is replaced by the field
+fn field_checks(field: &FieldDescriptorProto) -> Vec<String> {
+    let field_name = field.name.as_deref().unwrap_or_default();
+    let options = field.options.clone().unwrap_or_default();
+    let repeated = field.label == Some(LABEL_REPEATED);
+    let field_type = field.r#type.unwrap_or_default();
+    let mut checks = vec![];
+
+    if options.field_behavior.contains(&FIELD_BEHAVIOR_REQUIRED) {
+        match field_type {
+            _ if repeated => checks.push("prost_validator::not_empty(&{})".to_string()),
+            TYPE_STRING | TYPE_BYTES => {
+                checks.push("prost_validator::required_len(&{})".to_string())
+            }
+            TYPE_MESSAGE => checks.push("prost_validator::required_message(&{})".to_string()),
+            // Enum and bool default values are valid values
+            TYPE_ENUM | TYPE_BOOL => {}
+            _ => checks.push("prost_validator::required_number({})".to_string()),
+        }
+    }
+
+    // Enum values must be defined, prost represents enums as i32
+    if field_type == TYPE_ENUM {
+        if let Some(enum_name) = package_type_name(field) {
+            let check = format!(
+                "prost_validator::enum_value({}::from_i32(*x).is_some())",
+                enum_name
+            );
+            checks.push(if repeated {
+                format!("prost_validator::each(&{{}}, |x| {})", check)
+            } else {
+                check.replace("*x", "{}")
+            });
+        }
+    }
+
+    if let Some(rules) = options.rules {
+        let mut string_checks = vec![];
+        if rules.email {
+            string_checks.push("prost_validator::email(x)".to_string());
+        }
+        if rules.url {
+            string_checks.push("prost_validator::url(x)".to_string());
+        }
+        if rules.min_len > 0 || rules.max_len > 0 {
+            let check = format!(
+                "prost_validator::length(x, {}, {})",
+                option_literal(rules.min_len),
+                option_literal(rules.max_len)
+            );
+            string_checks.push(if rules.length_code.is_empty() {
+                check
+            } else {
+                format!("prost_validator::code({}, {:?})", check, rules.length_code)
+            });
+        }
+        assert!(
+            string_checks.is_empty() || field_type == TYPE_STRING,
+            "validate.rules on field `{}` requires string type",
+            field_name
+        );
+        for check in string_checks {
+            checks.push(if repeated {
+                format!("prost_validator::each(&{{}}, |x| {})", check)
+            } else {
+                check.replace("(x", "(&{}")
+            });
+        }
+
+        if rules.max_items > 0 {
+            assert!(
+                repeated,
+                "validate.rules.max_items on field `{}` requires repeated field",
+                field_name
+            );
+            checks.push(format!(
+                "prost_validator::max_items(&{{}}, {})",
+                rules.max_items
+            ));
+        }
+    }
+    checks
+}
+
+/// Returns nested validation of message fields in package, errors are merged into
+/// result so that field paths can be returned (e.g. `tags[2].name`)
+fn field_nested(field: &FieldDescriptorProto) -> Option<String> {
+    if field.r#type != Some(TYPE_MESSAGE) {
+        return None;
+    }
+    package_type_name(field)?;
+    Some(if field.label == Some(LABEL_REPEATED) {
+        "result = ::validator::ValidationErrors::merge_all(result, {name}, {}.iter().map(|x| ::validator::ValidationErrors::merge(Ok(()), {name}, ::validator::Validate::validate(x))).collect());".to_string()
+    } else {
+        "if let Some(x) = &{} { result = ::validator::ValidationErrors::merge(result, {name}, ::validator::Validate::validate(x)); }".to_string()
+    })
+}
+
+/// Returns name of field type if it is a top level type in package
+fn package_type_name(field: &FieldDescriptorProto) -> Option<&str> {
+    let type_name = field.type_name.as_deref()?;
+    let name = type_name.strip_prefix(&format!(".{}.", VALIDATE_PACKAGE))?;
+    if name.contains('.') {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+fn option_literal(value: u32) -> String {
+    if value > 0 {
+        format!("Some({})", value)
+    } else {
+        "None".to_string()
+    }
+}
+
+/// Returns Rust identifier of field, prost uses raw identifiers for keywords
+fn field_ident(name: &str) -> String {
+    match name {
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "false" | "fn" | "for" | "if"
+        | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+        | "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use"
+        | "where" | "while" => format!("r#{}", name),
+        _ => name.to_string(),
+    }
+}
+
+// Subset of `google/protobuf/descriptor.proto` required to read field options,
+// `prost_types` can't be used because it does not decode extensions
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorSet {
+    #[prost(message, repeated, tag = "1")]
+    file: Vec<FileDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorProto {
+    #[prost(string, optional, tag = "2")]
+    package: Option<String>,
+    #[prost(message, repeated, tag = "4")]
+    message_type: Vec<DescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    field: Vec<FieldDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(int32, optional, tag = "4")]
+    label: Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    r#type: Option<i32>,
+    #[prost(string, optional, tag = "6")]
+    type_name: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    options: Option<FieldOptions>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldOptions {
+    /// `google.api.field_behavior` extension
+    #[prost(int32, repeated, tag = "1052")]
+    field_behavior: Vec<i32>,
+    /// `validate.rules` extension
+    #[prost(message, optional, tag = "51200")]
+    rules: Option<FieldRules>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldRules {
+    #[prost(bool, tag = "1")]
+    email: bool,
+    #[prost(bool, tag = "2")]
+    url: bool,
+    #[prost(uint32, tag = "3")]
+    min_len: u32,
+    #[prost(uint32, tag = "4")]
+    max_len: u32,
+    #[prost(uint32, tag = "5")]
+    max_items: u32,
+    #[prost(string, tag = "6")]
+    length_code: String,
+}
@@ -10,14 +10,14 @@
 // that generated types have documentation
 //#![deny(missing_docs)]
 
-#[macro_use]
-extern crate validator;
-
 /// API module
 pub mod api {
     /// Proto definitions
     use crate::prost_validator;
     tonic::include_proto!("api");
+
+    // Validate trait implementations generated by `build.rs`
+    include!(concat!(env!("OUT_DIR"), "/api.validate.rs"));
 }
 
 /// Google module
@@ -34,9 +34,92 @@ pub mod google {
 
 /// Prost wrappers for validator library
 ///
-/// See `build.rs` file for adding these to prost message fields
+/// See `build.rs` file for how these are added to prost messages using annotations
 pub mod prost_validator {
-    use validator::ValidationError;
+    use std::borrow::Cow;
+    use validator::{ValidationError, ValidationErrors};
+
+    /// Single or multiple errors for a field
+    pub trait FieldErrors {
+        fn into_errors(self) -> Vec<ValidationError>;
+    }
+
+    impl FieldErrors for ValidationError {
+        fn into_errors(self) -> Vec<ValidationError> {
+            vec![self]
+        }
+    }
+
+    impl FieldErrors for Vec<ValidationError> {
+        fn into_errors(self) -> Vec<ValidationError> {
+            self
+        }
+    }
+
+    /// Add field errors to errors if result is an error
+    pub fn add<E: FieldErrors>(
+        errors: &mut ValidationErrors,
+        field: &'static str,
+        result: Result<(), E>,
+    ) {
+        if let Err(err) = result {
+            for err in err.into_errors() {
+                errors.add(field, err);
+            }
+        }
+    }
+
+    /// Validate each item of repeated field, errors have an `index` parameter
+    /// which is used to return field paths such as `photo_urls[1]`
+    pub fn each<T>(
+        v: &[T],
+        f: impl Fn(&T) -> Result<(), ValidationError>,
+    ) -> Result<(), Vec<ValidationError>> {
+        let errors: Vec<ValidationError> = v
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| {
+                f(x).err().map(|mut err| {
+                    err.add_param(Cow::Borrowed("index"), &i);
+                    err
+                })
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Replace code of error, used to keep codes which clients depend on
+    pub fn code(
+        result: Result<(), ValidationError>,
+        code: &'static str,
+    ) -> Result<(), ValidationError> {
+        result.map_err(|mut err| {
+            err.code = Cow::Borrowed(code);
+            err
+        })
+    }
+
+    pub fn enum_value(defined: bool) -> Result<(), ValidationError> {
+        if defined {
+            Ok(())
+        } else {
+            Err(ValidationError::new("enum_invalid"))
+        }
+    }
+
+    pub fn max_items<T>(v: &[T], max: usize) -> Result<(), ValidationError> {
+        if v.len() > max {
+            let mut err = ValidationError::new("max_items_invalid");
+            err.add_param(Cow::Borrowed("max"), &max);
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
 
     pub fn email(s: &str) -> Result<(), ValidationError> {
         if validator::validate_email(s) {
@@ -46,16 +129,60 @@ pub mod prost_validator {
         }
     }
 
-    pub fn user_name(s: &str) -> Result<(), ValidationError> {
-        if validator::validate_length(s, None, Some(64), None) {
+    pub fn length(s: &str, min: Option<u64>, max: Option<u64>) -> Result<(), ValidationError> {
+        if validator::validate_length(s, min, max, None) {
+            Ok(())
+        } else {
+            let mut err = ValidationError::new("length_invalid");
+            if let Some(min) = min {
+                err.add_param(Cow::Borrowed("min"), &min);
+            }
+            if let Some(max) = max {
+                err.add_param(Cow::Borrowed("max"), &max);
+            }
+            Err(err)
+        }
+    }
+
+    pub fn required_len<T: AsRef<[u8]> + ?Sized>(v: &T) -> Result<(), ValidationError> {
+        if v.as_ref().is_empty() {
+            Err(ValidationError::new("required_invalid"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn required_message<T>(v: &Option<T>) -> Result<(), ValidationError> {
+        if v.is_none() {
+            Err(ValidationError::new("required_invalid"))
+        } else {
             Ok(())
+        }
+    }
+
+    pub fn required_number<T: Default + PartialEq>(v: T) -> Result<(), ValidationError> {
+        if v == T::default() {
+            Err(ValidationError::new("required_invalid"))
         } else {
-            Err(ValidationError::new("user_name_invalid"))
+            Ok(())
         }
     }
 
+    pub fn not_empty<T>(v: &[T]) -> Result<(), ValidationError> {
+        if v.is_empty() {
+            Err(ValidationError::new("not_empty_invalid"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Accepts absolute URLs and root relative paths (e.g. photo URLs when storage
+    /// public URL is not configured)
     pub fn url(s: &str) -> Result<(), ValidationError> {
-        if validator::validate_url(s) {
+        let relative = s.starts_with('/') && !s.starts_with("//");
+        if validator::validate_url(s)
+            || (relative && validator::validate_url(format!("http://localhost{}", s)))
+        {
             Ok(())
         } else {
             Err(ValidationError::new("url_invalid"))
@@ -74,18 +201,58 @@ mod tests {
             email: "validemail@example.com".to_string(),
             name: "validname".to_string(),
         };
-        assert_eq!(user.validate().is_ok(), true);
+        assert!(user.validate().is_ok());
 
         let user = User {
             email: "notanemail".to_string(),
             name: "validname".to_string(),
         };
-        assert_eq!(user.validate().is_ok(), false);
+        assert!(user.validate().is_err());
 
         let user = User {
             email: "validemail@example.com".to_string(),
             name: "abcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabcab".to_string(),
         };
-        assert_eq!(user.validate().is_ok(), false);
+        let errors = user.validate().unwrap_err();
+        assert_eq!(errors.field_errors()["name"][0].code, "user_name_invalid");
+    }
+
+    #[test]
+    fn required_validate_test() {
+        let pet = Pet {
+            name: "name".to_string(),
+            photo_urls: vec!["https://example.com/photo.png".to_string()],
+            ..Default::default()
+        };
+        assert!(pet.validate().is_ok());
+
+        let pet = Pet {
+            name: "name".to_string(),
+            photo_urls: vec!["/api.Petshop/PetPhotoGet/1/a.png".to_string()],
+            ..Default::default()
+        };
+        assert!(pet.validate().is_ok());
+
+        let pet = Pet {
+            name: "name".to_string(),
+            photo_urls: vec!["//example.com/a.png".to_string()],
+            ..Default::default()
+        };
+        assert!(pet.validate().is_err());
+
+        let errors = Pet::default().validate().unwrap_err();
+        let errors = errors.field_errors();
+        assert_eq!(errors["name"][0].code, "required_invalid");
+        assert_eq!(errors["photo_urls"][0].code, "not_empty_invalid");
+
+        let query = PetPhotoQuery {
+            pet_id: 0,
+            photo_id: "a.png".to_string(),
+        };
+        let errors = query.validate().unwrap_err();
+        assert!(errors.field_errors().contains_key("pet_id"));
+
+        // Messages without annotations are always valid
+        assert!(Echo::default().validate().is_ok());
     }
 }
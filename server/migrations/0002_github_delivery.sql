@@ -0,0 +1,5 @@
+CREATE TABLE github_delivery (
+    id TEXT PRIMARY KEY,
+    event TEXT NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
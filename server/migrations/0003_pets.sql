@@ -0,0 +1,25 @@
+CREATE TABLE pet (
+    id BIGSERIAL PRIMARY KEY,
+    category_id BIGINT,
+    category_name TEXT,
+    name TEXT NOT NULL,
+    photo_urls TEXT[] NOT NULL DEFAULT '{}',
+    tags JSONB NOT NULL DEFAULT '[]',
+    status INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX pet_status_idx ON pet (status);
+CREATE INDEX pet_tags_idx ON pet USING GIN (tags jsonb_path_ops);
+
+CREATE TABLE pet_photo (
+    id TEXT PRIMARY KEY,
+    pet_id BIGINT NOT NULL REFERENCES pet (id) ON DELETE CASCADE,
+    content_type TEXT NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    storage_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX pet_photo_pet_id_idx ON pet_photo (pet_id);
@@ -0,0 +1,38 @@
+CREATE TABLE pet_event (
+    id BIGSERIAL PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    pet JSONB NOT NULL,
+    previous JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX pet_event_created_at_idx ON pet_event (created_at);
+
+-- Records pet changes and notifies listeners of the event id, the transaction lock
+-- orders event ids by commit so listeners reading after an id do not skip events
+CREATE FUNCTION pet_event_notify() RETURNS TRIGGER AS $$
+DECLARE
+    event_id BIGINT;
+BEGIN
+    PERFORM pg_advisory_xact_lock(7346858);
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('created', to_jsonb(NEW))
+        RETURNING id INTO event_id;
+    ELSIF TG_OP = 'UPDATE' THEN
+        INSERT INTO pet_event (event_type, pet, previous)
+        VALUES ('updated', to_jsonb(NEW), to_jsonb(OLD))
+        RETURNING id INTO event_id;
+    ELSE
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('deleted', to_jsonb(OLD))
+        RETURNING id INTO event_id;
+    END IF;
+    PERFORM pg_notify('pet_event', event_id::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER pet_event_trigger
+AFTER INSERT OR UPDATE OR DELETE ON pet
+FOR EACH ROW EXECUTE PROCEDURE pet_event_notify();
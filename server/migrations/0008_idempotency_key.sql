@@ -0,0 +1,17 @@
+-- Responses of requests with idempotency keys, response is null while the first
+-- request is in progress and it is locked until the lock expires
+CREATE TABLE idempotency_key (
+    identity TEXT NOT NULL,
+    method TEXT NOT NULL,
+    key TEXT NOT NULL,
+    request_hash TEXT NOT NULL,
+    response_headers JSONB,
+    response_body BYTEA,
+    response_trailers JSONB,
+    locked_until TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (identity, method, key)
+);
+
+CREATE INDEX idempotency_key_expires_at_idx ON idempotency_key (expires_at);
@@ -0,0 +1,2 @@
+-- Version of pet for optimistic concurrency control, incremented by each update
+ALTER TABLE pet ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
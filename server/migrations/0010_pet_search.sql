@@ -0,0 +1,10 @@
+-- Full-text search document of pet name, category and tag names, weighted by field
+CREATE FUNCTION pet_search_document(name TEXT, category_name TEXT, tags JSONB)
+RETURNS TSVECTOR AS $$
+    SELECT setweight(to_tsvector('english', coalesce(name, '')), 'A')
+        || setweight(to_tsvector('english', coalesce(category_name, '')), 'B')
+        || setweight(jsonb_to_tsvector('english', tags, '["string"]'), 'C')
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE INDEX pet_search_idx ON pet USING GIN (pet_search_document(name, category_name, tags));
+CREATE INDEX pet_category_name_idx ON pet (category_name);
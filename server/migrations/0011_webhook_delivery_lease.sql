@@ -0,0 +1,6 @@
+-- Pending deliveries are leased by the task sending them, deliveries with an expired
+-- lease were interrupted (e.g. by a crash) and are resumed
+ALTER TABLE webhook_delivery ADD COLUMN locked_until TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX webhook_delivery_pending_idx ON webhook_delivery (locked_until)
+WHERE status = 'pending';
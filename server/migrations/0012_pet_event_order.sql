@@ -0,0 +1,28 @@
+-- Events are ordered by the transaction which recorded them instead of a lock which
+-- serialized pet writes, readers only read events of transactions older than any
+-- running transaction so events committed out of order are not skipped
+ALTER TABLE pet_event ADD COLUMN xid BIGINT NOT NULL DEFAULT pg_current_xact_id()::TEXT::BIGINT;
+
+CREATE INDEX pet_event_xid_id_idx ON pet_event (xid, id);
+
+CREATE OR REPLACE FUNCTION pet_event_notify() RETURNS TRIGGER AS $$
+DECLARE
+    event_id BIGINT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('created', to_jsonb(NEW))
+        RETURNING id INTO event_id;
+    ELSIF TG_OP = 'UPDATE' THEN
+        INSERT INTO pet_event (event_type, pet, previous)
+        VALUES ('updated', to_jsonb(NEW), to_jsonb(OLD))
+        RETURNING id INTO event_id;
+    ELSE
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('deleted', to_jsonb(OLD))
+        RETURNING id INTO event_id;
+    END IF;
+    PERFORM pg_notify('pet_event', event_id::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,29 @@
+-- Search document is stored and indexed so searches do not recompute it for each pet
+ALTER TABLE pet ADD COLUMN search TSVECTOR
+    GENERATED ALWAYS AS (pet_search_document(name, category_name, tags)) STORED;
+
+DROP INDEX pet_search_idx;
+CREATE INDEX pet_search_idx ON pet USING GIN (search);
+
+-- Events record pets without their search document
+CREATE OR REPLACE FUNCTION pet_event_notify() RETURNS TRIGGER AS $$
+DECLARE
+    event_id BIGINT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('created', to_jsonb(NEW) - 'search')
+        RETURNING id INTO event_id;
+    ELSIF TG_OP = 'UPDATE' THEN
+        INSERT INTO pet_event (event_type, pet, previous)
+        VALUES ('updated', to_jsonb(NEW) - 'search', to_jsonb(OLD) - 'search')
+        RETURNING id INTO event_id;
+    ELSE
+        INSERT INTO pet_event (event_type, pet)
+        VALUES ('deleted', to_jsonb(OLD) - 'search')
+        RETURNING id INTO event_id;
+    END IF;
+    PERFORM pg_notify('pet_event', event_id::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
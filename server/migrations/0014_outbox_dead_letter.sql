@@ -0,0 +1,8 @@
+-- Failed events are retried after a backoff and dead lettered after the maximum
+-- number of attempts, dead events are kept for inspection and no longer block
+-- later events of their aggregate
+ALTER TABLE outbox ADD COLUMN retry_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE outbox ADD COLUMN dead_at TIMESTAMPTZ;
+
+DROP INDEX outbox_pending_idx;
+CREATE INDEX outbox_pending_idx ON outbox (id) WHERE published_at IS NULL AND dead_at IS NULL;
@@ -0,0 +1,63 @@
+//! # Audit
+//!
+use crate::internal::*;
+use petshop_proto::api::audit_server::Audit as AuditRpc;
+use petshop_proto::api::{AuditQuery, AuditRecords};
+use tonic::{Request, Response, Status};
+use validator::{ValidationError, ValidationErrors};
+
+#[tonic::async_trait]
+impl AuditRpc for Api {
+    #[tracing::instrument(skip(self))]
+    async fn audit_list(
+        &self,
+        request: Request<AuditQuery>,
+    ) -> Result<Response<AuditRecords>, Status> {
+        info!("audit_list request");
+        self.auth.api_or_user(&request).await?;
+        let actor = self.auth.actor(&request);
+        if !self.audit.is_admin(&actor) {
+            warn!("audit list permission denied: {}", actor);
+            return Err(tonic_status_error_info(&ERROR_PERMISSION_DENIED, &[]));
+        }
+
+        let query = request.into_inner();
+        self.validate(&query)?;
+        let actor = if query.actor.is_empty() {
+            None
+        } else {
+            Some(query.actor.as_str())
+        };
+        let before_id = if query.before_id > 0 {
+            Some(query.before_id)
+        } else {
+            None
+        };
+        let limit = if query.limit > 0 { query.limit } else { 50 }.clamp(1, 500);
+        let start_time = query.start_time.map(prost_timestamp_into_chrono);
+        let end_time = query.end_time.map(prost_timestamp_into_chrono);
+        let mut errors = ValidationErrors::new();
+        if let Some(None) = start_time {
+            errors.add("start_time", ValidationError::new("range"));
+        }
+        if let Some(None) = end_time {
+            errors.add("end_time", ValidationError::new("range"));
+        }
+        if !errors.is_empty() {
+            return Err(tonic_status_bad_request(&errors));
+        }
+
+        let records = self
+            .postgres
+            .audit_list(
+                start_time.flatten(),
+                end_time.flatten(),
+                actor,
+                before_id,
+                limit as i64,
+            )
+            .await?;
+
+        Ok(Response::new(AuditRecords { records }))
+    }
+}
@@ -16,11 +16,66 @@ impl Api {
         info!("streaming_task echo {:?}", echo);
         for _ in 0..3 {
             info!("echo {:?}", echo);
-            tx.send(Ok(echo.clone())).await.unwrap();
+            if tx.send(Ok(echo.clone())).await.is_err() {
+                info!("echo stream closed");
+                return;
+            }
             tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         }
         info!("echo stream done");
     }
+
+    /// Handle verified GitHub webhook delivery, deliveries which have already been
+    /// received (GitHub may redeliver) are ignored
+    async fn github_delivery(&self, delivery: GithubDelivery) -> Result<(), XErr> {
+        let inserted = self
+            .postgres
+            .github_delivery_insert(&delivery.id, delivery.event.name())
+            .await?;
+        if !inserted {
+            info!("github delivery {} already received", delivery.id);
+            return Ok(());
+        }
+
+        match delivery.event {
+            GithubEvent::Ping(ping) => self.github_ping(ping),
+            GithubEvent::Push(push) => self.github_push(push),
+            GithubEvent::PullRequest(pull_request) => self.github_pull_request(pull_request),
+            GithubEvent::Other(event) => info!("github {} event ignored", event),
+        }
+        Ok(())
+    }
+
+    fn github_ping(&self, ping: GithubPing) {
+        info!("github ping hook {}: {}", ping.hook_id, ping.zen);
+    }
+
+    fn github_push(&self, push: GithubPush) {
+        info!(
+            "github push {} {} {}..{} ({} commits)",
+            push.repository.full_name,
+            push.git_ref,
+            push.before,
+            push.after,
+            push.commits.len()
+        );
+        for commit in push.commits.iter() {
+            debug!("github push commit {}: {}", commit.id, commit.message);
+        }
+    }
+
+    fn github_pull_request(&self, pull_request: GithubPullRequest) {
+        info!(
+            "github pull_request {} #{} {} ({}, merged {}): {} {}",
+            pull_request.repository.full_name,
+            pull_request.number,
+            pull_request.action,
+            pull_request.pull_request.state,
+            pull_request.pull_request.merged,
+            pull_request.pull_request.title,
+            pull_request.pull_request.html_url
+        );
+    }
 }
 
 #[tonic::async_trait]
@@ -88,7 +143,8 @@ impl Example for Api {
         Ok(Response::new(body))
     }
 
-    #[tracing::instrument(skip(self))]
+    // Body is not logged, it may contain secrets and is large
+    #[tracing::instrument(skip(self, request), fields(content_type = %request.get_ref().content_type))]
     async fn webhook(&self, request: Request<HttpBody>) -> Result<Response<()>, Status> {
         let req = request.into_inner();
         info!("webhook request {}", req.content_type);
@@ -97,12 +153,41 @@ impl Example for Api {
             let req: serde_json::Value =
                 serde_urlencoded::from_bytes(&req.data).map_err(XErr::SerdeUrlencoded)?;
             info!("webhook data {}", req);
+        } else if Multipart::is_multipart(&req.content_type) {
+            // For example mailgun or other API webhooks which send attachments
+            let form = self.multipart.parse(&req)?;
+            for field in form.fields.iter() {
+                info!("webhook field {} {}", field.name, field.value);
+            }
+            for file in form.files.iter() {
+                info!(
+                    "webhook file {} {} {} ({} bytes)",
+                    file.name,
+                    file.filename,
+                    file.content_type,
+                    file.data.len()
+                );
+                debug!("webhook file headers {:?}", file.headers);
+            }
         }
-        // FIXME: Handle multipart form data here? Example for mailgun/other api webhook support?
 
         Ok(Response::new(()))
     }
 
+    // Body is not logged, it may contain secrets and is large
+    #[tracing::instrument(skip(self, request), fields(content_type = %request.get_ref().content_type))]
+    async fn github_webhook(&self, request: Request<HttpBody>) -> Result<Response<()>, Status> {
+        info!("github_webhook request");
+        let delivery = {
+            let req = request.get_ref();
+            self.github
+                .webhook(request.metadata(), &req.content_type, &req.data)?
+        };
+        info!("webhook github {} {}", delivery.event.name(), delivery.id);
+        self.github_delivery(delivery).await?;
+        Ok(Response::new(()))
+    }
+
     #[tracing::instrument(skip(self))]
     async fn csrf(&self, request: Request<()>) -> Result<Response<()>, Status> {
         info!("csrf request");
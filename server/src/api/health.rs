@@ -0,0 +1,113 @@
+//! # Health
+//!
+//! Reports status of API services with the `grpc.health.v1.Health` service, services
+//! are `NOT_SERVING` if a readiness check of a dependency they need fails, and all
+//! services are `NOT_SERVING` once shutdown begins so load balancers stop sending
+//! requests. The server status (empty service name) follows readiness
+use crate::internal::*;
+use futures::future::Future;
+use petshop_proto::api::{
+    audit_server::AuditServer, example_server::ExampleServer, petshop_server::PetshopServer,
+    tfb_server::TfbServer, webhook_server::WebhookServer,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::transport::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+/// Interval between readiness checks
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Name of server status
+const HEALTH_SERVER: &str = "";
+
+/// API services and names of readiness checks they need to pass to serve requests
+const HEALTH_SERVICES: &[(&str, &[&str])] = &[
+    (<ExampleServer<Api> as NamedService>::NAME, &[]),
+    (
+        <PetshopServer<Api> as NamedService>::NAME,
+        &["postgres", "migrations"],
+    ),
+    (<TfbServer<Api> as NamedService>::NAME, &["postgres"]),
+    (
+        <WebhookServer<Api> as NamedService>::NAME,
+        &["postgres", "migrations"],
+    ),
+    (
+        <AuditServer<Api> as NamedService>::NAME,
+        &["postgres", "migrations"],
+    ),
+];
+
+/// API Health
+#[derive(Debug)]
+pub struct ApiHealth {
+    reporter: HealthReporter,
+    statuses: HashMap<&'static str, ServingStatus>,
+}
+
+impl ApiHealth {
+    pub fn new(reporter: HealthReporter) -> Self {
+        Self {
+            reporter,
+            statuses: HashMap::new(),
+        }
+    }
+
+    /// Check readiness of dependencies and update status of services
+    pub async fn check(&mut self, api: &Api) {
+        let report = api.readiness_report().await;
+        let failed = report.failed();
+
+        for (name, dependencies) in HEALTH_SERVICES {
+            let status = if dependencies.iter().any(|x| failed.contains(x)) {
+                ServingStatus::NotServing
+            } else {
+                ServingStatus::Serving
+            };
+            self.status(name, status).await;
+        }
+        let status = if report.status != ReadinessStatus::Unready {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        self.status(HEALTH_SERVER, status).await;
+    }
+
+    /// Set status of all services to not serving
+    pub async fn shutdown(&mut self) {
+        for (name, _) in HEALTH_SERVICES {
+            self.status(name, ServingStatus::NotServing).await;
+        }
+        self.status(HEALTH_SERVER, ServingStatus::NotServing).await;
+    }
+
+    /// Set status of service if it has changed, so watchers are only notified of changes
+    async fn status(&mut self, name: &'static str, status: ServingStatus) {
+        if self.statuses.get(name) == Some(&status) {
+            return;
+        }
+        if self.statuses.contains_key(name) {
+            info!("health status of {:?} changed to {:?}", name, status);
+        }
+        self.statuses.insert(name, status);
+        self.reporter.set_service_status(name, status).await;
+    }
+}
+
+impl Api {
+    /// Check health periodically until shutdown, then set all services to not serving
+    pub async fn health_run(self, mut health: ApiHealth, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => health.check(&self).await,
+                _ = &mut shutdown => break,
+            }
+        }
+        health.shutdown().await;
+    }
+}
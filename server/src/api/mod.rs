@@ -2,22 +2,40 @@
 //!
 use crate::internal::*;
 use std::fmt;
-use tokio::sync::broadcast;
-use tonic::{Code, Status};
+use tonic::Status;
 
+pub use health::ApiHealth;
+
+mod audit;
 mod example;
+mod health;
 mod petshop;
+mod sse;
 mod tfb;
+mod webhook;
 
 /// API Server
 #[derive(Clone)]
 pub struct Api {
-    pub shutdown: Arc<broadcast::Sender<bool>>,
+    pub shutdown: Arc<Shutdown>,
     pub metrics: Arc<Metrics>,
     pub postgres: Arc<PostgresPool>,
     pub auth: Arc<Auth>,
+    pub audit: Arc<Audit>,
     pub clients: Arc<Clients>,
     pub csrf: Arc<Csrf>,
+    pub rate_limit: Arc<RateLimit>,
+    pub idempotency: Arc<Idempotency>,
+    pub limits: Arc<Limits>,
+    pub compression: Arc<Compression>,
+    pub sse: Arc<Sse>,
+    pub webhooks: Arc<Webhooks>,
+    pub pet_events: Arc<PetEvents>,
+    pub outbox: Arc<Outbox>,
+    pub github: Arc<Github>,
+    pub multipart: Arc<Multipart>,
+    pub storage: Arc<Storage>,
+    pub readiness: Arc<Readiness>,
 
     /// This is only here for TFB fortunes endpoint
     pub tfb_handlebars: Arc<handlebars::Handlebars<'static>>,
@@ -37,17 +55,67 @@ const TFB_FORTUNES_HTML: &str = "<!DOCTYPE html>
 </html>";
 
 impl Api {
-    pub fn from_config(
-        config: &Config,
-        shutdown_tx: broadcast::Sender<bool>,
-    ) -> Result<Self, XErr> {
-        let shutdown = Arc::new(shutdown_tx);
+    pub fn from_config(config: &Config) -> Result<Self, XErr> {
         let metrics = Arc::new(Metrics::from_config(config));
+        let shutdown = Arc::new(Shutdown::from_config(config, metrics.clone()));
         let postgres = Arc::new(PostgresPool::from_config(config, metrics.clone())?);
 
         let auth = Arc::new(Auth::from_config(config, postgres.clone()));
-        let clients = Arc::new(Clients::from_config(config)?);
+        let audit = Arc::new(Audit::from_config(config, postgres.clone(), auth.clone()));
+        let clients = Arc::new(Clients::from_config(config, metrics.clone())?);
         let csrf = Arc::new(Csrf::from_config(config, metrics.clone()));
+        let limits = Arc::new(Limits::from_config(config, metrics.clone()));
+        let compression = Arc::new(Compression::from_config(config, metrics.clone()));
+        let sse = Arc::new(Sse::from_config(config));
+        let rate_limit = Arc::new(RateLimit::from_config(
+            config,
+            metrics.clone(),
+            postgres.clone(),
+            shutdown.clone(),
+        ));
+        let idempotency = Arc::new(Idempotency::from_config(
+            config,
+            postgres.clone(),
+            shutdown.clone(),
+        ));
+        let webhooks = Arc::new(Webhooks::from_config(
+            config,
+            metrics.clone(),
+            postgres.clone(),
+            clients.clone(),
+            shutdown.clone(),
+        ));
+
+        let pet_events = Arc::new(PetEvents::from_config(
+            config,
+            metrics.clone(),
+            postgres.clone(),
+            shutdown.clone(),
+        ));
+
+        let outbox = Arc::new(Outbox::from_config(
+            config,
+            metrics.clone(),
+            postgres.clone(),
+            shutdown.clone(),
+            webhooks.clone(),
+        ));
+
+        let github = Arc::new(Github::from_config(config));
+        let multipart = Arc::new(Multipart::from_config(config, metrics.clone()));
+        let storage = Arc::new(Storage::from_config(config, metrics.clone()));
+
+        let readiness = Arc::new(Readiness::from_config(
+            config,
+            metrics.clone(),
+            vec![
+                shutdown.clone(),
+                postgres.clone(),
+                Arc::new(MigrationsCheck::new(postgres.clone())),
+                clients.clone(),
+                webhooks.clone(),
+            ],
+        ));
 
         let mut tfb_handlebars = handlebars::Handlebars::new();
         tfb_handlebars
@@ -59,8 +127,21 @@ impl Api {
             metrics,
             postgres,
             auth,
+            audit,
             clients,
             csrf,
+            rate_limit,
+            idempotency,
+            limits,
+            compression,
+            sse,
+            webhooks,
+            pet_events,
+            outbox,
+            github,
+            multipart,
+            storage,
+            readiness,
             tfb_handlebars: Arc::new(tfb_handlebars),
         })
     }
@@ -69,7 +150,11 @@ impl Api {
     ///
     /// This lets the application trigger a graceful exit rather than panicking
     pub fn _shutdown(&self) {
-        self.shutdown.send(true).expect("shutdown failed");
+        self.shutdown.signal();
+    }
+
+    pub fn shutdown(&self) -> Arc<Shutdown> {
+        self.shutdown.clone()
     }
 
     pub fn metrics(&self) -> Arc<Metrics> {
@@ -80,16 +165,52 @@ impl Api {
         self.csrf.clone()
     }
 
+    pub fn rate_limit(&self) -> Arc<RateLimit> {
+        self.rate_limit.clone()
+    }
+
+    pub fn idempotency(&self) -> Arc<Idempotency> {
+        self.idempotency.clone()
+    }
+
+    pub fn limits(&self) -> Arc<Limits> {
+        self.limits.clone()
+    }
+
+    pub fn compression(&self) -> Arc<Compression> {
+        self.compression.clone()
+    }
+
+    pub fn pet_events(&self) -> Arc<PetEvents> {
+        self.pet_events.clone()
+    }
+
+    pub fn outbox(&self) -> Arc<Outbox> {
+        self.outbox.clone()
+    }
+
+    pub fn webhooks(&self) -> Arc<Webhooks> {
+        self.webhooks.clone()
+    }
+
     /// Returns an error if requests can not be served
     ///
     /// [More information on liveness/readiness probes](https://blog.colinbreck.com/kubernetes-liveness-and-readiness-probes-how-to-avoid-shooting-yourself-in-the-foot/)
     pub async fn readiness(&self) -> Result<(), XErr> {
-        let postgres_ready = self.postgres.readiness().await;
-        self.metrics.api_ready(postgres_ready.is_ok());
-        postgres_ready?;
+        self.readiness.readiness().await?;
         Ok(())
     }
 
+    /// Returns readiness report with status of each dependency check
+    pub async fn readiness_report(&self) -> ReadinessReport {
+        self.readiness.report().await
+    }
+
+    /// Returns an error if server has not started
+    pub async fn startup(&self) -> Result<(), XErr> {
+        self.readiness.startup().await
+    }
+
     /// Validates request using derived validate method, logs validation errors
     /// and uses status details to return serialised errors to client
     ///
@@ -103,10 +224,7 @@ impl Api {
                 let anyhow_err: Error = err.clone().into();
                 warn!("{:#}", anyhow_err);
 
-                let status =
-                    tonic_status_with_details(Code::InvalidArgument, ERROR_VALIDATION, err)?;
-
-                Err(status)
+                Err(tonic_status_bad_request(&err))
             }
         }
     }
@@ -1,67 +1,500 @@
 //! # Petshop
 //!
 use crate::internal::*;
+use crate::services::{pet_json, PhotoError};
 use petshop_proto::api::petshop_server::Petshop;
-use petshop_proto::api::{Category, FindByStatus, FindByTag, Pet, Pets, Status as PetStatus, Tag};
-use tonic::{Request, Response, Status};
+use petshop_proto::api::{
+    FindByStatus, FindByTag, Pet, PetEvent, PetPhoto, PetPhotoChunk, PetPhotoQuery, PetSearchQuery,
+    PetSearchResult, PetUpdate, PetWatch, Pets,
+};
+use petshop_proto::google::api::HttpBody;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use validator::{ValidationError, ValidationErrors};
+
+/// Paths of pet fields which can be updated
+const PET_UPDATE_PATHS: &[&str] = &["category", "name", "photo_urls", "tags", "status"];
+
+/// Default and maximum number of pets per search page
+const PET_SEARCH_PAGE_SIZE: i32 = 20;
+const PET_SEARCH_PAGE_SIZE_MAX: i32 = 100;
+
+/// Maximum number of pet photos, matches `photo_urls` validation rules so uploads
+/// can not make pets invalid
+const PET_PHOTOS_MAX: usize = 32;
+
+impl Api {
+    /// Returns current pet with fields in paths replaced by fields of pet if its
+    /// version is current, the version is checked again when the pet is updated so
+    /// fields which are not in paths are not overwritten
+    async fn pet_merge_current(&self, pet: Pet, paths: &[String]) -> Result<Pet, Status> {
+        let current = self
+            .postgres
+            .pet_select(pet.id)
+            .await?
+            .ok_or_else(|| XErr::not_found("pet"))?;
+        if current.version != pet.version {
+            return Err(XErr::conflict("pet").into());
+        }
+        Ok(pet_merge(current, pet, paths))
+    }
+
+    /// Updates pet and records its changes, returns updated pet
+    async fn pet_update(&self, pet: &Pet, audit: &mut AuditEntry) -> Result<Pet, Status> {
+        let (previous, pet) = self
+            .postgres
+            .pet_update(pet)
+            .await?
+            .ok_or_else(|| XErr::not_found("pet"))?;
+        self.outbox.notify();
+        audit.changes(Some(pet_json(&previous)), Some(pet_json(&pet)));
+        Ok(pet)
+    }
+
+    /// Validates and stores photo, then appends its URL to pet
+    async fn pet_photo_put(
+        &self,
+        pet_id: i64,
+        content_type: &str,
+        data: Vec<u8>,
+        audit: &mut AuditEntry,
+    ) -> Result<Pet, Status> {
+        audit.target(pet_id);
+        let previous = self
+            .postgres
+            .pet_select(pet_id)
+            .await?
+            .ok_or_else(|| XErr::not_found("pet"))?;
+        if previous.photo_urls.len() >= PET_PHOTOS_MAX {
+            return Err(self
+                .storage
+                .photo_error(PhotoError::TooMany(PET_PHOTOS_MAX)));
+        }
+        let photo = self.storage.photo_put(pet_id, content_type, data).await?;
+        info!("pet {} photo {} stored", pet_id, photo.id);
+
+        // Photo is deleted if it can not be added to pet (e.g. pet was deleted or
+        // another photo was added after it was selected) so stored objects are not orphaned
+        let max_photos = PET_PHOTOS_MAX as i32;
+        let pet = match self
+            .postgres
+            .pet_photo_insert(pet_id, &photo, max_photos)
+            .await
+        {
+            Ok(Some(pet)) => pet,
+            result => {
+                self.storage.photo_delete(&photo).await;
+                result?;
+                return match self.postgres.pet_select(pet_id).await? {
+                    Some(_) => Err(self
+                        .storage
+                        .photo_error(PhotoError::TooMany(PET_PHOTOS_MAX))),
+                    None => Err(XErr::not_found("pet").into()),
+                };
+            }
+        };
+        self.outbox.notify();
+        audit.changes(Some(pet_json(&previous)), Some(pet_json(&pet)));
+        Ok(pet)
+    }
+}
 
 #[tonic::async_trait]
 impl Petshop for Api {
     #[tracing::instrument(skip(self))]
     async fn pet_post(&self, request: Request<Pet>) -> Result<Response<Pet>, Status> {
         info!("pet_post request");
-        Ok(Response::new(request.into_inner()))
+        let mut audit = self.audit.entry("/api.Petshop/PetPost", &request);
+
+        let result = async {
+            let pet = request.into_inner();
+            self.validate(&pet)?;
+            let pet = self.postgres.pet_insert(&pet).await?;
+            self.outbox.notify();
+            audit.target(pet.id);
+            audit.changes(None, Some(pet_json(&pet)));
+            Ok(pet)
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
     }
 
     #[tracing::instrument(skip(self))]
     async fn pet_put(&self, request: Request<Pet>) -> Result<Response<Pet>, Status> {
         info!("pet_put request");
-        Ok(Response::new(request.into_inner()))
+        let mut audit = self.audit.entry("/api.Petshop/PetPut", &request);
+
+        let result = async {
+            let pet = request.into_inner();
+            audit.target(pet.id);
+            self.validate(&pet)?;
+            let paths: Vec<String> = PET_UPDATE_PATHS.iter().map(|x| x.to_string()).collect();
+            let pet = self.pet_merge_current(pet, &paths).await?;
+            self.pet_update(&pet, &mut audit).await
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pet_patch(&self, request: Request<PetUpdate>) -> Result<Response<Pet>, Status> {
+        info!("pet_patch request");
+        let mut audit = self.audit.entry("/api.Petshop/PetPatch", &request);
+
+        let result = async {
+            let mut update = request.into_inner();
+            let paths = pet_update_paths(&update)?;
+            let pet = match update.pet.take() {
+                Some(pet) => pet,
+                None => {
+                    let mut errors = ValidationErrors::new();
+                    errors.add("pet", ValidationError::new("required"));
+                    return Err(tonic_status_bad_request(&errors));
+                }
+            };
+            audit.target(pet.id);
+            update.pet = Some(self.pet_merge_current(pet, &paths).await?);
+            self.validate(&update)?;
+            let pet = update.pet.expect("pet is merged");
+            self.pet_update(&pet, &mut audit).await
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
     }
 
     #[tracing::instrument(skip(self))]
     async fn pet_find_by_status(
         &self,
-        _request: Request<FindByStatus>,
+        request: Request<FindByStatus>,
     ) -> Result<Response<Pets>, Status> {
         info!("pet_find_by_status request");
-        let pet = Pet {
-            id: 1,
-            category: Some(Category {
-                id: 1,
-                name: "CategoryName1".to_string(),
-            }),
-            name: "PetName1".to_string(),
-            photo_urls: vec!["PhotoUrl1".to_string()],
-            tags: vec![Tag {
-                id: 1,
-                name: "TagName1".to_string(),
-            }],
-            status: PetStatus::Pending as i32,
+
+        let find = request.into_inner();
+        self.validate(&find)?;
+        let pets = self.postgres.pet_find_by_status(&find.status).await?;
+
+        Ok(Response::new(Pets { pets }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pet_find_by_tag(&self, request: Request<FindByTag>) -> Result<Response<Pets>, Status> {
+        info!("pet_find_by_tag request");
+
+        let find = request.into_inner();
+        self.validate(&find)?;
+        let pets = self.postgres.pet_find_by_tag(&find.tags).await?;
+
+        Ok(Response::new(Pets { pets }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pet_search(
+        &self,
+        request: Request<PetSearchQuery>,
+    ) -> Result<Response<PetSearchResult>, Status> {
+        info!("pet_search request");
+
+        let search = request.into_inner();
+        self.validate(&search)?;
+        let offset = match page_token_parse(&search) {
+            Some(offset) => offset,
+            None => {
+                let mut errors = ValidationErrors::new();
+                errors.add("page_token", ValidationError::new("invalid"));
+                return Err(tonic_status_bad_request(&errors));
+            }
         };
-        Ok(Response::new(Pets { pets: vec![pet] }))
+        let limit = if search.page_size > 0 {
+            search.page_size
+        } else {
+            PET_SEARCH_PAGE_SIZE
+        }
+        .clamp(1, PET_SEARCH_PAGE_SIZE_MAX) as i64;
+
+        let mut result = self.postgres.pet_search(&search, offset, limit).await?;
+        let next = offset + result.pets.len() as i64;
+        if !result.pets.is_empty() && next < result.total_size {
+            result.next_page_token = page_token_from_offset(&search, next);
+        }
+
+        Ok(Response::new(result))
     }
 
+    type WatchPetsStream = ReceiverStream<Result<PetEvent, Status>>;
+
     #[tracing::instrument(skip(self))]
-    async fn pet_find_by_tag(
+    async fn watch_pets(
         &self,
-        _request: Request<FindByTag>,
-    ) -> Result<Response<Pets>, Status> {
-        info!("pet_find_by_tag request");
-        let pet = Pet {
+        request: Request<PetWatch>,
+    ) -> Result<Response<Self::WatchPetsStream>, Status> {
+        info!("watch_pets request");
+
+        let watch = request.into_inner();
+        self.validate(&watch)?;
+        let after = self.pet_events.resume(&watch.resume_token).await?;
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(self.pet_events().watch(watch, after, tx));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn pet_photo_upload(&self, request: Request<PetPhoto>) -> Result<Response<Pet>, Status> {
+        info!("pet_photo_upload request");
+        let mut audit = self.audit.entry("/api.Petshop/PetPhotoUpload", &request);
+
+        let result = async {
+            let upload = request.into_inner();
+            audit.target(upload.pet_id);
+            self.validate(&upload)?;
+            let photo = upload.photo.unwrap_or_default();
+            let (content_type, data) = if Multipart::is_multipart(&photo.content_type) {
+                let file = self
+                    .multipart
+                    .parse(&photo)?
+                    .files
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| self.storage.photo_error(PhotoError::Empty))?;
+                (file.content_type, file.data)
+            } else {
+                (photo.content_type, photo.data)
+            };
+            self.pet_photo_put(upload.pet_id, &content_type, data, &mut audit)
+                .await
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn pet_photo_upload_stream(
+        &self,
+        request: Request<Streaming<PetPhotoChunk>>,
+    ) -> Result<Response<Pet>, Status> {
+        info!("pet_photo_upload_stream request");
+        let mut audit = self
+            .audit
+            .entry("/api.Petshop/PetPhotoUploadStream", &request);
+
+        let result = async {
+            let max_bytes = self.storage.max_photo_bytes();
+            let mut stream = request.into_inner();
+            // First chunk is validated before buffering the rest of the stream
+            let mut chunk = stream.message().await?.unwrap_or_default();
+            pet_photo_chunk_validate(&chunk)?;
+            let pet_id = chunk.pet_id;
+            let content_type = std::mem::take(&mut chunk.content_type);
+            let mut data = Vec::new();
+            loop {
+                if data.len() + chunk.data.len() > max_bytes {
+                    return Err(self.storage.photo_error(PhotoError::TooLarge(max_bytes)));
+                }
+                data.extend_from_slice(&chunk.data);
+                chunk = match stream.message().await? {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+            }
+
+            self.pet_photo_put(pet_id, &content_type, data, &mut audit)
+                .await
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pet_photo_get(
+        &self,
+        request: Request<PetPhotoQuery>,
+    ) -> Result<Response<HttpBody>, Status> {
+        info!("pet_photo_get request");
+
+        let get = request.into_inner();
+        self.validate(&get)?;
+        let (content_type, key) = self
+            .postgres
+            .pet_photo_select(get.pet_id, &get.photo_id)
+            .await?
+            .ok_or_else(|| XErr::not_found("pet_photo"))?;
+        let data = self
+            .storage
+            .get(&key)
+            .await?
+            .ok_or_else(|| XErr::not_found("pet_photo"))?;
+
+        Ok(Response::new(HttpBody {
+            content_type,
+            data,
+            extensions: vec![],
+        }))
+    }
+}
+
+/// Returns page token of offset, which is bound to search by a hash of its fields
+/// other than page token and size
+fn page_token_from_offset(search: &PetSearchQuery, offset: i64) -> String {
+    let mut token = offset.to_be_bytes().to_vec();
+    token.extend_from_slice(&page_token_search_hash(search));
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Returns offset of page token of search, or 0 if it is empty
+fn page_token_parse(search: &PetSearchQuery) -> Option<i64> {
+    if search.page_token.is_empty() {
+        return Some(0);
+    }
+    let token = base64::decode_config(&search.page_token, base64::URL_SAFE_NO_PAD).ok()?;
+    if token.len() != 16 || token[8..] != page_token_search_hash(search) {
+        return None;
+    }
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&token[..8]);
+    Some(i64::from_be_bytes(offset)).filter(|x| *x > 0)
+}
+
+fn page_token_search_hash(search: &PetSearchQuery) -> [u8; 8] {
+    let search = PetSearchQuery {
+        page_token: String::new(),
+        page_size: 0,
+        ..search.clone()
+    };
+    let hash = Sha256::digest(&prost_encode(&search));
+    let mut x = [0u8; 8];
+    x.copy_from_slice(&hash[..8]);
+    x
+}
+
+/// Validates first chunk of photo upload stream, which must have pet id and content type
+fn pet_photo_chunk_validate(chunk: &PetPhotoChunk) -> Result<(), Status> {
+    let mut errors = ValidationErrors::new();
+    if chunk.pet_id <= 0 {
+        errors.add("pet_id", ValidationError::new("required"));
+    }
+    if chunk.content_type.is_empty() {
+        errors.add("content_type", ValidationError::new("required"));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(tonic_status_bad_request(&errors))
+    }
+}
+
+/// Returns paths of update mask, or all paths if it is empty
+fn pet_update_paths(update: &PetUpdate) -> Result<Vec<String>, Status> {
+    let paths = update
+        .update_mask
+        .as_ref()
+        .map(|x| x.paths.clone())
+        .unwrap_or_default();
+    if paths.is_empty() {
+        return Ok(PET_UPDATE_PATHS.iter().map(|x| x.to_string()).collect());
+    }
+    if paths
+        .iter()
+        .any(|x| !PET_UPDATE_PATHS.contains(&x.as_str()))
+    {
+        let mut errors = ValidationErrors::new();
+        errors.add("update_mask", ValidationError::new("paths"));
+        return Err(tonic_status_bad_request(&errors));
+    }
+    Ok(paths)
+}
+
+/// Returns current pet with fields in paths replaced by fields of update
+fn pet_merge(current: Pet, update: Pet, paths: &[String]) -> Pet {
+    let mut pet = current;
+    for path in paths {
+        match path.as_str() {
+            "category" => pet.category = update.category.clone(),
+            "name" => pet.name = update.name.clone(),
+            "photo_urls" => pet.photo_urls = update.photo_urls.clone(),
+            "tags" => pet.tags = update.tags.clone(),
+            "status" => pet.status = update.status,
+            _ => {}
+        }
+    }
+    pet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petshop_proto::api::Tag;
+
+    #[test]
+    fn page_token_test() {
+        let search = |query: &str, page_token: String| PetSearchQuery {
+            query: query.to_string(),
+            page_token,
+            page_size: 10,
+            ..Default::default()
+        };
+        assert_eq!(page_token_parse(&search("dog", String::new())), Some(0));
+        let token = page_token_from_offset(&search("dog", String::new()), 20);
+        assert_eq!(page_token_parse(&search("dog", token.clone())), Some(20));
+        assert_eq!(page_token_parse(&search("cat", token)), None);
+        let token = page_token_from_offset(&search("dog", String::new()), 0);
+        assert_eq!(page_token_parse(&search("dog", token)), None);
+        assert_eq!(page_token_parse(&search("dog", "20".to_string())), None);
+        assert_eq!(page_token_parse(&search("dog", "token".to_string())), None);
+    }
+
+    #[test]
+    fn pet_merge_test() {
+        let current = Pet {
             id: 1,
-            category: Some(Category {
-                id: 1,
-                name: "CategoryName2".to_string(),
-            }),
-            name: "PetName2".to_string(),
-            photo_urls: vec!["PhotoUrl2".to_string()],
+            name: "rex".to_string(),
+            photo_urls: vec!["https://example.com/a.png".to_string()],
             tags: vec![Tag {
                 id: 1,
-                name: "TagName2".to_string(),
+                name: "dog".to_string(),
             }],
-            status: PetStatus::Pending as i32,
+            status: 0,
+            version: 3,
+            ..Default::default()
+        };
+        let update = Pet {
+            id: 1,
+            status: 2,
+            version: 3,
+            ..Default::default()
+        };
+
+        let mut request = PetUpdate {
+            pet: Some(update.clone()),
+            update_mask: Some(prost_types::FieldMask {
+                paths: vec!["status".to_string()],
+            }),
         };
-        Ok(Response::new(Pets { pets: vec![pet] }))
+        let paths = pet_update_paths(&request).unwrap();
+        let pet = pet_merge(current.clone(), update.clone(), &paths);
+        assert_eq!(pet.status, 2);
+        assert_eq!(pet.name, "rex");
+        assert_eq!(pet.tags, current.tags);
+
+        request.update_mask = None;
+        let paths = pet_update_paths(&request).unwrap();
+        assert_eq!(pet_merge(current, update.clone(), &paths), update);
+
+        request.update_mask = Some(prost_types::FieldMask {
+            paths: vec!["id".to_string()],
+        });
+        assert!(pet_update_paths(&request).is_err());
     }
 }
@@ -0,0 +1,192 @@
+//! # Server-Sent Events
+//!
+//! Server streaming RPCs served as server-sent events by the `SseService`, request
+//! messages are parsed from query string parameters, repeated fields use repeated
+//! parameters (e.g. `?status=AVAILABLE&status=PENDING&tags=dog`)
+use crate::internal::*;
+use crate::services::{errors_locale, pet_json};
+use futures::StreamExt;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use petshop_proto::api::example_server::Example;
+use petshop_proto::api::petshop_server::Petshop;
+use petshop_proto::api::{Echo, PetEvent, PetEventType, PetWatch, Status as PetStatus};
+use std::collections::HashMap;
+use tonic::body::BoxBody;
+use tonic::{Request, Status};
+
+const SSE_EXAMPLE_STREAMING: &str = "/api.Example/Streaming";
+const SSE_PETSHOP_WATCH_PETS: &str = "/api.Petshop/WatchPets";
+
+/// Query string parameters, repeated parameters have multiple values
+type SseQuery = HashMap<String, Vec<String>>;
+
+impl Api {
+    /// Returns true if path is a server streaming RPC served as server-sent events
+    pub fn sse_route(path: &str) -> bool {
+        [SSE_EXAMPLE_STREAMING, SSE_PETSHOP_WATCH_PETS].contains(&path)
+    }
+
+    /// Handle server-sent events request by calling the RPC request handler
+    #[tracing::instrument(skip(self, req))]
+    pub async fn sse_request_handler(&self, req: HyperRequest<Body>) -> HyperResponse<BoxBody> {
+        let locale = errors_locale(
+            req.headers()
+                .get(http::header::ACCEPT_LANGUAGE)
+                .and_then(|x| x.to_str().ok()),
+        );
+        let last_event_id = self.sse.last_event_id(req.headers());
+        let query = sse_query(req.uri().query());
+        let (parts, _) = req.into_parts();
+        info!("sse request {}", parts.uri.path());
+
+        let response = match parts.uri.path() {
+            SSE_EXAMPLE_STREAMING => {
+                let echo = Echo {
+                    message: sse_query_value(&query, "message"),
+                };
+                self.sse_streaming(locale, parts, echo, last_event_id).await
+            }
+            SSE_PETSHOP_WATCH_PETS => {
+                let watch = PetWatch {
+                    status: sse_query_values(&query, "status")
+                        .iter()
+                        .map(|x| pet_status_parse(x))
+                        .collect(),
+                    tags: sse_query_values(&query, "tags"),
+                    // Resume token parameter is used by the first request, then
+                    // reconnecting clients send the id of the last event received
+                    resume_token: last_event_id
+                        .unwrap_or_else(|| sse_query_value(&query, "resume_token")),
+                };
+                self.sse_watch_pets(locale, parts, watch).await
+            }
+            path => Err(XErr::not_found(path).into()),
+        };
+        response.unwrap_or_else(|status| self.sse.error_response(locale, &status))
+    }
+
+    /// Echo events are numbered, reconnecting clients skip events already received
+    async fn sse_streaming(
+        &self,
+        locale: &'static str,
+        parts: http::request::Parts,
+        echo: Echo,
+        last_event_id: Option<String>,
+    ) -> Result<HyperResponse<BoxBody>, Status> {
+        let skip = last_event_id
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+        let stream = Example::streaming(self, sse_request(parts, echo))
+            .await?
+            .into_inner()
+            .enumerate()
+            .skip(skip)
+            .map(|(i, echo)| {
+                echo.map(|echo| SseEvent {
+                    id: (i + 1).to_string(),
+                    data: json!({ "message": echo.message }),
+                })
+            });
+        Ok(self.sse.response(locale, stream))
+    }
+
+    /// Pet events use resume tokens as ids
+    async fn sse_watch_pets(
+        &self,
+        locale: &'static str,
+        parts: http::request::Parts,
+        watch: PetWatch,
+    ) -> Result<HyperResponse<BoxBody>, Status> {
+        let stream = Petshop::watch_pets(self, sse_request(parts, watch))
+            .await?
+            .into_inner()
+            .map(|event| {
+                event.map(|event| SseEvent {
+                    id: event.resume_token.clone(),
+                    data: pet_event_json(&event),
+                })
+            });
+        Ok(self.sse.response(locale, stream))
+    }
+}
+
+/// Returns tonic request with HTTP request headers as metadata
+fn sse_request<T>(parts: http::request::Parts, message: T) -> Request<T> {
+    Request::from_http(HyperRequest::from_parts(parts, message))
+}
+
+/// Returns query string parameters, empty values are ignored so `?tags=` is not a filter
+fn sse_query(query: Option<&str>) -> SseQuery {
+    let mut values = SseQuery::new();
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        if value.is_empty() {
+            continue;
+        }
+        values
+            .entry(key.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    values
+}
+
+fn sse_query_value(query: &SseQuery, key: &str) -> String {
+    query
+        .get(key)
+        .and_then(|x| x.first())
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn sse_query_values(query: &SseQuery, key: &str) -> Vec<String> {
+    query.get(key).cloned().unwrap_or_default()
+}
+
+/// Returns status value from name, unknown names return an invalid value
+/// which fails validation
+fn pet_status_parse(name: &str) -> i32 {
+    (0..)
+        .map_while(PetStatus::from_i32)
+        .find(|x| format!("{:?}", x).eq_ignore_ascii_case(name))
+        .map(|x| x as i32)
+        .unwrap_or(-1)
+}
+
+fn pet_event_json(event: &PetEvent) -> serde_json::Value {
+    let event_type = PetEventType::from_i32(event.r#type).unwrap_or(PetEventType::Created);
+    json!({
+        "type": format!("{:?}", event_type).to_uppercase(),
+        "pet": event.pet.as_ref().map(pet_json),
+        "resume_token": event.resume_token,
+        "created_at": event
+            .created_at
+            .clone()
+            .and_then(prost_timestamp_into_chrono)
+            .map(|x| x.to_rfc3339()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_query_test() {
+        let query = sse_query(Some(
+            "status=sold&status=PENDING&tags=dog&tags=&message=hello%20world",
+        ));
+        assert_eq!(sse_query_value(&query, "message"), "hello world");
+        assert_eq!(sse_query_values(&query, "tags"), vec!["dog"]);
+        assert!(sse_query_values(&query, "other").is_empty());
+
+        let status: Vec<i32> = sse_query_values(&query, "status")
+            .iter()
+            .map(|x| pet_status_parse(x))
+            .collect();
+        assert_eq!(
+            status,
+            vec![PetStatus::Sold as i32, PetStatus::Pending as i32]
+        );
+        assert_eq!(pet_status_parse("unknown"), -1);
+    }
+}
@@ -35,13 +35,7 @@ impl Tfb for Api {
     #[tracing::instrument(skip(self))]
     async fn tfb_queries(&self, request: Request<Queries>) -> Result<Response<ListValue>, Status> {
         let queries = request.into_inner().queries;
-        let queries = if queries < 1 {
-            1
-        } else if queries > 500 {
-            500
-        } else {
-            queries
-        };
+        let queries = queries.clamp(1, 500);
         let worlds = self.postgres.db_world_queries(queries).await?;
 
         let values: Vec<Value> = worlds
@@ -91,13 +85,7 @@ impl Tfb for Api {
     #[tracing::instrument(skip(self))]
     async fn tfb_updates(&self, request: Request<Queries>) -> Result<Response<ListValue>, Status> {
         let queries = request.into_inner().queries;
-        let queries = if queries < 1 {
-            1
-        } else if queries > 500 {
-            500
-        } else {
-            queries
-        };
+        let queries = queries.clamp(1, 500);
         let worlds = self.postgres.db_world_updates(queries).await?;
 
         let values: Vec<Value> = worlds
@@ -0,0 +1,118 @@
+//! # Webhook
+//!
+use crate::internal::*;
+use petshop_proto::api::webhook_server::Webhook;
+use petshop_proto::api::{
+    WebhookDeliveries, WebhookDelivery, WebhookDeliveryQuery, WebhookRedeliver, WebhookSubscriber,
+};
+use tonic::{Request, Response, Status};
+use validator::{ValidationError, ValidationErrors};
+
+/// Length of generated subscriber secrets
+const WEBHOOK_SECRET_LENGTH: usize = 32;
+
+#[tonic::async_trait]
+impl Webhook for Api {
+    #[tracing::instrument(skip(self, request))]
+    async fn webhook_subscriber_post(
+        &self,
+        request: Request<WebhookSubscriber>,
+    ) -> Result<Response<WebhookSubscriber>, Status> {
+        info!("webhook_subscriber_post request");
+        let mut audit = self
+            .audit
+            .entry("/api.Webhook/WebhookSubscriberPost", &request);
+
+        let result = async {
+            self.auth.api_or_user(&request).await?;
+
+            let mut subscriber = request.into_inner();
+            self.validate(&subscriber)?;
+            if let Err(err) = self.webhooks.destination_check(&subscriber.url).await {
+                let err: Error = err.into();
+                info!("webhook subscriber destination rejected: {:#}", err);
+                let mut errors = ValidationErrors::new();
+                errors.add("url", ValidationError::new("destination"));
+                return Err(tonic_status_bad_request(&errors));
+            }
+
+            // Secret is returned in this response only, generate one if not provided
+            if subscriber.secret.is_empty() {
+                subscriber.secret = random_string(WEBHOOK_SECRET_LENGTH);
+            }
+            subscriber.active = true;
+            let subscriber = self.postgres.webhook_subscriber_insert(subscriber).await?;
+            audit.target(subscriber.id);
+            audit.changes(None, Some(webhook_subscriber_json(&subscriber)));
+            Ok(subscriber)
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn webhook_delivery_list(
+        &self,
+        request: Request<WebhookDeliveryQuery>,
+    ) -> Result<Response<WebhookDeliveries>, Status> {
+        info!("webhook_delivery_list request");
+        self.auth.api_or_user(&request).await?;
+
+        let query = request.into_inner();
+        let subscriber_id = if query.subscriber_id > 0 {
+            Some(query.subscriber_id)
+        } else {
+            None
+        };
+        let limit = if query.limit > 0 { query.limit } else { 50 }.clamp(1, 500);
+        let deliveries = self
+            .postgres
+            .webhook_delivery_list(subscriber_id, None, limit as i64)
+            .await?;
+
+        Ok(Response::new(WebhookDeliveries { deliveries }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn webhook_delivery_redeliver(
+        &self,
+        request: Request<WebhookRedeliver>,
+    ) -> Result<Response<WebhookDelivery>, Status> {
+        info!("webhook_delivery_redeliver request");
+        let mut audit = self
+            .audit
+            .entry("/api.Webhook/WebhookDeliveryRedeliver", &request);
+
+        let result = async {
+            self.auth.api_or_user(&request).await?;
+
+            let redeliver = request.into_inner();
+            audit.target(redeliver.id);
+            self.validate(&redeliver)?;
+            let id = redeliver.id;
+            if self.webhooks.redeliver(id).await?.is_none() {
+                return Err(XErr::not_found("webhook_delivery").into());
+            }
+            self.postgres
+                .webhook_delivery_list(None, Some(id), 1)
+                .await?
+                .pop()
+                .ok_or_else(|| XErr::not_found("webhook_delivery").into())
+        }
+        .await;
+        self.audit.record(audit, &result).await;
+
+        Ok(Response::new(result?))
+    }
+}
+
+fn webhook_subscriber_json(subscriber: &WebhookSubscriber) -> serde_json::Value {
+    json!({
+        "id": subscriber.id,
+        "url": subscriber.url,
+        "event_types": subscriber.event_types,
+        "active": subscriber.active,
+    })
+}
@@ -1,6 +1,8 @@
 //! # Configuration
 //!
 use crate::internal::*;
+use crate::services::{request_id_current, Redact, RedactConfig, RedactWriter};
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
 use tracing_subscriber::fmt::time::ChronoUtc;
@@ -13,17 +15,208 @@ use tracing_subscriber::EnvFilter;
 #[derive(Debug, Clone)]
 pub struct Config {
     pub tracing_json: bool,
+    pub redact: RedactConfig,
     pub api_addr: SocketAddr,
     pub internal_addr: SocketAddr,
     pub metrics_name: String,
     pub csrf: Option<CsrfConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub limits: LimitsConfig,
+    pub compression: CompressionConfig,
+    pub transport: TransportConfig,
+    pub sse: SseConfig,
     pub clients: ClientsConfig,
+    pub webhooks: WebhooksConfig,
+    pub pet_events: PetEventsConfig,
+    pub outbox: OutboxConfig,
+    pub audit: AuditConfig,
+    pub idempotency: IdempotencyConfig,
+    pub readiness: ReadinessConfig,
+    pub shutdown: ShutdownConfig,
+    pub github: Option<GithubConfig>,
+    pub multipart: MultipartConfig,
+    pub storage: StorageConfig,
     pub postgres: deadpool_postgres::Config,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Transport Configuration
+///
+/// TCP and HTTP/2 options of API server, undefined options use hyper defaults
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive_seconds: Option<u64>,
+    pub http2_keepalive_interval_seconds: Option<u64>,
+    pub http2_keepalive_timeout_seconds: Option<u64>,
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub http2_initial_stream_window_size: Option<u32>,
+    pub http2_initial_connection_window_size: Option<u32>,
+    pub http2_max_frame_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 struct ClientsConfigLoad {
     http_timeout_seconds: Option<u64>,
+    default_policy: Option<ClientsPolicyConfigLoad>,
+    policies: Option<Vec<ClientsPolicyConfigLoad>>,
+    cache: Option<ClientsCacheConfigLoad>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ClientsCacheConfigLoad {
+    max_bytes: Option<usize>,
+    max_entry_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ClientsPolicyConfigLoad {
+    host: Option<String>,
+    retries: Option<u32>,
+    retry_idempotent_only: Option<bool>,
+    backoff_base_millis: Option<u64>,
+    backoff_max_millis: Option<u64>,
+    breaker_failure_threshold: Option<u32>,
+    breaker_open_seconds: Option<u64>,
+    concurrency_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct LimitsConfigLoad {
+    concurrency_limit_per_connection: Option<usize>,
+    concurrency_limit: Option<usize>,
+    queue_limit: Option<usize>,
+    request_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct CompressionConfigLoad {
+    encodings: Option<Vec<String>>,
+    level: Option<u32>,
+    min_bytes: Option<usize>,
+    max_message_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct TransportConfigLoad {
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive_seconds: Option<u64>,
+    http2_keepalive_interval_seconds: Option<u64>,
+    http2_keepalive_timeout_seconds: Option<u64>,
+    http2_max_concurrent_streams: Option<u32>,
+    http2_initial_stream_window_size: Option<u32>,
+    http2_initial_connection_window_size: Option<u32>,
+    http2_max_frame_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RateLimitConfigLoad {
+    backend: Option<String>,
+    forwarded_trusted_hops: Option<usize>,
+    default_policy: Option<RateLimitPolicyConfigLoad>,
+    policies: Option<Vec<RateLimitPolicyConfigLoad>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RateLimitPolicyConfigLoad {
+    method: Option<String>,
+    requests_per_second: Option<f64>,
+    burst: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct WebhooksConfigLoad {
+    max_attempts: Option<u32>,
+    backoff_base_seconds: Option<u64>,
+    backoff_max_seconds: Option<u64>,
+    backlog_max: Option<i64>,
+    delivery_timeout_seconds: Option<u64>,
+    allow_private_destinations: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct SseConfigLoad {
+    heartbeat_seconds: Option<u64>,
+    retry_millis: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct PetEventsConfigLoad {
+    channel_capacity: Option<usize>,
+    replay_batch_size: Option<i64>,
+    retention_hours: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct OutboxConfigLoad {
+    sinks: Option<Vec<String>>,
+    broadcast_capacity: Option<usize>,
+    file_path: Option<String>,
+    poll_interval_millis: Option<u64>,
+    batch_size: Option<i64>,
+    retention_hours: Option<u64>,
+    max_attempts: Option<i32>,
+    backoff_base_seconds: Option<u64>,
+    backoff_max_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RedactConfigLoad {
+    fields: Option<Vec<String>>,
+    metadata_keys: Option<Vec<String>>,
+    patterns: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct IdempotencyConfigLoad {
+    methods: Option<Vec<String>>,
+    ttl_hours: Option<u64>,
+    lock_seconds: Option<u64>,
+    max_body_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AuditConfigLoad {
+    admins: Option<Vec<String>>,
+    masked_fields: Option<Vec<String>>,
+    forwarded_trusted_hops: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ShutdownConfigLoad {
+    pre_stop_delay_seconds: Option<u64>,
+    grace_period_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ReadinessConfigLoad {
+    timeout_millis: Option<u64>,
+    checks: Option<HashMap<String, ReadinessCheckConfigLoad>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ReadinessCheckConfigLoad {
+    criticality: Option<String>,
+    timeout_millis: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct MultipartConfigLoad {
+    max_bytes: Option<usize>,
+    max_part_bytes: Option<usize>,
+    max_parts: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct StorageConfigLoad {
+    backend: Option<String>,
+    path: Option<String>,
+    public_url: Option<String>,
+    max_photo_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct GithubConfigLoad {
+    webhook_secret: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -49,6 +242,7 @@ struct CsrfConfigLoad {
 #[derive(Debug, Clone, Deserialize)]
 struct ConfigLoad {
     tracing_json: Option<bool>,
+    redact: Option<RedactConfigLoad>,
     api_host: Option<String>,
     api_port: Option<u16>,
     internal_host: Option<String>,
@@ -56,6 +250,21 @@ struct ConfigLoad {
     metrics_name: Option<String>,
     clients: Option<ClientsConfigLoad>,
     csrf: Option<CsrfConfigLoad>,
+    rate_limit: Option<RateLimitConfigLoad>,
+    limits: Option<LimitsConfigLoad>,
+    compression: Option<CompressionConfigLoad>,
+    transport: Option<TransportConfigLoad>,
+    sse: Option<SseConfigLoad>,
+    webhooks: Option<WebhooksConfigLoad>,
+    pet_events: Option<PetEventsConfigLoad>,
+    outbox: Option<OutboxConfigLoad>,
+    audit: Option<AuditConfigLoad>,
+    idempotency: Option<IdempotencyConfigLoad>,
+    readiness: Option<ReadinessConfigLoad>,
+    shutdown: Option<ShutdownConfigLoad>,
+    github: Option<GithubConfigLoad>,
+    multipart: Option<MultipartConfigLoad>,
+    storage: Option<StorageConfigLoad>,
     postgres: Option<deadpool_postgres::Config>,
 }
 
@@ -67,6 +276,36 @@ impl TryFrom<ConfigLoad> for Config {
         // Warnings/other information can be printed here to inform users about options
         // Can't use log macros here because tracing has not been initialised yet
         let tracing_json = Config::opt_or_default("tracing_json", value.tracing_json, false);
+        let redact = value.redact.unwrap_or_default();
+        let redact = RedactConfig {
+            fields: Self::opt_or_default(
+                "redact.fields",
+                redact.fields,
+                ["email", "password", "secret", "token", "api_key"]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            metadata_keys: Self::opt_or_default(
+                "redact.metadata_keys",
+                redact.metadata_keys,
+                ["authorization", "cookie", "set-cookie", "x-xsrf-token"]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            patterns: Self::opt_or_default(
+                "redact.patterns",
+                redact.patterns,
+                vec![
+                    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string(),
+                    r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+".to_string(),
+                ],
+            ),
+        };
+        if Redact::from_config(&redact).is_err() {
+            return Err(XErr::config("redact is invalid").into());
+        }
         let api_host = Config::opt_or_default("api_host", value.api_host, "127.0.0.1".to_string());
         let api_port = Config::opt_or_default("api_port", value.api_port, 5000);
         let api_addr: SocketAddr = format!("{}:{}", api_host, api_port).parse()?;
@@ -80,21 +319,307 @@ impl TryFrom<ConfigLoad> for Config {
         let metrics_name =
             Config::opt_or_default("metrics_name", value.metrics_name, NAME.to_string());
 
-        let clients = if let Some(clients) = value.clients {
-            let http_timeout_seconds = Self::opt_or_default(
-                "clients.http_timeout_seconds",
-                clients.http_timeout_seconds,
-                60,
+        let clients = value.clients.unwrap_or_default();
+        let http_timeout_seconds = Self::opt_or_default(
+            "clients.http_timeout_seconds",
+            clients.http_timeout_seconds,
+            60,
+        );
+        let default_policy = Self::clients_policy(
+            "clients.default_policy",
+            clients.default_policy.unwrap_or_default(),
+            &ClientsPolicyConfig {
+                host: None,
+                retries: 2,
+                retry_idempotent_only: true,
+                backoff_base_millis: 100,
+                backoff_max_millis: 2000,
+                breaker_failure_threshold: 5,
+                breaker_open_seconds: 30,
+                concurrency_limit: 64,
+            },
+        );
+        // Per host policies use the default policy for undefined values
+        let mut policies = Vec::new();
+        for (i, policy) in clients.policies.unwrap_or_default().into_iter().enumerate() {
+            let name = format!("clients.policies[{}]", i);
+            let host = match policy.host.as_ref() {
+                Some(host) => host.to_lowercase(),
+                None => {
+                    return Err(XErr::config(&format!("{}.host is not configured", name)).into())
+                }
+            };
+            let mut policy = Self::clients_policy(&name, policy, &default_policy);
+            policy.host = Some(host);
+            policies.push(policy);
+        }
+        let cache = if let Some(cache) = clients.cache {
+            let max_bytes =
+                Self::opt_or_default("clients.cache.max_bytes", cache.max_bytes, 16 * 1024 * 1024);
+            let max_entry_bytes = Self::opt_or_default(
+                "clients.cache.max_entry_bytes",
+                cache.max_entry_bytes,
+                1024 * 1024,
             );
-            ClientsConfig {
-                http_timeout_seconds,
-            }
+            Some(ClientsCacheConfig {
+                max_bytes,
+                max_entry_bytes,
+            })
         } else {
-            let http_timeout_seconds =
-                Self::opt_or_default("clients.http_timeout_seconds", None, 60);
-            ClientsConfig {
-                http_timeout_seconds,
-            }
+            println!("Config: clients.cache is not configured, defaulting to disabled");
+            None
+        };
+        let clients = ClientsConfig {
+            http_timeout_seconds,
+            default_policy,
+            policies,
+            cache,
+        };
+
+        let webhooks = value.webhooks.unwrap_or_default();
+        let webhooks = WebhooksConfig {
+            max_attempts: Self::opt_or_default("webhooks.max_attempts", webhooks.max_attempts, 5),
+            backoff_base_seconds: Self::opt_or_default(
+                "webhooks.backoff_base_seconds",
+                webhooks.backoff_base_seconds,
+                10,
+            ),
+            backoff_max_seconds: Self::opt_or_default(
+                "webhooks.backoff_max_seconds",
+                webhooks.backoff_max_seconds,
+                600,
+            ),
+            backlog_max: Self::opt_or_default("webhooks.backlog_max", webhooks.backlog_max, 1000),
+            delivery_timeout_seconds: Self::opt_or_default(
+                "webhooks.delivery_timeout_seconds",
+                webhooks.delivery_timeout_seconds,
+                900,
+            ),
+            allow_private_destinations: Self::opt_or_default(
+                "webhooks.allow_private_destinations",
+                webhooks.allow_private_destinations,
+                false,
+            ),
+        };
+        // Deliveries must be leased for longer than they wait before a retry
+        if webhooks.max_attempts == 0
+            || webhooks.delivery_timeout_seconds <= webhooks.backoff_max_seconds
+        {
+            return Err(XErr::config("webhooks is invalid").into());
+        }
+
+        let pet_events = value.pet_events.unwrap_or_default();
+        let pet_events = PetEventsConfig {
+            channel_capacity: Self::opt_or_default(
+                "pet_events.channel_capacity",
+                pet_events.channel_capacity,
+                1024,
+            ),
+            replay_batch_size: Self::opt_or_default(
+                "pet_events.replay_batch_size",
+                pet_events.replay_batch_size,
+                500,
+            ),
+            retention_hours: Self::opt_or_default(
+                "pet_events.retention_hours",
+                pet_events.retention_hours,
+                24,
+            ),
+        };
+        if pet_events.channel_capacity == 0 || pet_events.replay_batch_size <= 0 {
+            return Err(XErr::config("pet_events is invalid").into());
+        }
+
+        let outbox = value.outbox.unwrap_or_default();
+        let mut outbox_sinks = Vec::new();
+        for sink in Self::opt_or_default("outbox.sinks", outbox.sinks, vec!["webhooks".to_string()])
+        {
+            let sink = match sink.as_ref() {
+                "broadcast" => OutboxSinkConfig::Broadcast {
+                    capacity: Self::opt_or_default(
+                        "outbox.broadcast_capacity",
+                        outbox.broadcast_capacity,
+                        1024,
+                    ),
+                },
+                "webhooks" => OutboxSinkConfig::Webhooks,
+                "file" => OutboxSinkConfig::File {
+                    path: Self::opt("outbox.file_path", outbox.file_path.clone()),
+                },
+                _ => return Err(XErr::config("outbox.sinks is not supported").into()),
+            };
+            outbox_sinks.push(sink);
+        }
+        let outbox = OutboxConfig {
+            sinks: outbox_sinks,
+            poll_interval_millis: Self::opt_or_default(
+                "outbox.poll_interval_millis",
+                outbox.poll_interval_millis,
+                1000,
+            ),
+            batch_size: Self::opt_or_default("outbox.batch_size", outbox.batch_size, 100),
+            retention_hours: Self::opt_or_default(
+                "outbox.retention_hours",
+                outbox.retention_hours,
+                168,
+            ),
+            max_attempts: Self::opt_or_default("outbox.max_attempts", outbox.max_attempts, 10),
+            backoff_base_seconds: Self::opt_or_default(
+                "outbox.backoff_base_seconds",
+                outbox.backoff_base_seconds,
+                1,
+            ),
+            backoff_max_seconds: Self::opt_or_default(
+                "outbox.backoff_max_seconds",
+                outbox.backoff_max_seconds,
+                300,
+            ),
+        };
+        let outbox_invalid = outbox.poll_interval_millis == 0
+            || outbox.batch_size <= 0
+            || outbox.max_attempts <= 0
+            || outbox.backoff_base_seconds == 0
+            || outbox.backoff_base_seconds > outbox.backoff_max_seconds
+            || outbox
+                .sinks
+                .contains(&OutboxSinkConfig::Broadcast { capacity: 0 });
+        if outbox_invalid {
+            return Err(XErr::config("outbox is invalid").into());
+        }
+
+        let audit = value.audit.unwrap_or_default();
+        let audit = AuditConfig {
+            admins: Self::opt_or_default("audit.admins", audit.admins, vec![]),
+            masked_fields: Self::opt_or_default(
+                "audit.masked_fields",
+                audit.masked_fields,
+                ["secret", "password", "token", "api_key", "authorization"]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            forwarded_trusted_hops: Self::opt_or_default(
+                "audit.forwarded_trusted_hops",
+                audit.forwarded_trusted_hops,
+                0,
+            ),
+        };
+
+        let idempotency = value.idempotency.unwrap_or_default();
+        let idempotency = IdempotencyConfig {
+            methods: Self::opt_or_default(
+                "idempotency.methods",
+                idempotency.methods,
+                ["/api.Petshop/PetPost", "/api.Webhook/WebhookSubscriberPost"]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            ),
+            ttl_hours: Self::opt_or_default("idempotency.ttl_hours", idempotency.ttl_hours, 24),
+            lock_seconds: Self::opt_or_default(
+                "idempotency.lock_seconds",
+                idempotency.lock_seconds,
+                60,
+            ),
+            max_body_bytes: Self::opt_or_default(
+                "idempotency.max_body_bytes",
+                idempotency.max_body_bytes,
+                1024 * 1024,
+            ),
+        };
+        if idempotency.ttl_hours == 0 || idempotency.lock_seconds == 0 {
+            return Err(XErr::config("idempotency is invalid").into());
+        }
+
+        let readiness = value.readiness.unwrap_or_default();
+        let readiness_timeout_millis =
+            Self::opt_or_default("readiness.timeout_millis", readiness.timeout_millis, 2000);
+        let mut readiness_checks = HashMap::new();
+        for (name, check) in readiness.checks.unwrap_or_default() {
+            let criticality = match check.criticality.as_deref() {
+                Some("critical") => Some(ReadinessCriticality::Critical),
+                Some("degraded") => Some(ReadinessCriticality::Degraded),
+                Some(_) => {
+                    return Err(XErr::config(&format!(
+                        "readiness.checks.{}.criticality is not supported",
+                        name
+                    ))
+                    .into())
+                }
+                None => None,
+            };
+            readiness_checks.insert(
+                name,
+                ReadinessCheckConfig {
+                    criticality,
+                    timeout_millis: check.timeout_millis,
+                },
+            );
+        }
+        let readiness = ReadinessConfig {
+            timeout_millis: readiness_timeout_millis,
+            checks: readiness_checks,
+        };
+
+        let shutdown = value.shutdown.unwrap_or_default();
+        let shutdown = ShutdownConfig {
+            pre_stop_delay_seconds: Self::opt_or_default(
+                "shutdown.pre_stop_delay_seconds",
+                shutdown.pre_stop_delay_seconds,
+                5,
+            ),
+            grace_period_seconds: Self::opt_or_default(
+                "shutdown.grace_period_seconds",
+                shutdown.grace_period_seconds,
+                30,
+            ),
+        };
+
+        let github = value
+            .github
+            .and_then(|github| github.webhook_secret)
+            .map(|webhook_secret| GithubConfig { webhook_secret });
+        if github.is_none() {
+            println!("Config: github.webhook_secret is not configured, defaulting to disabled");
+        }
+
+        let multipart = value.multipart.unwrap_or_default();
+        let multipart = MultipartConfig {
+            max_bytes: Self::opt_or_default(
+                "multipart.max_bytes",
+                multipart.max_bytes,
+                10 * 1024 * 1024,
+            ),
+            max_part_bytes: Self::opt_or_default(
+                "multipart.max_part_bytes",
+                multipart.max_part_bytes,
+                5 * 1024 * 1024,
+            ),
+            max_parts: Self::opt_or_default("multipart.max_parts", multipart.max_parts, 64),
+        };
+
+        let storage = value.storage.unwrap_or_default();
+        let backend = Self::opt_or_default("storage.backend", storage.backend, "local".to_string());
+        let backend = match backend.as_ref() {
+            "local" => StorageBackendConfig::Local {
+                path: Self::opt_or_default("storage.path", storage.path, "data".to_string()),
+            },
+            _ => return Err(XErr::config("storage.backend is not supported").into()),
+        };
+        let storage = StorageConfig {
+            backend,
+            public_url: Self::opt_or_default(
+                "storage.public_url",
+                storage.public_url,
+                "".to_string(),
+            )
+            .trim_end_matches('/')
+            .to_string(),
+            max_photo_bytes: Self::opt_or_default(
+                "storage.max_photo_bytes",
+                storage.max_photo_bytes,
+                5 * 1024 * 1024,
+            ),
         };
 
         let csrf = if let Some(csrf) = value.csrf {
@@ -154,6 +679,166 @@ impl TryFrom<ConfigLoad> for Config {
             None
         };
 
+        let limits = value.limits.unwrap_or_default();
+        let limits = LimitsConfig {
+            concurrency_limit_per_connection: Self::opt_or_default(
+                "limits.concurrency_limit_per_connection",
+                limits.concurrency_limit_per_connection,
+                256,
+            ),
+            concurrency_limit: Self::opt_or_default(
+                "limits.concurrency_limit",
+                limits.concurrency_limit,
+                1024,
+            ),
+            queue_limit: Self::opt_or_default("limits.queue_limit", limits.queue_limit, 2048),
+            request_timeout_seconds: Self::opt_or_default(
+                "limits.request_timeout_seconds",
+                limits.request_timeout_seconds,
+                30,
+            ),
+        };
+        if limits.concurrency_limit_per_connection == 0
+            || limits.concurrency_limit == 0
+            || limits.request_timeout_seconds == 0
+        {
+            return Err(XErr::config("limits is invalid").into());
+        }
+
+        let compression = value.compression.unwrap_or_default();
+        let encodings = Self::opt_or_default(
+            "compression.encodings",
+            compression.encodings,
+            vec!["gzip".to_string()],
+        );
+        let mut compression_encodings = Vec::new();
+        for encoding in encodings {
+            match CompressionEncoding::from_name(&encoding) {
+                Some(encoding) => compression_encodings.push(encoding),
+                None => return Err(XErr::config("compression.encodings is not supported").into()),
+            }
+        }
+        // Messages must fit the largest payload (photo uploads and multipart forms),
+        // with room for the other fields of the message
+        let max_payload_bytes = multipart.max_bytes.max(storage.max_photo_bytes) + 64 * 1024;
+        let compression = CompressionConfig {
+            encodings: compression_encodings,
+            level: Self::opt_or_default("compression.level", compression.level, 6),
+            min_bytes: Self::opt_or_default("compression.min_bytes", compression.min_bytes, 1024),
+            max_message_bytes: Self::opt_or_default(
+                "compression.max_message_bytes",
+                compression.max_message_bytes,
+                max_payload_bytes.max(4 * 1024 * 1024),
+            ),
+        };
+        if compression.level > 9 || compression.max_message_bytes < max_payload_bytes {
+            return Err(XErr::config("compression is invalid").into());
+        }
+
+        let transport = value.transport.unwrap_or_default();
+        let transport = TransportConfig {
+            tcp_nodelay: Self::opt_or_default("transport.tcp_nodelay", transport.tcp_nodelay, true),
+            tcp_keepalive_seconds: Self::opt(
+                "transport.tcp_keepalive_seconds",
+                transport.tcp_keepalive_seconds,
+            ),
+            http2_keepalive_interval_seconds: Self::opt(
+                "transport.http2_keepalive_interval_seconds",
+                transport.http2_keepalive_interval_seconds,
+            ),
+            http2_keepalive_timeout_seconds: Self::opt(
+                "transport.http2_keepalive_timeout_seconds",
+                transport.http2_keepalive_timeout_seconds,
+            ),
+            http2_max_concurrent_streams: Self::opt(
+                "transport.http2_max_concurrent_streams",
+                transport.http2_max_concurrent_streams,
+            ),
+            http2_initial_stream_window_size: Self::opt(
+                "transport.http2_initial_stream_window_size",
+                transport.http2_initial_stream_window_size,
+            ),
+            http2_initial_connection_window_size: Self::opt(
+                "transport.http2_initial_connection_window_size",
+                transport.http2_initial_connection_window_size,
+            ),
+            http2_max_frame_size: Self::opt(
+                "transport.http2_max_frame_size",
+                transport.http2_max_frame_size,
+            ),
+        };
+        // HTTP/2 frame size must be between 2^14 and 2^24 - 1 (RFC 7540)
+        if let Some(max_frame_size) = transport.http2_max_frame_size {
+            if !(16_384..=16_777_215).contains(&max_frame_size) {
+                return Err(XErr::config("transport.http2_max_frame_size is invalid").into());
+            }
+        }
+
+        let sse = value.sse.unwrap_or_default();
+        let sse = SseConfig {
+            heartbeat_seconds: Self::opt_or_default(
+                "sse.heartbeat_seconds",
+                sse.heartbeat_seconds,
+                15,
+            ),
+            retry_millis: Self::opt_or_default("sse.retry_millis", sse.retry_millis, 5000),
+        };
+        if sse.heartbeat_seconds == 0 {
+            return Err(XErr::config("sse is invalid").into());
+        }
+
+        let rate_limit = if let Some(rate_limit) = value.rate_limit {
+            let backend = Self::opt_or_default(
+                "rate_limit.backend",
+                rate_limit.backend,
+                "memory".to_string(),
+            );
+            let backend = match backend.as_ref() {
+                "memory" => RateLimitBackendConfig::Memory,
+                "postgres" => RateLimitBackendConfig::Postgres,
+                _ => return Err(XErr::config("rate_limit.backend is not supported").into()),
+            };
+            let forwarded_trusted_hops = rate_limit.forwarded_trusted_hops;
+            if forwarded_trusted_hops.is_none() {
+                println!("Config: rate_limit.forwarded_trusted_hops is not configured, defaulting to peer address");
+            }
+            let default_policy = Self::rate_limit_policy(
+                "rate_limit.default_policy",
+                rate_limit.default_policy.unwrap_or_default(),
+                &RateLimitPolicyConfig {
+                    method: None,
+                    requests_per_second: 10.0,
+                    burst: 20,
+                },
+            )?;
+            // Per method policies use the default policy for undefined values
+            let mut policies = Vec::new();
+            for (i, policy) in rate_limit
+                .policies
+                .unwrap_or_default()
+                .into_iter()
+                .enumerate()
+            {
+                let name = format!("rate_limit.policies[{}]", i);
+                let method = match policy.method.as_ref() {
+                    Some(method) if method.starts_with('/') => method.clone(),
+                    _ => return Err(XErr::config(&format!("{}.method is invalid", name)).into()),
+                };
+                let mut policy = Self::rate_limit_policy(&name, policy, &default_policy)?;
+                policy.method = Some(method);
+                policies.push(policy);
+            }
+            Some(RateLimitConfig {
+                backend,
+                forwarded_trusted_hops,
+                default_policy,
+                policies,
+            })
+        } else {
+            println!("Config: rate_limit is not configured, defaulting to disabled");
+            None
+        };
+
         let mut postgres = if let Some(postgres) = value.postgres {
             postgres
         } else {
@@ -178,11 +863,27 @@ impl TryFrom<ConfigLoad> for Config {
 
         Ok(Config {
             tracing_json,
+            redact,
             api_addr,
             internal_addr,
             metrics_name,
             csrf,
+            rate_limit,
+            limits,
+            compression,
+            transport,
+            sse,
             clients,
+            webhooks,
+            pet_events,
+            outbox,
+            audit,
+            idempotency,
+            readiness,
+            shutdown,
+            github,
+            multipart,
+            storage,
             postgres,
         })
     }
@@ -215,15 +916,19 @@ impl Config {
     /// <https://cheatsheetseries.owasp.org/cheatsheets/REST_Security_Cheat_Sheet.html#error-handling>
     /// <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#which-events-to-log>
     /// <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#data-to-exclude>
+    ///
+    /// Sensitive data is redacted from output using the `redact` configuration
     pub fn init_panic_and_tracing(&self) {
+        let redact =
+            Arc::new(Redact::from_config(&self.redact).expect("redact from config failed"));
         if self.tracing_json {
-            Self::init_panic_json();
+            Self::init_panic_json(redact.clone());
         }
 
         let builder = tracing_subscriber::fmt()
             .with_env_filter(EnvFilter::from_default_env())
             .with_timer(ChronoUtc::default())
-            .with_writer(std::io::stderr);
+            .with_writer(RedactWriter::new(redact));
         if self.tracing_json {
             builder.json().init();
         } else {
@@ -233,8 +938,8 @@ impl Config {
         debug!("{:?}", self);
     }
 
-    fn init_panic_json() {
-        std::panic::set_hook(Box::new(|info| {
+    fn init_panic_json(redact: Arc<Redact>) {
+        std::panic::set_hook(Box::new(move |info| {
             let location = info.location().expect("panic location failed");
             let output = serde_json::to_string(&json!({
                 "timestamp": Utc::now().to_rfc3339(),
@@ -245,12 +950,78 @@ impl Config {
                 },
                 "target": NAME,
                 "version": VERSION,
+                "request_id": request_id_current(),
             }))
                 .expect("panic_json failed");
-            eprintln!("{}", output);
+            eprintln!("{}", redact.redact(&output));
         }));
     }
 
+    fn clients_policy(
+        name: &str,
+        value: ClientsPolicyConfigLoad,
+        default: &ClientsPolicyConfig,
+    ) -> ClientsPolicyConfig {
+        ClientsPolicyConfig {
+            host: None,
+            retries: Self::opt_or_default(
+                &format!("{}.retries", name),
+                value.retries,
+                default.retries,
+            ),
+            retry_idempotent_only: Self::opt_or_default(
+                &format!("{}.retry_idempotent_only", name),
+                value.retry_idempotent_only,
+                default.retry_idempotent_only,
+            ),
+            backoff_base_millis: Self::opt_or_default(
+                &format!("{}.backoff_base_millis", name),
+                value.backoff_base_millis,
+                default.backoff_base_millis,
+            ),
+            backoff_max_millis: Self::opt_or_default(
+                &format!("{}.backoff_max_millis", name),
+                value.backoff_max_millis,
+                default.backoff_max_millis,
+            ),
+            breaker_failure_threshold: Self::opt_or_default(
+                &format!("{}.breaker_failure_threshold", name),
+                value.breaker_failure_threshold,
+                default.breaker_failure_threshold,
+            ),
+            breaker_open_seconds: Self::opt_or_default(
+                &format!("{}.breaker_open_seconds", name),
+                value.breaker_open_seconds,
+                default.breaker_open_seconds,
+            ),
+            concurrency_limit: Self::opt_or_default(
+                &format!("{}.concurrency_limit", name),
+                value.concurrency_limit,
+                default.concurrency_limit,
+            ),
+        }
+    }
+
+    fn rate_limit_policy(
+        name: &str,
+        value: RateLimitPolicyConfigLoad,
+        default: &RateLimitPolicyConfig,
+    ) -> Result<RateLimitPolicyConfig> {
+        let policy = RateLimitPolicyConfig {
+            method: None,
+            requests_per_second: Self::opt_or_default(
+                &format!("{}.requests_per_second", name),
+                value.requests_per_second,
+                default.requests_per_second,
+            ),
+            burst: Self::opt_or_default(&format!("{}.burst", name), value.burst, default.burst),
+        };
+        if policy.requests_per_second <= 0.0 || policy.burst == 0 {
+            return Err(XErr::config(&format!("{} is invalid", name)).into());
+        }
+        Ok(policy)
+    }
+
     fn opt<T: fmt::Debug>(name: &str, value: Option<T>) -> Option<T> {
         if value.is_none() {
             println!("Config: {} is not configured, defaulting to none", name);
@@ -270,3 +1041,19 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhooks_config_test() {
+        std::env::set_var("TESTWEBHOOKS_POSTGRES__HOST", "localhost");
+        std::env::set_var("TESTWEBHOOKS_WEBHOOKS__MAX_ATTEMPTS", "1");
+        assert!(Config::load_with_prefix("TESTWEBHOOKS", None).is_ok());
+
+        std::env::set_var("TESTWEBHOOKS_WEBHOOKS__MAX_ATTEMPTS", "0");
+        let err = Config::load_with_prefix("TESTWEBHOOKS", None).unwrap_err();
+        assert_eq!(err.to_string(), "configuration error `webhooks is invalid`");
+    }
+}
@@ -3,12 +3,29 @@
 //! Some library types made public for easier use in modules.
 //!
 //! Internal HTTP server request handlers.
-pub use crate::api::Api;
+pub use crate::api::{Api, ApiHealth};
 pub use crate::config::Config;
 pub use crate::jobs::Jobs;
-pub use crate::postgres::{PostgresClient, PostgresPool};
+pub use crate::postgres::{PostgresClient, PostgresListener, PostgresPool};
 pub use crate::services::{
-    Auth, Clients, ClientsConfig, Csrf, CsrfConfig, CsrfService, Metrics, MetricsService,
+    Audit, AuditConfig, AuditEntry, Auth, CatalogueError, CircuitState, Clients,
+    ClientsCacheConfig, ClientsConfig, ClientsPolicyConfig, Compression, CompressionBody,
+    CompressionConfig, CompressionEncoding, CompressionService, Csrf, CsrfConfig, CsrfService,
+    ErrorsService, Github, GithubConfig, GithubDelivery, GithubEvent, GithubPing,
+    GithubPullRequest, GithubPush, Idempotency, IdempotencyConfig, IdempotencyLock,
+    IdempotencyResponse, IdempotencyScope, IdempotencyService, Limits, LimitsConfig, LimitsService,
+    Metrics, MetricsService, MigrationsCheck, Multipart, MultipartConfig, Outbox, OutboxConfig,
+    OutboxEvent, OutboxMessage, OutboxSink, OutboxSinkConfig, PetEventPosition, PetEventRecord,
+    PetEvents, PetEventsConfig, RateLimit, RateLimitBackendConfig, RateLimitConfig,
+    RateLimitPolicyConfig, RateLimitService, Readiness, ReadinessCheck, ReadinessCheckConfig,
+    ReadinessConfig, ReadinessCriticality, ReadinessReport, ReadinessStatus, RequestIdService,
+    Shutdown, ShutdownConfig, ShutdownPhase, Sse, SseConfig, SseEvent, SseService, Storage,
+    StorageBackendConfig, StorageConfig, StoragePhoto, WebhookDeliveryJob, Webhooks,
+    WebhooksConfig, ERROR_AUTHENTICATION, ERROR_CONFLICT, ERROR_CSRF_CHECK,
+    ERROR_ENCODING_UNSUPPORTED, ERROR_GENERIC, ERROR_GITHUB_WEBHOOK, ERROR_IDEMPOTENCY_IN_PROGRESS,
+    ERROR_IDEMPOTENCY_KEY_REUSED, ERROR_MESSAGE_TOO_LARGE, ERROR_NOT_FOUND,
+    ERROR_PERMISSION_DENIED, ERROR_RATE_LIMITED, ERROR_RESUME_TOKEN_INVALID, ERROR_UNAVAILABLE,
+    ERROR_VALIDATION,
 };
 pub use anyhow::{Error, Result};
 pub use chrono::Utc;
@@ -19,7 +36,10 @@ pub use std::time::SystemTime;
 pub use url::Url;
 
 use hyper::{Body, Method, Request, Response, StatusCode};
+use petshop_proto::google::rpc;
 use prost::Message;
+use std::collections::BTreeMap;
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
 
 /// Crate Name
 pub static NAME: &str = env!("CARGO_PKG_NAME");
@@ -30,10 +50,8 @@ pub static VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Crate User Agent
 pub static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
-pub static ERROR_GENERIC: &str = "Error";
-pub static ERROR_CSRF_CHECK: &str = "CsrfCheckError";
-pub static ERROR_AUTHENTICATION: &str = "AuthenticationError";
-pub static ERROR_VALIDATION: &str = "ValidationError";
+/// Delay clients are asked to wait before retrying unavailable requests
+pub const ERROR_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
 
 pub type HttpStatus = http::StatusCode;
 
@@ -51,15 +69,39 @@ pub enum XErr {
     #[error("internal uri error `{0}`")]
     InternalUri(String),
 
+    #[error("clients unavailable error `{0}`")]
+    ClientsUnavailable(String),
+
+    #[error("storage key error `{0}`")]
+    StorageKey(String),
+
+    #[error("not found error `{0}`")]
+    NotFound(String),
+
+    #[error("conflict error `{0}`")]
+    Conflict(String),
+
+    #[error("readiness error `{0}`")]
+    Readiness(String),
+
+    #[error("postgres listener error `{0}`")]
+    PostgresListener(String),
+
+    #[error("outbox sink error `{0}`")]
+    OutboxSink(String),
+
+    #[error("webhook destination error `{0}`")]
+    WebhookDestination(String),
+
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
     #[error("serde json error")]
     SerdeJson(#[from] serde_json::Error),
 
     #[error("serde urlencoded error")]
     SerdeUrlencoded(#[from] serde_urlencoded::de::Error),
 
-    #[error("prost encode error")]
-    ProstEncode(#[from] prost::EncodeError),
-
     #[error("postgres config error")]
     PostgresConfig(#[from] deadpool_postgres::config::ConfigError),
 
@@ -85,6 +127,39 @@ impl XErr {
     pub fn internal_uri(uri: &str) -> Self {
         Self::InternalUri(uri.to_string())
     }
+
+    pub fn clients_unavailable(host: &str) -> Self {
+        Self::ClientsUnavailable(host.to_string())
+    }
+
+    pub fn storage_key(key: &str) -> Self {
+        Self::StorageKey(key.to_string())
+    }
+
+    pub fn not_found(resource: &str) -> Self {
+        Self::NotFound(resource.to_string())
+    }
+
+    pub fn conflict(resource: &str) -> Self {
+        Self::Conflict(resource.to_string())
+    }
+
+    pub fn readiness(message: &str) -> Self {
+        Self::Readiness(message.to_string())
+    }
+
+    pub fn postgres_listener(message: &str) -> Self {
+        Self::PostgresListener(message.to_string())
+    }
+
+    pub fn outbox_sink(sink: &str, err: XErr) -> Self {
+        let err: Error = err.into();
+        Self::OutboxSink(format!("{}: {:#}", sink, err))
+    }
+
+    pub fn webhook_destination(message: &str) -> Self {
+        Self::WebhookDestination(message.to_string())
+    }
 }
 
 impl From<XErr> for tonic::Status {
@@ -96,10 +171,31 @@ impl From<XErr> for tonic::Status {
         //
         // <https://cheatsheetseries.owasp.org/cheatsheets/REST_Security_Cheat_Sheet.html#error-handling>
         // <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#which-events-to-log>
+        //
+        // Domain errors are returned with `ErrorInfo` details, and upstream failures
+        // as unavailable with `RetryInfo` details so clients know to retry later
+        match &err {
+            XErr::NotFound(resource) => {
+                return tonic_status_error_info(&ERROR_NOT_FOUND, &[("resource", resource)])
+            }
+            XErr::Conflict(resource) => {
+                return tonic_status_error_info(&ERROR_CONFLICT, &[("resource", resource)])
+            }
+            _ => {}
+        }
+        let unavailable = match &err {
+            XErr::ClientsUnavailable(_) => true,
+            XErr::Reqwest(err) => err.is_timeout() || err.is_connect(),
+            _ => false,
+        };
         let err: Error = err.into();
         warn!("{:#}", err);
 
-        tonic::Status::internal(ERROR_GENERIC)
+        if unavailable {
+            tonic_status_retry_info(&ERROR_UNAVAILABLE, ERROR_RETRY_DELAY)
+        } else {
+            tonic_status_error_info(&ERROR_GENERIC, &[])
+        }
     }
 }
 
@@ -112,7 +208,8 @@ pub async fn http_request_handler(api: Api, req: Request<Body>) -> Result<Respon
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/ping") => liveness_request_response(),
         (&Method::GET, "/liveness") => liveness_request_response(),
-        (&Method::GET, "/readiness") => readiness_request_response(&api).await,
+        (&Method::GET, "/readiness") => readiness_request_response(&api, req.uri().query()).await,
+        (&Method::GET, "/startup") => startup_request_response(&api).await,
         (&Method::GET, "/metrics") => metrics_request_response(&api),
         (_, uri) => Err(XErr::internal_uri(uri).into()),
     }
@@ -139,9 +236,32 @@ fn liveness_request_response() -> Result<Response<Body>> {
         .body("ok".into())?)
 }
 
-/// Kubernetes readiness request handler
-async fn readiness_request_response(api: &Api) -> Result<Response<Body>> {
-    api.readiness().await?;
+/// Kubernetes readiness request handler, with `verbose` query returns JSON report
+/// of checks (unready status code is 503)
+async fn readiness_request_response(api: &Api, query: Option<&str>) -> Result<Response<Body>> {
+    let verbose = query
+        .unwrap_or_default()
+        .split('&')
+        .any(|x| x == "verbose" || x.starts_with("verbose="));
+    if !verbose {
+        api.readiness().await?;
+        return liveness_request_response();
+    }
+
+    let report = api.readiness_report().await;
+    let status = match report.status {
+        ReadinessStatus::Unready => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&report)?.into())?)
+}
+
+/// Kubernetes startup request handler
+async fn startup_request_response(api: &Api) -> Result<Response<Body>> {
+    api.startup().await?;
     liveness_request_response()
 }
 
@@ -186,46 +306,162 @@ pub fn serde_into_prost_value(value: serde_json::Value) -> prost_types::Value {
     prost_types::Value { kind: Some(kind) }
 }
 
-/// Converts a serde derived Value into a prost Any
-pub fn serde_into_prost_any(value: serde_json::Value) -> Result<prost_types::Any, XErr> {
-    let value = serde_into_prost_value(value);
-    let mut value_buffer = bytes::BytesMut::new();
-
-    value.encode(&mut value_buffer).map_err(XErr::ProstEncode)?;
+/// Encodes prost message into bytes, encoding into a vector can't run out of capacity
+pub fn prost_encode(message: &impl Message) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(message.encoded_len());
+    message
+        .encode(&mut buffer)
+        .expect("prost encode into vec failed");
+    buffer
+}
 
-    Ok(prost_types::Any {
-        type_url: "type.googleapis.com/google.protobuf.Value".to_string(),
-        value: value_buffer.freeze().to_vec(),
-    })
+/// Encodes message into a prost Any with `type.googleapis.com` type URL
+pub fn prost_any(name: &str, message: &impl Message) -> prost_types::Any {
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/{}", name),
+        value: prost_encode(message),
+    }
 }
 
-/// Builds tonic status using serialisable value for details, this works with envoy
+/// Builds tonic status with `google.rpc.Status` details, this works with envoy
 /// the envoy setting `convert_grpc_status` to provide json error responses
 pub fn tonic_status_with_details(
     code: tonic::Code,
     message: impl Into<String>,
-    value: impl serde::Serialize,
-) -> Result<tonic::Status, tonic::Status> {
-    let value = serde_json::to_value(value).map_err(XErr::SerdeJson)?;
-    let details = serde_into_prost_any(value)?;
+    details: Vec<prost_types::Any>,
+) -> tonic::Status {
     let message: String = message.into();
-
-    let status = petshop_proto::google::rpc::Status {
+    let status = rpc::Status {
         code: code as i32,
         message: message.clone(),
-        details: vec![details],
+        details,
+    };
+    tonic::Status::with_details(code, message, prost_encode(&status).into())
+}
+
+/// Builds invalid argument status with `google.rpc.BadRequest` details, which has a
+/// field violation for each error using field paths from `validation_errors_paths`
+pub fn tonic_status_bad_request(errors: &ValidationErrors) -> tonic::Status {
+    let mut field_violations = vec![];
+    for (field, errors) in validation_errors_paths(errors) {
+        for err in errors {
+            field_violations.push(rpc::bad_request::FieldViolation {
+                field: field.clone(),
+                description: err
+                    .message
+                    .as_ref()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| err.code.to_string()),
+            });
+        }
+    }
+    let details = rpc::BadRequest { field_violations };
+    tonic_status_with_details(
+        ERROR_VALIDATION.grpc_code,
+        ERROR_VALIDATION.message,
+        vec![
+            prost_any("google.rpc.ErrorInfo", &ERROR_VALIDATION.error_info(&[])),
+            prost_any("google.rpc.BadRequest", &details),
+        ],
+    )
+}
+
+/// Builds status of catalogue error with `google.rpc.ErrorInfo` details
+pub fn tonic_status_error_info(error: &CatalogueError, metadata: &[(&str, &str)]) -> tonic::Status {
+    tonic_status_with_details(
+        error.grpc_code,
+        error.message,
+        vec![prost_any(
+            "google.rpc.ErrorInfo",
+            &error.error_info(metadata),
+        )],
+    )
+}
+
+/// Builds status of catalogue error with `google.rpc.ErrorInfo` and `google.rpc.RetryInfo`
+/// details for overload or upstream errors
+pub fn tonic_status_retry_info(
+    error: &CatalogueError,
+    retry_delay: std::time::Duration,
+) -> tonic::Status {
+    let details = rpc::RetryInfo {
+        retry_delay: Some(retry_delay.into()),
     };
+    tonic_status_with_details(
+        error.grpc_code,
+        error.message,
+        vec![
+            prost_any("google.rpc.ErrorInfo", &error.error_info(&[])),
+            prost_any("google.rpc.RetryInfo", &details),
+        ],
+    )
+}
+
+/// Flattens nested validation errors into a map of field paths to errors,
+/// for example `name`, `tags[2].name` or `photo_urls[1]`
+pub fn validation_errors_paths(
+    errors: &ValidationErrors,
+) -> BTreeMap<String, Vec<ValidationError>> {
+    fn paths_inner(
+        paths: &mut BTreeMap<String, Vec<ValidationError>>,
+        prefix: &str,
+        errors: &ValidationErrors,
+    ) {
+        for (field, kind) in errors.errors() {
+            let path = if prefix.is_empty() {
+                field.to_string()
+            } else {
+                format!("{}.{}", prefix, field)
+            };
+            match kind {
+                ValidationErrorsKind::Field(errors) => {
+                    for err in errors {
+                        // Repeated field item errors have an index parameter
+                        let mut err = err.clone();
+                        let path = match err.params.remove("index") {
+                            Some(index) => format!("{}[{}]", path, index),
+                            None => path.clone(),
+                        };
+                        paths.entry(path).or_default().push(err);
+                    }
+                }
+                ValidationErrorsKind::Struct(errors) => paths_inner(paths, &path, errors),
+                ValidationErrorsKind::List(list) => {
+                    for (i, errors) in list {
+                        paths_inner(paths, &format!("{}[{}]", path, i), errors);
+                    }
+                }
+            }
+        }
+    }
 
-    let mut status_buffer = bytes::BytesMut::with_capacity(4096);
-    status
-        .encode(&mut status_buffer)
-        .map_err(XErr::ProstEncode)?;
+    let mut paths = BTreeMap::new();
+    paths_inner(&mut paths, "", errors);
+    paths
+}
 
-    Ok(tonic::Status::with_details(
-        code,
-        message,
-        status_buffer.freeze(),
-    ))
+/// Generate and return a random alphanumeric string of length
+pub fn random_string(length: usize) -> String {
+    use rand::Rng;
+    let rng = rand::thread_rng();
+    rng.sample_iter(&rand::distributions::Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Converts a chrono timestamp into a prost Timestamp
+pub fn chrono_into_prost_timestamp(value: chrono::DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: value.timestamp(),
+        nanos: value.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// Converts a prost Timestamp into a chrono timestamp, returns none if it is out of range
+pub fn prost_timestamp_into_chrono(value: prost_types::Timestamp) -> Option<chrono::DateTime<Utc>> {
+    let nanos = u32::try_from(value.nanos).ok()?;
+    chrono::TimeZone::timestamp_opt(&Utc, value.seconds, nanos).single()
 }
 
 /// Returns grpc-status code from response headers, if header is not present assumed to be 0
@@ -241,3 +477,134 @@ pub fn http_headers_grpc_status(headers: &HttpHeaders) -> tonic::Code {
         None => tonic::Code::Ok,
     }
 }
+
+/// Returns client address from `x-forwarded-for` headers, or the peer address if there
+/// are not enough forwarded addresses
+pub fn http_headers_client_addr(headers: &HttpHeaders, trusted_hops: usize) -> Option<String> {
+    http_headers_forwarded_addr(headers, trusted_hops).or_else(|| http_headers_peer_addr(headers))
+}
+
+/// Returns peer address of connection added by the request id service
+pub fn http_headers_peer_addr(headers: &HttpHeaders) -> Option<String> {
+    headers
+        .get(crate::services::X_PEER_ADDR)
+        .and_then(|x| x.to_str().ok())
+        .map(String::from)
+}
+
+/// Returns client address from `x-forwarded-for` headers, envoy appends the client
+/// address so the last address is used, unless there are trusted proxies in front of envoy
+pub fn http_headers_forwarded_addr(headers: &HttpHeaders, trusted_hops: usize) -> Option<String> {
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect();
+    forwarded
+        .len()
+        .checked_sub(trusted_hops + 1)
+        .map(|i| forwarded[i].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petshop_proto::api::{Category, Pet, Tag};
+    use validator::Validate;
+
+    #[test]
+    fn prost_timestamp_into_chrono_test() {
+        let now = Utc::now();
+        assert_eq!(
+            prost_timestamp_into_chrono(chrono_into_prost_timestamp(now)),
+            Some(now)
+        );
+        let timestamp = |seconds, nanos| prost_types::Timestamp { seconds, nanos };
+        assert_eq!(prost_timestamp_into_chrono(timestamp(i64::MAX, 0)), None);
+        assert_eq!(prost_timestamp_into_chrono(timestamp(i64::MIN, 0)), None);
+        assert_eq!(prost_timestamp_into_chrono(timestamp(0, -1)), None);
+        assert_eq!(
+            prost_timestamp_into_chrono(timestamp(0, 2_000_000_000)),
+            None
+        );
+    }
+
+    #[test]
+    fn validation_errors_paths_test() {
+        let pet = Pet {
+            category: Some(Category {
+                id: 1,
+                name: "".to_string(),
+            }),
+            name: "name".to_string(),
+            photo_urls: vec![
+                "https://example.com/1.png".to_string(),
+                "notaurl".to_string(),
+            ],
+            tags: vec![
+                Tag {
+                    id: 1,
+                    name: "tag".to_string(),
+                },
+                Tag {
+                    id: 2,
+                    name: "x".repeat(65),
+                },
+            ],
+            status: 10,
+            ..Default::default()
+        };
+        let errors = validation_errors_paths(&pet.validate().unwrap_err());
+        let paths: Vec<&str> = errors.keys().map(|x| x.as_ref()).collect();
+        assert_eq!(
+            paths,
+            vec!["category.name", "photo_urls[1]", "status", "tags[1].name"]
+        );
+        assert_eq!(errors["photo_urls[1]"][0].code, "url_invalid");
+        assert!(!errors["photo_urls[1]"][0].params.contains_key("index"));
+        assert_eq!(errors["tags[1].name"][0].code, "length_invalid");
+    }
+
+    fn status_details(status: &tonic::Status) -> Vec<prost_types::Any> {
+        let status = rpc::Status::decode(status.details()).unwrap();
+        status.details
+    }
+
+    #[test]
+    fn tonic_status_details_test() {
+        let pet = Pet {
+            name: "name".to_string(),
+            photo_urls: vec!["notaurl".to_string()],
+            ..Default::default()
+        };
+        let status = tonic_status_bad_request(&pet.validate().unwrap_err());
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let details = status_details(&status);
+        assert_eq!(
+            details[1].type_url,
+            "type.googleapis.com/google.rpc.BadRequest"
+        );
+        let bad_request = rpc::BadRequest::decode(details[1].value.as_slice()).unwrap();
+        assert_eq!(bad_request.field_violations[0].field, "photo_urls[0]");
+
+        let status: tonic::Status = XErr::not_found("pet").into();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let details = status_details(&status);
+        let error_info = rpc::ErrorInfo::decode(details[0].value.as_slice()).unwrap();
+        assert_eq!(error_info.reason, "NOT_FOUND");
+        assert_eq!(error_info.domain, crate::services::ERROR_DOMAIN);
+        assert_eq!(error_info.metadata["resource"], "pet");
+
+        let status: tonic::Status = XErr::conflict("pet").into();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+
+        let status: tonic::Status = XErr::clients_unavailable("localhost").into();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let details = status_details(&status);
+        let retry_info = rpc::RetryInfo::decode(details[1].value.as_slice()).unwrap();
+        assert_eq!(retry_info.retry_delay.unwrap().seconds, 5);
+    }
+}
@@ -3,6 +3,10 @@
 //! Examples using cron in docker and minikube to run jobs can
 //! be found in the `examples` directory
 use crate::internal::*;
+use crate::services::errors_export;
+
+/// Directory error catalogue is exported to
+const ERRORS_EXPORT_DIR: &str = "dist/clients";
 
 /// Jobs
 pub struct Jobs;
@@ -12,6 +16,8 @@ impl Jobs {
     pub async fn run(config: Config, job: &str) -> Result<()> {
         match job {
             "example" => Self::example(&config).await,
+            "migrate" => Self::migrate(&config).await,
+            "errors" => Self::errors(&config).await,
             _ => Err(XErr::jobs("job not found").into()),
         }
     }
@@ -27,4 +33,30 @@ impl Jobs {
         info!("finishing Jobs::example");
         Ok(())
     }
+
+    /// Apply postgres schema migrations
+    #[tracing::instrument(skip(config))]
+    pub async fn migrate(config: &Config) -> Result<()> {
+        info!("starting Jobs::migrate");
+
+        let mut pg = PostgresClient::from_config(config).await?;
+        pg.migrate().await?;
+
+        info!("finishing Jobs::migrate");
+        Ok(())
+    }
+
+    /// Export error catalogue as JSON for clients
+    #[tracing::instrument(skip(_config))]
+    pub async fn errors(_config: &Config) -> Result<()> {
+        info!("starting Jobs::errors");
+
+        tokio::fs::create_dir_all(ERRORS_EXPORT_DIR).await?;
+        let path = format!("{}/errors.json", ERRORS_EXPORT_DIR);
+        let export = serde_json::to_vec_pretty(&errors_export())?;
+        tokio::fs::write(&path, export).await?;
+
+        info!("finishing Jobs::errors ({})", path);
+        Ok(())
+    }
 }
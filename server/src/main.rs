@@ -20,8 +20,10 @@ use crate::internal::*;
 use clap::{App, Arg};
 use hyper::service::{make_service_fn, service_fn};
 use petshop_proto::api::{
-    example_server::ExampleServer, petshop_server::PetshopServer, tfb_server::TfbServer,
+    audit_server::AuditServer, example_server::ExampleServer, petshop_server::PetshopServer,
+    tfb_server::TfbServer, webhook_server::WebhookServer,
 };
+use std::time::Duration;
 use tokio::sync::broadcast;
 
 mod api;
@@ -69,40 +71,133 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 /// Start server and await until termination
 async fn server_run(config: Config) -> Result<()> {
-    // Build shutdown broadcast channel
-    let (shutdown_tx, shutdown_rx1) = broadcast::channel::<bool>(8);
-    let shutdown_rx2 = shutdown_tx.subscribe();
-
     // Build gRPC health service
-    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
 
     // Build API services
-    let api = Api::from_config(&config, shutdown_tx)?;
+    let api = Api::from_config(&config)?;
+
+    // Wait for shutdown signal and drain in phases
+    let shutdown = api.shutdown();
+    let shutdown_rx = shutdown.subscribe();
+    let shutdown_drain = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal(shutdown_rx).await;
+        shutdown_drain.drain().await;
+    });
+
+    // Listen for pet changes and send them to watchers until shutdown
+    shutdown.spawn(api.pet_events().run(config.clone()));
+    shutdown.spawn(api.outbox().run(config.clone()));
+    shutdown.spawn(api.outbox().consume());
+    shutdown.spawn(api.webhooks().run());
+    shutdown.spawn(api.idempotency().run());
+    shutdown.spawn(api.rate_limit().run());
 
     // FIXME: Additional gRPC services after being defined in proto library
     // must be added/implemented in this crate, and added to the envoy
     // configuration for JSON transcoding
 
-    let example_service = MetricsService::wrap(api.metrics(), ExampleServer::new(api.clone()));
+    let example_service =
+        IdempotencyService::wrap(api.idempotency(), ExampleServer::new(api.clone()));
+    let example_service = CompressionService::wrap(api.compression(), example_service);
+    let example_service = SseService::wrap(api.clone(), example_service);
+    let example_service = MetricsService::wrap(api.metrics(), example_service);
     let example_service = CsrfService::wrap(api.csrf(), example_service);
-    health_reporter.set_serving::<ExampleServer<Api>>().await;
+    let example_service = RateLimitService::wrap(api.rate_limit(), example_service);
+    let example_service = LimitsService::wrap(api.limits(), example_service);
+    let example_service = ErrorsService::wrap(example_service);
+    let example_service = RequestIdService::wrap(example_service);
 
-    let petshop_service = MetricsService::wrap(api.metrics(), PetshopServer::new(api.clone()));
+    let petshop_service =
+        IdempotencyService::wrap(api.idempotency(), PetshopServer::new(api.clone()));
+    let petshop_service = CompressionService::wrap(api.compression(), petshop_service);
+    let petshop_service = SseService::wrap(api.clone(), petshop_service);
+    let petshop_service = MetricsService::wrap(api.metrics(), petshop_service);
     let petshop_service = CsrfService::wrap(api.csrf(), petshop_service);
-    health_reporter.set_serving::<PetshopServer<Api>>().await;
+    let petshop_service = RateLimitService::wrap(api.rate_limit(), petshop_service);
+    let petshop_service = LimitsService::wrap(api.limits(), petshop_service);
+    let petshop_service = ErrorsService::wrap(petshop_service);
+    let petshop_service = RequestIdService::wrap(petshop_service);
+
+    let tfb_service = CompressionService::wrap(api.compression(), TfbServer::new(api.clone()));
+    let tfb_service = MetricsService::wrap(api.metrics(), tfb_service);
+    let tfb_service = LimitsService::wrap(api.limits(), tfb_service);
+    let tfb_service = ErrorsService::wrap(tfb_service);
+    let tfb_service = RequestIdService::wrap(tfb_service);
+
+    let webhook_service =
+        IdempotencyService::wrap(api.idempotency(), WebhookServer::new(api.clone()));
+    let webhook_service = CompressionService::wrap(api.compression(), webhook_service);
+    let webhook_service = MetricsService::wrap(api.metrics(), webhook_service);
+    let webhook_service = CsrfService::wrap(api.csrf(), webhook_service);
+    let webhook_service = RateLimitService::wrap(api.rate_limit(), webhook_service);
+    let webhook_service = LimitsService::wrap(api.limits(), webhook_service);
+    let webhook_service = ErrorsService::wrap(webhook_service);
+    let webhook_service = RequestIdService::wrap(webhook_service);
+
+    let audit_service = IdempotencyService::wrap(api.idempotency(), AuditServer::new(api.clone()));
+    let audit_service = CompressionService::wrap(api.compression(), audit_service);
+    let audit_service = MetricsService::wrap(api.metrics(), audit_service);
+    let audit_service = CsrfService::wrap(api.csrf(), audit_service);
+    let audit_service = RateLimitService::wrap(api.rate_limit(), audit_service);
+    let audit_service = LimitsService::wrap(api.limits(), audit_service);
+    let audit_service = ErrorsService::wrap(audit_service);
+    let audit_service = RequestIdService::wrap(audit_service);
 
-    let tfb_service = MetricsService::wrap(api.metrics(), TfbServer::new(api.clone()));
-    health_reporter.set_serving::<TfbServer<Api>>().await;
+    // Set initial health status and check periodically until shutdown
+    let mut health = ApiHealth::new(health_reporter);
+    health.check(&api).await;
+    tokio::spawn(
+        api.clone()
+            .health_run(health, shutdown.clone().wait_owned(ShutdownPhase::Draining)),
+    );
 
     // Build and serve tonic api server
     info!("api listening on {}", config.api_addr);
-    let api_server = tonic::transport::Server::builder()
+    let transport = &config.transport;
+    let mut api_server = tonic::transport::Server::builder()
         .trace_fn(|_| tracing::info_span!(NAME))
+        .concurrency_limit_per_connection(api.limits().concurrency_limit_per_connection())
+        .tcp_nodelay(transport.tcp_nodelay)
+        .tcp_keepalive(transport.tcp_keepalive_seconds.map(Duration::from_secs))
+        .http2_keepalive_interval(
+            transport
+                .http2_keepalive_interval_seconds
+                .map(Duration::from_secs),
+        )
+        .http2_keepalive_timeout(
+            transport
+                .http2_keepalive_timeout_seconds
+                .map(Duration::from_secs),
+        )
+        .max_concurrent_streams(transport.http2_max_concurrent_streams)
+        .initial_stream_window_size(transport.http2_initial_stream_window_size)
+        .initial_connection_window_size(transport.http2_initial_connection_window_size)
+        .max_frame_size(transport.http2_max_frame_size);
+    let api_server = api_server
+        .timeout(api.limits().request_timeout())
         .add_service(health_service)
         .add_service(example_service)
         .add_service(petshop_service)
         .add_service(tfb_service)
-        .serve_with_shutdown(config.api_addr, shutdown_signal(shutdown_rx1));
+        .add_service(webhook_service)
+        .add_service(audit_service)
+        .serve_with_shutdown(
+            config.api_addr,
+            shutdown.clone().wait_owned(ShutdownPhase::Closing),
+        );
+
+    // In-flight requests and background tasks have the grace period to finish
+    let api_shutdown = shutdown.clone();
+    let api_server = async move {
+        let res = api_shutdown.deadline(api_server).await;
+        if res.is_none() {
+            warn!("shutdown grace period expired, dropping requests");
+        }
+        api_shutdown.terminate().await;
+        res.unwrap_or(Ok(()))
+    };
 
     // Build and serve hyper internal server
     info!("internal listening on {}", config.internal_addr);
@@ -117,9 +212,20 @@ async fn server_run(config: Config) -> Result<()> {
     });
     let internal_server = hyper::Server::bind(&config.internal_addr)
         .serve(internal_service)
-        .with_graceful_shutdown(shutdown_signal(shutdown_rx2));
+        .with_graceful_shutdown(shutdown.clone().wait_owned(ShutdownPhase::Terminated));
+
+    // Shutdown gracefully if the internal server fails, so that the api server is
+    // not left running without readiness and metrics
+    let internal_server = async move {
+        let res = internal_server.await;
+        if res.is_err() {
+            shutdown.signal();
+        }
+        res
+    };
 
-    // Await server termination via signal
+    // Await server termination via shutdown phases, the internal server stops last
+    // so readiness and metrics are available while draining
     let (api_server, internal_server) = tokio::join!(api_server, internal_server);
     api_server?;
     internal_server?;
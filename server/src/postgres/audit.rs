@@ -0,0 +1,92 @@
+//! # Postgres Audit
+//!
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+use petshop_proto::api::AuditRecord;
+use prost_types::value::Kind;
+
+impl PostgresPool {
+    /// Insert audit record of entry with masked diff and outcome
+    pub async fn audit_insert(
+        &self,
+        entry: &AuditEntry,
+        diff: &serde_json::Value,
+        outcome: &str,
+    ) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    INSERT INTO audit_log
+                        (actor, method, target_id, diff, client_addr, request_id, outcome)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ",
+            )
+            .await?;
+        client
+            .execute(
+                &st,
+                &[
+                    &entry.actor,
+                    &entry.method,
+                    &entry.target_id,
+                    diff,
+                    &entry.client_addr,
+                    &entry.request_id,
+                    &outcome,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Returns audit records in time range of actor before id, newest first
+    pub async fn audit_list(
+        &self,
+        start_time: Option<chrono::DateTime<Utc>>,
+        end_time: Option<chrono::DateTime<Utc>>,
+        actor: Option<&str>,
+        before_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    SELECT id, actor, method, target_id, diff, client_addr, request_id,
+                        outcome, created_at
+                    FROM audit_log
+                    WHERE ($1::TIMESTAMPTZ IS NULL OR created_at >= $1)
+                        AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2)
+                        AND ($3::TEXT IS NULL OR actor = $3)
+                        AND ($4::BIGINT IS NULL OR id < $4)
+                    ORDER BY id DESC
+                    LIMIT $5
+                ",
+            )
+            .await?;
+        let rows = client
+            .query(&st, &[&start_time, &end_time, &actor, &before_id, &limit])
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let diff = match serde_into_prost_value(row.get(4)).kind {
+                    Some(Kind::StructValue(diff)) => Some(diff),
+                    _ => None,
+                };
+                AuditRecord {
+                    id: row.get(0),
+                    actor: row.get(1),
+                    method: row.get(2),
+                    target_id: row.get(3),
+                    diff,
+                    client_addr: row.get(5),
+                    request_id: row.get(6),
+                    outcome: row.get(7),
+                    created_at: Some(chrono_into_prost_timestamp(row.get(8))),
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,22 @@
+//! # Postgres GitHub
+//!
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+
+impl PostgresPool {
+    /// Insert GitHub webhook delivery, returns false if delivery has already been received
+    pub async fn github_delivery_insert(&self, id: &str, event: &str) -> Result<bool, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    INSERT INTO github_delivery (id, event)
+                    VALUES ($1, $2)
+                    ON CONFLICT (id) DO NOTHING
+                ",
+            )
+            .await?;
+        let inserted = client.execute(&st, &[&id, &event]).await?;
+        Ok(inserted == 1)
+    }
+}
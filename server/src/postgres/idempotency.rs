@@ -0,0 +1,166 @@
+//! # Postgres Idempotency
+//!
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+
+impl PostgresPool {
+    /// Lock key if it does not exist, has expired, or its lock has expired before a
+    /// response was stored for a request with the same hash, otherwise returns the
+    /// state of the key
+    pub async fn idempotency_lock(
+        &self,
+        scope: &IdempotencyScope,
+        request_hash: &str,
+        locked_until: chrono::DateTime<Utc>,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<IdempotencyLock, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    INSERT INTO idempotency_key AS k
+                        (identity, method, key, request_hash, locked_until, expires_at)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (identity, method, key) DO UPDATE SET
+                        request_hash = EXCLUDED.request_hash,
+                        response_headers = NULL,
+                        response_body = NULL,
+                        response_trailers = NULL,
+                        locked_until = EXCLUDED.locked_until,
+                        expires_at = EXCLUDED.expires_at,
+                        created_at = now()
+                    WHERE k.expires_at < now()
+                        OR (k.response_headers IS NULL
+                            AND k.locked_until < now()
+                            AND k.request_hash = EXCLUDED.request_hash)
+                    RETURNING locked_until
+                ",
+            )
+            .await?;
+        let row = client
+            .query_opt(
+                &st,
+                &[
+                    &scope.identity,
+                    &scope.method,
+                    &scope.key,
+                    &request_hash,
+                    &locked_until,
+                    &expires_at,
+                ],
+            )
+            .await?;
+        if let Some(row) = row {
+            return Ok(IdempotencyLock::Acquired(row.get(0)));
+        }
+
+        let st = client
+            .prepare(
+                "
+                    SELECT request_hash, response_headers, response_body, response_trailers
+                    FROM idempotency_key
+                    WHERE identity = $1 AND method = $2 AND key = $3
+                ",
+            )
+            .await?;
+        let row = client
+            .query_opt(&st, &[&scope.identity, &scope.method, &scope.key])
+            .await?;
+        // Key may have been released after the insert, so the request can be retried
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(IdempotencyLock::InProgress),
+        };
+        let hash: String = row.get(0);
+        if hash != request_hash {
+            return Ok(IdempotencyLock::Reused);
+        }
+        let headers: Option<serde_json::Value> = row.get(1);
+        match headers {
+            Some(headers) => {
+                let trailers: Option<serde_json::Value> = row.get(3);
+                Ok(IdempotencyLock::Completed(IdempotencyResponse {
+                    headers: serde_json::from_value(headers)?,
+                    body: row.get::<_, Option<Vec<u8>>>(2).unwrap_or_default(),
+                    trailers: trailers.map(serde_json::from_value).transpose()?,
+                }))
+            }
+            None => Ok(IdempotencyLock::InProgress),
+        }
+    }
+
+    /// Store response of key if it is still locked by the request, a request whose
+    /// lock expired may have been taken over by a retry
+    pub async fn idempotency_complete(
+        &self,
+        scope: &IdempotencyScope,
+        locked_until: chrono::DateTime<Utc>,
+        response: &IdempotencyResponse,
+    ) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    UPDATE idempotency_key
+                    SET response_headers = $5, response_body = $6, response_trailers = $7
+                    WHERE identity = $1 AND method = $2 AND key = $3 AND locked_until = $4
+                ",
+            )
+            .await?;
+        let headers = serde_json::to_value(&response.headers)?;
+        let trailers = response
+            .trailers
+            .as_ref()
+            .map(serde_json::to_value)
+            .transpose()?;
+        client
+            .execute(
+                &st,
+                &[
+                    &scope.identity,
+                    &scope.method,
+                    &scope.key,
+                    &locked_until,
+                    &headers,
+                    &response.body,
+                    &trailers,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Delete key if a response has not been stored and it is still locked by the request
+    pub async fn idempotency_release(
+        &self,
+        scope: &IdempotencyScope,
+        locked_until: chrono::DateTime<Utc>,
+    ) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    DELETE FROM idempotency_key
+                    WHERE identity = $1 AND method = $2 AND key = $3 AND locked_until = $4
+                        AND response_headers IS NULL
+                ",
+            )
+            .await?;
+        client
+            .execute(
+                &st,
+                &[&scope.identity, &scope.method, &scope.key, &locked_until],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Delete expired keys, returns number of deleted keys
+    pub async fn idempotency_delete_expired(&self) -> Result<u64, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare("DELETE FROM idempotency_key WHERE expires_at < now()")
+            .await?;
+        Ok(client.execute(&st, &[]).await?)
+    }
+}
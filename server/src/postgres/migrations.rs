@@ -0,0 +1,147 @@
+//! # Postgres Migrations
+//!
+//! Schema migrations are applied in order by the `migrate` job, applied
+//! versions are recorded in the `schema_migrations` table
+use crate::internal::*;
+use crate::postgres::{PostgresClient, PostgresPool};
+
+/// Migration version, name and SQL
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (
+        1,
+        "webhooks",
+        include_str!("../../migrations/0001_webhooks.sql"),
+    ),
+    (
+        2,
+        "github_delivery",
+        include_str!("../../migrations/0002_github_delivery.sql"),
+    ),
+    (3, "pets", include_str!("../../migrations/0003_pets.sql")),
+    (
+        4,
+        "rate_limit",
+        include_str!("../../migrations/0004_rate_limit.sql"),
+    ),
+    (
+        5,
+        "pet_event",
+        include_str!("../../migrations/0005_pet_event.sql"),
+    ),
+    (
+        6,
+        "outbox",
+        include_str!("../../migrations/0006_outbox.sql"),
+    ),
+    (
+        7,
+        "audit_log",
+        include_str!("../../migrations/0007_audit_log.sql"),
+    ),
+    (
+        8,
+        "idempotency_key",
+        include_str!("../../migrations/0008_idempotency_key.sql"),
+    ),
+    (
+        9,
+        "pet_version",
+        include_str!("../../migrations/0009_pet_version.sql"),
+    ),
+    (
+        10,
+        "pet_search",
+        include_str!("../../migrations/0010_pet_search.sql"),
+    ),
+    (
+        11,
+        "webhook_delivery_lease",
+        include_str!("../../migrations/0011_webhook_delivery_lease.sql"),
+    ),
+    (
+        12,
+        "pet_event_order",
+        include_str!("../../migrations/0012_pet_event_order.sql"),
+    ),
+    (
+        13,
+        "pet_search_column",
+        include_str!("../../migrations/0013_pet_search_column.sql"),
+    ),
+    (
+        14,
+        "outbox_dead_letter",
+        include_str!("../../migrations/0014_outbox_dead_letter.sql"),
+    ),
+];
+
+const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )
+";
+
+/// Advisory lock key used to prevent concurrent migrations
+const MIGRATIONS_LOCK: i64 = 7_346_857;
+
+impl PostgresPool {
+    /// Returns versions of migrations which have not been applied
+    pub async fn migrations_pending(&self) -> Result<Vec<i32>, XErr> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+            .await?;
+        let applied: Vec<i32> = if row.get(0) {
+            client
+                .query("SELECT version FROM schema_migrations", &[])
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(MIGRATIONS
+            .iter()
+            .map(|(version, _, _)| *version)
+            .filter(|x| !applied.contains(x))
+            .collect())
+    }
+}
+
+impl PostgresClient {
+    /// Apply migrations which have not already been applied, each migration
+    /// is run in its own transaction
+    pub async fn migrate(&mut self) -> Result<(), XErr> {
+        self.client.batch_execute(MIGRATIONS_TABLE).await?;
+
+        for (version, name, sql) in MIGRATIONS {
+            let transaction = self.client.transaction().await?;
+            transaction
+                .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATIONS_LOCK])
+                .await?;
+            let applied = transaction
+                .query_opt(
+                    "SELECT version FROM schema_migrations WHERE version = $1",
+                    &[version],
+                )
+                .await?;
+
+            if applied.is_none() {
+                info!("applying migration {} {}", version, name);
+                transaction.batch_execute(sql).await?;
+                transaction
+                    .execute(
+                        "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                        &[version, name],
+                    )
+                    .await?;
+            }
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -3,8 +3,21 @@
 //! <https://cheatsheetseries.owasp.org/cheatsheets/Database_Security_Cheat_Sheet.html>
 //! <https://cheatsheetseries.owasp.org/cheatsheets/SQL_Injection_Prevention_Cheat_Sheet.html>
 use crate::internal::*;
+use futures::StreamExt;
 use petshop_proto::api::{Fortune, World};
 use std::fmt;
+use tokio::sync::mpsc;
+use tokio_postgres::AsyncMessage;
+
+mod audit;
+mod github;
+mod idempotency;
+mod migrations;
+mod outbox;
+mod pet_events;
+mod pets;
+mod rate_limit;
+mod webhooks;
 
 /// Postgres Pool
 pub struct PostgresPool {
@@ -13,6 +26,16 @@ pub struct PostgresPool {
     metrics: Arc<Metrics>,
 }
 
+/// Postgres Listener
+///
+/// Dedicated connection which receives notifications of channels, pool connections
+/// can not be used as their notifications are dropped
+pub struct PostgresListener {
+    /// Connection is closed when client is dropped
+    _client: tokio_postgres::Client,
+    notifications: mpsc::UnboundedReceiver<tokio_postgres::Notification>,
+}
+
 /// Postgres Client
 ///
 /// FIXME: Is there a way to refactor this so that pool/clients can be used
@@ -87,8 +110,7 @@ impl PostgresPool {
             .collect::<FuturesUnordered<_>>()
             .collect::<Vec<_>>()
             .await;
-        let worlds: Result<Vec<_>, _> = worlds.into_iter().collect();
-        Ok(worlds?)
+        worlds.into_iter().collect()
     }
 
     pub async fn db_world_updates(&self, queries: i32) -> Result<Vec<World>, XErr> {
@@ -174,12 +196,58 @@ impl PostgresClient {
     }
 }
 
+impl PostgresListener {
+    /// Connect and listen to channels, channel names must be identifiers
+    pub async fn from_config(config: &Config, channels: &[&str]) -> Result<Self, XErr> {
+        let pg_config = config.postgres.get_pg_config()?;
+        let (client, mut connection) = pg_config.connect(tokio_postgres::NoTls).await?;
+        let (tx, notifications) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        if tx.send(notification).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("listener connection error: {:#}", err);
+                        return;
+                    }
+                }
+            }
+        });
+
+        for channel in channels {
+            client.batch_execute(&format!("LISTEN {}", channel)).await?;
+        }
+        Ok(Self {
+            _client: client,
+            notifications,
+        })
+    }
+
+    /// Returns next notification, or none if the connection was closed
+    pub async fn recv(&mut self) -> Option<tokio_postgres::Notification> {
+        self.notifications.recv().await
+    }
+}
+
 impl fmt::Debug for PostgresPool {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PostgresPool").finish()
     }
 }
 
+impl fmt::Debug for PostgresListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostgresListener").finish()
+    }
+}
+
 impl fmt::Debug for PostgresClient {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PostgresClient").finish()
@@ -0,0 +1,151 @@
+//! # Postgres Outbox
+//!
+use crate::internal::*;
+use crate::postgres::{PostgresClient, PostgresPool};
+use tokio_postgres::Row;
+
+const OUTBOX_COLUMNS: &str =
+    "id, aggregate_type, aggregate_id, event_type, payload, attempts, retry_at, created_at";
+
+/// Insert outbox message in transaction of the domain change, so the message is
+/// committed if and only if the change is committed
+pub(super) async fn outbox_insert(
+    transaction: &deadpool_postgres::Transaction<'_>,
+    message: &OutboxMessage,
+) -> Result<(), XErr> {
+    let st = transaction
+        .prepare(
+            "
+                INSERT INTO outbox (aggregate_type, aggregate_id, event_type, payload)
+                VALUES ($1, $2, $3, $4)
+            ",
+        )
+        .await?;
+    transaction
+        .execute(
+            &st,
+            &[
+                &message.aggregate_type,
+                &message.aggregate_id,
+                &message.event_type,
+                &message.payload,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+impl PostgresPool {
+    /// Returns unpublished events which are not dead after id in order, up to limit
+    pub async fn outbox_select_pending(
+        &self,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<OutboxEvent>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    SELECT {}
+                    FROM outbox
+                    WHERE published_at IS NULL AND dead_at IS NULL AND id > $1
+                    ORDER BY id
+                    LIMIT $2
+                ",
+                OUTBOX_COLUMNS
+            ))
+            .await?;
+        let rows = client.query(&st, &[&after, &limit]).await?;
+        Ok(rows.iter().map(outbox_event_from_row).collect())
+    }
+
+    /// Mark event published
+    pub async fn outbox_published(&self, id: i64) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    UPDATE outbox
+                    SET attempts = attempts + 1, last_error = NULL, published_at = now()
+                    WHERE id = $1
+                ",
+            )
+            .await?;
+        client.execute(&st, &[&id]).await?;
+        Ok(())
+    }
+
+    /// Record failed attempt to publish event, it is retried by a relay after time
+    pub async fn outbox_failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: chrono::DateTime<Utc>,
+    ) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    UPDATE outbox
+                    SET attempts = attempts + 1, last_error = $2, retry_at = $3
+                    WHERE id = $1
+                ",
+            )
+            .await?;
+        client.execute(&st, &[&id, &error, &retry_at]).await?;
+        Ok(())
+    }
+
+    /// Record last failed attempt to publish event and mark it dead, it is not retried
+    pub async fn outbox_dead(&self, id: i64, error: &str) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    UPDATE outbox
+                    SET attempts = attempts + 1, last_error = $2, dead_at = now()
+                    WHERE id = $1
+                ",
+            )
+            .await?;
+        client.execute(&st, &[&id, &error]).await?;
+        Ok(())
+    }
+
+    /// Delete events published before time, returns number of events deleted
+    pub async fn outbox_delete_published_before(
+        &self,
+        before: chrono::DateTime<Utc>,
+    ) -> Result<u64, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare("DELETE FROM outbox WHERE published_at < $1")
+            .await?;
+        Ok(client.execute(&st, &[&before]).await?)
+    }
+}
+
+impl PostgresClient {
+    /// Try to take session advisory lock, returns false if it is held by another
+    /// session, the lock is released when the connection is closed
+    pub async fn advisory_lock_try(&self, key: i64) -> Result<bool, XErr> {
+        let row = self
+            .client
+            .query_one("SELECT pg_try_advisory_lock($1)", &[&key])
+            .await?;
+        Ok(row.get(0))
+    }
+}
+
+fn outbox_event_from_row(row: &Row) -> OutboxEvent {
+    OutboxEvent {
+        id: row.get(0),
+        aggregate_type: row.get(1),
+        aggregate_id: row.get(2),
+        event_type: row.get(3),
+        payload: row.get(4),
+        attempts: row.get(5),
+        retry_at: row.get(6),
+        created_at: row.get(7),
+    }
+}
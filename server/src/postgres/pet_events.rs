@@ -0,0 +1,119 @@
+//! # Postgres Pet Events
+//!
+use super::pets::pet_from_row_at;
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+use petshop_proto::api::PetEventType;
+use tokio_postgres::Row;
+
+/// Columns of pet and previous pet (from `jsonb_populate_record`), then event columns
+const PET_EVENT_COLUMNS: &str = "
+    p.id, p.category_id, p.category_name, p.name, p.photo_urls, p.tags, p.status, p.version,
+    q.id, q.category_id, q.category_name, q.name, q.photo_urls, q.tags, q.status, q.version,
+    e.id, e.event_type, e.created_at, e.previous IS NOT NULL, e.xid
+";
+
+/// Oldest transaction id which may still be running, events of older transactions
+/// are committed or rolled back
+const PET_EVENT_XMIN: &str = "pg_snapshot_xmin(pg_current_snapshot())::TEXT::BIGINT";
+
+impl PostgresPool {
+    /// Returns events after position in order, up to limit, events of transactions
+    /// which may still be running (and any after them) are not returned yet
+    pub async fn pet_event_select_after(
+        &self,
+        after: PetEventPosition,
+        limit: i64,
+    ) -> Result<Vec<PetEventRecord>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    SELECT {}
+                    FROM pet_event e
+                    CROSS JOIN LATERAL jsonb_populate_record(NULL::pet, e.pet) p
+                    CROSS JOIN LATERAL jsonb_populate_record(NULL::pet, e.previous) q
+                    WHERE (e.xid, e.id) > ($1, $2) AND e.xid < {}
+                    ORDER BY e.xid, e.id
+                    LIMIT $3
+                ",
+                PET_EVENT_COLUMNS, PET_EVENT_XMIN
+            ))
+            .await?;
+        let rows = client.query(&st, &[&after.xid, &after.id, &limit]).await?;
+        Ok(rows.iter().map(pet_event_from_row).collect())
+    }
+
+    /// Returns position of last event which can be read, or the start if there are
+    /// no events
+    pub async fn pet_event_last_position(&self) -> Result<PetEventPosition, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    SELECT xid, id
+                    FROM pet_event
+                    WHERE xid < {}
+                    ORDER BY xid DESC, id DESC
+                    LIMIT 1
+                ",
+                PET_EVENT_XMIN
+            ))
+            .await?;
+        let row = client.query_opt(&st, &[]).await?;
+        Ok(row
+            .map(|row| PetEventPosition {
+                xid: row.get(0),
+                id: row.get(1),
+            })
+            .unwrap_or_default())
+    }
+
+    /// Returns position of event if it exists, events are deleted oldest first so
+    /// events after an existing event have not been deleted
+    pub async fn pet_event_position(&self, id: i64) -> Result<Option<PetEventPosition>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare("SELECT xid FROM pet_event WHERE id = $1")
+            .await?;
+        let row = client.query_opt(&st, &[&id]).await?;
+        Ok(row.map(|row| PetEventPosition {
+            xid: row.get(0),
+            id,
+        }))
+    }
+
+    /// Delete events created before time, returns number of events deleted
+    pub async fn pet_event_delete_before(
+        &self,
+        before: chrono::DateTime<Utc>,
+    ) -> Result<u64, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare("DELETE FROM pet_event WHERE created_at < $1")
+            .await?;
+        Ok(client.execute(&st, &[&before]).await?)
+    }
+}
+
+fn pet_event_from_row(row: &Row) -> PetEventRecord {
+    let event_type: &str = row.get(17);
+    let event_type = match event_type {
+        "created" => PetEventType::Created,
+        "deleted" => PetEventType::Deleted,
+        _ => PetEventType::Updated,
+    };
+    let previous: bool = row.get(19);
+    PetEventRecord {
+        id: row.get(16),
+        event_type,
+        pet: pet_from_row_at(row, 0),
+        previous: if previous {
+            Some(pet_from_row_at(row, 8))
+        } else {
+            None
+        },
+        created_at: row.get(18),
+        xid: row.get(20),
+    }
+}
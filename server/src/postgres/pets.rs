@@ -0,0 +1,413 @@
+//! # Postgres Pets
+//!
+use super::outbox::outbox_insert;
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+use crate::services::{EVENT_PET_CREATED, EVENT_PET_UPDATED};
+use petshop_proto::api::{
+    Category, Pet, PetFacet, PetSearchQuery, PetSearchResult, PetSearchSort, PetSearchTagMatch,
+    PetStatusFacet, Tag,
+};
+use tokio_postgres::{IsolationLevel, Row};
+
+const PET_COLUMNS: &str = "id, category_id, category_name, name, photo_urls, tags, status, version";
+
+/// Search query, none if query is empty or only has stop words, parameter is query
+const PET_SEARCH_TSQUERY: &str = "
+    (CASE WHEN numnode(websearch_to_tsquery('english', $1::TEXT)) > 0
+        THEN websearch_to_tsquery('english', $1::TEXT)
+    END)
+";
+
+/// Search filters of status, tags with tag match all and category, since
+/// parameters are known when searches are planned filters which are not set are
+/// removed from the plan and the others use the indexes of pet columns
+const PET_SEARCH_STATUS_MATCH: &str = "(cardinality($2::INTEGER[]) = 0 OR status = ANY($2))";
+const PET_SEARCH_TAGS_MATCH: &str = "
+    (cardinality($3::TEXT[]) = 0 OR CASE WHEN $4::BOOLEAN
+        THEN tags @> (SELECT jsonb_agg(jsonb_build_object('name', x)) FROM unnest($3) x)
+        ELSE EXISTS (SELECT 1 FROM jsonb_array_elements(tags) t WHERE t->>'name' = ANY($3))
+    END)
+";
+const PET_SEARCH_CATEGORY_MATCH: &str = "($5::TEXT = '' OR category_name = $5)";
+
+/// Maximum number of tag and category facets
+const PET_SEARCH_FACETS_MAX: i64 = 50;
+
+impl PostgresPool {
+    /// Insert pet and its created event, returns pet with id
+    pub async fn pet_insert(&self, pet: &Pet) -> Result<Pet, XErr> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let st = transaction
+            .prepare(&format!(
+                "
+                    INSERT INTO pet (category_id, category_name, name, photo_urls, tags, status)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    RETURNING {}
+                ",
+                PET_COLUMNS
+            ))
+            .await?;
+        let (category_id, category_name) = pet_category(pet);
+        let row = transaction
+            .query_one(
+                &st,
+                &[
+                    &category_id,
+                    &category_name,
+                    &pet.name,
+                    &pet.photo_urls,
+                    &pet_tags(pet),
+                    &pet.status,
+                ],
+            )
+            .await?;
+        let pet = pet_from_row(&row);
+        outbox_insert(&transaction, &OutboxMessage::pet(EVENT_PET_CREATED, &pet)).await?;
+        transaction.commit().await?;
+        Ok(pet)
+    }
+
+    /// Update pet if its version is current and insert its updated event, returns
+    /// previous and updated pet or none if pet does not exist
+    pub async fn pet_update(&self, pet: &Pet) -> Result<Option<(Pet, Pet)>, XErr> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let st = transaction
+            .prepare(&format!(
+                "SELECT {} FROM pet WHERE id = $1 FOR UPDATE",
+                PET_COLUMNS
+            ))
+            .await?;
+        let previous = match transaction.query_opt(&st, &[&pet.id]).await? {
+            Some(row) => pet_from_row(&row),
+            None => return Ok(None),
+        };
+        if previous.version != pet.version {
+            return Err(XErr::conflict("pet"));
+        }
+        let st = transaction
+            .prepare(&format!(
+                "
+                    UPDATE pet
+                    SET category_id = $2, category_name = $3, name = $4, photo_urls = $5,
+                        tags = $6, status = $7, version = version + 1, updated_at = now()
+                    WHERE id = $1
+                    RETURNING {}
+                ",
+                PET_COLUMNS
+            ))
+            .await?;
+        let (category_id, category_name) = pet_category(pet);
+        let row = transaction
+            .query_one(
+                &st,
+                &[
+                    &pet.id,
+                    &category_id,
+                    &category_name,
+                    &pet.name,
+                    &pet.photo_urls,
+                    &pet_tags(pet),
+                    &pet.status,
+                ],
+            )
+            .await?;
+        let pet = pet_from_row(&row);
+        outbox_insert(&transaction, &OutboxMessage::pet(EVENT_PET_UPDATED, &pet)).await?;
+        transaction.commit().await?;
+        Ok(Some((previous, pet)))
+    }
+
+    /// Returns pet by id
+    pub async fn pet_select(&self, id: i64) -> Result<Option<Pet>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!("SELECT {} FROM pet WHERE id = $1", PET_COLUMNS))
+            .await?;
+        let row = client.query_opt(&st, &[&id]).await?;
+        Ok(row.as_ref().map(pet_from_row))
+    }
+
+    /// Returns pets with any of status
+    pub async fn pet_find_by_status(&self, status: &[i32]) -> Result<Vec<Pet>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    SELECT {}
+                    FROM pet
+                    WHERE status = ANY($1)
+                    ORDER BY id
+                ",
+                PET_COLUMNS
+            ))
+            .await?;
+        let rows = client.query(&st, &[&status]).await?;
+        Ok(rows.iter().map(pet_from_row).collect())
+    }
+
+    /// Returns pets with any of tag names
+    pub async fn pet_find_by_tag(&self, tags: &[String]) -> Result<Vec<Pet>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    SELECT {}
+                    FROM pet
+                    WHERE EXISTS (
+                        SELECT 1 FROM jsonb_array_elements(tags) t WHERE t->>'name' = ANY($1)
+                    )
+                    ORDER BY id
+                ",
+                PET_COLUMNS
+            ))
+            .await?;
+        let rows = client.query(&st, &[&tags]).await?;
+        Ok(rows.iter().map(pet_from_row).collect())
+    }
+
+    /// Returns page of pets matching search at offset, total number of matching
+    /// pets and facet counts
+    pub async fn pet_search(
+        &self,
+        search: &PetSearchQuery,
+        offset: i64,
+        limit: i64,
+    ) -> Result<PetSearchResult, XErr> {
+        let mut client = self.pool.get().await?;
+        // Page and facets are read from the same snapshot
+        let transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await?;
+        let order = match PetSearchSort::from_i32(search.sort) {
+            Some(PetSearchSort::Name) => "lower(name), id",
+            Some(PetSearchSort::Newest) => "created_at DESC, id DESC",
+            _ => "rank DESC, id",
+        };
+        let query_match = format!("({0} IS NULL OR search @@ {0})", PET_SEARCH_TSQUERY);
+        let tag_all = search.tag_match == PetSearchTagMatch::All as i32;
+        let params: [&(dyn tokio_postgres::types::ToSql + Sync); 5] = [
+            &search.query,
+            &search.status,
+            &search.tags,
+            &tag_all,
+            &search.category,
+        ];
+
+        let st = transaction
+            .prepare(&format!(
+                "
+                    SELECT {columns}, COALESCE(ts_rank(search, {tsquery}), 0) AS rank
+                    FROM pet
+                    WHERE {query} AND {status} AND {tags} AND {category}
+                    ORDER BY {order}
+                    LIMIT $6 OFFSET $7
+                ",
+                columns = PET_COLUMNS,
+                tsquery = PET_SEARCH_TSQUERY,
+                query = query_match,
+                status = PET_SEARCH_STATUS_MATCH,
+                tags = PET_SEARCH_TAGS_MATCH,
+                category = PET_SEARCH_CATEGORY_MATCH,
+                order = order,
+            ))
+            .await?;
+        let rows = transaction
+            .query(&st, &[&params[..], &[&limit, &offset]].concat())
+            .await?;
+        let pets = rows.iter().map(pet_from_row).collect();
+
+        // Facets of each field match the other filters, so counts of values which
+        // are not selected show the pets a filter would add
+        let st = transaction
+            .prepare(&format!(
+                "
+                    SELECT 'total', NULL, count(*)
+                    FROM pet
+                    WHERE {query} AND {status} AND {tags} AND {category}
+                    UNION ALL
+                    SELECT 'status', status::TEXT, count(*)
+                    FROM pet
+                    WHERE {query} AND {tags} AND {category}
+                    GROUP BY status
+                    UNION ALL
+                    (
+                        SELECT 'tag', t->>'name', count(DISTINCT id)
+                        FROM pet, jsonb_array_elements(tags) t
+                        WHERE {query} AND {status} AND {category} AND (NOT $4 OR {tags})
+                        GROUP BY t->>'name'
+                        ORDER BY 3 DESC, 2
+                        LIMIT $6
+                    )
+                    UNION ALL
+                    (
+                        SELECT 'category', category_name, count(*)
+                        FROM pet
+                        WHERE {query} AND {status} AND {tags} AND category_name IS NOT NULL
+                        GROUP BY category_name
+                        ORDER BY 3 DESC, 2
+                        LIMIT $6
+                    )
+                ",
+                query = query_match,
+                status = PET_SEARCH_STATUS_MATCH,
+                tags = PET_SEARCH_TAGS_MATCH,
+                category = PET_SEARCH_CATEGORY_MATCH,
+            ))
+            .await?;
+        let rows = transaction
+            .query(&st, &[&params[..], &[&PET_SEARCH_FACETS_MAX]].concat())
+            .await?;
+        transaction.commit().await?;
+
+        let mut result = PetSearchResult {
+            pets,
+            ..Default::default()
+        };
+        for row in rows {
+            let facet: &str = row.get(0);
+            let name: Option<String> = row.get(1);
+            let count: i64 = row.get(2);
+            let name = name.unwrap_or_default();
+            match facet {
+                "total" => result.total_size = count,
+                "status" => result.status_facets.push(PetStatusFacet {
+                    status: name.parse().unwrap_or_default(),
+                    count,
+                }),
+                "tag" => result.tag_facets.push(PetFacet { name, count }),
+                _ => result.category_facets.push(PetFacet { name, count }),
+            }
+        }
+        result.status_facets.sort_by_key(|x| x.status);
+        Ok(result)
+    }
+
+    /// Insert pet photo, append its URL to pet and insert its updated event, returns
+    /// none if pet does not exist or already has `max_photos` photos
+    pub async fn pet_photo_insert(
+        &self,
+        pet_id: i64,
+        photo: &StoragePhoto,
+        max_photos: i32,
+    ) -> Result<Option<Pet>, XErr> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let st = transaction
+            .prepare(&format!(
+                "
+                    UPDATE pet
+                    SET photo_urls = array_append(photo_urls, $2), version = version + 1,
+                        updated_at = now()
+                    WHERE id = $1 AND cardinality(photo_urls) < $3
+                    RETURNING {}
+                ",
+                PET_COLUMNS
+            ))
+            .await?;
+        let row = match transaction
+            .query_opt(&st, &[&pet_id, &photo.url, &max_photos])
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let st = transaction
+            .prepare(
+                "
+                    INSERT INTO pet_photo (id, pet_id, content_type, size_bytes, storage_key)
+                    VALUES ($1, $2, $3, $4, $5)
+                ",
+            )
+            .await?;
+        transaction
+            .execute(
+                &st,
+                &[
+                    &photo.id,
+                    &pet_id,
+                    &photo.content_type,
+                    &photo.size_bytes,
+                    &photo.key,
+                ],
+            )
+            .await?;
+        let pet = pet_from_row(&row);
+        outbox_insert(&transaction, &OutboxMessage::pet(EVENT_PET_UPDATED, &pet)).await?;
+        transaction.commit().await?;
+        Ok(Some(pet))
+    }
+
+    /// Returns pet photo content type and storage key
+    pub async fn pet_photo_select(
+        &self,
+        pet_id: i64,
+        id: &str,
+    ) -> Result<Option<(String, String)>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    SELECT content_type, storage_key
+                    FROM pet_photo
+                    WHERE pet_id = $1 AND id = $2
+                ",
+            )
+            .await?;
+        let row = client.query_opt(&st, &[&pet_id, &id]).await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+}
+
+fn pet_category(pet: &Pet) -> (Option<i64>, Option<String>) {
+    match pet.category.as_ref() {
+        Some(category) => (Some(category.id), Some(category.name.clone())),
+        None => (None, None),
+    }
+}
+
+fn pet_tags(pet: &Pet) -> serde_json::Value {
+    pet.tags
+        .iter()
+        .map(|x| json!({ "id": x.id, "name": x.name }))
+        .collect()
+}
+
+fn pet_from_row(row: &Row) -> Pet {
+    pet_from_row_at(row, 0)
+}
+
+/// Returns pet from `PET_COLUMNS` starting at column index
+pub(super) fn pet_from_row_at(row: &Row, i: usize) -> Pet {
+    let category_id: Option<i64> = row.get(i + 1);
+    let category_name: Option<String> = row.get(i + 2);
+    let tags: serde_json::Value = row.get(i + 5);
+    Pet {
+        id: row.get(i),
+        category: category_id.map(|id| Category {
+            id,
+            name: category_name.unwrap_or_default(),
+        }),
+        name: row.get(i + 3),
+        photo_urls: row.get(i + 4),
+        tags: tags
+            .as_array()
+            .map(|tags| {
+                tags.iter()
+                    .map(|x| Tag {
+                        id: x["id"].as_i64().unwrap_or_default(),
+                        name: x["name"].as_str().unwrap_or_default().to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        status: row.get(i + 6),
+        // Events stored before pets had versions do not have one
+        version: row.get::<_, Option<i64>>(i + 7).unwrap_or_default(),
+    }
+}
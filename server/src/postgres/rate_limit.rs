@@ -0,0 +1,57 @@
+//! # Postgres Rate Limit
+//!
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+use std::time::Duration;
+
+impl PostgresPool {
+    /// Refill token bucket with key and take a token if one is available, returns
+    /// remaining tokens and true if request is allowed
+    ///
+    /// Refill is calculated using database time so buckets are consistent across
+    /// replicas, all `SET` expressions use the previous row values
+    pub async fn rate_limit_take(
+        &self,
+        key: &str,
+        requests_per_second: f64,
+        burst: f64,
+    ) -> Result<(f64, bool), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    INSERT INTO rate_limit_bucket AS b (key, tokens, allowed)
+                    VALUES ($1, $3::DOUBLE PRECISION - 1, true)
+                    ON CONFLICT (key) DO UPDATE SET
+                        tokens = CASE
+                            WHEN LEAST($3, b.tokens + EXTRACT(EPOCH FROM now() - b.updated_at)::DOUBLE PRECISION * $2::DOUBLE PRECISION) >= 1
+                            THEN LEAST($3, b.tokens + EXTRACT(EPOCH FROM now() - b.updated_at)::DOUBLE PRECISION * $2::DOUBLE PRECISION) - 1
+                            ELSE LEAST($3, b.tokens + EXTRACT(EPOCH FROM now() - b.updated_at)::DOUBLE PRECISION * $2::DOUBLE PRECISION)
+                        END,
+                        allowed = LEAST($3, b.tokens + EXTRACT(EPOCH FROM now() - b.updated_at)::DOUBLE PRECISION * $2::DOUBLE PRECISION) >= 1,
+                        updated_at = now()
+                    RETURNING tokens, allowed
+                ",
+            )
+            .await?;
+        let row = client
+            .query_one(&st, &[&key, &requests_per_second, &burst])
+            .await?;
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Delete buckets which have not been updated for refill duration, they are
+    /// full and the same as new buckets, returns number of deleted buckets
+    pub async fn rate_limit_delete_refilled(&self, refill: Duration) -> Result<u64, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    DELETE FROM rate_limit_bucket
+                    WHERE updated_at < now() - make_interval(secs => $1)
+                ",
+            )
+            .await?;
+        Ok(client.execute(&st, &[&refill.as_secs_f64()]).await?)
+    }
+}
@@ -0,0 +1,339 @@
+//! # Postgres Webhooks
+//!
+use crate::internal::*;
+use crate::postgres::PostgresPool;
+use petshop_proto::api::{
+    WebhookDelivery, WebhookDeliveryAttempt, WebhookDeliveryStatus, WebhookSubscriber,
+};
+use std::collections::HashMap;
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_DELIVERED: &str = "delivered";
+const STATUS_FAILED: &str = "failed";
+
+const WEBHOOK_DELIVERY_STATUS_UPDATE: &str = "
+    UPDATE webhook_delivery
+    SET status = $2, updated_at = now()
+    WHERE id = $1
+";
+
+/// Returns jobs of updated deliveries in `d` with subscriber details and number of attempts
+const WEBHOOK_DELIVERY_JOBS: &str = "
+    SELECT d.id, d.event_type, d.payload, d.created_at, s.url, s.secret,
+        (SELECT COUNT(*) FROM webhook_delivery_attempt a WHERE a.delivery_id = d.id)
+    FROM d
+    JOIN webhook_subscriber s ON s.id = d.subscriber_id
+";
+
+impl PostgresPool {
+    /// Insert webhook subscriber, returns subscriber with id
+    pub async fn webhook_subscriber_insert(
+        &self,
+        subscriber: WebhookSubscriber,
+    ) -> Result<WebhookSubscriber, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    INSERT INTO webhook_subscriber (url, event_types, secret, active)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id
+                ",
+            )
+            .await?;
+        let row = client
+            .query_one(
+                &st,
+                &[
+                    &subscriber.url,
+                    &subscriber.event_types,
+                    &subscriber.secret,
+                    &subscriber.active,
+                ],
+            )
+            .await?;
+        Ok(WebhookSubscriber {
+            id: row.get(0),
+            ..subscriber
+        })
+    }
+
+    /// Insert webhook delivery leased until time for each active subscriber of event type
+    pub async fn webhook_delivery_insert(
+        &self,
+        event_type: &str,
+        payload: &serde_json::Value,
+        locked_until: chrono::DateTime<Utc>,
+    ) -> Result<Vec<WebhookDeliveryJob>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    WITH d AS (
+                        INSERT INTO webhook_delivery
+                            (subscriber_id, event_type, payload, locked_until)
+                        SELECT id, $1, $2, $3
+                        FROM webhook_subscriber
+                        WHERE active AND $1 = ANY(event_types)
+                        RETURNING id, subscriber_id, event_type, payload, created_at
+                    )
+                    {}
+                ",
+                WEBHOOK_DELIVERY_JOBS
+            ))
+            .await?;
+        let rows = client
+            .query(&st, &[&event_type, payload, &locked_until])
+            .await?;
+        Ok(rows.iter().map(webhook_delivery_job_from_row).collect())
+    }
+
+    /// Reset webhook delivery status to pending and lease it until time, returns
+    /// delivery if it exists, or a conflict error if the delivery is already pending
+    /// and its lease has not expired
+    pub async fn webhook_delivery_redeliver(
+        &self,
+        id: i64,
+        locked_until: chrono::DateTime<Utc>,
+    ) -> Result<Option<WebhookDeliveryJob>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    WITH d AS (
+                        UPDATE webhook_delivery
+                        SET status = $2, locked_until = $3, updated_at = now()
+                        WHERE id = $1 AND (status <> $2 OR locked_until < now())
+                        RETURNING id, subscriber_id, event_type, payload, created_at
+                    )
+                    {}
+                ",
+                WEBHOOK_DELIVERY_JOBS
+            ))
+            .await?;
+        let row = client
+            .query_opt(&st, &[&id, &STATUS_PENDING, &locked_until])
+            .await?;
+        if row.is_none() {
+            let exists = client
+                .query_opt("SELECT 1 FROM webhook_delivery WHERE id = $1", &[&id])
+                .await?;
+            if exists.is_some() {
+                return Err(XErr::conflict("webhook_delivery"));
+            }
+        }
+        Ok(row.as_ref().map(webhook_delivery_job_from_row))
+    }
+
+    /// Lease pending webhook deliveries with expired leases until time, up to limit
+    pub async fn webhook_delivery_resume(
+        &self,
+        locked_until: chrono::DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDeliveryJob>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(&format!(
+                "
+                    WITH d AS (
+                        UPDATE webhook_delivery
+                        SET locked_until = $2, updated_at = now()
+                        WHERE id IN (
+                            SELECT id
+                            FROM webhook_delivery
+                            WHERE status = $1 AND locked_until < now()
+                            ORDER BY id
+                            LIMIT $3
+                            FOR UPDATE SKIP LOCKED
+                        )
+                        RETURNING id, subscriber_id, event_type, payload, created_at
+                    )
+                    {}
+                ",
+                WEBHOOK_DELIVERY_JOBS
+            ))
+            .await?;
+        let rows = client
+            .query(&st, &[&STATUS_PENDING, &locked_until, &limit])
+            .await?;
+        Ok(rows.iter().map(webhook_delivery_job_from_row).collect())
+    }
+
+    /// Extend lease of pending webhook delivery until time
+    pub async fn webhook_delivery_lease(
+        &self,
+        id: i64,
+        locked_until: chrono::DateTime<Utc>,
+    ) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    UPDATE webhook_delivery
+                    SET locked_until = $3
+                    WHERE id = $1 AND status = $2
+                ",
+            )
+            .await?;
+        client
+            .execute(&st, &[&id, &STATUS_PENDING, &locked_until])
+            .await?;
+        Ok(())
+    }
+
+    /// Insert webhook delivery attempt, and mark delivery as delivered if successful
+    pub async fn webhook_delivery_attempt_insert(
+        &self,
+        delivery_id: i64,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<String>,
+        duration_millis: i64,
+        delivered: bool,
+    ) -> Result<(), XErr> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let st = transaction
+            .prepare(
+                "
+                    INSERT INTO webhook_delivery_attempt
+                        (delivery_id, attempt, status_code, error, duration_millis)
+                    VALUES ($1, $2, $3, $4, $5)
+                ",
+            )
+            .await?;
+        transaction
+            .execute(
+                &st,
+                &[
+                    &delivery_id,
+                    &attempt,
+                    &status_code,
+                    &error,
+                    &duration_millis,
+                ],
+            )
+            .await?;
+        if delivered {
+            transaction
+                .execute(
+                    WEBHOOK_DELIVERY_STATUS_UPDATE,
+                    &[&delivery_id, &STATUS_DELIVERED],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Mark webhook delivery as failed
+    pub async fn webhook_delivery_failed(&self, id: i64) -> Result<(), XErr> {
+        let client = self.pool.get().await?;
+        client
+            .execute(WEBHOOK_DELIVERY_STATUS_UPDATE, &[&id, &STATUS_FAILED])
+            .await?;
+        Ok(())
+    }
+
+    /// Returns number of pending webhook deliveries
+    pub async fn webhook_delivery_pending_count(&self) -> Result<i64, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare("SELECT COUNT(*) FROM webhook_delivery WHERE status = $1")
+            .await?;
+        let row = client.query_one(&st, &[&STATUS_PENDING]).await?;
+        Ok(row.get(0))
+    }
+
+    /// Returns recent webhook deliveries with attempts, optionally filtered by subscriber
+    pub async fn webhook_delivery_list(
+        &self,
+        subscriber_id: Option<i64>,
+        id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, XErr> {
+        let client = self.pool.get().await?;
+        let st = client
+            .prepare(
+                "
+                    SELECT id, subscriber_id, event_type, status, created_at, updated_at
+                    FROM webhook_delivery
+                    WHERE ($1::BIGINT IS NULL OR subscriber_id = $1)
+                        AND ($2::BIGINT IS NULL OR id = $2)
+                    ORDER BY created_at DESC, id DESC
+                    LIMIT $3
+                ",
+            )
+            .await?;
+        let rows = client.query(&st, &[&subscriber_id, &id, &limit]).await?;
+        let mut deliveries: Vec<WebhookDelivery> = rows
+            .into_iter()
+            .map(|row| {
+                let status: String = row.get(3);
+                WebhookDelivery {
+                    id: row.get(0),
+                    subscriber_id: row.get(1),
+                    event_type: row.get(2),
+                    status: webhook_delivery_status(&status) as i32,
+                    attempts: vec![],
+                    created_at: Some(chrono_into_prost_timestamp(row.get(4))),
+                    updated_at: Some(chrono_into_prost_timestamp(row.get(5))),
+                }
+            })
+            .collect();
+
+        let ids: Vec<i64> = deliveries.iter().map(|x| x.id).collect();
+        let st = client
+            .prepare(
+                "
+                    SELECT delivery_id, attempt, status_code, error, duration_millis, created_at
+                    FROM webhook_delivery_attempt
+                    WHERE delivery_id = ANY($1)
+                    ORDER BY attempt
+                ",
+            )
+            .await?;
+        let rows = client.query(&st, &[&ids]).await?;
+        let mut attempts: HashMap<i64, Vec<WebhookDeliveryAttempt>> = HashMap::new();
+        for row in rows {
+            let status_code: Option<i32> = row.get(2);
+            let error: Option<String> = row.get(3);
+            attempts
+                .entry(row.get(0))
+                .or_default()
+                .push(WebhookDeliveryAttempt {
+                    attempt: row.get(1),
+                    status_code: status_code.unwrap_or_default(),
+                    error: error.unwrap_or_default(),
+                    duration_millis: row.get(4),
+                    created_at: Some(chrono_into_prost_timestamp(row.get(5))),
+                });
+        }
+        for delivery in deliveries.iter_mut() {
+            delivery.attempts = attempts.remove(&delivery.id).unwrap_or_default();
+        }
+
+        Ok(deliveries)
+    }
+}
+
+fn webhook_delivery_job_from_row(row: &tokio_postgres::Row) -> WebhookDeliveryJob {
+    let attempts: i64 = row.get(6);
+    WebhookDeliveryJob {
+        id: row.get(0),
+        event_type: row.get(1),
+        payload: row.get(2),
+        created_at: row.get(3),
+        url: row.get(4),
+        secret: row.get(5),
+        attempts: attempts as i32,
+    }
+}
+
+fn webhook_delivery_status(status: &str) -> WebhookDeliveryStatus {
+    match status {
+        STATUS_DELIVERED => WebhookDeliveryStatus::Delivered,
+        STATUS_FAILED => WebhookDeliveryStatus::Failed,
+        _ => WebhookDeliveryStatus::Pending,
+    }
+}
@@ -0,0 +1,283 @@
+//! # Audit
+//!
+//! Append-only audit log of mutating requests
+//!
+//! - Handlers of mutating RPCs create an entry with the actor from `Auth`, method,
+//!   client address and request id, then record it with the outcome of the request
+//! - Entries include a diff of changed fields, values of sensitive fields are masked
+//! - Records are stored in the `audit_log` table which rejects updates and deletes
+//! - Admins can list records by time range and actor with the `Audit` service
+//! - Requests rejected before they are authenticated are not recorded, so clients
+//!   can't fill the append-only table, they are rate limited by client address instead
+//!
+//! Actors are trusted as `Auth` reports them: users from `x-auth-request-*` headers
+//! set by the authenticating proxy, and API keys which are not verified yet. The server
+//! must only be reachable through the proxy, which has to strip these headers from
+//! client requests, otherwise actors (including admins) can be spoofed. Example envoy
+//! listeners without the ext_authz filter remove them with `request_headers_to_remove`
+//!
+//! <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#which-events-to-log>
+//! <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#data-to-exclude>
+use crate::internal::*;
+use crate::services::{ACTOR_ANONYMOUS, X_REQUEST_ID};
+use std::collections::BTreeSet;
+use std::fmt;
+use tonic::{Code, Request, Status};
+
+/// Value of masked fields in diff
+const AUDIT_MASKED: &str = "[masked]";
+
+/// Audit Configuration
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub admins: Vec<String>,
+    pub masked_fields: Vec<String>,
+    pub forwarded_trusted_hops: usize,
+}
+
+/// Audit entry of a request, recorded with the outcome when it has finished
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub method: &'static str,
+    pub target_id: String,
+    pub client_addr: String,
+    pub request_id: String,
+    previous: Option<serde_json::Value>,
+    current: Option<serde_json::Value>,
+}
+
+/// Audit
+pub struct Audit {
+    config: AuditConfig,
+    postgres: Arc<PostgresPool>,
+    auth: Arc<Auth>,
+}
+
+impl Audit {
+    pub fn from_config(config: &Config, postgres: Arc<PostgresPool>, auth: Arc<Auth>) -> Self {
+        Self {
+            config: config.audit.clone(),
+            postgres,
+            auth,
+        }
+    }
+
+    /// Returns entry of request to method
+    pub fn entry<T>(&self, method: &'static str, request: &Request<T>) -> AuditEntry {
+        let headers = request.metadata().clone().into_headers();
+        let client_addr = http_headers_client_addr(&headers, self.config.forwarded_trusted_hops)
+            .unwrap_or_default();
+        let request_id = headers
+            .get(X_REQUEST_ID)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        AuditEntry {
+            actor: self.auth.actor(request),
+            method,
+            target_id: String::new(),
+            client_addr,
+            request_id,
+            previous: None,
+            current: None,
+        }
+    }
+
+    /// Returns true if actor is an admin, see module docs for the trust boundary
+    pub fn is_admin(&self, actor: &str) -> bool {
+        self.config.admins.iter().any(|x| x == actor)
+    }
+
+    /// Record entry with outcome of request, errors are logged so the response
+    /// of a request which has already made changes is not affected
+    pub async fn record<T>(&self, entry: AuditEntry, result: &Result<T, Status>) {
+        if !audit_recorded(&entry.actor, result) {
+            info!("audit skipped unauthenticated {}", entry.method);
+            return;
+        }
+        let diff = audit_diff(
+            entry.previous.as_ref(),
+            entry.current.as_ref(),
+            &self.config.masked_fields,
+        );
+        let outcome = audit_outcome(result);
+        if let Err(err) = self.postgres.audit_insert(&entry, &diff, &outcome).await {
+            let err: Error = err.into();
+            warn!(
+                "audit record error: {} {} {} {}: {:#}",
+                entry.actor, entry.method, entry.target_id, outcome, err
+            );
+        }
+    }
+}
+
+impl AuditEntry {
+    /// Set id of resource changed by request
+    pub fn target(&mut self, id: impl ToString) {
+        self.target_id = id.to_string();
+    }
+
+    /// Set resource before and after request, previous is none for created resources
+    pub fn changes(
+        &mut self,
+        previous: Option<serde_json::Value>,
+        current: Option<serde_json::Value>,
+    ) {
+        self.previous = previous;
+        self.current = current;
+    }
+}
+
+/// Returns object of changed fields with `old` and `new` values, values of
+/// masked fields and fields nested in them are masked
+fn audit_diff(
+    previous: Option<&serde_json::Value>,
+    current: Option<&serde_json::Value>,
+    masked: &[String],
+) -> serde_json::Value {
+    let empty = serde_json::Map::new();
+    let previous = previous.and_then(|x| x.as_object()).unwrap_or(&empty);
+    let current = current.and_then(|x| x.as_object()).unwrap_or(&empty);
+
+    let keys: BTreeSet<&String> = previous.keys().chain(current.keys()).collect();
+    let mut diff = serde_json::Map::new();
+    for key in keys {
+        let old = previous.get(key).unwrap_or(&serde_json::Value::Null);
+        let new = current.get(key).unwrap_or(&serde_json::Value::Null);
+        if old == new {
+            continue;
+        }
+        let (old, new) = if audit_masked(key, masked) {
+            (audit_mask_value(old), audit_mask_value(new))
+        } else {
+            (audit_mask(old, masked), audit_mask(new, masked))
+        };
+        diff.insert(key.clone(), json!({ "old": old, "new": new }));
+    }
+    serde_json::Value::Object(diff)
+}
+
+fn audit_masked(key: &str, masked: &[String]) -> bool {
+    masked.iter().any(|x| x.eq_ignore_ascii_case(key))
+}
+
+/// Returns value with values of masked fields in nested objects masked
+fn audit_mask(value: &serde_json::Value, masked: &[String]) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(x) => serde_json::Value::Object(
+            x.iter()
+                .map(|(k, v)| {
+                    let v = if audit_masked(k, masked) {
+                        audit_mask_value(v)
+                    } else {
+                        audit_mask(v, masked)
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+        ),
+        serde_json::Value::Array(x) => {
+            serde_json::Value::Array(x.iter().map(|v| audit_mask(v, masked)).collect())
+        }
+        value => value.clone(),
+    }
+}
+
+/// Returns masked value, null values are not masked so it is clear when a
+/// sensitive field was set or cleared
+fn audit_mask_value(value: &serde_json::Value) -> serde_json::Value {
+    if value.is_null() {
+        serde_json::Value::Null
+    } else {
+        json!(AUDIT_MASKED)
+    }
+}
+
+/// Returns true if outcome of request should be recorded, failures of anonymous or
+/// unauthenticated requests are not
+fn audit_recorded<T>(actor: &str, result: &Result<T, Status>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(status) => actor != ACTOR_ANONYMOUS && status.code() != Code::Unauthenticated,
+    }
+}
+
+/// Returns name of gRPC status code of result
+fn audit_outcome<T>(result: &Result<T, Status>) -> String {
+    let code = match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    };
+    format!("{:?}", code)
+}
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Audit").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_diff_test() {
+        let masked = vec!["secret".to_string()];
+        let previous = json!({
+            "id": 1,
+            "name": "rex",
+            "secret": "abc",
+            "tags": [{ "name": "dog" }],
+        });
+        let current = json!({
+            "id": 1,
+            "name": "max",
+            "secret": "xyz",
+            "tags": [{ "name": "dog", "secret": "nested" }],
+        });
+        let diff = audit_diff(Some(&previous), Some(&current), &masked);
+        assert_eq!(
+            diff,
+            json!({
+                "name": { "old": "rex", "new": "max" },
+                "secret": { "old": "[masked]", "new": "[masked]" },
+                "tags": {
+                    "old": [{ "name": "dog" }],
+                    "new": [{ "name": "dog", "secret": "[masked]" }],
+                },
+            })
+        );
+
+        let diff = audit_diff(None, Some(&json!({ "id": 2, "Secret": "" })), &masked);
+        assert_eq!(
+            diff,
+            json!({
+                "id": { "old": null, "new": 2 },
+                "Secret": { "old": null, "new": "[masked]" },
+            })
+        );
+        assert_eq!(audit_diff(None, None, &masked), json!({}));
+
+        assert_eq!(audit_outcome::<()>(&Ok(())), "Ok");
+        assert_eq!(
+            audit_outcome::<()>(&Err(Status::not_found("pet"))),
+            "NotFound"
+        );
+
+        assert!(audit_recorded::<()>(
+            "api:1",
+            &Err(Status::not_found("pet"))
+        ));
+        assert!(audit_recorded::<()>(ACTOR_ANONYMOUS, &Ok(())));
+        assert!(!audit_recorded::<()>(
+            ACTOR_ANONYMOUS,
+            &Err(Status::not_found("pet"))
+        ));
+        assert!(!audit_recorded::<()>(
+            "api:1",
+            &Err(Status::unauthenticated("key"))
+        ));
+    }
+}
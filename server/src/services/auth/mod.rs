@@ -1,8 +1,12 @@
 //! # Auth
 use crate::internal::*;
 use petshop_proto::api::User;
+use sha2::{Digest, Sha256};
 use tonic::{Code, Request, Status};
 
+/// Actor of unauthenticated requests
+pub const ACTOR_ANONYMOUS: &str = "anonymous";
+
 /// Auth
 pub struct Auth {
     _postgres: Arc<PostgresPool>,
@@ -15,6 +19,11 @@ impl Auth {
         }
     }
 
+    /// Returns unauthenticated status with `ErrorInfo` details
+    pub fn unauthenticated() -> Status {
+        tonic_status_error_info(&ERROR_AUTHENTICATION, &[])
+    }
+
     /// Parses request metadata to extract authenticated user (works with auth example)
     pub fn user_interceptor(request: &Request<()>) -> Result<User, Status> {
         let email = request.metadata().get("x-auth-request-email");
@@ -25,9 +34,9 @@ impl Auth {
                     email: email.to_string(),
                     name: user.to_string(),
                 }),
-                _ => Err(Status::unauthenticated(ERROR_AUTHENTICATION)),
+                _ => Err(Self::unauthenticated()),
             },
-            _ => Err(Status::unauthenticated(ERROR_AUTHENTICATION)),
+            _ => Err(Self::unauthenticated()),
         }
     }
 
@@ -43,20 +52,42 @@ impl Auth {
                     email: "apiconsumer@petshop.com".to_string(),
                     name: auth.to_string(),
                 }),
-                _ => Err(Status::unauthenticated(ERROR_AUTHENTICATION)),
+                _ => Err(Self::unauthenticated()),
             },
-            _ => Err(Status::unauthenticated(ERROR_AUTHENTICATION)),
+            _ => Err(Self::unauthenticated()),
         }
     }
 
+    /// Returns actor of authenticated request, the API key (`api:{hash}`, hashed so it
+    /// is not stored or logged) or user (`user:{email}`)
+    ///
+    /// API key takes precedence over user as in `api_or_user`, so requests are audited
+    /// as the identity they are authorised as
+    pub fn actor_interceptor(request: &Request<()>) -> Option<String> {
+        if let Ok(user) = Self::api_interceptor(request) {
+            let hash = Sha256::digest(user.name.as_bytes());
+            return Some(format!("api:{}", hex::encode(&hash[..16])));
+        }
+        if let Ok(user) = Self::user_interceptor(request) {
+            return Some(format!("user:{}", user.email));
+        }
+        None
+    }
+
+    /// Returns actor of request, or anonymous if it is not authenticated
+    pub fn actor<T>(&self, request: &Request<T>) -> String {
+        Self::actor_interceptor(&metadata_request(request))
+            .unwrap_or_else(|| ACTOR_ANONYMOUS.to_string())
+    }
+
     /// Wraps user interceptor function in case config is useful here later on
-    pub async fn user(&self, request: &Request<()>) -> Result<User, Status> {
-        Self::user_interceptor(request)
+    pub async fn user<T>(&self, request: &Request<T>) -> Result<User, Status> {
+        Self::user_interceptor(&metadata_request(request))
     }
 
     /// Wraps api interceptor function in case config is useful here later on
-    pub async fn api(&self, request: &Request<()>) -> Result<User, Status> {
-        Self::api_interceptor(request)
+    pub async fn api<T>(&self, request: &Request<T>) -> Result<User, Status> {
+        Self::api_interceptor(&metadata_request(request))
     }
 
     /// Parses request metadata to return authenticated user, which may be provided by oauth2-proxy
@@ -69,7 +100,7 @@ impl Auth {
     /// all private endpoints will call this function
     ///
     /// <https://cheatsheetseries.owasp.org/cheatsheets/REST_Security_Cheat_Sheet.html#api-keys>
-    pub async fn api_or_user(&self, request: &Request<()>) -> Result<User, Status> {
+    pub async fn api_or_user<T>(&self, request: &Request<T>) -> Result<User, Status> {
         match self.api(request).await {
             Ok(user) => Ok(user),
             Err(err) => match err.code() {
@@ -79,3 +110,37 @@ impl Auth {
         }
     }
 }
+
+/// Returns request with copy of metadata so that interceptor functions can be used
+/// with requests that have a message
+fn metadata_request<T>(request: &Request<T>) -> Request<()> {
+    let mut metadata_request = Request::new(());
+    *metadata_request.metadata_mut() = request.metadata().clone();
+    metadata_request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actor_interceptor_test() {
+        let mut request = Request::new(());
+        assert_eq!(Auth::actor_interceptor(&request), None);
+
+        let metadata = request.metadata_mut();
+        metadata.insert("x-auth-request-email", "admin@example.com".parse().unwrap());
+        metadata.insert("x-auth-request-user", "admin".parse().unwrap());
+        assert_eq!(
+            Auth::actor_interceptor(&request).unwrap(),
+            "user:admin@example.com"
+        );
+
+        // API key takes precedence as in `api_or_user`
+        let metadata = request.metadata_mut();
+        metadata.insert("authorization", "key".parse().unwrap());
+        assert!(Auth::actor_interceptor(&request)
+            .unwrap()
+            .starts_with("api:"));
+    }
+}
@@ -0,0 +1,476 @@
+//! # Clients Cache
+//!
+//! In-memory shared HTTP cache for GET responses, implementing a subset of RFC 7234:
+//!
+//! - Freshness from `Cache-Control: s-maxage/max-age` or `Expires`
+//! - `no-store`, `private` and `Vary: *` responses are never stored
+//! - `no-cache` responses are stored but always revalidated
+//! - Stale responses with an `ETag` or `Last-Modified` validator are revalidated
+//!   using `If-None-Match` or `If-Modified-Since`
+//! - `Vary` request header values must match the stored response
+//!
+//! <https://datatracker.ietf.org/doc/html/rfc7234>
+use crate::internal::*;
+use bytes::Bytes;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Request, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients Cache Configuration
+#[derive(Debug, Clone)]
+pub struct ClientsCacheConfig {
+    pub max_bytes: usize,
+    pub max_entry_bytes: usize,
+}
+
+/// Cached response
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    lifetime: Duration,
+    no_cache: bool,
+    last_used: u64,
+}
+
+/// Result of looking up a request in the cache
+#[derive(Debug)]
+pub enum CacheLookup {
+    Fresh(CacheEntry),
+    Stale(CacheEntry),
+    Miss,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, CacheEntry>,
+    size: usize,
+    tick: u64,
+}
+
+/// Clients Cache
+#[derive(Debug)]
+pub struct ClientsCache {
+    config: ClientsCacheConfig,
+    inner: Mutex<CacheInner>,
+}
+
+/// Parsed `Cache-Control` header directives that are supported
+#[derive(Debug, Default, PartialEq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl ClientsCache {
+    pub fn new(config: ClientsCacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Returns cached entry for request if one exists with matching vary headers
+    pub fn lookup(&self, req: &Request) -> CacheLookup {
+        self.lookup_at(req, Instant::now())
+    }
+
+    fn lookup_at(&self, req: &Request, now: Instant) -> CacheLookup {
+        if CacheControl::parse(req.headers()).no_store {
+            return CacheLookup::Miss;
+        }
+
+        let mut inner = self.inner.lock().expect("clients cache lock failed");
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let entry = match inner.entries.get_mut(req.url().as_str()) {
+            Some(entry) if entry.vary_matches(req.headers()) => {
+                entry.last_used = tick;
+                entry.clone()
+            }
+            _ => return CacheLookup::Miss,
+        };
+
+        if entry.is_fresh(now) {
+            CacheLookup::Fresh(entry)
+        } else if entry.has_validator() {
+            CacheLookup::Stale(entry)
+        } else {
+            inner.remove(req.url().as_str());
+            CacheLookup::Miss
+        }
+    }
+
+    /// Returns maximum size of a response body which is stored
+    pub fn max_entry_bytes(&self) -> usize {
+        self.config.max_entry_bytes.min(self.config.max_bytes)
+    }
+
+    /// Returns true if a response with status and headers can be stored, a content length
+    /// over the entry limit is checked here to avoid buffering large responses, responses
+    /// which are stale when received and have no validator are not stored
+    pub fn is_storable(&self, req: &Request, status: StatusCode, headers: &HeaderMap) -> bool {
+        let req_cc = CacheControl::parse(req.headers());
+        let res_cc = CacheControl::parse(headers);
+        let expired = freshness_lifetime(&res_cc, headers) == Duration::default()
+            && !headers.contains_key(header::ETAG)
+            && !headers.contains_key(header::LAST_MODIFIED);
+        let vary_any = header_values(headers, header::VARY).any(|x| x.trim() == "*");
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        status == StatusCode::OK
+            && !req_cc.no_store
+            && !res_cc.no_store
+            && !res_cc.private
+            && !vary_any
+            && !expired
+            && content_length <= self.max_entry_bytes()
+    }
+
+    /// Store response for request, evicting least recently used entries if over size limit
+    pub fn store(&self, req: &Request, status: StatusCode, headers: HeaderMap, body: Bytes) {
+        self.store_at(req, status, headers, body, Instant::now())
+    }
+
+    fn store_at(
+        &self,
+        req: &Request,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        now: Instant,
+    ) {
+        if !self.is_storable(req, status, &headers) || body.len() > self.max_entry_bytes() {
+            return;
+        }
+
+        let res_cc = CacheControl::parse(&headers);
+        let vary = header_values(&headers, header::VARY)
+            .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+            .map(|name| {
+                let value = req.headers().get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let lifetime = freshness_lifetime(&res_cc, &headers);
+
+        let mut inner = self.inner.lock().expect("clients cache lock failed");
+        inner.tick += 1;
+        let entry = CacheEntry {
+            vary,
+            status,
+            headers,
+            body,
+            stored_at: now,
+            lifetime,
+            no_cache: res_cc.no_cache,
+            last_used: inner.tick,
+        };
+        let key = req.url().to_string();
+        inner.remove(&key);
+        inner.size += entry.body.len();
+        inner.entries.insert(key, entry);
+
+        while inner.size > self.config.max_bytes {
+            let lru = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match lru {
+                Some(lru) => inner.remove(&lru),
+                None => break,
+            }
+        }
+    }
+
+    /// Update stored entry after a `304 Not Modified` revalidation response
+    pub fn freshen(&self, req: &Request, headers: &HeaderMap) -> Option<CacheEntry> {
+        self.freshen_at(req, headers, Instant::now())
+    }
+
+    fn freshen_at(&self, req: &Request, headers: &HeaderMap, now: Instant) -> Option<CacheEntry> {
+        let mut inner = self.inner.lock().expect("clients cache lock failed");
+        let entry = inner.entries.get_mut(req.url().as_str())?;
+
+        entry.headers.remove(header::AGE);
+        for name in [
+            header::AGE,
+            header::CACHE_CONTROL,
+            header::DATE,
+            header::ETAG,
+            header::EXPIRES,
+            header::LAST_MODIFIED,
+        ]
+        .iter()
+        {
+            if let Some(value) = headers.get(name) {
+                entry.headers.insert(name.clone(), value.clone());
+            }
+        }
+        let res_cc = CacheControl::parse(&entry.headers);
+        entry.lifetime = freshness_lifetime(&res_cc, &entry.headers);
+        entry.no_cache = res_cc.no_cache;
+        entry.stored_at = now;
+
+        Some(entry.clone())
+    }
+}
+
+impl CacheInner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, now: Instant) -> bool {
+        !self.no_cache && now.saturating_duration_since(self.stored_at) < self.lifetime
+    }
+
+    fn has_validator(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+
+    fn vary_matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+
+    /// Add conditional headers to request for revalidation
+    pub fn conditional_request(&self, req: &mut Request) {
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            req.headers_mut()
+                .insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            req.headers_mut()
+                .insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// Build response from cached entry
+    pub fn response(&self) -> reqwest::Response {
+        let mut res = http::Response::new(self.body.clone());
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res.into()
+    }
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        for directive in header_values(headers, header::CACHE_CONTROL) {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim().to_lowercase();
+            let value = parts
+                .next()
+                .map(|x| x.trim().trim_matches('"'))
+                .and_then(|x| x.parse::<u64>().ok());
+            match name.as_ref() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "max-age" => cc.max_age = value,
+                "s-maxage" => cc.s_maxage = value,
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+/// Returns freshness lifetime of response minus its current age
+fn freshness_lifetime(cc: &CacheControl, headers: &HeaderMap) -> Duration {
+    let lifetime = match cc.s_maxage.or(cc.max_age) {
+        Some(seconds) => Duration::from_secs(seconds),
+        None => {
+            let expires = header_date(headers, header::EXPIRES);
+            let date = header_date(headers, header::DATE).unwrap_or_else(SystemTime::now);
+            match expires {
+                Some(expires) => expires.duration_since(date).unwrap_or_default(),
+                // Heuristic freshness is not supported
+                None => Duration::default(),
+            }
+        }
+    };
+    let age = headers
+        .get(header::AGE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    lifetime.checked_sub(age).unwrap_or_default()
+}
+
+/// Returns comma separated values from all headers of name
+fn header_values(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| httpdate::parse_http_date(x).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_bytes: usize) -> ClientsCache {
+        ClientsCache::new(ClientsCacheConfig {
+            max_bytes,
+            max_entry_bytes: 8,
+        })
+    }
+
+    fn request(url: &str) -> Request {
+        Request::new(reqwest::Method::GET, Url::from_str(url).unwrap())
+    }
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn cache_control_parse_test() {
+        let cc = CacheControl::parse(&headers(&[
+            ("cache-control", "public, max-age=60"),
+            ("cache-control", "s-maxage=\"30\", no-cache"),
+        ]));
+        assert_eq!(
+            cc,
+            CacheControl {
+                no_store: false,
+                no_cache: true,
+                private: false,
+                max_age: Some(60),
+                s_maxage: Some(30),
+            }
+        );
+        assert_eq!(
+            freshness_lifetime(&cc, &headers(&[("age", "10")])),
+            Duration::from_secs(20)
+        );
+    }
+
+    #[test]
+    fn cache_store_lookup_test() {
+        let now = Instant::now();
+        let cache = cache(64);
+        let req = request("http://localhost/a");
+        assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Miss));
+
+        let res_headers = headers(&[("cache-control", "max-age=60"), ("etag", "\"1\"")]);
+        cache.store_at(&req, StatusCode::OK, res_headers, "body".into(), now);
+        assert!(matches!(
+            cache.lookup_at(&req, now + Duration::from_secs(30)),
+            CacheLookup::Fresh(_)
+        ));
+
+        let later = now + Duration::from_secs(61);
+        let entry = match cache.lookup_at(&req, later) {
+            CacheLookup::Stale(entry) => entry,
+            _ => panic!("expected stale entry"),
+        };
+        let mut revalidate = request("http://localhost/a");
+        entry.conditional_request(&mut revalidate);
+        assert_eq!(revalidate.headers()[header::IF_NONE_MATCH], "\"1\"");
+
+        cache.freshen_at(&req, &headers(&[("cache-control", "max-age=10")]), later);
+        assert!(matches!(
+            cache.lookup_at(&req, later + Duration::from_secs(5)),
+            CacheLookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn cache_not_storable_test() {
+        let now = Instant::now();
+        let cache = cache(64);
+        let req = request("http://localhost/a");
+
+        for res_headers in &[
+            headers(&[("cache-control", "no-store, max-age=60")]),
+            headers(&[("cache-control", "private, max-age=60")]),
+            headers(&[("cache-control", "max-age=60"), ("vary", "*")]),
+        ] {
+            let res_headers = res_headers.clone();
+            cache.store_at(&req, StatusCode::OK, res_headers, "body".into(), now);
+            assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Miss));
+        }
+
+        // Response larger than entry limit
+        let res_headers = headers(&[("cache-control", "max-age=60")]);
+        cache.store_at(&req, StatusCode::OK, res_headers, "too large".into(), now);
+        assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Miss));
+
+        // Responses which expire immediately are only stored with a validator
+        for res_headers in &[
+            headers(&[]),
+            headers(&[("cache-control", "max-age=60"), ("age", "60")]),
+        ] {
+            assert!(!cache.is_storable(&req, StatusCode::OK, res_headers));
+        }
+        let res_headers = headers(&[("cache-control", "no-cache"), ("etag", "\"1\"")]);
+        assert!(cache.is_storable(&req, StatusCode::OK, &res_headers));
+        assert!(cache.inner.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn cache_vary_and_eviction_test() {
+        let now = Instant::now();
+        let cache = cache(8);
+        let mut req = request("http://localhost/a");
+        req.headers_mut()
+            .insert(header::ACCEPT, HeaderValue::from_static("text/html"));
+
+        let res_headers = headers(&[("cache-control", "max-age=60"), ("vary", "accept")]);
+        cache.store_at(&req, StatusCode::OK, res_headers, "aaaa".into(), now);
+        assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Fresh(_)));
+
+        let other = request("http://localhost/a");
+        assert!(matches!(cache.lookup_at(&other, now), CacheLookup::Miss));
+
+        // Least recently used entry is evicted when over size limit
+        let res_headers = headers(&[("cache-control", "max-age=60")]);
+        let b = request("http://localhost/b");
+        cache.store_at(&b, StatusCode::OK, res_headers.clone(), "bbbb".into(), now);
+        assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Fresh(_)));
+        let c = request("http://localhost/c");
+        cache.store_at(&c, StatusCode::OK, res_headers, "cccc".into(), now);
+        assert!(matches!(cache.lookup_at(&b, now), CacheLookup::Miss));
+        assert!(matches!(cache.lookup_at(&req, now), CacheLookup::Fresh(_)));
+        assert!(matches!(cache.lookup_at(&c, now), CacheLookup::Fresh(_)));
+    }
+}
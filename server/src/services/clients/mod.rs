@@ -1,22 +1,68 @@
 //! # Clients
+//!
+//! Outbound HTTP requests are sent using a per-host policy which can configure:
+//!
+//! - Retries with jittered exponential backoff (optionally for idempotent methods only)
+//! - Circuit breaker with half-open probing
+//! - Concurrency limit
+//!
+//! GET responses can optionally be stored in an in-memory cache, see `cache` module
+//!
 use crate::internal::*;
-use reqwest::Response;
+use crate::services::{request_id_current, X_REQUEST_ID};
+use bytes::BytesMut;
+use futures::{future, StreamExt};
+use http::header::HeaderValue;
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use cache::{CacheLookup, ClientsCache};
+
+pub use cache::ClientsCacheConfig;
+pub use policy::{CircuitBreaker, CircuitState, ClientsPolicyConfig};
+
+mod cache;
+mod policy;
 
 /// Clients Configuration
 #[derive(Debug, Clone)]
 pub struct ClientsConfig {
     pub http_timeout_seconds: u64,
+    pub default_policy: ClientsPolicyConfig,
+    pub policies: Vec<ClientsPolicyConfig>,
+    pub cache: Option<ClientsCacheConfig>,
 }
 
 /// Clients
 pub struct Clients {
-    _config: ClientsConfig,
+    config: ClientsConfig,
+    metrics: Arc<Metrics>,
     http: reqwest::Client,
+    http_no_redirect: reqwest::Client,
+    hosts: Mutex<ClientsHosts>,
+    cache: Option<ClientsCache>,
+}
+
+/// Maximum number of hosts with state, the least recently used host is removed
+/// when another host is added so requests to many hosts do not grow memory
+const CLIENTS_HOSTS_MAX: usize = 1024;
+
+/// Clients host state, created on first request to host
+struct ClientsHost {
+    host: String,
+    /// Metrics label, hosts without a configured policy share the `default` label
+    label: String,
+    policy: ClientsPolicyConfig,
+    breaker: CircuitBreaker,
+    semaphore: Semaphore,
 }
 
 impl Clients {
-    pub fn from_config(config: &Config) -> Result<Self, XErr> {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Result<Self, XErr> {
         let config = config.clients.clone();
 
         let http = reqwest::ClientBuilder::new()
@@ -24,17 +70,346 @@ impl Clients {
             .timeout(Duration::from_secs(config.http_timeout_seconds))
             .use_rustls_tls()
             .build()?;
+        let http_no_redirect = reqwest::ClientBuilder::new()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(config.http_timeout_seconds))
+            .use_rustls_tls()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        let cache = config.cache.clone().map(ClientsCache::new);
 
         Ok(Self {
-            _config: config,
+            config,
+            metrics,
             http,
+            http_no_redirect,
+            hosts: Mutex::new(ClientsHosts::default()),
+            cache,
         })
     }
 
-    /// Returns response from a GET request to url
+    /// Returns response from a GET request to url, served from cache if configured
     pub async fn get(&self, url: &str) -> Result<Response, XErr> {
-        let req = self.http.get(url);
-        let res = req.send().await?;
-        Ok(res)
+        let req = self.http.get(url).build()?;
+        match self.cache.as_ref() {
+            Some(cache) => self.send_cached(cache, req).await,
+            None => self.send(req).await,
+        }
+    }
+
+    /// Sends request if there is no fresh response in cache, stale responses
+    /// are revalidated and storable responses are buffered into the cache
+    async fn send_cached(&self, cache: &ClientsCache, mut req: Request) -> Result<Response, XErr> {
+        let lookup_req = req.try_clone().expect("get request clone failed");
+
+        let stale = match cache.lookup(&lookup_req) {
+            CacheLookup::Fresh(entry) => {
+                self.metrics.clients_cache_hit_counter_inc();
+                return Ok(entry.response());
+            }
+            CacheLookup::Stale(entry) => {
+                self.metrics.clients_cache_revalidate_counter_inc();
+                entry.conditional_request(&mut req);
+                true
+            }
+            CacheLookup::Miss => {
+                self.metrics.clients_cache_miss_counter_inc();
+                false
+            }
+        };
+
+        let mut res = self.send(req).await?;
+        if stale && res.status() == StatusCode::NOT_MODIFIED {
+            if let Some(entry) = cache.freshen(&lookup_req, res.headers()) {
+                return Ok(entry.response());
+            }
+            // Entry was evicted while revalidating, so request is sent again without
+            // the conditional headers to get a full response
+            let req = lookup_req.try_clone().expect("get request clone failed");
+            res = self.send(req).await?;
+        }
+        if !cache.is_storable(&lookup_req, res.status(), res.headers()) {
+            return Ok(res);
+        }
+
+        // Body is buffered up to the entry limit, larger responses without a content
+        // length are returned with the buffered chunks followed by the rest of the body
+        let status = res.status();
+        let headers = res.headers().clone();
+        let mut res = res;
+        let mut body = BytesMut::new();
+        while let Some(chunk) = res.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > cache.max_entry_bytes() {
+                let buffered = futures::stream::once(future::ready(Ok(body.freeze())));
+                let body = reqwest::Body::wrap_stream(buffered.chain(res.bytes_stream()));
+                return Ok(clients_response(status, headers, body));
+            }
+        }
+        let body = body.freeze();
+        cache.store(&lookup_req, status, headers.clone(), body.clone());
+        Ok(clients_response(status, headers, body))
+    }
+
+    /// Returns request builder, requests must be sent using `send` to use host policies
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.http.request(method, url)
+    }
+
+    /// Sends request using the policy configured for the request host
+    ///
+    /// Connection errors, timeouts and some server error statuses are retried if the
+    /// policy allows it, if retries are exhausted the last response or error is returned
+    ///
+    /// Id of the current request is forwarded if the request does not have one
+    pub async fn send(&self, req: Request) -> Result<Response, XErr> {
+        self.send_with(&self.http, req).await
+    }
+
+    /// Sends request like `send` without following redirects, used for requests to
+    /// destinations which are checked before sending (e.g. webhooks), redirect
+    /// responses are returned
+    pub async fn send_without_redirects(&self, req: Request) -> Result<Response, XErr> {
+        self.send_with(&self.http_no_redirect, req).await
+    }
+
+    async fn send_with(&self, http: &reqwest::Client, mut req: Request) -> Result<Response, XErr> {
+        if let Some(request_id) = request_id_current() {
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                req.headers_mut().entry(X_REQUEST_ID).or_insert(value);
+            }
+        }
+        let host = self.host(req.url());
+        let retries = if host.policy.retry_idempotent_only && !method_is_idempotent(req.method()) {
+            0
+        } else {
+            host.policy.retries
+        };
+
+        let mut attempt = 0;
+        let mut req = Some(req);
+        loop {
+            let current = req.take().expect("request missing");
+            // Requests with streaming bodies can not be cloned, so are never retried
+            req = if attempt < retries {
+                current.try_clone()
+            } else {
+                None
+            };
+
+            let res = self.attempt(http, &host, current).await;
+            let retry = match res.as_ref() {
+                Ok(res) => status_is_retryable(res.status()),
+                Err(XErr::Reqwest(err)) => err.is_timeout() || err.is_connect(),
+                Err(_) => false,
+            };
+            if !retry || req.is_none() {
+                return res;
+            }
+
+            attempt += 1;
+            let backoff = host.policy.backoff(attempt);
+            debug!(
+                "clients retry {} attempt {} in {:?}",
+                host.host, attempt, backoff
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Send a single request attempt through the host circuit breaker and concurrency limit
+    async fn attempt(
+        &self,
+        http: &reqwest::Client,
+        host: &ClientsHost,
+        req: Request,
+    ) -> Result<Response, XErr> {
+        if !host.breaker.try_acquire() {
+            self.metrics
+                .clients_breaker_state(&host.label, host.breaker.state());
+            return Err(XErr::clients_unavailable(&host.host));
+        }
+
+        let permit = host.semaphore.acquire().await;
+        self.metrics.clients_attempt_counter_inc(&host.label);
+        let res = http.execute(req).await;
+        drop(permit);
+
+        let failure = match res.as_ref() {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        };
+        if failure {
+            self.metrics.clients_failure_counter_inc(&host.label);
+            host.breaker.on_failure();
+        } else {
+            host.breaker.on_success();
+        }
+        self.metrics
+            .clients_breaker_state(&host.label, host.breaker.state());
+
+        res.map_err(XErr::Reqwest)
+    }
+
+    /// Returns hosts with open circuit breakers
+    pub fn hosts_unavailable(&self) -> Vec<String> {
+        let hosts = self.hosts.lock().expect("clients hosts lock failed");
+        let mut hosts: Vec<String> = hosts
+            .hosts
+            .values()
+            .map(|(x, _)| x)
+            .filter(|x| x.breaker.state() == CircuitState::Open)
+            .map(|x| x.host.clone())
+            .collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Returns state for host, policies are matched on exact host or else use the default
+    fn host(&self, url: &Url) -> Arc<ClientsHost> {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let mut hosts = self.hosts.lock().expect("clients hosts lock failed");
+
+        hosts.get_or_insert(&host, CLIENTS_HOSTS_MAX, || {
+            let policy = self
+                .config
+                .policies
+                .iter()
+                .find(|x| x.host.as_deref() == Some(host.as_str()));
+            let label = match policy {
+                Some(_) => host.clone(),
+                None => "default".to_string(),
+            };
+            let policy = policy.unwrap_or(&self.config.default_policy).clone();
+            ClientsHost::new(host.clone(), label, policy)
+        })
+    }
+}
+
+impl fmt::Debug for Clients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clients").finish()
+    }
+}
+
+/// Clients hosts with state and when they were last used
+#[derive(Default)]
+struct ClientsHosts {
+    hosts: HashMap<String, (Arc<ClientsHost>, u64)>,
+    tick: u64,
+}
+
+impl ClientsHosts {
+    /// Returns state for host, or inserts it after removing the least recently used
+    /// host if there are max hosts, requests in progress keep removed state
+    fn get_or_insert(
+        &mut self,
+        host: &str,
+        max: usize,
+        f: impl FnOnce() -> ClientsHost,
+    ) -> Arc<ClientsHost> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((state, last_used)) = self.hosts.get_mut(host) {
+            *last_used = tick;
+            return state.clone();
+        }
+
+        if self.hosts.len() >= max {
+            let lru = self
+                .hosts
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(host, _)| host.clone());
+            if let Some(lru) = lru {
+                self.hosts.remove(&lru);
+            }
+        }
+        let state = Arc::new(f());
+        self.hosts.insert(host.to_string(), (state.clone(), tick));
+        state
+    }
+}
+
+impl ClientsHost {
+    fn new(host: String, label: String, policy: ClientsPolicyConfig) -> Self {
+        let breaker = CircuitBreaker::new(
+            policy.breaker_failure_threshold,
+            Duration::from_secs(policy.breaker_open_seconds),
+        );
+        let semaphore = Semaphore::new(policy.concurrency_limit.max(1));
+        Self {
+            host,
+            label,
+            policy,
+            breaker,
+            semaphore,
+        }
+    }
+}
+
+fn clients_response(
+    status: StatusCode,
+    headers: http::HeaderMap,
+    body: impl Into<reqwest::Body>,
+) -> Response {
+    let mut res = http::Response::new(body);
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    res.into()
+}
+
+/// Returns true for methods which can be safely retried
+fn method_is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Returns true for response statuses which may succeed if retried
+fn status_is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(name: &str) -> ClientsHost {
+        let policy = ClientsPolicyConfig {
+            host: None,
+            retries: 0,
+            retry_idempotent_only: true,
+            backoff_base_millis: 100,
+            backoff_max_millis: 1000,
+            breaker_failure_threshold: 2,
+            breaker_open_seconds: 30,
+            concurrency_limit: 8,
+        };
+        ClientsHost::new(name.to_string(), "default".to_string(), policy)
+    }
+
+    #[test]
+    fn clients_hosts_test() {
+        let mut hosts = ClientsHosts::default();
+        let a = hosts.get_or_insert("a", 2, || host("a"));
+        hosts.get_or_insert("b", 2, || host("b"));
+        let a2 = hosts.get_or_insert("a", 2, || host("a"));
+        assert!(Arc::ptr_eq(&a, &a2));
+
+        // Least recently used host is removed when there are max hosts
+        hosts.get_or_insert("c", 2, || host("c"));
+        assert_eq!(hosts.hosts.len(), 2);
+        assert!(hosts.hosts.contains_key("a"));
+        assert!(!hosts.hosts.contains_key("b"));
+        assert!(hosts.hosts.contains_key("c"));
     }
 }
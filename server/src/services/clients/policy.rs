@@ -0,0 +1,225 @@
+//! # Clients Policy
+//!
+//! Retry backoff and circuit breaker used by per-host client policies
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients Policy Configuration
+#[derive(Debug, Clone)]
+pub struct ClientsPolicyConfig {
+    pub host: Option<String>,
+    pub retries: u32,
+    pub retry_idempotent_only: bool,
+    pub backoff_base_millis: u64,
+    pub backoff_max_millis: u64,
+    pub breaker_failure_threshold: u32,
+    pub breaker_open_seconds: u64,
+    pub concurrency_limit: usize,
+}
+
+impl ClientsPolicyConfig {
+    /// Returns jittered delay before retry attempt (starting at 1)
+    ///
+    /// Uses "full jitter" where the delay is a random value between zero and the
+    /// capped exponential backoff, this spreads out retries from many clients
+    ///
+    /// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+        let cap = self.backoff_cap(attempt);
+        let millis = rand::thread_rng().gen_range(0..=cap);
+        Duration::from_millis(millis)
+    }
+
+    fn backoff_cap(&self, attempt: u32) -> u64 {
+        let exponent = attempt.saturating_sub(1).min(32);
+        self.backoff_base_millis
+            .saturating_mul(1 << exponent)
+            .min(self.backoff_max_millis)
+    }
+}
+
+/// Circuit breaker state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl CircuitState {
+    /// Value recorded in metrics
+    pub fn as_metric(self) -> u64 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CircuitInner {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Circuit Breaker
+///
+/// Opens after a number of consecutive failures and rejects requests until the open
+/// duration has elapsed, then allows a single probe request through (half-open) which
+/// closes the circuit on success or opens it again on failure
+///
+/// A failure threshold of 0 disables the breaker
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<CircuitInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_duration,
+            inner: Mutex::new(CircuitInner {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    /// Returns true if a request is allowed through the breaker
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().expect("circuit breaker lock failed");
+        let elapsed = match inner.opened_at {
+            Some(opened_at) => now >= opened_at + self.open_duration,
+            None => true,
+        };
+        match inner.state {
+            CircuitState::Closed => true,
+            // A probe that never completed (e.g. cancelled request) does
+            // not keep the circuit half open forever
+            CircuitState::Open | CircuitState::HalfOpen if elapsed => {
+                inner.state = CircuitState::HalfOpen;
+                inner.opened_at = Some(now);
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        }
+    }
+
+    /// Record a successful request, closes the circuit
+    pub fn on_success(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock failed");
+        inner.state = CircuitState::Closed;
+        inner.failures = 0;
+        inner.opened_at = None;
+    }
+
+    /// Record a failed request, opens the circuit if threshold is reached or probe failed
+    pub fn on_failure(&self) {
+        self.on_failure_at(Instant::now())
+    }
+
+    fn on_failure_at(&self, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("circuit breaker lock failed");
+        inner.failures = inner.failures.saturating_add(1);
+        let open = match inner.state {
+            CircuitState::Closed => inner.failures >= self.failure_threshold,
+            CircuitState::HalfOpen | CircuitState::Open => true,
+        };
+        if open {
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(now);
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner
+            .lock()
+            .expect("circuit breaker lock failed")
+            .state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ClientsPolicyConfig {
+        ClientsPolicyConfig {
+            host: None,
+            retries: 3,
+            retry_idempotent_only: true,
+            backoff_base_millis: 100,
+            backoff_max_millis: 1000,
+            breaker_failure_threshold: 2,
+            breaker_open_seconds: 30,
+            concurrency_limit: 8,
+        }
+    }
+
+    #[test]
+    fn backoff_test() {
+        let policy = policy();
+        assert_eq!(policy.backoff_cap(1), 100);
+        assert_eq!(policy.backoff_cap(2), 200);
+        assert_eq!(policy.backoff_cap(4), 800);
+        assert_eq!(policy.backoff_cap(5), 1000);
+        assert_eq!(policy.backoff_cap(100), 1000);
+        for attempt in 1..10 {
+            assert!(policy.backoff(attempt) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_test() {
+        let now = Instant::now();
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        assert!(breaker.try_acquire_at(now));
+
+        breaker.on_failure_at(now);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.on_failure_at(now);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(now + Duration::from_secs(10)));
+
+        // Only one probe is allowed while half open
+        let later = now + Duration::from_secs(31);
+        assert!(breaker.try_acquire_at(later));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire_at(later));
+
+        // Failed probe opens the circuit again
+        breaker.on_failure_at(later);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(later));
+
+        let later = later + Duration::from_secs(31);
+        assert!(breaker.try_acquire_at(later));
+        breaker.on_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire_at(later));
+    }
+
+    #[test]
+    fn circuit_breaker_disabled_test() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(30));
+        for _ in 0..10 {
+            breaker.on_failure();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+    }
+}
@@ -0,0 +1,80 @@
+//! # Compression Body
+//!
+use super::frame_split;
+use crate::internal::*;
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+
+/// Response body which compresses gRPC messages as they are sent
+pub struct CompressionBody {
+    compression: Arc<Compression>,
+    encoding: CompressionEncoding,
+    inner: BoxBody,
+    buf: BytesMut,
+}
+
+impl CompressionBody {
+    pub fn wrap(
+        compression: Arc<Compression>,
+        encoding: CompressionEncoding,
+        inner: BoxBody,
+    ) -> Self {
+        Self {
+            compression,
+            encoding,
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl HttpBody for CompressionBody {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(frame) = frame_split(&mut this.buf) {
+                let frame = this
+                    .compression
+                    .service_response_frame(this.encoding, frame);
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            match futures::ready!(Pin::new(&mut this.inner).poll_data(cx)) {
+                Some(Ok(chunk)) => this.buf.extend_from_slice(&chunk),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None if this.buf.is_empty() => return Poll::Ready(None),
+                None => {
+                    return Poll::Ready(Some(Err(tonic::Status::internal("message is incomplete"))))
+                }
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HttpHeaders>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_empty() && self.inner.is_end_stream()
+    }
+}
+
+impl fmt::Debug for CompressionBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompressionBody")
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
@@ -0,0 +1,379 @@
+//! # Compression
+//!
+//! gRPC message compression negotiated with `grpc-accept-encoding` and `grpc-encoding`
+//! headers, tonic does not support compression so messages are re-framed here:
+//!
+//! - Response messages of at least `min_bytes` are compressed with the first configured
+//!   encoding accepted by the client, smaller messages are sent uncompressed
+//! - Compressed request messages are decompressed before they reach services,
+//!   unsupported encodings return `Unimplemented`
+//! - Decoded request messages larger than `max_message_bytes` return `ResourceExhausted`,
+//!   this also limits the size of decompressed messages, it defaults to the largest
+//!   configured payload (`storage.max_photo_bytes` or `multipart.max_bytes`)
+//!
+//! Transcoded responses (JSON and fortunes HTML) are compressed by envoy
+//!
+//! <https://github.com/grpc/grpc/blob/master/doc/compression.md>
+use crate::internal::*;
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use http::header::HeaderValue;
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::io::{Read, Write};
+
+pub use body::CompressionBody;
+pub use service::CompressionService;
+
+mod body;
+mod service;
+
+const GRPC_ENCODING: &str = "grpc-encoding";
+const GRPC_ACCEPT_ENCODING: &str = "grpc-accept-encoding";
+const GRPC_ENCODING_IDENTITY: &str = "identity";
+
+/// Length of gRPC message prefix, compressed flag and message length
+const FRAME_PREFIX_BYTES: usize = 5;
+
+/// Compression Encoding Configuration
+///
+/// Add variants here to support other encodings (e.g. zstd)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionEncoding {
+    Gzip,
+}
+
+/// Compression Configuration
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub encodings: Vec<CompressionEncoding>,
+    pub level: u32,
+    pub min_bytes: usize,
+    pub max_message_bytes: usize,
+}
+
+/// Compression
+#[derive(Debug)]
+pub struct Compression {
+    config: CompressionConfig,
+    metrics: Arc<Metrics>,
+    accept_encoding: HeaderValue,
+}
+
+impl CompressionEncoding {
+    /// Returns encoding from `grpc-encoding` name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "gzip" => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Returns `grpc-encoding` name of encoding
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+        }
+    }
+
+    fn compress(&self, level: u32, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress data, reading at most `max_bytes + 1` bytes so oversized messages
+    /// are detected without decompressing all of them
+    fn decompress(&self, max_bytes: usize, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut out = Vec::new();
+        match self {
+            Self::Gzip => {
+                GzDecoder::new(data)
+                    .take(max_bytes as u64 + 1)
+                    .read_to_end(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Compression {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let accept_encoding = if config.compression.encodings.is_empty() {
+            GRPC_ENCODING_IDENTITY.to_string()
+        } else {
+            config
+                .compression
+                .encodings
+                .iter()
+                .map(|x| x.name())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        Self {
+            config: config.compression.clone(),
+            metrics,
+            accept_encoding: HeaderValue::from_str(&accept_encoding)
+                .expect("accept encoding header invalid"),
+        }
+    }
+
+    /// Used in tower service to return encoding of request messages, returns an
+    /// error status if the encoding is not supported
+    pub fn service_request_encoding(
+        &self,
+        headers: &HttpHeaders,
+    ) -> Result<Option<CompressionEncoding>, tonic::Status> {
+        encoding_request(&self.config, headers)
+    }
+
+    /// Used in tower service to return encoding of response messages, the first
+    /// configured encoding accepted by the client
+    pub fn service_response_encoding(&self, headers: &HttpHeaders) -> Option<CompressionEncoding> {
+        encoding_accepted(&self.config, headers)
+    }
+
+    /// Used in tower service to add encoding headers to response
+    pub fn service_response_headers(
+        &self,
+        encoding: Option<CompressionEncoding>,
+        headers: &mut HttpHeaders,
+    ) {
+        headers.insert(GRPC_ACCEPT_ENCODING, self.accept_encoding.clone());
+        if let Some(encoding) = encoding {
+            headers.insert(GRPC_ENCODING, HeaderValue::from_static(encoding.name()));
+        }
+    }
+
+    /// Used in tower service to decode request messages as they are received
+    pub fn service_request_body(
+        self: Arc<Self>,
+        encoding: Option<CompressionEncoding>,
+        body: Body,
+    ) -> Body {
+        let stream = futures::stream::try_unfold(
+            (self, body, BytesMut::new()),
+            move |(compression, mut body, mut buf)| async move {
+                loop {
+                    if let Some(len) = frame_len(&buf) {
+                        // Checked before the message is buffered
+                        message_len_check(&compression.config, len)?;
+                    }
+                    if let Some(frame) = frame_split(&mut buf) {
+                        let frame = frame_decompress(&compression.config, encoding, frame)?;
+                        return Ok(Some((frame, (compression, body, buf))));
+                    }
+                    match body.data().await {
+                        Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                        Some(Err(err)) => return Err(tonic::Status::internal(err.to_string())),
+                        None if buf.is_empty() => return Ok(None),
+                        None => return Err(tonic::Status::internal("message is incomplete")),
+                    }
+                }
+            },
+        );
+        Body::wrap_stream(stream)
+    }
+
+    /// Used in response body to encode message frames
+    pub fn service_response_frame(&self, encoding: CompressionEncoding, frame: Bytes) -> Bytes {
+        match frame_compress(&self.config, encoding, &frame) {
+            Some(compressed) => {
+                self.metrics
+                    .compression_bytes(frame.len(), compressed.len());
+                compressed
+            }
+            None => frame,
+        }
+    }
+}
+
+/// Returns encoding of request messages from `grpc-encoding` header
+fn encoding_request(
+    config: &CompressionConfig,
+    headers: &HttpHeaders,
+) -> Result<Option<CompressionEncoding>, tonic::Status> {
+    let name = match headers.get(GRPC_ENCODING).and_then(|x| x.to_str().ok()) {
+        Some(name) if name != GRPC_ENCODING_IDENTITY => name,
+        _ => return Ok(None),
+    };
+    match CompressionEncoding::from_name(name) {
+        Some(encoding) if config.encodings.contains(&encoding) => Ok(Some(encoding)),
+        _ => Err(tonic_status_error_info(
+            &ERROR_ENCODING_UNSUPPORTED,
+            &[("encoding", name)],
+        )),
+    }
+}
+
+/// Returns first configured encoding in `grpc-accept-encoding` header
+fn encoding_accepted(
+    config: &CompressionConfig,
+    headers: &HttpHeaders,
+) -> Option<CompressionEncoding> {
+    let accepted: Vec<CompressionEncoding> = headers
+        .get_all(GRPC_ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(CompressionEncoding::from_name)
+        .collect();
+    config
+        .encodings
+        .iter()
+        .find(|x| accepted.contains(x))
+        .copied()
+}
+
+fn message_len_check(config: &CompressionConfig, len: usize) -> Result<(), tonic::Status> {
+    if len > config.max_message_bytes {
+        Err(tonic_status_error_info(
+            &ERROR_MESSAGE_TOO_LARGE,
+            &[("max_bytes", &config.max_message_bytes.to_string())],
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns uncompressed frame of request message
+fn frame_decompress(
+    config: &CompressionConfig,
+    encoding: Option<CompressionEncoding>,
+    frame: Bytes,
+) -> Result<Bytes, tonic::Status> {
+    if frame[0] == 0 {
+        return Ok(frame);
+    }
+    let encoding = encoding.ok_or_else(|| {
+        tonic::Status::internal("message is compressed but grpc-encoding is not defined")
+    })?;
+    let data = encoding
+        .decompress(config.max_message_bytes, &frame[FRAME_PREFIX_BYTES..])
+        .map_err(|err| tonic::Status::internal(format!("message is invalid: {}", err)))?;
+    message_len_check(config, data.len())?;
+    Ok(frame_build(false, &data))
+}
+
+/// Returns compressed frame of response message, or none if the message is smaller
+/// than the minimum size or does not get smaller when compressed
+fn frame_compress(
+    config: &CompressionConfig,
+    encoding: CompressionEncoding,
+    frame: &[u8],
+) -> Option<Bytes> {
+    let data = &frame[FRAME_PREFIX_BYTES..];
+    if frame[0] != 0 || data.len() < config.min_bytes {
+        return None;
+    }
+    match encoding.compress(config.level, data) {
+        Ok(compressed) if compressed.len() < data.len() => Some(frame_build(true, &compressed)),
+        Ok(_) => None,
+        Err(err) => {
+            warn!("compression error: {}", err);
+            None
+        }
+    }
+}
+
+/// Returns message length of frame at start of buffer if prefix is available
+fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < FRAME_PREFIX_BYTES {
+        return None;
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&buf[1..FRAME_PREFIX_BYTES]);
+    Some(u32::from_be_bytes(len) as usize)
+}
+
+/// Splits complete frame (prefix and message) from start of buffer
+fn frame_split(buf: &mut BytesMut) -> Option<Bytes> {
+    let len = FRAME_PREFIX_BYTES + frame_len(buf)?;
+    if buf.len() < len {
+        return None;
+    }
+    Some(buf.split_to(len).freeze())
+}
+
+/// Returns frame of message with compressed flag
+fn frame_build(compressed: bool, data: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(FRAME_PREFIX_BYTES + data.len());
+    frame.put_u8(compressed as u8);
+    frame.put_u32(data.len() as u32);
+    frame.put_slice(data);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CompressionConfig {
+        CompressionConfig {
+            encodings: vec![CompressionEncoding::Gzip],
+            level: 6,
+            min_bytes: 16,
+            max_message_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn compression_frame_test() {
+        let config = config();
+        let gzip = CompressionEncoding::Gzip;
+        let mut buf = BytesMut::new();
+        buf.put_slice(&frame_build(false, b"small"));
+        let large = vec![b'a'; 512];
+        buf.put_slice(&frame_build(false, &large)[..100]);
+
+        let small = frame_split(&mut buf).unwrap();
+        assert_eq!(frame_len(&small), Some(5));
+        assert!(frame_compress(&config, gzip, &small).is_none());
+        assert!(frame_split(&mut buf).is_none());
+        buf.put_slice(&frame_build(false, &large)[100..]);
+
+        let large = frame_split(&mut buf).unwrap();
+        let compressed = frame_compress(&config, gzip, &large).unwrap();
+        assert_eq!(compressed[0], 1);
+        assert!(compressed.len() < large.len());
+        let decompressed = frame_decompress(&config, Some(gzip), compressed.clone()).unwrap();
+        assert_eq!(decompressed, large);
+        assert!(frame_decompress(&config, None, compressed).is_err());
+
+        let too_large = frame_build(false, &vec![b'a'; 2048]);
+        let too_large = frame_compress(&config, gzip, &too_large).unwrap();
+        let status = frame_decompress(&config, Some(gzip), too_large).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn compression_encoding_test() {
+        let config = config();
+        let mut headers = HttpHeaders::new();
+        assert_eq!(encoding_request(&config, &headers).unwrap(), None);
+        assert_eq!(encoding_accepted(&config, &headers), None);
+
+        headers.insert(GRPC_ENCODING, "zstd".parse().unwrap());
+        headers.insert(
+            GRPC_ACCEPT_ENCODING,
+            "identity,deflate, gzip".parse().unwrap(),
+        );
+        let status = encoding_request(&config, &headers).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+        assert_eq!(
+            encoding_accepted(&config, &headers),
+            Some(CompressionEncoding::Gzip)
+        );
+
+        headers.insert(GRPC_ENCODING, "gzip".parse().unwrap());
+        assert_eq!(
+            encoding_request(&config, &headers).unwrap(),
+            Some(CompressionEncoding::Gzip)
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! # Compression Service
+//!
+use crate::internal::*;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to compress and decompress gRPC messages
+#[derive(Debug, Clone)]
+pub struct CompressionService<S> {
+    compression: Arc<Compression>,
+    inner: S,
+}
+
+impl<S> CompressionService<S> {
+    pub fn wrap(compression: Arc<Compression>, api: S) -> Self {
+        Self {
+            compression,
+            inner: api,
+        }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for CompressionService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let compression = self.compression.clone();
+
+        Box::pin(async move {
+            let request_encoding = match compression.service_request_encoding(req.headers()) {
+                Ok(encoding) => encoding,
+                Err(status) => {
+                    let mut res = status.to_http();
+                    compression.service_response_headers(None, res.headers_mut());
+                    return Ok(res);
+                }
+            };
+            let response_encoding = compression.service_response_encoding(req.headers());
+
+            let (mut parts, body) = req.into_parts();
+            parts.headers.remove(http::header::CONTENT_LENGTH);
+            let body = compression
+                .clone()
+                .service_request_body(request_encoding, body);
+            let req = HyperRequest::from_parts(parts, body);
+
+            let res = svc.call(req).await?;
+            let (mut parts, body) = res.into_parts();
+            compression.service_response_headers(response_encoding, &mut parts.headers);
+            let body = match response_encoding {
+                Some(encoding) => BoxBody::new(CompressionBody::wrap(compression, encoding, body)),
+                None => body,
+            };
+            Ok(HyperResponse::from_parts(parts, body))
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for CompressionService<S> {
+    const NAME: &'static str = S::NAME;
+}
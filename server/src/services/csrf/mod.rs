@@ -79,7 +79,7 @@ impl Csrf {
                     }
                 }
 
-                Err(tonic::Status::permission_denied(ERROR_CSRF_CHECK))
+                Err(tonic_status_error_info(&ERROR_CSRF_CHECK, &[]))
             }
         } else {
             Ok(())
@@ -314,16 +314,6 @@ fn header_remove_value(headers: &mut HttpHeaders, header_name: &str) -> Option<S
     }
 }
 
-/// Generate and return a random alphanumeric string of length
-fn random_string(length: usize) -> String {
-    use rand::Rng;
-    let rng = rand::thread_rng();
-    rng.sample_iter(&rand::distributions::Alphanumeric)
-        .take(length)
-        .map(char::from)
-        .collect()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -335,20 +325,20 @@ mod tests {
 
         let http_localhost_1234 = Url::from_str("http://localhost:1234").unwrap();
         let http_localhost_4180 = Url::from_str("http://localhost:4180").unwrap();
-        assert_eq!(match_allow_origin(http_localhost_1234, &allow_origin), true);
-        assert_eq!(match_allow_origin(http_localhost_4180, &allow_origin), true);
+        assert!(match_allow_origin(http_localhost_1234, &allow_origin));
+        assert!(match_allow_origin(http_localhost_4180, &allow_origin));
 
         let http_foo = Url::from_str("http://foo").unwrap();
         let https_localhost = Url::from_str("https://localhost").unwrap();
-        assert_eq!(match_allow_origin(http_foo, &allow_origin), false);
-        assert_eq!(match_allow_origin(https_localhost, &allow_origin), false);
+        assert!(!match_allow_origin(http_foo, &allow_origin));
+        assert!(!match_allow_origin(https_localhost, &allow_origin));
 
         let example_org = Url::from_str("http://example.org").unwrap();
         let allow_origin = vec![example_org.clone()];
 
         let attacker_com = Url::from_str("http://example.org.attacker.com").unwrap();
-        assert_eq!(match_allow_origin(example_org, &allow_origin), true);
-        assert_eq!(match_allow_origin(attacker_com, &allow_origin), false);
+        assert!(match_allow_origin(example_org, &allow_origin));
+        assert!(!match_allow_origin(attacker_com, &allow_origin));
     }
 
     #[test]
@@ -0,0 +1,636 @@
+//! # Errors
+//!
+//! Catalogue of errors returned by request handlers, each error has:
+//!
+//! - A stable machine code, returned as the `google.rpc.ErrorInfo` reason
+//! - A gRPC code and equivalent HTTP status code (for transcoded requests)
+//! - Message templates in supported locales, `{key}` placeholders are replaced
+//!   with values from the `ErrorInfo` metadata
+//!
+//! The `ErrorsService` adds a `google.rpc.LocalizedMessage` to error responses
+//! in the locale chosen from the `accept-language` header. The catalogue can be
+//! exported as JSON with the `errors` job so clients can show consistent messages
+//!
+use crate::internal::*;
+use http::header::{HeaderValue, CONTENT_LANGUAGE};
+use petshop_proto::google::rpc;
+use prost::Message;
+use std::collections::HashMap;
+use tonic::Code;
+
+pub use service::ErrorsService;
+
+mod service;
+
+/// Domain of `google.rpc.ErrorInfo` details
+pub static ERROR_DOMAIN: &str = "petshop";
+
+/// Supported locales, the first is used by default
+pub static ERROR_LOCALES: &[&str] = &["en", "de", "es", "fr"];
+
+const GRPC_STATUS: &str = "grpc-status";
+
+const GRPC_MESSAGE: &str = "grpc-message";
+
+const GRPC_STATUS_DETAILS: &str = "grpc-status-details-bin";
+
+/// Catalogue Error
+#[derive(Debug)]
+pub struct CatalogueError {
+    pub code: &'static str,
+    pub message: &'static str,
+    pub grpc_code: Code,
+    pub http_status: HttpStatus,
+    pub templates: &'static [(&'static str, &'static str)],
+}
+
+pub static ERROR_GENERIC: CatalogueError = CatalogueError {
+    code: "INTERNAL",
+    message: "Error",
+    grpc_code: Code::Internal,
+    http_status: HttpStatus::INTERNAL_SERVER_ERROR,
+    templates: &[
+        ("en", "Something went wrong, please try again later"),
+        (
+            "de",
+            "Etwas ist schiefgelaufen, bitte versuchen Sie es später erneut",
+        ),
+        ("es", "Algo salió mal, inténtelo de nuevo más tarde"),
+        (
+            "fr",
+            "Une erreur s'est produite, veuillez réessayer plus tard",
+        ),
+    ],
+};
+
+pub static ERROR_VALIDATION: CatalogueError = CatalogueError {
+    code: "VALIDATION",
+    message: "ValidationError",
+    grpc_code: Code::InvalidArgument,
+    http_status: HttpStatus::BAD_REQUEST,
+    templates: &[
+        (
+            "en",
+            "The request is invalid, check the fields and try again",
+        ),
+        (
+            "de",
+            "Die Anfrage ist ungültig, überprüfen Sie die Felder und versuchen Sie es erneut",
+        ),
+        (
+            "es",
+            "La solicitud no es válida, revise los campos e inténtelo de nuevo",
+        ),
+        (
+            "fr",
+            "La requête est invalide, vérifiez les champs et réessayez",
+        ),
+    ],
+};
+
+pub static ERROR_AUTHENTICATION: CatalogueError = CatalogueError {
+    code: "AUTHENTICATION",
+    message: "AuthenticationError",
+    grpc_code: Code::Unauthenticated,
+    http_status: HttpStatus::UNAUTHORIZED,
+    templates: &[
+        ("en", "You must sign in to continue"),
+        ("de", "Sie müssen sich anmelden, um fortzufahren"),
+        ("es", "Debe iniciar sesión para continuar"),
+        ("fr", "Vous devez vous connecter pour continuer"),
+    ],
+};
+
+pub static ERROR_PERMISSION_DENIED: CatalogueError = CatalogueError {
+    code: "PERMISSION_DENIED",
+    message: "PermissionDeniedError",
+    grpc_code: Code::PermissionDenied,
+    http_status: HttpStatus::FORBIDDEN,
+    templates: &[
+        ("en", "You do not have permission to do this"),
+        ("de", "Sie haben keine Berechtigung, dies zu tun"),
+        ("es", "No tiene permiso para hacer esto"),
+        ("fr", "Vous n'avez pas la permission de faire cela"),
+    ],
+};
+
+pub static ERROR_CSRF_CHECK: CatalogueError = CatalogueError {
+    code: "CSRF_CHECK",
+    message: "CsrfCheckError",
+    grpc_code: Code::PermissionDenied,
+    http_status: HttpStatus::FORBIDDEN,
+    templates: &[
+        (
+            "en",
+            "Your session has expired, reload the page and try again",
+        ),
+        (
+            "de",
+            "Ihre Sitzung ist abgelaufen, laden Sie die Seite neu und versuchen Sie es erneut",
+        ),
+        (
+            "es",
+            "Su sesión ha caducado, recargue la página e inténtelo de nuevo",
+        ),
+        (
+            "fr",
+            "Votre session a expiré, rechargez la page et réessayez",
+        ),
+    ],
+};
+
+pub static ERROR_NOT_FOUND: CatalogueError = CatalogueError {
+    code: "NOT_FOUND",
+    message: "NotFoundError",
+    grpc_code: Code::NotFound,
+    http_status: HttpStatus::NOT_FOUND,
+    templates: &[
+        ("en", "The requested {resource} was not found"),
+        (
+            "de",
+            "Die angeforderte Ressource {resource} wurde nicht gefunden",
+        ),
+        ("es", "No se encontró el recurso {resource} solicitado"),
+        ("fr", "La ressource {resource} demandée est introuvable"),
+    ],
+};
+
+pub static ERROR_CONFLICT: CatalogueError = CatalogueError {
+    code: "CONFLICT",
+    message: "ConflictError",
+    grpc_code: Code::Aborted,
+    http_status: HttpStatus::CONFLICT,
+    templates: &[
+        (
+            "en",
+            "The {resource} conflicts with its current state, reload and try again",
+        ),
+        (
+            "de",
+            "Die Ressource {resource} steht im Konflikt mit ihrem aktuellen Zustand, laden Sie neu und versuchen Sie es erneut",
+        ),
+        (
+            "es",
+            "El recurso {resource} entra en conflicto con su estado actual, recargue e inténtelo de nuevo",
+        ),
+        (
+            "fr",
+            "La ressource {resource} est en conflit avec son état actuel, rechargez et réessayez",
+        ),
+    ],
+};
+
+pub static ERROR_UNAVAILABLE: CatalogueError = CatalogueError {
+    code: "UNAVAILABLE",
+    message: "UnavailableError",
+    grpc_code: Code::Unavailable,
+    http_status: HttpStatus::SERVICE_UNAVAILABLE,
+    templates: &[
+        (
+            "en",
+            "The service is temporarily unavailable, please try again later",
+        ),
+        (
+            "de",
+            "Der Dienst ist vorübergehend nicht verfügbar, bitte versuchen Sie es später erneut",
+        ),
+        (
+            "es",
+            "El servicio no está disponible temporalmente, inténtelo de nuevo más tarde",
+        ),
+        (
+            "fr",
+            "Le service est temporairement indisponible, veuillez réessayer plus tard",
+        ),
+    ],
+};
+
+pub static ERROR_GITHUB_WEBHOOK: CatalogueError = CatalogueError {
+    code: "GITHUB_WEBHOOK_INVALID",
+    message: "ValidationError",
+    grpc_code: Code::InvalidArgument,
+    http_status: HttpStatus::BAD_REQUEST,
+    templates: &[
+        ("en", "The GitHub webhook {field} is invalid"),
+        ("de", "Das GitHub-Webhook-Feld {field} ist ungültig"),
+        ("es", "El campo {field} del webhook de GitHub no es válido"),
+        ("fr", "Le champ {field} du webhook GitHub est invalide"),
+    ],
+};
+
+pub static ERROR_RATE_LIMITED: CatalogueError = CatalogueError {
+    code: "RATE_LIMITED",
+    message: "RateLimitError",
+    grpc_code: Code::ResourceExhausted,
+    http_status: HttpStatus::TOO_MANY_REQUESTS,
+    templates: &[
+        ("en", "Too many requests, please wait and try again"),
+        (
+            "de",
+            "Zu viele Anfragen, bitte warten Sie und versuchen Sie es erneut",
+        ),
+        ("es", "Demasiadas solicitudes, espere e inténtelo de nuevo"),
+        ("fr", "Trop de requêtes, veuillez patienter et réessayer"),
+    ],
+};
+
+pub static ERROR_ENCODING_UNSUPPORTED: CatalogueError = CatalogueError {
+    code: "ENCODING_UNSUPPORTED",
+    message: "EncodingError",
+    grpc_code: Code::Unimplemented,
+    http_status: HttpStatus::UNSUPPORTED_MEDIA_TYPE,
+    templates: &[
+        ("en", "The message encoding {encoding} is not supported"),
+        (
+            "de",
+            "Die Nachrichtenkodierung {encoding} wird nicht unterstützt",
+        ),
+        (
+            "es",
+            "La codificación de mensajes {encoding} no es compatible",
+        ),
+        (
+            "fr",
+            "L'encodage de message {encoding} n'est pas pris en charge",
+        ),
+    ],
+};
+
+pub static ERROR_MESSAGE_TOO_LARGE: CatalogueError = CatalogueError {
+    code: "MESSAGE_TOO_LARGE",
+    message: "MessageSizeError",
+    grpc_code: Code::ResourceExhausted,
+    http_status: HttpStatus::PAYLOAD_TOO_LARGE,
+    templates: &[
+        (
+            "en",
+            "The message is too large, the maximum size is {max_bytes} bytes",
+        ),
+        (
+            "de",
+            "Die Nachricht ist zu groß, die maximale Größe beträgt {max_bytes} Bytes",
+        ),
+        (
+            "es",
+            "El mensaje es demasiado grande, el tamaño máximo es {max_bytes} bytes",
+        ),
+        (
+            "fr",
+            "Le message est trop volumineux, la taille maximale est de {max_bytes} octets",
+        ),
+    ],
+};
+
+pub static ERROR_RESUME_TOKEN_INVALID: CatalogueError = CatalogueError {
+    code: "RESUME_TOKEN_INVALID",
+    message: "ResumeTokenError",
+    grpc_code: Code::OutOfRange,
+    http_status: HttpStatus::BAD_REQUEST,
+    templates: &[
+        (
+            "en",
+            "The resume token is invalid or has expired, watch again without it",
+        ),
+        (
+            "de",
+            "Das Fortsetzungstoken ist ungültig oder abgelaufen, beobachten Sie erneut ohne es",
+        ),
+        (
+            "es",
+            "El token de reanudación no es válido o ha caducado, observe de nuevo sin él",
+        ),
+        (
+            "fr",
+            "Le jeton de reprise est invalide ou a expiré, observez à nouveau sans lui",
+        ),
+    ],
+};
+
+pub static ERROR_IDEMPOTENCY_IN_PROGRESS: CatalogueError = CatalogueError {
+    code: "IDEMPOTENCY_IN_PROGRESS",
+    message: "IdempotencyInProgressError",
+    grpc_code: Code::Aborted,
+    http_status: HttpStatus::CONFLICT,
+    templates: &[
+        (
+            "en",
+            "A request with this idempotency key is in progress, try again later",
+        ),
+        (
+            "de",
+            "Eine Anfrage mit diesem Idempotenzschlüssel wird bearbeitet, versuchen Sie es später erneut",
+        ),
+        (
+            "es",
+            "Una solicitud con esta clave de idempotencia está en curso, inténtelo más tarde",
+        ),
+        (
+            "fr",
+            "Une requête avec cette clé d'idempotence est en cours, réessayez plus tard",
+        ),
+    ],
+};
+
+pub static ERROR_IDEMPOTENCY_KEY_REUSED: CatalogueError = CatalogueError {
+    code: "IDEMPOTENCY_KEY_REUSED",
+    message: "IdempotencyKeyReusedError",
+    grpc_code: Code::FailedPrecondition,
+    http_status: HttpStatus::UNPROCESSABLE_ENTITY,
+    templates: &[
+        (
+            "en",
+            "The idempotency key was used for a different request, use a new key",
+        ),
+        (
+            "de",
+            "Der Idempotenzschlüssel wurde für eine andere Anfrage verwendet, verwenden Sie einen neuen Schlüssel",
+        ),
+        (
+            "es",
+            "La clave de idempotencia se usó para una solicitud diferente, use una clave nueva",
+        ),
+        (
+            "fr",
+            "La clé d'idempotence a été utilisée pour une autre requête, utilisez une nouvelle clé",
+        ),
+    ],
+};
+
+/// All errors in catalogue
+pub static ERRORS: &[&CatalogueError] = &[
+    &ERROR_GENERIC,
+    &ERROR_VALIDATION,
+    &ERROR_AUTHENTICATION,
+    &ERROR_PERMISSION_DENIED,
+    &ERROR_CSRF_CHECK,
+    &ERROR_NOT_FOUND,
+    &ERROR_CONFLICT,
+    &ERROR_UNAVAILABLE,
+    &ERROR_GITHUB_WEBHOOK,
+    &ERROR_RATE_LIMITED,
+    &ERROR_ENCODING_UNSUPPORTED,
+    &ERROR_MESSAGE_TOO_LARGE,
+    &ERROR_RESUME_TOKEN_INVALID,
+    &ERROR_IDEMPOTENCY_IN_PROGRESS,
+    &ERROR_IDEMPOTENCY_KEY_REUSED,
+];
+
+impl CatalogueError {
+    /// Returns `ErrorInfo` details of error with metadata
+    pub fn error_info(&self, metadata: &[(&str, &str)]) -> rpc::ErrorInfo {
+        rpc::ErrorInfo {
+            reason: self.code.to_string(),
+            domain: ERROR_DOMAIN.to_string(),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Returns message template in locale, or in the default locale if not defined
+    pub fn template(&self, locale: &str) -> &'static str {
+        self.templates
+            .iter()
+            .find(|(x, _)| *x == locale)
+            .or_else(|| self.templates.first())
+            .map(|(_, template)| *template)
+            .unwrap_or(self.message)
+    }
+
+    /// Returns message in locale with placeholders replaced by metadata values
+    pub fn render(&self, locale: &str, metadata: &HashMap<String, String>) -> String {
+        let mut message = self.template(locale).to_string();
+        for (key, value) in metadata {
+            message = message.replace(&format!("{{{}}}", key), value);
+        }
+        message
+    }
+}
+
+/// Returns error in catalogue with code
+pub fn errors_find(code: &str) -> Option<&'static CatalogueError> {
+    ERRORS.iter().find(|x| x.code == code).copied()
+}
+
+/// Returns supported locale with the highest quality value in `accept-language`
+/// header, or the default locale
+///
+/// <https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Accept-Language>
+pub fn errors_locale(accept_language: Option<&str>) -> &'static str {
+    let mut locale = (ERROR_LOCALES[0], 0.0);
+    for language in accept_language.unwrap_or_default().split(',') {
+        let mut parts = language.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let primary = tag.split('-').next().unwrap_or_default().to_lowercase();
+        let quality = parts
+            .find_map(|x| x.trim().strip_prefix("q="))
+            .map(|x| x.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        if let Some(supported) = ERROR_LOCALES.iter().find(|x| **x == primary) {
+            if quality > locale.1 {
+                locale = (supported, quality);
+            }
+        }
+    }
+    locale.0
+}
+
+/// Returns error in catalogue and its `ErrorInfo` from status details
+pub fn errors_info(status: &rpc::Status) -> Option<(&'static CatalogueError, rpc::ErrorInfo)> {
+    let error_info = status
+        .details
+        .iter()
+        .filter(|x| x.type_url == "type.googleapis.com/google.rpc.ErrorInfo")
+        .find_map(|x| rpc::ErrorInfo::decode(x.value.as_slice()).ok())
+        .filter(|x| x.domain == ERROR_DOMAIN)?;
+    let error = errors_find(&error_info.reason)?;
+    Some((error, error_info))
+}
+
+/// Adds `LocalizedMessage` to status details in response headers, this applies
+/// to errors returned by request handlers which are sent in headers without a body
+///
+/// Details are only added if they include `ErrorInfo` for an error in catalogue
+pub fn errors_localize(locale: &'static str, headers: &mut HttpHeaders) {
+    let mut status = match headers
+        .get(GRPC_STATUS_DETAILS)
+        .and_then(|x| base64::decode(x.as_bytes()).ok())
+        .and_then(|x| rpc::Status::decode(x.as_slice()).ok())
+    {
+        Some(status) => status,
+        None => return,
+    };
+
+    let (error, error_info) = match errors_info(&status) {
+        Some(info) => info,
+        None => return,
+    };
+
+    let localized = rpc::LocalizedMessage {
+        locale: locale.to_string(),
+        message: error.render(locale, &error_info.metadata),
+    };
+    status
+        .details
+        .push(prost_any("google.rpc.LocalizedMessage", &localized));
+
+    let details = base64::encode_config(prost_encode(&status), base64::STANDARD_NO_PAD);
+    if let Ok(details) = HeaderValue::from_str(&details) {
+        headers.insert(GRPC_STATUS_DETAILS, details);
+        headers.insert(CONTENT_LANGUAGE, HeaderValue::from_static(locale));
+    }
+}
+
+/// Adds `RequestInfo` with request id to status details in response headers, this
+/// applies to errors sent in headers without a body like `errors_localize`
+///
+/// Details are created from the status code and message if not present
+pub fn errors_request_info(request_id: &str, headers: &mut HttpHeaders) {
+    let code = match headers.get(GRPC_STATUS) {
+        Some(code) => Code::from_bytes(code.as_bytes()),
+        None => return,
+    };
+    if code == Code::Ok {
+        return;
+    }
+
+    let details = headers
+        .get(GRPC_STATUS_DETAILS)
+        .and_then(|x| base64::decode(x.as_bytes()).ok())
+        .and_then(|x| rpc::Status::decode(x.as_slice()).ok());
+    let mut status = match details {
+        Some(status) => status,
+        None => {
+            // Status parses the percent encoded message, details are not copied as
+            // it panics if they are not valid base64
+            let mut message = HttpHeaders::new();
+            for name in [GRPC_STATUS, GRPC_MESSAGE].iter() {
+                if let Some(value) = headers.get(*name) {
+                    message.insert(*name, value.clone());
+                }
+            }
+            rpc::Status {
+                code: code as i32,
+                message: tonic::Status::from_header_map(&message)
+                    .map(|x| x.message().to_string())
+                    .unwrap_or_default(),
+                details: Vec::new(),
+            }
+        }
+    };
+
+    let request_info = rpc::RequestInfo {
+        request_id: request_id.to_string(),
+        serving_data: String::new(),
+    };
+    status
+        .details
+        .push(prost_any("google.rpc.RequestInfo", &request_info));
+
+    let details = base64::encode_config(prost_encode(&status), base64::STANDARD_NO_PAD);
+    if let Ok(details) = HeaderValue::from_str(&details) {
+        headers.insert(GRPC_STATUS_DETAILS, details);
+    }
+}
+
+/// Returns catalogue as JSON value
+pub fn errors_export() -> serde_json::Value {
+    let errors: serde_json::Map<String, serde_json::Value> = ERRORS
+        .iter()
+        .map(|error| {
+            let templates: serde_json::Map<String, serde_json::Value> = error
+                .templates
+                .iter()
+                .map(|(locale, template)| (locale.to_string(), json!(template)))
+                .collect();
+            (
+                error.code.to_string(),
+                json!({
+                    "message": error.message,
+                    "grpc_code": error.grpc_code as i32,
+                    "http_status": error.http_status.as_u16(),
+                    "templates": templates,
+                }),
+            )
+        })
+        .collect();
+
+    json!({
+        "domain": ERROR_DOMAIN,
+        "locales": ERROR_LOCALES,
+        "errors": errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_catalogue_test() {
+        for error in ERRORS {
+            assert_eq!(errors_find(error.code).unwrap().code, error.code);
+            for locale in ERROR_LOCALES {
+                assert!(error.templates.iter().any(|(x, _)| x == locale));
+            }
+        }
+        let export = errors_export();
+        assert_eq!(export["errors"]["NOT_FOUND"]["grpc_code"], 5);
+        assert_eq!(export["errors"]["NOT_FOUND"]["http_status"], 404);
+    }
+
+    #[test]
+    fn errors_locale_test() {
+        assert_eq!(errors_locale(None), "en");
+        assert_eq!(errors_locale(Some("fr-CH, fr;q=0.9, en;q=0.8")), "fr");
+        assert_eq!(errors_locale(Some("en;q=0.5, de;q=0.7, *;q=0.1")), "de");
+        assert_eq!(errors_locale(Some("ja, ES")), "es");
+        assert_eq!(errors_locale(Some("ja")), "en");
+    }
+
+    #[test]
+    fn errors_localize_test() {
+        let status = tonic_status_error_info(&ERROR_NOT_FOUND, &[("resource", "pet")]);
+        let mut headers = status.to_http().headers().clone();
+        errors_localize("de", &mut headers);
+
+        let status = tonic::Status::from_header_map(&headers).unwrap();
+        let status = rpc::Status::decode(status.details()).unwrap();
+        let localized = rpc::LocalizedMessage::decode(status.details[1].value.as_slice()).unwrap();
+        assert_eq!(localized.locale, "de");
+        assert_eq!(
+            localized.message,
+            "Die angeforderte Ressource pet wurde nicht gefunden"
+        );
+        assert_eq!(headers[CONTENT_LANGUAGE], "de");
+    }
+
+    #[test]
+    fn errors_request_info_test() {
+        let status = tonic_status_error_info(&ERROR_NOT_FOUND, &[("resource", "pet")]);
+        let mut headers = status.to_http().headers().clone();
+        errors_request_info("abc", &mut headers);
+
+        let status = tonic::Status::from_header_map(&headers).unwrap();
+        let status = rpc::Status::decode(status.details()).unwrap();
+        let request_info = rpc::RequestInfo::decode(status.details[1].value.as_slice()).unwrap();
+        assert_eq!(request_info.request_id, "abc");
+
+        let mut headers = tonic::Status::internal("pet 1%")
+            .to_http()
+            .headers()
+            .clone();
+        errors_request_info("abc", &mut headers);
+        let status = tonic::Status::from_header_map(&headers).unwrap();
+        let status = rpc::Status::decode(status.details()).unwrap();
+        assert_eq!(status.code, Code::Internal as i32);
+        assert_eq!(status.message, "pet 1%");
+        assert_eq!(status.details.len(), 1);
+
+        let mut headers = HttpHeaders::new();
+        errors_request_info("abc", &mut headers);
+        assert!(headers.is_empty());
+    }
+}
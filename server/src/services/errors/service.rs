@@ -0,0 +1,58 @@
+//! # Errors Service
+//!
+use super::{errors_locale, errors_localize};
+use hyper::{header::ACCEPT_LANGUAGE, Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to add localised messages to error responses
+#[derive(Debug, Clone)]
+pub struct ErrorsService<S> {
+    inner: S,
+}
+
+impl<S> ErrorsService<S> {
+    pub fn wrap(api: S) -> Self {
+        Self { inner: api }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for ErrorsService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let locale = errors_locale(
+            req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|x| x.to_str().ok()),
+        );
+
+        Box::pin(async move {
+            let mut res = svc.call(req).await?;
+
+            errors_localize(locale, res.headers_mut());
+
+            Ok(res)
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for ErrorsService<S> {
+    const NAME: &'static str = S::NAME;
+}
@@ -0,0 +1,293 @@
+//! # GitHub
+//!
+//! Receiving GitHub webhooks, based on the documentation at the following links:
+//! <https://docs.github.com/en/developers/webhooks-and-events/securing-your-webhooks>
+//! <https://docs.github.com/en/developers/webhooks-and-events/webhook-events-and-payloads>
+//!
+//! - Payload signature in `X-Hub-Signature-256` header is verified in constant time
+//! - Payload is parsed into a typed event using the `X-GitHub-Event` header
+//! - Delivery is identified by the `X-GitHub-Delivery` header for idempotency
+//!
+use crate::internal::*;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+const X_GITHUB_EVENT: &str = "x-github-event";
+const X_GITHUB_DELIVERY: &str = "x-github-delivery";
+const X_HUB_SIGNATURE_256: &str = "x-hub-signature-256";
+
+/// GitHub Configuration
+#[derive(Clone)]
+pub struct GithubConfig {
+    pub webhook_secret: String,
+}
+
+/// GitHub
+pub struct Github {
+    config: Option<GithubConfig>,
+}
+
+/// GitHub webhook delivery
+#[derive(Debug)]
+pub struct GithubDelivery {
+    pub id: String,
+    pub event: GithubEvent,
+}
+
+/// GitHub webhook events which have typed handlers
+#[derive(Debug)]
+pub enum GithubEvent {
+    Ping(GithubPing),
+    Push(GithubPush),
+    PullRequest(GithubPullRequest),
+    Other(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubPing {
+    pub zen: String,
+    pub hook_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubPush {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub before: String,
+    pub after: String,
+    pub repository: GithubRepository,
+    #[serde(default)]
+    pub commits: Vec<GithubCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubPullRequest {
+    pub action: String,
+    pub number: i64,
+    pub pull_request: GithubPullRequestDetails,
+    pub repository: GithubRepository,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubPullRequestDetails {
+    pub title: String,
+    pub html_url: String,
+    pub state: String,
+    #[serde(default)]
+    pub merged: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubRepository {
+    pub full_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubCommit {
+    pub id: String,
+    pub message: String,
+}
+
+impl Github {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            config: config.github.clone(),
+        }
+    }
+
+    /// Verifies signature of webhook request and parses the delivery, requests are
+    /// rejected if the webhook secret is not configured
+    pub fn webhook(
+        &self,
+        metadata: &MetadataMap,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<GithubDelivery, Status> {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => {
+                warn!("github webhook secret is not configured");
+                return Err(Auth::unauthenticated());
+            }
+        };
+
+        let signature =
+            metadata_str(metadata, X_HUB_SIGNATURE_256).ok_or_else(Auth::unauthenticated)?;
+        if !verify_signature(&config.webhook_secret, signature, body) {
+            warn!("github webhook signature is invalid");
+            return Err(Auth::unauthenticated());
+        }
+
+        let id = metadata_str(metadata, X_GITHUB_DELIVERY)
+            .ok_or_else(|| webhook_invalid(X_GITHUB_DELIVERY))?
+            .to_string();
+        let event = metadata_str(metadata, X_GITHUB_EVENT)
+            .ok_or_else(|| webhook_invalid(X_GITHUB_EVENT))?;
+        let event = GithubEvent::parse(event, content_type, body).map_err(|err| {
+            warn!("github webhook parse error: {:#}", err);
+            webhook_invalid("body")
+        })?;
+
+        Ok(GithubDelivery { id, event })
+    }
+}
+
+/// Returns invalid argument status with `ErrorInfo` details of invalid part of webhook
+fn webhook_invalid(field: &str) -> Status {
+    tonic_status_error_info(&ERROR_GITHUB_WEBHOOK, &[("field", field)])
+}
+
+impl fmt::Debug for GithubConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GithubConfig").finish()
+    }
+}
+
+impl fmt::Debug for Github {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Github").finish()
+    }
+}
+
+impl GithubEvent {
+    /// Parse event payload, webhooks can be configured to use JSON or form
+    /// encoded content types (where JSON is in the `payload` field)
+    pub fn parse(event: &str, content_type: &str, body: &[u8]) -> Result<Self, XErr> {
+        let payload = if content_type == "application/x-www-form-urlencoded" {
+            let mut form: HashMap<String, String> = serde_urlencoded::from_bytes(body)?;
+            form.remove("payload").unwrap_or_default().into_bytes()
+        } else {
+            body.to_vec()
+        };
+
+        Ok(match event {
+            "ping" => Self::Ping(serde_json::from_slice(&payload)?),
+            "push" => Self::Push(serde_json::from_slice(&payload)?),
+            "pull_request" => Self::PullRequest(serde_json::from_slice(&payload)?),
+            _ => Self::Other(event.to_string()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Ping(_) => "ping",
+            Self::Push(_) => "push",
+            Self::PullRequest(_) => "pull_request",
+            Self::Other(event) => event,
+        }
+    }
+}
+
+/// Returns true if `sha256=` prefixed hex signature matches HMAC-SHA256 of body,
+/// the comparison is constant time
+fn verify_signature(secret: &str, signature: &str, body: &[u8]) -> bool {
+    let signature = match signature
+        .strip_prefix("sha256=")
+        .and_then(|x| hex::decode(x).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    mac.verify(&signature).is_ok()
+}
+
+fn metadata_str<'a>(metadata: &'a MetadataMap, key: &str) -> Option<&'a str> {
+    metadata.get(key).and_then(|x| x.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PING: &str = r#"{"zen":"Keep it logically awesome.","hook_id":1}"#;
+
+    fn github(secret: Option<&str>) -> Github {
+        Github {
+            config: secret.map(|x| GithubConfig {
+                webhook_secret: x.to_string(),
+            }),
+        }
+    }
+
+    fn metadata(signature: Option<&str>) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(X_GITHUB_EVENT, "ping".parse().unwrap());
+        metadata.insert(X_GITHUB_DELIVERY, "1".parse().unwrap());
+        if let Some(signature) = signature {
+            metadata.insert(X_HUB_SIGNATURE_256, signature.parse().unwrap());
+        }
+        metadata
+    }
+
+    #[test]
+    fn verify_signature_test() {
+        // Example from GitHub documentation
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(
+            "It's a Secret to Everybody",
+            signature,
+            b"Hello, World!"
+        ));
+        assert!(!verify_signature("wrong", signature, b"Hello, World!"));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            signature,
+            b"Hello"
+        ));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            "sha1=abc",
+            b"Hello, World!"
+        ));
+    }
+
+    #[test]
+    fn webhook_test() {
+        let body = PING.as_bytes();
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").unwrap();
+        mac.update(body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+        let delivery = github(Some("secret"))
+            .webhook(&metadata(Some(&signature)), "application/json", body)
+            .unwrap();
+        assert_eq!(delivery.id, "1");
+        assert!(matches!(delivery.event, GithubEvent::Ping(ref x) if x.hook_id == 1));
+
+        for (github, metadata) in [
+            (github(Some("secret")), metadata(None)),
+            (github(Some("other")), metadata(Some(&signature))),
+            (github(None), metadata(Some(&signature))),
+        ] {
+            let err = github
+                .webhook(&metadata, "application/json", body)
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn event_parse_test() {
+        let form = serde_urlencoded::to_string([("payload", PING)]).unwrap();
+        let event =
+            GithubEvent::parse("ping", "application/x-www-form-urlencoded", form.as_bytes())
+                .unwrap();
+        assert_eq!(event.name(), "ping");
+
+        let push = r#"{"ref":"refs/heads/main","before":"a","after":"b","repository":{"full_name":"mojzu/petshop"}}"#;
+        let event = GithubEvent::parse("push", "application/json", push.as_bytes()).unwrap();
+        assert!(matches!(event, GithubEvent::Push(ref x) if x.git_ref == "refs/heads/main"));
+
+        let event = GithubEvent::parse("issues", "application/json", b"{}").unwrap();
+        assert_eq!(event.name(), "issues");
+        assert!(GithubEvent::parse("push", "application/json", b"{}").is_err());
+    }
+}
@@ -0,0 +1,588 @@
+//! # Idempotency
+//!
+//! Idempotency keys let clients safely retry mutating requests, for example after
+//! a network failure, without creating duplicates
+//!
+//! - Requests to configured methods with an `idempotency-key` header are locked by
+//!   identity, method and key, and their response is stored in postgres until it expires
+//! - Repeated requests replay the stored response with an `idempotency-replayed` header
+//! - Concurrent duplicates return `Aborted` while the first request is in progress
+//! - Reuse of a key with a different request body returns `FailedPrecondition`
+//! - Responses with retryable status codes are not stored so the request can be
+//!   retried with the same key, as are requests without an authenticated identity
+//! - Authentication, permission and CSRF failures are not stored, so the request can
+//!   be retried with the same key after credentials or tokens are refreshed
+//! - Requests are decompressed before they are compared and responses are stored
+//!   uncompressed, the compression service encodes responses for each client
+//! - Request bodies are buffered up to `max_body_bytes`, larger requests are passed
+//!   through without locking the key
+//!
+//! <https://datatracker.ietf.org/doc/html/draft-ietf-httpapi-idempotency-key-header>
+use crate::internal::*;
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue};
+use hyper::body::HttpBody;
+use hyper::Response as HyperResponse;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::Code;
+use validator::{ValidationError, ValidationErrors};
+
+pub use service::IdempotencyService;
+
+mod service;
+
+/// Idempotency key header
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Header added to replayed responses
+const IDEMPOTENCY_REPLAYED: &str = "idempotency-replayed";
+
+/// Maximum length of idempotency keys
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+/// Response headers which are not stored, they are added per response, responses
+/// are stored uncompressed and encoded for each client by the compression service
+const IDEMPOTENCY_HEADERS_EXCLUDED: &[&str] = &[
+    "date",
+    "content-length",
+    "set-cookie",
+    "grpc-encoding",
+    "grpc-accept-encoding",
+];
+
+/// Interval between deleting expired keys
+const IDEMPOTENCY_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Idempotency Configuration
+#[derive(Debug, Clone)]
+pub struct IdempotencyConfig {
+    pub methods: Vec<String>,
+    pub ttl_hours: u64,
+    pub lock_seconds: u64,
+    pub max_body_bytes: usize,
+}
+
+/// Idempotency
+pub struct Idempotency {
+    config: IdempotencyConfig,
+    postgres: Arc<PostgresPool>,
+    shutdown: Arc<Shutdown>,
+}
+
+/// Scope of idempotency key, keys of different identities and methods are independent
+#[derive(Debug, Clone)]
+pub struct IdempotencyScope {
+    pub identity: String,
+    pub method: String,
+    pub key: String,
+}
+
+/// Result of locking idempotency key
+#[derive(Debug)]
+pub enum IdempotencyLock {
+    /// Key is locked by this request until the time, which identifies the lock
+    Acquired(chrono::DateTime<Utc>),
+    /// Key is locked by another request in progress
+    InProgress,
+    /// Key was used for a request with a different body
+    Reused,
+    /// Key was used for a request which has completed
+    Completed(IdempotencyResponse),
+}
+
+/// Stored response, gRPC responses have trailers unless they are trailers-only
+#[derive(Debug, Clone)]
+pub struct IdempotencyResponse {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub trailers: Option<Vec<(String, String)>>,
+}
+
+/// Buffered response body with trailers
+#[derive(Debug)]
+pub struct IdempotencyBody {
+    data: Option<Bytes>,
+    trailers: Option<HttpHeaders>,
+}
+
+impl Idempotency {
+    pub fn from_config(
+        config: &Config,
+        postgres: Arc<PostgresPool>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        Self {
+            config: config.idempotency.clone(),
+            postgres,
+            shutdown,
+        }
+    }
+
+    /// Returns scope of request if method is configured and it has a key and an
+    /// authenticated identity, or an error status if the key is invalid
+    pub fn service_scope(
+        &self,
+        path: &str,
+        headers: &HttpHeaders,
+    ) -> Result<Option<IdempotencyScope>, tonic::Status> {
+        idempotency_scope(&self.config, path, headers)
+    }
+
+    /// Returns true if request body can be stored
+    pub fn service_body_storable(&self, body: &[u8]) -> bool {
+        body.len() <= self.config.max_body_bytes
+    }
+
+    /// Lock key for request with body, or return state of the key if it is locked
+    /// or has a stored response, body messages are decompressed by the compression
+    /// service so the hash does not depend on `grpc-encoding`
+    pub async fn lock(
+        &self,
+        scope: &IdempotencyScope,
+        body: &[u8],
+    ) -> Result<IdempotencyLock, XErr> {
+        let request_hash = hex::encode(Sha256::digest(body));
+        let now = Utc::now();
+        let locked_until = now + chrono::Duration::seconds(self.config.lock_seconds as i64);
+        let expires_at = now + chrono::Duration::hours(self.config.ttl_hours as i64);
+        self.postgres
+            .idempotency_lock(scope, &request_hash, locked_until, expires_at)
+            .await
+    }
+
+    /// Store response of request which locked key if it is storable, otherwise
+    /// release the lock so the request can be retried, errors are logged
+    ///
+    /// Key is not changed if the lock expired and was acquired by another request
+    pub async fn complete(
+        &self,
+        scope: &IdempotencyScope,
+        locked_until: chrono::DateTime<Utc>,
+        response: &IdempotencyResponse,
+    ) {
+        let result = if response.storable(self.config.max_body_bytes) {
+            self.postgres
+                .idempotency_complete(scope, locked_until, response)
+                .await
+        } else {
+            self.postgres.idempotency_release(scope, locked_until).await
+        };
+        if let Err(err) = result {
+            let err: Error = err.into();
+            warn!(
+                "idempotency complete error: {} {}: {:#}",
+                scope.identity, scope.method, err
+            );
+        }
+    }
+
+    /// Delete expired keys until shutdown is closing
+    pub async fn run(self: Arc<Self>) {
+        let mut prune = tokio::time::interval(IDEMPOTENCY_PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = prune.tick() => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+            match self.postgres.idempotency_delete_expired().await {
+                Ok(0) => {}
+                Ok(deleted) => info!("idempotency deleted {} expired keys", deleted),
+                Err(err) => {
+                    let err: Error = err.into();
+                    warn!("idempotency prune error: {:#}", err);
+                }
+            }
+        }
+        info!("idempotency prune stopped");
+    }
+
+    /// Returns status of lock which is not acquired, or none if it is
+    pub fn service_status(&self, lock: &IdempotencyLock) -> Option<tonic::Status> {
+        idempotency_status(lock)
+    }
+}
+
+impl IdempotencyResponse {
+    /// Buffers response, returns an error status if the body fails
+    pub async fn from_http(
+        res: HyperResponse<tonic::body::BoxBody>,
+    ) -> Result<Self, tonic::Status> {
+        let (parts, mut body) = res.into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk?);
+        }
+        let trailers = body.trailers().await?;
+        Ok(Self {
+            headers: idempotency_headers_into_vec(&parts.headers),
+            body: data,
+            trailers: trailers.as_ref().map(idempotency_headers_into_vec),
+        })
+    }
+
+    /// Returns trailers-only response of status
+    pub fn from_status(status: tonic::Status) -> Self {
+        Self {
+            headers: idempotency_headers_into_vec(status.to_http().headers()),
+            body: Vec::new(),
+            trailers: None,
+        }
+    }
+
+    /// Returns response, replayed responses have a header to show they were stored
+    pub fn into_http(self, replayed: bool) -> HyperResponse<tonic::body::BoxBody> {
+        let mut res = HyperResponse::new(tonic::body::BoxBody::new(IdempotencyBody {
+            data: Some(Bytes::from(self.body)),
+            trailers: self.trailers.as_deref().map(idempotency_headers_from_vec),
+        }));
+        *res.headers_mut() = idempotency_headers_from_vec(&self.headers);
+        if replayed {
+            res.headers_mut()
+                .insert(IDEMPOTENCY_REPLAYED, HeaderValue::from_static("true"));
+        }
+        res
+    }
+
+    /// Returns gRPC status code of response
+    pub fn code(&self) -> Code {
+        self.trailers
+            .as_ref()
+            .unwrap_or(&self.headers)
+            .iter()
+            .find(|(name, _)| name == "grpc-status")
+            .map(|(_, value)| Code::from_bytes(value.as_bytes()))
+            .unwrap_or(Code::Unknown)
+    }
+
+    /// Returns true if response is not too large and its status is not retryable,
+    /// or an authentication failure which may succeed with other credentials
+    fn storable(&self, max_body_bytes: usize) -> bool {
+        let retryable = matches!(
+            self.code(),
+            Code::Cancelled
+                | Code::Unknown
+                | Code::DeadlineExceeded
+                | Code::ResourceExhausted
+                | Code::Aborted
+                | Code::Internal
+                | Code::Unavailable
+                | Code::Unauthenticated
+                | Code::PermissionDenied
+        );
+        !retryable && self.body.len() <= max_body_bytes
+    }
+}
+
+impl HttpBody for IdempotencyBody {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let data = self.get_mut().data.take().filter(|x| !x.is_empty());
+        Poll::Ready(data.map(Ok))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HttpHeaders>, Self::Error>> {
+        Poll::Ready(Ok(self.get_mut().trailers.take()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.as_ref().map(|x| x.is_empty()).unwrap_or(true) && self.trailers.is_none()
+    }
+}
+
+fn idempotency_scope(
+    config: &IdempotencyConfig,
+    path: &str,
+    headers: &HttpHeaders,
+) -> Result<Option<IdempotencyScope>, tonic::Status> {
+    if !config.methods.iter().any(|x| x == path) {
+        return Ok(None);
+    }
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= IDEMPOTENCY_KEY_MAX_LEN => key,
+        _ => {
+            let mut errors = ValidationErrors::new();
+            errors.add(IDEMPOTENCY_KEY, ValidationError::new("length"));
+            return Err(tonic_status_bad_request(&errors));
+        }
+    };
+
+    let mut request = tonic::Request::new(());
+    *request.metadata_mut() = MetadataMap::from_headers(headers.clone());
+    Ok(
+        Auth::actor_interceptor(&request).map(|identity| IdempotencyScope {
+            identity,
+            method: path.to_string(),
+            key: key.to_string(),
+        }),
+    )
+}
+
+fn idempotency_status(lock: &IdempotencyLock) -> Option<tonic::Status> {
+    match lock {
+        IdempotencyLock::InProgress => Some(tonic_status_retry_info(
+            &ERROR_IDEMPOTENCY_IN_PROGRESS,
+            ERROR_RETRY_DELAY,
+        )),
+        IdempotencyLock::Reused => {
+            Some(tonic_status_error_info(&ERROR_IDEMPOTENCY_KEY_REUSED, &[]))
+        }
+        _ => None,
+    }
+}
+
+/// Returns stored headers, headers which are not valid strings are dropped
+fn idempotency_headers_into_vec(headers: &HttpHeaders) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !IDEMPOTENCY_HEADERS_EXCLUDED.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn idempotency_headers_from_vec(headers: &[(String, String)]) -> HttpHeaders {
+    let mut map = HttpHeaders::new();
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            map.append(name, value);
+        }
+    }
+    map
+}
+
+impl fmt::Debug for Idempotency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Idempotency").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHOD: &str = "/api.Petshop/PetPost";
+
+    fn config() -> IdempotencyConfig {
+        IdempotencyConfig {
+            methods: vec![METHOD.to_string()],
+            ttl_hours: 24,
+            lock_seconds: 60,
+            max_body_bytes: 1024,
+        }
+    }
+
+    /// Requires postgres configuration in environment
+    fn idempotency() -> Idempotency {
+        let mut config = Config::load(None).unwrap();
+        config.idempotency = self::config();
+        let metrics = Arc::new(Metrics::from_config(&config));
+        let postgres = Arc::new(PostgresPool::from_config(&config, metrics.clone()).unwrap());
+        let shutdown = Arc::new(Shutdown::from_config(&config, metrics));
+        Idempotency::from_config(&config, postgres, shutdown)
+    }
+
+    fn scope() -> IdempotencyScope {
+        IdempotencyScope {
+            identity: "api:test".to_string(),
+            method: METHOD.to_string(),
+            key: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    #[test]
+    fn idempotency_scope_test() {
+        let config = config();
+        let mut headers = HttpHeaders::new();
+        assert!(idempotency_scope(&config, METHOD, &headers)
+            .unwrap()
+            .is_none());
+
+        // Requests without an authenticated identity are not locked
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("k1"));
+        assert!(idempotency_scope(&config, METHOD, &headers)
+            .unwrap()
+            .is_none());
+
+        headers.insert("authorization", HeaderValue::from_static("key"));
+        let scope = idempotency_scope(&config, METHOD, &headers)
+            .unwrap()
+            .unwrap();
+        assert!(scope.identity.starts_with("api:"));
+        assert_eq!(scope.method, METHOD);
+        assert_eq!(scope.key, "k1");
+        assert!(
+            idempotency_scope(&config, "/api.Petshop/PetSearch", &headers)
+                .unwrap()
+                .is_none()
+        );
+
+        let key = HeaderValue::from_str(&"k".repeat(IDEMPOTENCY_KEY_MAX_LEN + 1)).unwrap();
+        headers.insert(IDEMPOTENCY_KEY, key);
+        let status = idempotency_scope(&config, METHOD, &headers).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn idempotency_status_test() {
+        let status = idempotency_status(&IdempotencyLock::InProgress);
+        assert_eq!(status.unwrap().code(), Code::Aborted);
+        let status = idempotency_status(&IdempotencyLock::Reused);
+        assert_eq!(status.unwrap().code(), Code::FailedPrecondition);
+        assert!(idempotency_status(&IdempotencyLock::Acquired(Utc::now())).is_none());
+        let response = IdempotencyResponse::from_status(tonic::Status::not_found("pet"));
+        assert!(idempotency_status(&IdempotencyLock::Completed(response)).is_none());
+    }
+
+    /// Requires postgres with migrations, configured in `CONFIG_POSTGRES__*` environment
+    /// variables, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn idempotency_lock_test() {
+        let idempotency = idempotency();
+        let scope = scope();
+        let locked_until = match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Acquired(locked_until) => locked_until,
+            lock => panic!("lock is not acquired: {:?}", lock),
+        };
+
+        // Concurrent duplicate while the first request is in progress
+        let lock = idempotency.lock(&scope, b"a").await.unwrap();
+        let status = idempotency.service_status(&lock).unwrap();
+        assert_eq!(status.code(), Code::Aborted);
+
+        // Key reused with a different body
+        let lock = idempotency.lock(&scope, b"b").await.unwrap();
+        let status = idempotency.service_status(&lock).unwrap();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        // Stored response is replayed
+        let response = IdempotencyResponse::from_status(tonic::Status::not_found("pet"));
+        idempotency.complete(&scope, locked_until, &response).await;
+        match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Completed(stored) => {
+                assert_eq!(stored.code(), Code::NotFound);
+                assert_eq!(
+                    stored.into_http(true).headers()[IDEMPOTENCY_REPLAYED],
+                    "true"
+                );
+            }
+            lock => panic!("lock is not completed: {:?}", lock),
+        }
+
+        // Retryable responses release the key
+        let scope = self::scope();
+        let locked_until = match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Acquired(locked_until) => locked_until,
+            lock => panic!("lock is not acquired: {:?}", lock),
+        };
+        let response = IdempotencyResponse::from_status(tonic::Status::unavailable("postgres"));
+        idempotency.complete(&scope, locked_until, &response).await;
+        let lock = idempotency.lock(&scope, b"b").await.unwrap();
+        assert!(matches!(lock, IdempotencyLock::Acquired(_)));
+
+        // Expired lock of a request which did not complete is taken over by a
+        // retry, but not by a request with a different body
+        let idempotency = Idempotency {
+            config: IdempotencyConfig {
+                lock_seconds: 0,
+                ..self::config()
+            },
+            postgres: idempotency.postgres.clone(),
+            shutdown: idempotency.shutdown.clone(),
+        };
+        let scope = self::scope();
+        let stale_locked_until = match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Acquired(locked_until) => locked_until,
+            lock => panic!("lock is not acquired: {:?}", lock),
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let lock = idempotency.lock(&scope, b"b").await.unwrap();
+        assert!(matches!(lock, IdempotencyLock::Reused));
+        let locked_until = match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Acquired(locked_until) => locked_until,
+            lock => panic!("lock is not acquired: {:?}", lock),
+        };
+
+        // Taken over lock is not released or completed by the request whose
+        // lock expired
+        let stale = IdempotencyResponse::from_status(tonic::Status::unavailable("postgres"));
+        idempotency
+            .complete(&scope, stale_locked_until, &stale)
+            .await;
+        let lock = idempotency.lock(&scope, b"b").await.unwrap();
+        assert!(matches!(lock, IdempotencyLock::Reused));
+        let response = IdempotencyResponse::from_status(tonic::Status::not_found("pet"));
+        idempotency.complete(&scope, locked_until, &response).await;
+        let stale = IdempotencyResponse::from_status(tonic::Status::already_exists("pet"));
+        idempotency
+            .complete(&scope, stale_locked_until, &stale)
+            .await;
+        match idempotency.lock(&scope, b"a").await.unwrap() {
+            IdempotencyLock::Completed(stored) => assert_eq!(stored.code(), Code::NotFound),
+            lock => panic!("lock is not completed: {:?}", lock),
+        }
+    }
+
+    #[tokio::test]
+    async fn idempotency_response_test() {
+        let mut trailers = HttpHeaders::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let mut res = HyperResponse::new(tonic::body::BoxBody::new(IdempotencyBody {
+            data: Some(Bytes::from_static(b"\x00\x00\x00\x00\x02\x08\x01")),
+            trailers: Some(trailers),
+        }));
+        res.headers_mut()
+            .insert("content-type", HeaderValue::from_static("application/grpc"));
+        res.headers_mut()
+            .insert("set-cookie", HeaderValue::from_static("a=b"));
+
+        let response = IdempotencyResponse::from_http(res).await.unwrap();
+        assert_eq!(response.code(), Code::Ok);
+        assert!(response.storable(1024));
+        assert!(!response.storable(4));
+        assert_eq!(
+            response.headers,
+            vec![("content-type".to_string(), "application/grpc".to_string())]
+        );
+
+        let res = response.clone().into_http(true);
+        assert_eq!(res.headers()[IDEMPOTENCY_REPLAYED], "true");
+        let replayed = IdempotencyResponse::from_http(res).await.unwrap();
+        assert_eq!(replayed.body, response.body);
+        assert_eq!(replayed.trailers, response.trailers);
+
+        let res = tonic::Status::unavailable("postgres").to_http();
+        let response = IdempotencyResponse::from_http(res).await.unwrap();
+        assert_eq!(response.code(), Code::Unavailable);
+        assert!(response.trailers.is_none());
+        assert!(!response.storable(1024));
+
+        let response = IdempotencyResponse::from_status(tonic::Status::permission_denied("csrf"));
+        assert_eq!(response.code(), Code::PermissionDenied);
+        assert!(!response.storable(1024));
+    }
+}
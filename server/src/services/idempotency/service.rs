@@ -0,0 +1,109 @@
+//! # Idempotency Service
+//!
+use crate::internal::*;
+use bytes::BytesMut;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to store and replay responses of requests with idempotency keys
+#[derive(Debug, Clone)]
+pub struct IdempotencyService<S> {
+    idempotency: Arc<Idempotency>,
+    inner: S,
+}
+
+impl<S> IdempotencyService<S> {
+    pub fn wrap(idempotency: Arc<Idempotency>, api: S) -> Self {
+        Self {
+            idempotency,
+            inner: api,
+        }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for IdempotencyService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let idempotency = self.idempotency.clone();
+
+        Box::pin(async move {
+            let scope = match idempotency.service_scope(req.uri().path(), req.headers()) {
+                Ok(Some(scope)) => scope,
+                Ok(None) => return svc.call(req).await,
+                Err(status) => return Ok(status.to_http()),
+            };
+
+            // Request body is buffered to compare it with the body of the first request,
+            // bodies which are too large to store are passed through without a key
+            let (parts, mut body) = req.into_parts();
+            let mut buf = BytesMut::new();
+            while let Some(chunk) = body.data().await {
+                match chunk {
+                    Ok(chunk) => buf.extend_from_slice(&chunk),
+                    Err(_) => return Ok(tonic::Status::cancelled("request body failed").to_http()),
+                }
+                if !idempotency.service_body_storable(&buf) {
+                    info!(
+                        "idempotency skipped, request body too large: {}",
+                        scope.method
+                    );
+                    let buffered = futures::stream::once(async move { Ok(buf.freeze()) });
+                    let body = Body::wrap_stream(buffered.chain(body));
+                    return svc.call(HyperRequest::from_parts(parts, body)).await;
+                }
+            }
+            let body = buf.freeze();
+
+            let lock = match idempotency.lock(&scope, &body).await {
+                Ok(lock) => lock,
+                Err(err) => return Ok(tonic::Status::from(err).to_http()),
+            };
+            if let Some(status) = idempotency.service_status(&lock) {
+                return Ok(status.to_http());
+            }
+            let locked_until = match lock {
+                IdempotencyLock::Acquired(locked_until) => locked_until,
+                IdempotencyLock::Completed(response) => {
+                    info!("idempotency replayed: {} {}", scope.identity, scope.method);
+                    return Ok(response.into_http(true));
+                }
+                _ => unreachable!("idempotency lock without status"),
+            };
+
+            let res = svc
+                .call(HyperRequest::from_parts(parts, Body::from(body)))
+                .await?;
+            let response = match IdempotencyResponse::from_http(res).await {
+                Ok(response) => response,
+                Err(status) => IdempotencyResponse::from_status(status),
+            };
+            idempotency.complete(&scope, locked_until, &response).await;
+
+            Ok(response.into_http(false))
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for IdempotencyService<S> {
+    const NAME: &'static str = S::NAME;
+}
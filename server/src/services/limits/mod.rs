@@ -0,0 +1,132 @@
+//! # Limits
+//!
+//! Limits on API server requests so bursts degrade gracefully:
+//!
+//! - Per connection concurrency limit and default request timeout are applied by the
+//!   tonic server, the `grpc-timeout` deadline is also honoured, handler futures are
+//!   dropped and `Cancelled` is returned when the timeout expires
+//! - Global concurrency limit shared by all services, requests wait in a queue
+//!   for a permit and are shed with `Unavailable` if the queue is full
+//!
+//! Permits are held until response headers are returned, streaming response bodies
+//! are not counted
+use crate::internal::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub use service::LimitsService;
+
+mod service;
+
+/// Delay clients are asked to wait before retrying shed requests
+const SHED_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Limits Configuration
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    pub concurrency_limit_per_connection: usize,
+    pub concurrency_limit: usize,
+    pub queue_limit: usize,
+    pub request_timeout_seconds: u64,
+}
+
+/// Limits
+#[derive(Debug)]
+pub struct Limits {
+    config: LimitsConfig,
+    metrics: Arc<Metrics>,
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+/// Permit to handle request, updates metrics when dropped
+#[derive(Debug)]
+pub struct LimitsPermit {
+    _permit: OwnedSemaphorePermit,
+    limits: Arc<Limits>,
+}
+
+/// Decrements queued requests when dropped, including if the request is cancelled
+struct QueuedGuard<'a>(&'a Limits);
+
+impl Limits {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config: config.limits.clone(),
+            metrics,
+            semaphore: Arc::new(Semaphore::new(config.limits.concurrency_limit)),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Default request timeout
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.request_timeout_seconds)
+    }
+
+    /// Concurrent requests limit per connection
+    pub fn concurrency_limit_per_connection(&self) -> usize {
+        self.config.concurrency_limit_per_connection
+    }
+
+    /// Used in tower service to wait for permit to handle request, returns none
+    /// if the queue is full and the request should be shed
+    pub async fn service_request_handler(self: Arc<Self>) -> Option<LimitsPermit> {
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+                let guard = QueuedGuard(&self);
+                if queued > self.config.queue_limit {
+                    drop(guard);
+                    self.metrics.limits_shed_counter_inc();
+                    warn!("request shed, queue is full ({})", queued);
+                    return None;
+                }
+                self.metrics_record();
+
+                let permit = self
+                    .semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore closed");
+                drop(guard);
+                permit
+            }
+        };
+        self.metrics_record();
+        Some(LimitsPermit {
+            _permit: permit,
+            limits: self,
+        })
+    }
+
+    /// Used in tower service to return status of shed request
+    pub fn service_status(&self) -> tonic::Status {
+        tonic_status_retry_info(&ERROR_UNAVAILABLE, SHED_RETRY_DELAY)
+    }
+
+    fn metrics_record(&self) {
+        let inflight = self.config.concurrency_limit - self.semaphore.available_permits();
+        let queued = self.queued.load(Ordering::SeqCst);
+        self.metrics.limits_state(inflight, queued);
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for LimitsPermit {
+    fn drop(&mut self) {
+        // Permit is released after this so in flight requests includes this one
+        let inflight =
+            self.limits.config.concurrency_limit - self.limits.semaphore.available_permits() - 1;
+        let queued = self.limits.queued.load(Ordering::SeqCst);
+        self.limits.metrics.limits_state(inflight, queued);
+    }
+}
@@ -0,0 +1,58 @@
+//! # Limits Service
+//!
+use crate::internal::*;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to apply global concurrency limit and shed load
+#[derive(Debug, Clone)]
+pub struct LimitsService<S> {
+    limits: Arc<Limits>,
+    inner: S,
+}
+
+impl<S> LimitsService<S> {
+    pub fn wrap(limits: Arc<Limits>, api: S) -> Self {
+        Self { limits, inner: api }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for LimitsService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let limits = self.limits.clone();
+
+        Box::pin(async move {
+            let permit = match limits.clone().service_request_handler().await {
+                Some(permit) => permit,
+                None => return Ok(limits.service_status().to_http()),
+            };
+
+            let res = svc.call(req).await;
+            drop(permit);
+            res
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for LimitsService<S> {
+    const NAME: &'static str = S::NAME;
+}
@@ -1,10 +1,12 @@
 //! # Metrics
 //!
 use crate::internal::*;
-use opentelemetry::metrics::{BoundCounter, BoundValueRecorder};
+use opentelemetry::metrics::{BoundCounter, BoundValueRecorder, Counter, ValueRecorder};
+use opentelemetry::KeyValue;
 use opentelemetry_prometheus::PrometheusExporter;
 use prometheus::{Encoder, TextEncoder};
 use std::fmt;
+use std::time::Duration;
 
 pub use service::MetricsService;
 
@@ -21,9 +23,30 @@ pub struct Metrics {
     latency: BoundValueRecorder<'static, f64>,
     csrf_error_counter: BoundCounter<'static, u64>,
     validate_error_counter: BoundCounter<'static, u64>,
+    rate_limit_counter: BoundCounter<'static, u64>,
+    limits_inflight: BoundValueRecorder<'static, u64>,
+    limits_queued: BoundValueRecorder<'static, u64>,
+    limits_shed_counter: BoundCounter<'static, u64>,
+    compression_uncompressed_counter: BoundCounter<'static, u64>,
+    compression_compressed_counter: BoundCounter<'static, u64>,
     internal_counter: BoundCounter<'static, u64>,
     internal_error_counter: BoundCounter<'static, u64>,
     postgres_ready: BoundValueRecorder<'static, u64>,
+    clients_attempt_counter: Counter<u64>,
+    clients_failure_counter: Counter<u64>,
+    clients_breaker_state: ValueRecorder<u64>,
+    clients_cache_hit_counter: BoundCounter<'static, u64>,
+    clients_cache_miss_counter: BoundCounter<'static, u64>,
+    clients_cache_revalidate_counter: BoundCounter<'static, u64>,
+    webhooks_attempt_counter: BoundCounter<'static, u64>,
+    webhooks_failure_counter: BoundCounter<'static, u64>,
+    pet_events_counter: BoundCounter<'static, u64>,
+    pet_events_watchers: BoundValueRecorder<'static, u64>,
+    outbox_published_counter: BoundCounter<'static, u64>,
+    outbox_failure_counter: BoundCounter<'static, u64>,
+    outbox_dead_counter: BoundCounter<'static, u64>,
+    shutdown_phase: BoundValueRecorder<'static, u64>,
+    shutdown_phase_duration: ValueRecorder<f64>,
 }
 
 impl Metrics {
@@ -64,6 +87,36 @@ impl Metrics {
             .with_description("Total number of API server validation check errors.")
             .init()
             .bind(&[]);
+        let rate_limit_counter = meter
+            .u64_counter(format!("{}.api_rate_limit_counter_total", name))
+            .with_description("Total number of API server requests rejected by rate limit.")
+            .init()
+            .bind(&[]);
+        let limits_inflight = meter
+            .u64_value_recorder(format!("{}.api_limits_inflight", name))
+            .with_description("Number of API server requests in flight.")
+            .init()
+            .bind(&[]);
+        let limits_queued = meter
+            .u64_value_recorder(format!("{}.api_limits_queued", name))
+            .with_description("Number of API server requests queued for concurrency limit.")
+            .init()
+            .bind(&[]);
+        let limits_shed_counter = meter
+            .u64_counter(format!("{}.api_limits_shed_counter_total", name))
+            .with_description("Total number of API server requests shed because queue is full.")
+            .init()
+            .bind(&[]);
+        let compression_uncompressed_counter = meter
+            .u64_counter(format!("{}.api_compression_uncompressed_bytes_total", name))
+            .with_description("Total bytes of API server response messages before compression.")
+            .init()
+            .bind(&[]);
+        let compression_compressed_counter = meter
+            .u64_counter(format!("{}.api_compression_compressed_bytes_total", name))
+            .with_description("Total bytes of API server response messages after compression.")
+            .init()
+            .bind(&[]);
 
         let internal_counter = meter
             .u64_counter(format!("{}.internal_counter_total", name))
@@ -82,6 +135,85 @@ impl Metrics {
             .init()
             .bind(&[]);
 
+        let clients_attempt_counter = meter
+            .u64_counter(format!("{}.clients_attempt_counter_total", name))
+            .with_description("Total number of outbound client request attempts by host label.")
+            .init();
+        let clients_failure_counter = meter
+            .u64_counter(format!("{}.clients_failure_counter_total", name))
+            .with_description("Total number of outbound client request failures by host label.")
+            .init();
+        let clients_breaker_state = meter
+            .u64_value_recorder(format!("{}.clients_breaker_state", name))
+            .with_description(
+                "Outbound circuit breaker state by host label (0 closed, 1 half open, 2 open).",
+            )
+            .init();
+        let clients_cache_hit_counter = meter
+            .u64_counter(format!("{}.clients_cache_hit_counter_total", name))
+            .with_description("Total number of outbound client requests served from cache.")
+            .init()
+            .bind(&[]);
+        let clients_cache_miss_counter = meter
+            .u64_counter(format!("{}.clients_cache_miss_counter_total", name))
+            .with_description("Total number of outbound client requests not found in cache.")
+            .init()
+            .bind(&[]);
+        let clients_cache_revalidate_counter = meter
+            .u64_counter(format!("{}.clients_cache_revalidate_counter_total", name))
+            .with_description(
+                "Total number of outbound client requests revalidating stale cache entries.",
+            )
+            .init()
+            .bind(&[]);
+
+        let webhooks_attempt_counter = meter
+            .u64_counter(format!("{}.webhooks_attempt_counter_total", name))
+            .with_description("Total number of outbound webhook delivery attempts.")
+            .init()
+            .bind(&[]);
+        let webhooks_failure_counter = meter
+            .u64_counter(format!("{}.webhooks_failure_counter_total", name))
+            .with_description(
+                "Total number of outbound webhook deliveries failed after all attempts.",
+            )
+            .init()
+            .bind(&[]);
+        let pet_events_counter = meter
+            .u64_counter(format!("{}.pet_events_counter_total", name))
+            .with_description("Total number of pet events sent to watchers.")
+            .init()
+            .bind(&[]);
+        let pet_events_watchers = meter
+            .u64_value_recorder(format!("{}.pet_events_watchers", name))
+            .with_description("Number of pet event watchers connected.")
+            .init()
+            .bind(&[]);
+        let outbox_published_counter = meter
+            .u64_counter(format!("{}.outbox_published_counter_total", name))
+            .with_description("Total number of outbox events published to all sinks.")
+            .init()
+            .bind(&[]);
+        let outbox_failure_counter = meter
+            .u64_counter(format!("{}.outbox_failure_counter_total", name))
+            .with_description("Total number of failed attempts to publish outbox events.")
+            .init()
+            .bind(&[]);
+        let outbox_dead_counter = meter
+            .u64_counter(format!("{}.outbox_dead_counter_total", name))
+            .with_description("Total number of outbox events dead lettered after max attempts.")
+            .init()
+            .bind(&[]);
+        let shutdown_phase = meter
+            .u64_value_recorder(format!("{}.shutdown_phase", name))
+            .with_description("Shutdown phase (0 running, 1 draining, 2 closing, 3 terminated).")
+            .init()
+            .bind(&[]);
+        let shutdown_phase_duration = meter
+            .f64_value_recorder(format!("{}.shutdown_phase_seconds", name))
+            .with_description("Duration of shutdown phases in seconds by phase.")
+            .init();
+
         Self {
             exporter,
             ready,
@@ -90,9 +222,30 @@ impl Metrics {
             latency,
             csrf_error_counter,
             validate_error_counter,
+            rate_limit_counter,
+            limits_inflight,
+            limits_queued,
+            limits_shed_counter,
+            compression_uncompressed_counter,
+            compression_compressed_counter,
             internal_counter,
             internal_error_counter,
             postgres_ready,
+            clients_attempt_counter,
+            clients_failure_counter,
+            clients_breaker_state,
+            clients_cache_hit_counter,
+            clients_cache_miss_counter,
+            clients_cache_revalidate_counter,
+            webhooks_attempt_counter,
+            webhooks_failure_counter,
+            pet_events_counter,
+            pet_events_watchers,
+            outbox_published_counter,
+            outbox_failure_counter,
+            outbox_dead_counter,
+            shutdown_phase,
+            shutdown_phase_duration,
         }
     }
 
@@ -112,6 +265,29 @@ impl Metrics {
         self.validate_error_counter.add(1);
     }
 
+    #[inline]
+    pub fn rate_limit_counter_inc(&self) {
+        self.rate_limit_counter.add(1);
+    }
+
+    #[inline]
+    pub fn limits_state(&self, inflight: usize, queued: usize) {
+        self.limits_inflight.record(inflight as u64);
+        self.limits_queued.record(queued as u64);
+    }
+
+    #[inline]
+    pub fn limits_shed_counter_inc(&self) {
+        self.limits_shed_counter.add(1);
+    }
+
+    #[inline]
+    pub fn compression_bytes(&self, uncompressed: usize, compressed: usize) {
+        self.compression_uncompressed_counter
+            .add(uncompressed as u64);
+        self.compression_compressed_counter.add(compressed as u64);
+    }
+
     #[inline]
     pub fn internal_counter_inc(&self) {
         self.internal_counter.add(1);
@@ -128,6 +304,89 @@ impl Metrics {
         self.postgres_ready.record(value);
     }
 
+    #[inline]
+    pub fn clients_attempt_counter_inc(&self, host: &str) {
+        self.clients_attempt_counter
+            .add(1, &[KeyValue::new("host", host.to_string())]);
+    }
+
+    #[inline]
+    pub fn clients_failure_counter_inc(&self, host: &str) {
+        self.clients_failure_counter
+            .add(1, &[KeyValue::new("host", host.to_string())]);
+    }
+
+    #[inline]
+    pub fn clients_breaker_state(&self, host: &str, state: CircuitState) {
+        self.clients_breaker_state.record(
+            state.as_metric(),
+            &[KeyValue::new("host", host.to_string())],
+        );
+    }
+
+    #[inline]
+    pub fn clients_cache_hit_counter_inc(&self) {
+        self.clients_cache_hit_counter.add(1);
+    }
+
+    #[inline]
+    pub fn clients_cache_miss_counter_inc(&self) {
+        self.clients_cache_miss_counter.add(1);
+    }
+
+    #[inline]
+    pub fn clients_cache_revalidate_counter_inc(&self) {
+        self.clients_cache_revalidate_counter.add(1);
+    }
+
+    #[inline]
+    pub fn webhooks_attempt_counter_inc(&self) {
+        self.webhooks_attempt_counter.add(1);
+    }
+
+    #[inline]
+    pub fn webhooks_failure_counter_inc(&self) {
+        self.webhooks_failure_counter.add(1);
+    }
+
+    #[inline]
+    pub fn pet_events_counter_inc(&self) {
+        self.pet_events_counter.add(1);
+    }
+
+    #[inline]
+    pub fn pet_events_watchers(&self, watchers: usize) {
+        self.pet_events_watchers.record(watchers as u64);
+    }
+
+    #[inline]
+    pub fn outbox_published_counter_inc(&self) {
+        self.outbox_published_counter.add(1);
+    }
+
+    #[inline]
+    pub fn outbox_failure_counter_inc(&self) {
+        self.outbox_failure_counter.add(1);
+    }
+
+    #[inline]
+    pub fn outbox_dead_counter_inc(&self) {
+        self.outbox_dead_counter.add(1);
+    }
+
+    #[inline]
+    pub fn shutdown_phase(&self, phase: u64) {
+        self.shutdown_phase.record(phase);
+    }
+
+    #[inline]
+    pub fn shutdown_phase_duration(&self, phase: &str, duration: Duration) {
+        self.shutdown_phase_duration.record(
+            duration.as_secs_f64(),
+            &[KeyValue::new("phase", phase.to_string())],
+        );
+    }
+
     #[inline]
     pub fn service_request_handler(&self) -> SystemTime {
         self.counter.add(1);
@@ -1,8 +1,29 @@
 //! # Services
 //!
+mod audit;
 mod auth;
 mod clients;
+mod compression;
 mod csrf;
+mod errors;
+mod github;
+mod idempotency;
+mod limits;
 mod metrics;
+mod multipart;
+mod outbox;
+mod pet_events;
+mod rate_limit;
+mod readiness;
+mod redact;
+mod request_id;
+mod shutdown;
+mod sse;
+mod storage;
+mod webhooks;
 
-pub use crate::services::{auth::*, clients::*, csrf::*, metrics::*};
+pub use crate::services::{
+    audit::*, auth::*, clients::*, compression::*, csrf::*, errors::*, github::*, idempotency::*,
+    limits::*, metrics::*, multipart::*, outbox::*, pet_events::*, rate_limit::*, readiness::*,
+    redact::*, request_id::*, shutdown::*, sse::*, storage::*, webhooks::*,
+};
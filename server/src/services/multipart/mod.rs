@@ -0,0 +1,487 @@
+//! # Multipart
+//!
+//! Parsing `multipart/form-data` request bodies received as `HttpBody`, based on:
+//! <https://datatracker.ietf.org/doc/html/rfc7578>
+//! <https://cheatsheetseries.owasp.org/cheatsheets/File_Upload_Cheat_Sheet.html>
+//!
+//! - Body is split into parts using the boundary from the content type
+//! - Part headers are parsed, parts with a filename are returned as files
+//! - Size of body, size of each part and number of parts are limited
+//!
+//! Errors are returned as `InvalidArgument` with validation error details for the `data`
+//! field of the request body
+use crate::internal::*;
+use petshop_proto::google::api::HttpBody;
+use std::borrow::Cow;
+use std::fmt;
+use tonic::Status;
+use validator::{ValidationError, ValidationErrors};
+
+const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+
+/// Maximum length of boundary parameter
+const BOUNDARY_MAX_LEN: usize = 70;
+
+/// Maximum length of headers section of each part
+const HEADERS_MAX_BYTES: usize = 8 * 1024;
+
+/// Multipart Configuration
+#[derive(Debug, Clone)]
+pub struct MultipartConfig {
+    pub max_bytes: usize,
+    pub max_part_bytes: usize,
+    pub max_parts: usize,
+}
+
+/// Multipart
+pub struct Multipart {
+    config: MultipartConfig,
+    metrics: Arc<Metrics>,
+}
+
+/// Parsed multipart form
+#[derive(Debug, Default)]
+pub struct MultipartForm {
+    pub fields: Vec<MultipartField>,
+    pub files: Vec<MultipartFile>,
+}
+
+/// Text field part
+#[derive(Debug)]
+pub struct MultipartField {
+    pub name: String,
+    pub value: String,
+}
+
+/// File part (has a filename in content disposition header)
+#[derive(Debug)]
+pub struct MultipartFile {
+    pub name: String,
+    pub filename: String,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+/// Multipart Errors
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MultipartError {
+    #[error("content type is not multipart/form-data")]
+    ContentType,
+
+    #[error("boundary is missing or invalid")]
+    Boundary,
+
+    #[error("body exceeds {0} bytes")]
+    TooLarge(usize),
+
+    #[error("part `{0}` exceeds {1} bytes")]
+    PartTooLarge(String, usize),
+
+    #[error("body exceeds {0} parts")]
+    TooManyParts(usize),
+
+    #[error("malformed body, {0}")]
+    Malformed(&'static str),
+}
+
+impl Multipart {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config: config.multipart.clone(),
+            metrics,
+        }
+    }
+
+    /// Returns true if content type is `multipart/form-data`
+    pub fn is_multipart(content_type: &str) -> bool {
+        content_type
+            .split(';')
+            .next()
+            .map(|x| x.trim().eq_ignore_ascii_case(MULTIPART_FORM_DATA))
+            .unwrap_or(false)
+    }
+
+    /// Parse multipart body, logs errors and returns status with details
+    pub fn parse(&self, body: &HttpBody) -> Result<MultipartForm, Status> {
+        match parse_body(&self.config, &body.content_type, &body.data) {
+            Ok(form) => Ok(form),
+            Err(err) => {
+                self.metrics.validate_error_counter_inc();
+                warn!("multipart error: {}", err);
+                Err(tonic_status_bad_request(&err.validation_errors()))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Multipart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multipart").finish()
+    }
+}
+
+impl MultipartError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ContentType => "multipart_content_type",
+            Self::Boundary => "multipart_boundary",
+            Self::TooLarge(_) => "multipart_too_large",
+            Self::PartTooLarge(_, _) => "multipart_part_too_large",
+            Self::TooManyParts(_) => "multipart_too_many_parts",
+            Self::Malformed(_) => "multipart_malformed",
+        }
+    }
+
+    /// Returns errors in the same format as derived validation, so clients
+    /// can handle them in the same way
+    pub fn validation_errors(&self) -> ValidationErrors {
+        let mut err = ValidationError::new(self.code());
+        err.message = Some(Cow::Owned(self.to_string()));
+        match self {
+            Self::TooLarge(max) | Self::TooManyParts(max) => {
+                err.add_param(Cow::Borrowed("max"), max);
+            }
+            Self::PartTooLarge(name, max) => {
+                err.add_param(Cow::Borrowed("name"), name);
+                err.add_param(Cow::Borrowed("max"), max);
+            }
+            _ => {}
+        }
+        let mut errors = ValidationErrors::new();
+        errors.add("data", err);
+        errors
+    }
+}
+
+/// Split body into parts using boundary from content type
+fn parse_body(
+    config: &MultipartConfig,
+    content_type: &str,
+    data: &[u8],
+) -> Result<MultipartForm, MultipartError> {
+    if !Multipart::is_multipart(content_type) {
+        return Err(MultipartError::ContentType);
+    }
+    if data.len() > config.max_bytes {
+        return Err(MultipartError::TooLarge(config.max_bytes));
+    }
+    let delimiter = format!("--{}", boundary(content_type)?).into_bytes();
+    let mut close_delimiter = b"\r\n".to_vec();
+    close_delimiter.extend_from_slice(&delimiter);
+
+    // Preamble before first delimiter is ignored
+    let mut pos = if data.starts_with(&delimiter) {
+        delimiter.len()
+    } else {
+        find(data, &close_delimiter, 0).ok_or(MultipartError::Malformed("missing boundary"))?
+            + close_delimiter.len()
+    };
+
+    let mut form = MultipartForm::default();
+    let mut parts = 0;
+    loop {
+        let rest = &data[pos..];
+        if rest.starts_with(b"--") {
+            // Epilogue after final delimiter is ignored
+            return Ok(form);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err(MultipartError::Malformed(
+                "expected line break after boundary",
+            ));
+        }
+        pos += 2;
+
+        parts += 1;
+        if parts > config.max_parts {
+            return Err(MultipartError::TooManyParts(config.max_parts));
+        }
+
+        let (headers, content_start) = if data[pos..].starts_with(b"\r\n") {
+            (vec![], pos + 2)
+        } else {
+            let end = find(data, b"\r\n\r\n", pos)
+                .ok_or(MultipartError::Malformed("missing end of part headers"))?;
+            if end - pos > HEADERS_MAX_BYTES {
+                return Err(MultipartError::Malformed("part headers too large"));
+            }
+            (part_headers(&data[pos..end])?, end + 4)
+        };
+        let content_end = find(data, &close_delimiter, content_start)
+            .ok_or(MultipartError::Malformed("missing closing boundary"))?;
+        pos = content_end + close_delimiter.len();
+
+        let part = Part::from_headers(headers)?;
+        let content = &data[content_start..content_end];
+        if content.len() > config.max_part_bytes {
+            return Err(MultipartError::PartTooLarge(
+                part.name,
+                config.max_part_bytes,
+            ));
+        }
+
+        match part.filename {
+            Some(filename) => form.files.push(MultipartFile {
+                name: part.name,
+                filename,
+                content_type: part
+                    .content_type
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+                headers: part.headers,
+                data: content.to_vec(),
+            }),
+            None => form.fields.push(MultipartField {
+                name: part.name,
+                value: String::from_utf8(content.to_vec())
+                    .map_err(|_| MultipartError::Malformed("field is not valid utf-8"))?,
+            }),
+        }
+    }
+}
+
+/// Part details from headers
+struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl Part {
+    fn from_headers(headers: Vec<(String, String)>) -> Result<Self, MultipartError> {
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = None;
+        for (key, value) in headers.iter() {
+            if key == "content-disposition" {
+                let (disposition, params) = header_params(value);
+                if !disposition.eq_ignore_ascii_case("form-data") {
+                    return Err(MultipartError::Malformed(
+                        "content disposition is not form-data",
+                    ));
+                }
+                for (param, value) in params {
+                    match param.as_ref() {
+                        "name" => name = Some(value),
+                        "filename" => filename = Some(value),
+                        _ => {}
+                    }
+                }
+            } else if key == "content-type" {
+                content_type = Some(value.to_string());
+            }
+        }
+
+        Ok(Self {
+            name: name.ok_or(MultipartError::Malformed("part name is missing"))?,
+            filename,
+            content_type,
+            headers,
+        })
+    }
+}
+
+/// Returns boundary parameter of content type
+fn boundary(content_type: &str) -> Result<String, MultipartError> {
+    let (_, params) = header_params(content_type);
+    let boundary = params
+        .into_iter()
+        .find(|(key, _)| key == "boundary")
+        .map(|(_, value)| value)
+        .ok_or(MultipartError::Boundary)?;
+    if boundary.is_empty() || boundary.len() > BOUNDARY_MAX_LEN {
+        return Err(MultipartError::Boundary);
+    }
+    Ok(boundary)
+}
+
+/// Parse part header lines into lowercase names and values
+fn part_headers(data: &[u8]) -> Result<Vec<(String, String)>, MultipartError> {
+    let data = std::str::from_utf8(data)
+        .map_err(|_| MultipartError::Malformed("part headers are not valid utf-8"))?;
+    data.split("\r\n")
+        .map(|line| {
+            let mut split = line.splitn(2, ':');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => {
+                    Ok((name.trim().to_lowercase(), value.trim().to_string()))
+                }
+                _ => Err(MultipartError::Malformed("invalid part header")),
+            }
+        })
+        .collect()
+}
+
+/// Parse header value with parameters, e.g. `form-data; name="file"; filename="a.png"`,
+/// parameter names are lowercase and quoted values are unescaped
+fn header_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut chars = value.chars().peekable();
+    let mut segments = vec![];
+    let mut segment = String::new();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                segment.push(c);
+            }
+            '\\' if quoted => {
+                segment.push(c);
+                if let Some(c) = chars.next() {
+                    segment.push(c);
+                }
+            }
+            ';' if !quoted => segments.push(std::mem::take(&mut segment)),
+            _ => segment.push(c),
+        }
+    }
+    segments.push(segment);
+
+    let mut segments = segments.into_iter();
+    let value = segments.next().unwrap_or_default().trim().to_string();
+    let params = segments
+        .filter_map(|segment| {
+            let mut split = segment.splitn(2, '=');
+            let key = split.next()?.trim().to_lowercase();
+            let value = split.next()?.trim();
+            let value = match value.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+                Some(value) => unescape(value),
+                None => value.to_string(),
+            };
+            Some((key, value))
+        })
+        .collect();
+    (value, params)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                out.push(c);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Returns index of first occurrence of needle in haystack at or after position
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|x| x == needle)
+        .map(|x| x + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"XyZ\"";
+
+    fn config() -> MultipartConfig {
+        MultipartConfig {
+            max_bytes: 1024,
+            max_part_bytes: 64,
+            max_parts: 4,
+        }
+    }
+
+    fn body(parts: &[&str]) -> Vec<u8> {
+        let mut body = b"preamble".to_vec();
+        for part in parts {
+            body.extend_from_slice(b"\r\n--XyZ\r\n");
+            body.extend_from_slice(part.as_bytes());
+        }
+        body.extend_from_slice(b"\r\n--XyZ--\r\nepilogue");
+        body
+    }
+
+    #[test]
+    fn parse_body_test() {
+        let data = body(&[
+            "Content-Disposition: form-data; name=\"sender\"\r\n\r\nbob@example.com",
+            "content-disposition: form-data; name=\"attachment-1\"; filename=\"a \\\"b\\\"; c.txt\"\r\nContent-Type: text/plain\r\n\r\nline 1\r\nline 2",
+            "Content-Disposition: form-data; name=\"empty\"\r\n\r\n",
+        ]);
+        let form = parse_body(&config(), CONTENT_TYPE, &data).unwrap();
+        assert_eq!(form.fields.len(), 2);
+        assert_eq!(form.fields[0].name, "sender");
+        assert_eq!(form.fields[0].value, "bob@example.com");
+        assert_eq!(form.fields[1].value, "");
+
+        assert_eq!(form.files.len(), 1);
+        let file = &form.files[0];
+        assert_eq!(file.name, "attachment-1");
+        assert_eq!(file.filename, "a \"b\"; c.txt");
+        assert_eq!(file.content_type, "text/plain");
+        assert_eq!(file.data, b"line 1\r\nline 2");
+        assert_eq!(file.headers.len(), 2);
+    }
+
+    #[test]
+    fn parse_body_limits_test() {
+        let part = format!(
+            "Content-Disposition: form-data; name=\"a\"\r\n\r\n{}",
+            "x".repeat(65)
+        );
+        assert_eq!(
+            parse_body(&config(), CONTENT_TYPE, &body(&[&part])).unwrap_err(),
+            MultipartError::PartTooLarge("a".to_string(), 64)
+        );
+
+        let part = "Content-Disposition: form-data; name=\"a\"\r\n\r\nx";
+        assert_eq!(
+            parse_body(&config(), CONTENT_TYPE, &body(&[part; 5])).unwrap_err(),
+            MultipartError::TooManyParts(4)
+        );
+
+        let data = vec![0; 1025];
+        assert_eq!(
+            parse_body(&config(), CONTENT_TYPE, &data).unwrap_err(),
+            MultipartError::TooLarge(1024)
+        );
+    }
+
+    #[test]
+    fn parse_body_malformed_test() {
+        let part = "Content-Disposition: form-data; name=\"a\"\r\n\r\nx";
+        for (content_type, data) in [
+            ("application/json", body(&[part])),
+            ("multipart/form-data", body(&[part])),
+            (CONTENT_TYPE, b"--XyZ\r\nno closing boundary".to_vec()),
+            (
+                CONTENT_TYPE,
+                body(&["Content-Disposition: form-data\r\n\r\nx"]),
+            ),
+            (
+                CONTENT_TYPE,
+                body(&["Content-Disposition: attachment; name=\"a\"\r\n\r\nx"]),
+            ),
+            (CONTENT_TYPE, body(&["Invalid header\r\n\r\nx"])),
+            (
+                CONTENT_TYPE,
+                b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n\xff\r\n--XyZ--"
+                    .to_vec(),
+            ),
+        ] {
+            assert!(parse_body(&config(), content_type, &data).is_err());
+        }
+    }
+
+    #[test]
+    fn validation_errors_test() {
+        let errors = MultipartError::TooLarge(1024).validation_errors();
+        let value = serde_json::to_value(errors).unwrap();
+        assert_eq!(value["data"][0]["code"], "multipart_too_large");
+        assert_eq!(value["data"][0]["params"]["max"], 1024);
+    }
+}
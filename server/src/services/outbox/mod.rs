@@ -0,0 +1,539 @@
+//! # Outbox
+//!
+//! Transactional outbox for reliable publication of domain events
+//!
+//! - Events are inserted into the `outbox` table in the same transaction as the domain
+//!   change, so events are not lost if the server stops after the change is committed
+//! - A relay task publishes pending events in id order to the configured sinks, and
+//!   marks them published after all sinks accept them
+//! - Delivery is at-least-once, events are published again if the relay stops before
+//!   marking them published or any sink fails, so consumers should be idempotent
+//! - Events of an aggregate are published in order, an event which fails is retried
+//!   with exponential backoff and blocks the later events of its aggregate until it
+//!   is published by a later relay
+//! - Events which fail max attempts are dead lettered, they are kept in the `outbox`
+//!   table with their last error and no longer block their aggregate
+//! - One relay per database holds a session advisory lock, relays of other servers
+//!   wait to take the lock if it is released
+//! - Published events older than the retention period are deleted
+//!
+//! <https://microservices.io/patterns/data/transactional-outbox.html>
+use crate::internal::*;
+use petshop_proto::api::{Pet, Status as PetStatus};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+
+pub use sinks::{OutboxBroadcastSink, OutboxFileSink};
+
+mod sinks;
+
+/// Aggregate type of pet events
+pub const AGGREGATE_PET: &str = "pet";
+
+/// Advisory lock key held by the relay
+const OUTBOX_RELAY_LOCK: i64 = 7_346_859;
+
+/// Delay before the relay reconnects after an error
+const OUTBOX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Interval between deleting published events older than the retention period
+const OUTBOX_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Outbox Sink Configuration
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutboxSinkConfig {
+    /// In-process broadcast channel
+    Broadcast { capacity: usize },
+    /// Outbound webhooks to subscribers
+    Webhooks,
+    /// JSON lines appended to file, or written to stdout if path is not defined
+    File { path: Option<String> },
+}
+
+/// Outbox Configuration
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    pub sinks: Vec<OutboxSinkConfig>,
+    pub poll_interval_millis: u64,
+    pub batch_size: i64,
+    pub retention_hours: u64,
+    pub max_attempts: i32,
+    pub backoff_base_seconds: u64,
+    pub backoff_max_seconds: u64,
+}
+
+/// Outbox message inserted with a domain change
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub aggregate_type: &'static str,
+    pub aggregate_id: String,
+    pub event_type: &'static str,
+    pub payload: serde_json::Value,
+}
+
+/// Outbox event stored in postgres
+#[derive(Debug, Clone)]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub retry_at: chrono::DateTime<Utc>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+/// Aggregates blocked in a relay by an event which failed or waits for a retry,
+/// so later events of the aggregate are skipped and published in order
+#[derive(Debug, Default)]
+struct OutboxBlocked(HashSet<(String, String)>);
+
+/// Outbox Sink
+///
+/// Implement this trait to publish events to other destinations (e.g. message brokers),
+/// sinks may receive an event more than once
+#[tonic::async_trait]
+pub trait OutboxSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns an error if the event was not accepted, it is published again later
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr>;
+}
+
+/// Outbox
+pub struct Outbox {
+    config: OutboxConfig,
+    metrics: Arc<Metrics>,
+    postgres: Arc<PostgresPool>,
+    shutdown: Arc<Shutdown>,
+    sinks: Vec<Arc<dyn OutboxSink>>,
+    broadcast: Option<broadcast::Sender<Arc<OutboxEvent>>>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn from_config(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        postgres: Arc<PostgresPool>,
+        shutdown: Arc<Shutdown>,
+        webhooks: Arc<Webhooks>,
+    ) -> Self {
+        let mut sinks: Vec<Arc<dyn OutboxSink>> = Vec::new();
+        let mut broadcast = None;
+        for sink in config.outbox.sinks.iter() {
+            match sink {
+                OutboxSinkConfig::Broadcast { capacity } => {
+                    let sink = OutboxBroadcastSink::new(*capacity);
+                    broadcast = Some(sink.sender());
+                    sinks.push(Arc::new(sink));
+                }
+                OutboxSinkConfig::Webhooks => sinks.push(webhooks.clone()),
+                OutboxSinkConfig::File { path } => {
+                    sinks.push(Arc::new(OutboxFileSink::new(path.as_deref())))
+                }
+            }
+        }
+        Self {
+            config: config.outbox.clone(),
+            metrics,
+            postgres,
+            shutdown,
+            sinks,
+            broadcast,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Wake the relay after a transaction with outbox messages is committed,
+    /// messages committed by other servers are published after the poll interval
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Returns receiver of events published to the broadcast sink, or none if it
+    /// is not configured, used by in-process consumers
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Arc<OutboxEvent>>> {
+        self.broadcast.as_ref().map(|x| x.subscribe())
+    }
+
+    /// Example in-process consumer which logs events published to the broadcast
+    /// sink until shutdown is closing, returns if the sink is not configured
+    pub async fn consume(self: Arc<Self>) {
+        let mut events = match self.subscribe() {
+            Some(events) => events,
+            None => return,
+        };
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => info!(
+                        "outbox consumer event {} {} {}",
+                        event.id, event.event_type, event.aggregate_id
+                    ),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("outbox consumer skipped {} events", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+        }
+        info!("outbox consumer stopped");
+    }
+
+    /// Relay events while this server holds the relay lock until shutdown is
+    /// closing, then relays pending events once more, reconnects after errors
+    pub async fn run(self: Arc<Self>, config: Config) {
+        loop {
+            match self.relay_leader(&config).await {
+                Ok(()) => break,
+                Err(err) => {
+                    let err: Error = err.into();
+                    warn!("outbox relay error: {:#}", err);
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(OUTBOX_RECONNECT_DELAY) => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+        }
+        info!("outbox relay stopped");
+    }
+
+    /// Wait to take the relay lock, then relay events when notified or polled,
+    /// the lock is released when the connection is closed
+    async fn relay_leader(&self, config: &Config) -> Result<(), XErr> {
+        let client = PostgresClient::from_config(config).await?;
+        let mut poll =
+            tokio::time::interval(Duration::from_millis(self.config.poll_interval_millis));
+        while !client.advisory_lock_try(OUTBOX_RELAY_LOCK).await? {
+            tokio::select! {
+                _ = poll.tick() => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => return Ok(()),
+            }
+        }
+        info!("outbox relay started");
+
+        let mut prune = tokio::time::interval(OUTBOX_PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {}
+                _ = self.notify.notified() => {}
+                _ = prune.tick() => self.prune().await,
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+            // Relay stops if lock connection was closed, so another server can take it
+            client.check().await?;
+            self.relay().await?;
+        }
+
+        // Events committed by requests which finished before closing are published
+        self.relay().await
+    }
+
+    /// Publish pending events in order, events of aggregates with a failed event
+    /// are skipped so they are published in order by a later relay
+    async fn relay(&self) -> Result<(), XErr> {
+        let mut after = 0;
+        let mut blocked = OutboxBlocked::default();
+        loop {
+            let events = self
+                .postgres
+                .outbox_select_pending(after, self.config.batch_size)
+                .await?;
+            let done = (events.len() as i64) < self.config.batch_size;
+            for event in events {
+                after = event.id;
+                if !blocked.ready(&event, Utc::now()) {
+                    continue;
+                }
+                match self.publish(&event).await {
+                    Ok(()) => {
+                        self.metrics.outbox_published_counter_inc();
+                        self.postgres.outbox_published(event.id).await?;
+                    }
+                    Err(err) => {
+                        self.metrics.outbox_failure_counter_inc();
+                        let err: Error = err.into();
+                        let attempts = event.attempts + 1;
+                        warn!(
+                            "outbox event {} attempt {} error: {:#}",
+                            event.id, attempts, err
+                        );
+                        let error = format!("{:#}", err);
+                        match self.config.backoff(attempts) {
+                            Some(backoff) => {
+                                let retry_at = Utc::now()
+                                    + chrono::Duration::from_std(backoff)
+                                        .expect("outbox backoff is in range");
+                                self.postgres
+                                    .outbox_failed(event.id, &error, retry_at)
+                                    .await?;
+                                blocked.block(&event);
+                            }
+                            None => {
+                                self.metrics.outbox_dead_counter_inc();
+                                warn!(
+                                    "outbox event {} dead lettered after {} attempts",
+                                    event.id, attempts
+                                );
+                                self.postgres.outbox_dead(event.id, &error).await?;
+                            }
+                        }
+                    }
+                }
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Publish event to all sinks, sinks before a failed sink receive it again
+    #[tracing::instrument(skip(self, event), fields(id = event.id, event_type = %event.event_type))]
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr> {
+        for sink in self.sinks.iter() {
+            sink.publish(event)
+                .await
+                .map_err(|err| XErr::outbox_sink(sink.name(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Delete published events older than the retention period
+    async fn prune(&self) {
+        let retention = chrono::Duration::hours(self.config.retention_hours as i64);
+        match self
+            .postgres
+            .outbox_delete_published_before(Utc::now() - retention)
+            .await
+        {
+            Ok(0) => {}
+            Ok(deleted) => info!("outbox deleted {} published events", deleted),
+            Err(err) => {
+                let err: Error = err.into();
+                warn!("outbox prune error: {:#}", err);
+            }
+        }
+    }
+}
+
+impl OutboxConfig {
+    /// Returns exponential backoff before retry of event after failed attempts, or
+    /// none if it reached max attempts and is dead lettered
+    fn backoff(&self, attempts: i32) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = (attempts.max(1) - 1).min(32) as u32;
+        let seconds = self
+            .backoff_base_seconds
+            .saturating_mul(1 << exponent)
+            .min(self.backoff_max_seconds);
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+impl OutboxBlocked {
+    /// Returns true if event can be published, an event which waits for a retry
+    /// blocks its aggregate
+    fn ready(&mut self, event: &OutboxEvent, now: chrono::DateTime<Utc>) -> bool {
+        if self.0.contains(&outbox_aggregate(event)) {
+            return false;
+        }
+        if event.retry_at > now {
+            self.block(event);
+            return false;
+        }
+        true
+    }
+
+    fn block(&mut self, event: &OutboxEvent) {
+        self.0.insert(outbox_aggregate(event));
+    }
+}
+
+fn outbox_aggregate(event: &OutboxEvent) -> (String, String) {
+    (event.aggregate_type.clone(), event.aggregate_id.clone())
+}
+
+impl OutboxMessage {
+    /// Returns pet event message with pet as JSON payload
+    pub fn pet(event_type: &'static str, pet: &Pet) -> Self {
+        Self {
+            aggregate_type: AGGREGATE_PET,
+            aggregate_id: pet.id.to_string(),
+            event_type,
+            payload: pet_json(pet),
+        }
+    }
+}
+
+/// Returns JSON representation of pet used in event payloads
+pub fn pet_json(pet: &Pet) -> serde_json::Value {
+    let status = PetStatus::from_i32(pet.status).unwrap_or(PetStatus::Available);
+    json!({
+        "id": pet.id,
+        "category": pet.category.as_ref().map(|x| json!({ "id": x.id, "name": x.name })),
+        "name": pet.name,
+        "photo_urls": pet.photo_urls,
+        "tags": pet.tags.iter().map(|x| json!({ "id": x.id, "name": x.name })).collect::<Vec<_>>(),
+        "status": format!("{:?}", status).to_uppercase(),
+        "version": pet.version,
+    })
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{EVENT_PET_CREATED, EVENT_PET_UPDATED};
+    use std::sync::Mutex;
+
+    fn config() -> OutboxConfig {
+        OutboxConfig {
+            sinks: vec![],
+            poll_interval_millis: 1000,
+            batch_size: 100,
+            retention_hours: 168,
+            max_attempts: 2,
+            backoff_base_seconds: 1,
+            backoff_max_seconds: 4,
+        }
+    }
+
+    fn event(id: i64, aggregate_id: &str, retry_at: chrono::DateTime<Utc>) -> OutboxEvent {
+        OutboxEvent {
+            id,
+            aggregate_type: AGGREGATE_PET.to_string(),
+            aggregate_id: aggregate_id.to_string(),
+            event_type: EVENT_PET_UPDATED.to_string(),
+            payload: json!({}),
+            attempts: 0,
+            retry_at,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Sink which records events of aggregates, and fails created events of aggregate
+    #[derive(Default)]
+    struct TestSink {
+        aggregates: Vec<String>,
+        fail: String,
+        published: Mutex<Vec<(String, String)>>,
+    }
+
+    #[tonic::async_trait]
+    impl OutboxSink for TestSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr> {
+            if !self.aggregates.contains(&event.aggregate_id) {
+                return Ok(());
+            }
+            let mut published = self.published.lock().unwrap();
+            published.push((event.aggregate_id.clone(), event.event_type.clone()));
+            if event.aggregate_id == self.fail && event.event_type == EVENT_PET_CREATED {
+                return Err(XErr::config("test sink failed"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn outbox_backoff_test() {
+        let mut config = config();
+        assert_eq!(config.backoff(1), Some(Duration::from_secs(1)));
+        assert_eq!(config.backoff(2), None);
+        config.max_attempts = 10;
+        assert_eq!(config.backoff(3), Some(Duration::from_secs(4)));
+        assert_eq!(config.backoff(9), Some(Duration::from_secs(4)));
+        assert_eq!(config.backoff(10), None);
+    }
+
+    #[test]
+    fn outbox_blocked_test() {
+        let now = Utc::now();
+        let mut blocked = OutboxBlocked::default();
+        assert!(blocked.ready(&event(1, "1", now), now));
+
+        // Event which waits for a retry blocks later events of its aggregate
+        let retry_at = now + chrono::Duration::seconds(1);
+        assert!(!blocked.ready(&event(2, "2", retry_at), now));
+        assert!(!blocked.ready(&event(3, "2", now), now));
+        assert!(blocked.ready(&event(4, "1", now), now));
+
+        // Failed event blocks later events of its aggregate
+        let failed = event(5, "3", now);
+        assert!(blocked.ready(&failed, now));
+        blocked.block(&failed);
+        assert!(!blocked.ready(&event(6, "3", now), now));
+        assert!(blocked.ready(&event(7, "1", now), now));
+    }
+
+    /// Requires postgres with migrations, configured in `CONFIG_POSTGRES__*` environment
+    /// variables, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn outbox_relay_test() {
+        let mut config = Config::load(None).unwrap();
+        config.outbox = self::config();
+        let metrics = Arc::new(Metrics::from_config(&config));
+        let postgres = Arc::new(PostgresPool::from_config(&config, metrics.clone()).unwrap());
+        let shutdown = Arc::new(Shutdown::from_config(&config, metrics.clone()));
+
+        let pet = |name: &str| Pet {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let a = postgres.pet_insert(&pet("a")).await.unwrap();
+        let b = postgres.pet_insert(&pet("b")).await.unwrap();
+        postgres.pet_update(&a).await.unwrap();
+        postgres.pet_update(&b).await.unwrap();
+        let (a, b) = (a.id.to_string(), b.id.to_string());
+
+        let sink = Arc::new(TestSink {
+            aggregates: vec![a.clone(), b.clone()],
+            fail: b.clone(),
+            ..Default::default()
+        });
+        let outbox = Outbox {
+            config: config.outbox.clone(),
+            metrics,
+            postgres,
+            shutdown,
+            sinks: vec![sink.clone()],
+            broadcast: None,
+            notify: Notify::new(),
+        };
+        let published = || std::mem::take(&mut *sink.published.lock().unwrap());
+        let created = |x: &str| (x.to_string(), EVENT_PET_CREATED.to_string());
+        let updated = |x: &str| (x.to_string(), EVENT_PET_UPDATED.to_string());
+
+        // Failed event blocks the later event of its aggregate only
+        outbox.relay().await.unwrap();
+        assert_eq!(published(), vec![created(&a), created(&b), updated(&a)]);
+
+        // Failed event is not retried before its backoff
+        outbox.relay().await.unwrap();
+        assert!(published().is_empty());
+
+        // Event is dead lettered after max attempts and no longer blocks its aggregate
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        outbox.relay().await.unwrap();
+        assert_eq!(published(), vec![created(&b), updated(&b)]);
+        outbox.relay().await.unwrap();
+        assert!(published().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+//! # Outbox Sinks
+//!
+use super::OutboxSink;
+use crate::internal::*;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+/// In-process broadcast sink, events are dropped if there are no receivers
+#[derive(Debug)]
+pub struct OutboxBroadcastSink {
+    sender: broadcast::Sender<Arc<OutboxEvent>>,
+}
+
+/// File sink which appends events as JSON lines, or writes them to stdout
+#[derive(Debug)]
+pub struct OutboxFileSink {
+    path: Option<PathBuf>,
+}
+
+impl OutboxBroadcastSink {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn sender(&self) -> broadcast::Sender<Arc<OutboxEvent>> {
+        self.sender.clone()
+    }
+}
+
+#[tonic::async_trait]
+impl OutboxSink for OutboxBroadcastSink {
+    fn name(&self) -> &'static str {
+        "broadcast"
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr> {
+        // Error is returned if there are no receivers
+        self.sender.send(Arc::new(event.clone())).ok();
+        Ok(())
+    }
+}
+
+impl OutboxFileSink {
+    pub fn new(path: Option<&str>) -> Self {
+        Self {
+            path: path.map(PathBuf::from),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl OutboxSink for OutboxFileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr> {
+        let mut line = serde_json::to_vec(&outbox_event_json(event))?;
+        line.push(b'\n');
+        match &self.path {
+            Some(path) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(&line).await?;
+                file.flush().await?;
+            }
+            None => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(&line).await?;
+                stdout.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Webhooks sink creates deliveries of event for subscribers, deliveries are
+/// retried by webhooks so the event is accepted once they are created
+#[tonic::async_trait]
+impl OutboxSink for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), XErr> {
+        Webhooks::publish(self, &event.event_type, event.payload.clone()).await
+    }
+}
+
+fn outbox_event_json(event: &OutboxEvent) -> serde_json::Value {
+    json!({
+        "id": event.id,
+        "aggregate_type": event.aggregate_type,
+        "aggregate_id": event.aggregate_id,
+        "event": event.event_type,
+        "created_at": event.created_at.to_rfc3339(),
+        "data": event.payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outbox_file_sink_test() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.jsonl", std::process::id()));
+        let sink = OutboxFileSink::new(path.to_str());
+        let mut event = OutboxEvent {
+            id: 1,
+            aggregate_type: "pet".to_string(),
+            aggregate_id: "42".to_string(),
+            event_type: "pet.created".to_string(),
+            payload: json!({ "id": 42 }),
+            attempts: 0,
+            retry_at: Utc::now(),
+            created_at: Utc::now(),
+        };
+        sink.publish(&event).await.unwrap();
+        event.id = 2;
+        event.event_type = "pet.updated".to_string();
+        sink.publish(&event).await.unwrap();
+
+        let lines = tokio::fs::read_to_string(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        let events: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "pet.created");
+        assert_eq!(events[1]["id"], 2);
+        assert_eq!(events[1]["aggregate_id"], "42");
+        assert_eq!(events[1]["data"]["id"], 42);
+    }
+}
@@ -0,0 +1,391 @@
+//! # Pet Events
+//!
+//! Real-time feed of pet changes for the `WatchPets` RPC
+//!
+//! - A postgres trigger records pet changes in the `pet_event` table, and notifies
+//!   the `pet_event` channel with the event id
+//! - One listener per server reads events after the last event it has seen when
+//!   notified or polled, and fans them out in-process to watchers over a broadcast channel
+//! - Events are read in order of the transaction which recorded them, and only once
+//!   older transactions have finished, so concurrent pet writes are not serialized
+//!   and events committed out of order are not skipped, a long running transaction
+//!   delays events until it finishes
+//! - Watchers with a resume token are sent events after it from postgres first, and
+//!   watchers which lag behind the broadcast channel catch up from postgres
+//! - Events older than the retention period are deleted, and resume tokens of deleted
+//!   events are rejected so clients know they missed events
+use crate::internal::*;
+use petshop_proto::api::{Pet, PetEvent, PetEventType, PetWatch};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Interval;
+use tonic::Status;
+
+/// Postgres notification channel of pet events
+pub const PET_EVENT_CHANNEL: &str = "pet_event";
+
+/// Delay before the listener reconnects after an error
+const PET_EVENTS_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Interval between reading events without a notification, events are read after
+/// the transactions which delayed them finish
+const PET_EVENTS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval between deleting events older than the retention period
+const PET_EVENTS_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Pet Events Configuration
+#[derive(Debug, Clone)]
+pub struct PetEventsConfig {
+    pub channel_capacity: usize,
+    pub replay_batch_size: i64,
+    pub retention_hours: u64,
+}
+
+/// Pet event stored in postgres, previous pet is defined for updates
+#[derive(Debug, Clone)]
+pub struct PetEventRecord {
+    pub id: i64,
+    pub event_type: PetEventType,
+    pub pet: Pet,
+    pub previous: Option<Pet>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub xid: i64,
+}
+
+/// Position of event in order of the transaction which recorded it, then its id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PetEventPosition {
+    pub xid: i64,
+    pub id: i64,
+}
+
+/// Pet Events
+pub struct PetEvents {
+    config: PetEventsConfig,
+    metrics: Arc<Metrics>,
+    postgres: Arc<PostgresPool>,
+    shutdown: Arc<Shutdown>,
+    sender: broadcast::Sender<Arc<PetEventRecord>>,
+    watchers: AtomicUsize,
+}
+
+impl PetEvents {
+    pub fn from_config(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        postgres: Arc<PostgresPool>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        let (sender, _) = broadcast::channel(config.pet_events.channel_capacity);
+        Self {
+            config: config.pet_events.clone(),
+            metrics,
+            postgres,
+            shutdown,
+            sender,
+            watchers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Listen for events and send them to watchers until shutdown is closing,
+    /// reconnects after errors
+    pub async fn run(self: Arc<Self>, config: Config) {
+        let mut last = None;
+        let mut prune = tokio::time::interval(PET_EVENTS_PRUNE_INTERVAL);
+        loop {
+            if let Err(err) = self.listen(&config, &mut last, &mut prune).await {
+                let err: Error = err.into();
+                warn!("pet events listener error: {:#}", err);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(PET_EVENTS_RECONNECT_DELAY) => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+        }
+        info!("pet events listener stopped");
+    }
+
+    /// Returns position of event after which watcher is resumed, errors if resume
+    /// token is invalid or its event has been deleted
+    pub async fn resume(&self, resume_token: &str) -> Result<Option<PetEventPosition>, Status> {
+        if resume_token.is_empty() {
+            return Ok(None);
+        }
+        let position = match resume_token_parse(resume_token) {
+            Some(id) => self.postgres.pet_event_position(id).await?,
+            None => None,
+        };
+        match position {
+            Some(position) => Ok(Some(position)),
+            None => Err(tonic_status_error_info(&ERROR_RESUME_TOKEN_INVALID, &[])),
+        }
+    }
+
+    /// Send events matching watch to watcher, after resumed event or from now, until
+    /// the watcher disconnects or shutdown is closing
+    pub async fn watch(
+        self: Arc<Self>,
+        watch: PetWatch,
+        after: Option<PetEventPosition>,
+        tx: mpsc::Sender<Result<PetEvent, Status>>,
+    ) {
+        let watchers = self.watchers.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.pet_events_watchers(watchers);
+
+        tokio::select! {
+            result = self.watch_inner(&watch, after, &tx) => {
+                if let Err(err) = result {
+                    tx.send(Err(err.into())).await.ok();
+                }
+            }
+            _ = self.shutdown.wait(ShutdownPhase::Closing) => {}
+            _ = tx.closed() => {}
+        }
+
+        let watchers = self.watchers.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.metrics.pet_events_watchers(watchers);
+    }
+
+    /// Subscribe before reading the last event so no events are missed between them,
+    /// events which were already sent are skipped by position
+    async fn watch_inner(
+        &self,
+        watch: &PetWatch,
+        after: Option<PetEventPosition>,
+        tx: &mpsc::Sender<Result<PetEvent, Status>>,
+    ) -> Result<(), XErr> {
+        let mut rx = self.sender.subscribe();
+        let mut last = match after {
+            Some(after) => after,
+            None => self.postgres.pet_event_last_position().await?,
+        };
+        loop {
+            last = match self.replay(watch, last, tx).await? {
+                Some(last) => last,
+                None => return Ok(()),
+            };
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        if event.position() <= last {
+                            continue;
+                        }
+                        last = event.position();
+                        if event.matches(watch) && tx.send(Ok(event.event())).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        info!("pet events watcher lagged by {} events", skipped);
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Send events after position from postgres, returns position of last event or
+    /// none if the watcher disconnected
+    async fn replay(
+        &self,
+        watch: &PetWatch,
+        mut last: PetEventPosition,
+        tx: &mpsc::Sender<Result<PetEvent, Status>>,
+    ) -> Result<Option<PetEventPosition>, XErr> {
+        loop {
+            let events = self
+                .postgres
+                .pet_event_select_after(last, self.config.replay_batch_size)
+                .await?;
+            let done = (events.len() as i64) < self.config.replay_batch_size;
+            for event in events {
+                last = event.position();
+                if event.matches(watch) && tx.send(Ok(event.event())).await.is_err() {
+                    return Ok(None);
+                }
+            }
+            if done {
+                return Ok(Some(last));
+            }
+        }
+    }
+
+    /// Listen to notifications until shutdown is closing, events missed while not
+    /// listening are read after connecting
+    async fn listen(
+        &self,
+        config: &Config,
+        last: &mut Option<PetEventPosition>,
+        prune: &mut Interval,
+    ) -> Result<(), XErr> {
+        let mut listener = PostgresListener::from_config(config, &[PET_EVENT_CHANNEL]).await?;
+        let mut after = match *last {
+            Some(last) => last,
+            None => self.postgres.pet_event_last_position().await?,
+        };
+        info!("pet events listening after event {}", after.id);
+        let mut poll = tokio::time::interval(PET_EVENTS_POLL_INTERVAL);
+
+        loop {
+            after = self.poll(after).await?;
+            *last = Some(after);
+
+            tokio::select! {
+                notification = listener.recv() => {
+                    if notification.is_none() {
+                        return Err(XErr::postgres_listener("connection closed"));
+                    }
+                }
+                _ = poll.tick() => {}
+                _ = prune.tick() => self.prune().await,
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => return Ok(()),
+            }
+        }
+    }
+
+    /// Send events after position to watchers, returns position of last event
+    async fn poll(&self, mut after: PetEventPosition) -> Result<PetEventPosition, XErr> {
+        loop {
+            let events = self
+                .postgres
+                .pet_event_select_after(after, self.config.replay_batch_size)
+                .await?;
+            let done = (events.len() as i64) < self.config.replay_batch_size;
+            for event in events {
+                after = event.position();
+                self.metrics.pet_events_counter_inc();
+                // Error is returned if there are no watchers
+                self.sender.send(Arc::new(event)).ok();
+            }
+            if done {
+                return Ok(after);
+            }
+        }
+    }
+
+    /// Delete events older than the retention period
+    async fn prune(&self) {
+        let retention = chrono::Duration::hours(self.config.retention_hours as i64);
+        match self
+            .postgres
+            .pet_event_delete_before(Utc::now() - retention)
+            .await
+        {
+            Ok(0) => {}
+            Ok(deleted) => info!("pet events deleted {} expired events", deleted),
+            Err(err) => {
+                let err: Error = err.into();
+                warn!("pet events prune error: {:#}", err);
+            }
+        }
+    }
+}
+
+impl PetEventRecord {
+    pub fn position(&self) -> PetEventPosition {
+        PetEventPosition {
+            xid: self.xid,
+            id: self.id,
+        }
+    }
+
+    /// Returns true if pet matches watch filters, or matched them before an update
+    pub fn matches(&self, watch: &PetWatch) -> bool {
+        pet_watch_matches(watch, &self.pet)
+            || self
+                .previous
+                .as_ref()
+                .map(|x| pet_watch_matches(watch, x))
+                .unwrap_or(false)
+    }
+
+    /// Returns event message for watchers
+    pub fn event(&self) -> PetEvent {
+        PetEvent {
+            r#type: self.event_type as i32,
+            pet: Some(self.pet.clone()),
+            resume_token: resume_token_from_id(self.id),
+            created_at: Some(chrono_into_prost_timestamp(self.created_at)),
+        }
+    }
+}
+
+/// Returns true if pet has any of watch status and any of watch tags, empty
+/// filters match all pets
+fn pet_watch_matches(watch: &PetWatch, pet: &Pet) -> bool {
+    let status = watch.status.is_empty() || watch.status.contains(&pet.status);
+    let tags = watch.tags.is_empty() || pet.tags.iter().any(|x| watch.tags.contains(&x.name));
+    status && tags
+}
+
+/// Resume tokens are opaque to clients, currently the event id
+fn resume_token_from_id(id: i64) -> String {
+    id.to_string()
+}
+
+fn resume_token_parse(resume_token: &str) -> Option<i64> {
+    resume_token.parse().ok().filter(|x| *x > 0)
+}
+
+impl fmt::Debug for PetEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PetEvents").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petshop_proto::api::{Status as PetStatus, Tag};
+
+    fn pet(status: PetStatus, tag: &str) -> Pet {
+        Pet {
+            status: status as i32,
+            tags: vec![Tag {
+                id: 1,
+                name: tag.to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pet_event_matches_test() {
+        let mut event = PetEventRecord {
+            id: 1,
+            event_type: PetEventType::Created,
+            pet: pet(PetStatus::Available, "dog"),
+            previous: None,
+            created_at: Utc::now(),
+            xid: 1,
+        };
+        let mut watch = PetWatch::default();
+        assert!(event.matches(&watch));
+
+        watch.status = vec![PetStatus::Available as i32];
+        watch.tags = vec!["cat".to_string(), "dog".to_string()];
+        assert!(event.matches(&watch));
+        watch.tags = vec!["cat".to_string()];
+        assert!(!event.matches(&watch));
+
+        // Pet which no longer matches after update is sent so it can be removed
+        watch.tags = vec![];
+        event.event_type = PetEventType::Updated;
+        event.pet = pet(PetStatus::Sold, "dog");
+        assert!(!event.matches(&watch));
+        event.previous = Some(pet(PetStatus::Available, "dog"));
+        assert!(event.matches(&watch));
+    }
+
+    #[test]
+    fn resume_token_test() {
+        assert_eq!(resume_token_parse(&resume_token_from_id(42)), Some(42));
+        assert_eq!(resume_token_parse("0"), None);
+        assert_eq!(resume_token_parse("-1"), None);
+        assert_eq!(resume_token_parse("token"), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! # Rate Limit Memory
+//!
+//! Stores token buckets in memory, limits are per replica
+//!
+//! Buckets are bounded, the least recently used bucket is evicted when a new bucket
+//! is added at the limit, it has most likely refilled so its client gets a new one
+use super::{bucket_take, RateLimitBackend, RateLimitPolicyConfig};
+use crate::internal::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Maximum number of buckets
+const MAX_BUCKETS: usize = 100_000;
+
+/// Memory rate limit backend
+#[derive(Debug, Default)]
+pub struct MemoryRateLimit {
+    buckets: Mutex<MemoryBuckets>,
+}
+
+/// Buckets by key with tokens, update time and sequence, and keys by sequence
+/// of their last update so the least recently used bucket is first
+#[derive(Debug, Default)]
+struct MemoryBuckets {
+    buckets: HashMap<String, (f64, Instant, u64)>,
+    recent: BTreeMap<u64, String>,
+    sequence: u64,
+}
+
+impl MemoryRateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MemoryBuckets {
+    fn take(
+        &mut self,
+        key: &str,
+        policy: &RateLimitPolicyConfig,
+        now: Instant,
+        max_buckets: usize,
+    ) -> (f64, bool) {
+        let bucket = self.buckets.get(key).copied();
+        let (tokens, allowed) = bucket_take(
+            bucket.map(|(tokens, _, _)| tokens),
+            bucket.map_or_else(Default::default, |(_, updated, _)| {
+                now.duration_since(updated)
+            }),
+            policy,
+        );
+
+        match bucket {
+            Some((_, _, sequence)) => {
+                self.recent.remove(&sequence);
+            }
+            None if self.buckets.len() >= max_buckets => {
+                if let Some((_, key)) = self.recent.pop_first() {
+                    self.buckets.remove(&key);
+                }
+            }
+            None => {}
+        }
+        self.sequence += 1;
+        self.buckets
+            .insert(key.to_string(), (tokens, now, self.sequence));
+        self.recent.insert(self.sequence, key.to_string());
+        (tokens, allowed)
+    }
+
+    /// Remove buckets not updated since before time, returns number removed
+    fn prune(&mut self, before: Instant) -> u64 {
+        let mut removed = 0;
+        while let Some(entry) = self.recent.first_entry() {
+            match self.buckets.get(entry.get()) {
+                Some((_, updated, _)) if *updated >= before => break,
+                _ => {
+                    let key = entry.remove();
+                    self.buckets.remove(&key);
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimitBackend for MemoryRateLimit {
+    async fn take(&self, key: &str, policy: &RateLimitPolicyConfig) -> Result<(f64, bool), XErr> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limit lock failed");
+        Ok(buckets.take(key, policy, now, MAX_BUCKETS))
+    }
+
+    async fn prune(&self, refill: Duration) -> Result<u64, XErr> {
+        let now = Instant::now();
+        match now.checked_sub(refill) {
+            Some(before) => Ok(self
+                .buckets
+                .lock()
+                .expect("rate limit lock failed")
+                .prune(before)),
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_buckets_test() {
+        let policy = RateLimitPolicyConfig {
+            method: None,
+            requests_per_second: 1.0,
+            burst: 2,
+        };
+        let start = Instant::now();
+        let mut buckets = MemoryBuckets::default();
+        assert_eq!(buckets.take("a", &policy, start, 2), (1.0, true));
+        assert_eq!(buckets.take("b", &policy, start, 2), (1.0, true));
+        assert_eq!(buckets.take("a", &policy, start, 2), (0.0, true));
+
+        // Least recently used bucket is evicted at the limit
+        assert_eq!(buckets.take("c", &policy, start, 2), (1.0, true));
+        assert_eq!(buckets.buckets.len(), 2);
+        assert!(!buckets.buckets.contains_key("b"));
+        assert_eq!(buckets.take("a", &policy, start, 2), (0.0, false));
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(buckets.take("c", &policy, later, 2), (1.0, true));
+        assert_eq!(buckets.prune(later), 1);
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.recent.len(), 1);
+        assert!(buckets.buckets.contains_key("c"));
+    }
+}
@@ -0,0 +1,343 @@
+//! # Rate Limit
+//!
+//! Token bucket rate limiting of API requests, each identity has a bucket per
+//! policy which refills at `requests_per_second` up to `burst` tokens
+//!
+//! - Identity is the client address, credentials are not verified before handlers
+//!   so users and API keys are not used as identities
+//! - Client address is the peer address, unless `forwarded_trusted_hops` is configured,
+//!   then it is read from `x-forwarded-for`. Only configure it when the server is
+//!   only reachable through envoy (which appends the client address), otherwise
+//!   clients can set the header to get a new bucket for each request
+//! - Policies are matched by gRPC method path (`/api.Example/ClientGet`) or
+//!   service prefix (`/api.Petshop/`), in order, otherwise the default policy is used
+//! - Buckets are stored in memory, or in postgres to be consistent across replicas
+//! - Buckets which have refilled are pruned in a background task
+//!
+//! Responses include `ratelimit-limit`, `ratelimit-remaining` and `ratelimit-reset`
+//! headers, limited requests return `ResourceExhausted` with `RetryInfo` details
+//!
+//! <https://datatracker.ietf.org/doc/html/draft-ietf-httpapi-ratelimit-headers>
+use crate::internal::*;
+use crate::services::ACTOR_ANONYMOUS;
+use http::header::{HeaderValue, RETRY_AFTER};
+use std::fmt;
+use std::time::Duration;
+
+pub use memory::MemoryRateLimit;
+pub use postgres::PostgresRateLimit;
+pub use service::RateLimitService;
+
+mod memory;
+mod postgres;
+mod service;
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+/// Interval between deleting refilled buckets
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rate Limit Backend Configuration
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitBackendConfig {
+    Memory,
+    Postgres,
+}
+
+/// Rate Limit Policy Configuration
+#[derive(Debug, Clone)]
+pub struct RateLimitPolicyConfig {
+    pub method: Option<String>,
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+/// Rate Limit Configuration
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub backend: RateLimitBackendConfig,
+    pub forwarded_trusted_hops: Option<usize>,
+    pub default_policy: RateLimitPolicyConfig,
+    pub policies: Vec<RateLimitPolicyConfig>,
+}
+
+/// Rate Limit Backend
+///
+/// Implement this trait to add other shared backends (e.g. Redis)
+#[tonic::async_trait]
+pub trait RateLimitBackend: fmt::Debug + Send + Sync {
+    /// Refill bucket with key and take a token if available, returns remaining
+    /// tokens and true if request is allowed
+    async fn take(&self, key: &str, policy: &RateLimitPolicyConfig) -> Result<(f64, bool), XErr>;
+
+    /// Remove buckets which have not been updated for refill duration, returns
+    /// number of removed buckets
+    async fn prune(&self, refill: Duration) -> Result<u64, XErr>;
+}
+
+/// Rate Limit
+pub struct RateLimit {
+    config: Option<RateLimitConfig>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<Shutdown>,
+    backend: Box<dyn RateLimitBackend>,
+}
+
+/// Rate limit decision for request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+    pub retry_after: Duration,
+}
+
+impl RateLimitPolicyConfig {
+    /// Returns true if policy applies to gRPC method path
+    fn matches(&self, path: &str) -> bool {
+        match self.method.as_deref() {
+            Some(method) if method.ends_with('/') => path.starts_with(method),
+            Some(method) => path == method,
+            None => true,
+        }
+    }
+
+    /// Returns key of bucket for policy
+    fn key(&self) -> &str {
+        self.method.as_deref().unwrap_or("*")
+    }
+
+    /// Returns duration for an empty bucket to refill
+    fn refill(&self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.burst) / self.requests_per_second)
+    }
+}
+
+impl RateLimit {
+    pub fn from_config(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        postgres: Arc<PostgresPool>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        let backend: Box<dyn RateLimitBackend> = match config.rate_limit.as_ref() {
+            Some(x) if x.backend == RateLimitBackendConfig::Postgres => {
+                Box::new(PostgresRateLimit::new(postgres))
+            }
+            _ => Box::new(MemoryRateLimit::new()),
+        };
+        Self {
+            config: config.rate_limit.clone(),
+            metrics,
+            shutdown,
+            backend,
+        }
+    }
+
+    /// Delete refilled buckets until shutdown is closing
+    pub async fn run(self: Arc<Self>) {
+        // If configuration is None, rate limit is disabled
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        let refill = config
+            .policies
+            .iter()
+            .chain(std::iter::once(&config.default_policy))
+            .map(|x| x.refill())
+            .max()
+            .unwrap_or_default();
+
+        let mut prune = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = prune.tick() => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+            match self.backend.prune(refill).await {
+                Ok(0) => {}
+                Ok(deleted) => info!("rate limit deleted {} refilled buckets", deleted),
+                Err(err) => {
+                    let err: Error = err.into();
+                    warn!("rate limit prune error: {:#}", err);
+                }
+            }
+        }
+        info!("rate limit prune stopped");
+    }
+
+    /// Used in tower service to check rate limit of request, returns none if
+    /// rate limit is disabled or the backend failed (requests are allowed)
+    pub async fn service_request_handler(
+        &self,
+        path: &str,
+        headers: &HttpHeaders,
+    ) -> Option<RateLimitDecision> {
+        // If configuration is None, rate limit is disabled
+        let config = self.config.as_ref()?;
+        let policy = config
+            .policies
+            .iter()
+            .find(|x| x.matches(path))
+            .unwrap_or(&config.default_policy);
+        let identity = Self::identity(headers, config.forwarded_trusted_hops);
+        let key = format!("{}|{}", identity, policy.key());
+
+        match self.backend.take(&key, policy).await {
+            Ok((tokens, allowed)) => {
+                if !allowed {
+                    self.metrics.rate_limit_counter_inc();
+                    warn!("rate limit exceeded: {} {}", identity, path);
+                }
+                Some(RateLimitDecision::new(policy, tokens, allowed))
+            }
+            Err(err) => {
+                let err: Error = err.into();
+                warn!("rate limit error: {:#}", err);
+                None
+            }
+        }
+    }
+
+    /// Used in tower service to return status of limited request
+    pub fn service_status(&self, decision: &RateLimitDecision) -> tonic::Status {
+        tonic_status_retry_info(&ERROR_RATE_LIMITED, decision.retry_after)
+    }
+
+    /// Returns identity of request, the client address in `x-forwarded-for` header
+    /// if trusted hops are configured, or the peer address
+    fn identity(headers: &HttpHeaders, forwarded_trusted_hops: Option<usize>) -> String {
+        let addr = match forwarded_trusted_hops {
+            Some(trusted_hops) => http_headers_client_addr(headers, trusted_hops),
+            None => http_headers_peer_addr(headers),
+        };
+        match addr {
+            Some(addr) => format!("ip:{}", addr),
+            None => ACTOR_ANONYMOUS.to_string(),
+        }
+    }
+}
+
+impl RateLimitDecision {
+    fn new(policy: &RateLimitPolicyConfig, tokens: f64, allowed: bool) -> Self {
+        let tokens = tokens.max(0.0);
+        let burst = f64::from(policy.burst);
+        let retry_after = if allowed {
+            0.0
+        } else {
+            (1.0 - tokens) / policy.requests_per_second
+        };
+        Self {
+            allowed,
+            limit: policy.burst,
+            remaining: tokens.floor() as u32,
+            reset: Duration::from_secs_f64(
+                ((burst - tokens) / policy.requests_per_second).max(0.0),
+            ),
+            retry_after: Duration::from_secs_f64(retry_after.max(0.0)),
+        }
+    }
+
+    /// Adds rate limit headers to response
+    pub fn headers(&self, headers: &mut HttpHeaders) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(seconds(self.reset)));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds(self.retry_after)));
+        }
+    }
+}
+
+/// Returns token bucket refilled after elapsed duration and true if a token was
+/// taken, buckets start full
+fn bucket_take(
+    tokens: Option<f64>,
+    elapsed: Duration,
+    policy: &RateLimitPolicyConfig,
+) -> (f64, bool) {
+    let burst = f64::from(policy.burst);
+    let tokens = match tokens {
+        Some(tokens) => (tokens + elapsed.as_secs_f64() * policy.requests_per_second).min(burst),
+        None => burst,
+    };
+    if tokens >= 1.0 {
+        (tokens - 1.0, true)
+    } else {
+        (tokens, false)
+    }
+}
+
+/// Returns whole seconds rounded up
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RateLimitPolicyConfig {
+        RateLimitPolicyConfig {
+            method: Some("/api.Petshop/".to_string()),
+            requests_per_second: 2.0,
+            burst: 3,
+        }
+    }
+
+    #[test]
+    fn bucket_take_test() {
+        let policy = policy();
+        let (tokens, allowed) = bucket_take(None, Duration::from_secs(0), &policy);
+        assert_eq!((tokens, allowed), (2.0, true));
+        let (tokens, _) = bucket_take(Some(tokens), Duration::from_secs(0), &policy);
+        let (tokens, _) = bucket_take(Some(tokens), Duration::from_secs(0), &policy);
+        let (tokens, allowed) = bucket_take(Some(tokens), Duration::from_secs(0), &policy);
+        assert_eq!((tokens, allowed), (0.0, false));
+        let (tokens, allowed) = bucket_take(Some(tokens), Duration::from_millis(500), &policy);
+        assert_eq!((tokens, allowed), (0.0, true));
+        let (tokens, allowed) = bucket_take(Some(tokens), Duration::from_secs(60), &policy);
+        assert_eq!((tokens, allowed), (2.0, true));
+
+        let decision = RateLimitDecision::new(&policy, 0.5, false);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::from_millis(250));
+        assert_eq!(seconds(decision.reset), 2);
+
+        assert!(policy.matches("/api.Petshop/PetPost"));
+        assert!(!policy.matches("/api.Example/ClientGet"));
+    }
+
+    #[test]
+    fn identity_test() {
+        let mut headers = HttpHeaders::new();
+        assert_eq!(RateLimit::identity(&headers, Some(0)), "anonymous");
+
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        assert_eq!(RateLimit::identity(&headers, Some(0)), "ip:2.2.2.2");
+        assert_eq!(RateLimit::identity(&headers, Some(1)), "ip:1.1.1.1");
+        assert_eq!(RateLimit::identity(&headers, Some(2)), "anonymous");
+        assert_eq!(RateLimit::identity(&headers, None), "anonymous");
+
+        headers.insert(crate::services::X_PEER_ADDR, "3.3.3.3".parse().unwrap());
+        assert_eq!(RateLimit::identity(&headers, Some(2)), "ip:3.3.3.3");
+        assert_eq!(RateLimit::identity(&headers, Some(0)), "ip:2.2.2.2");
+        assert_eq!(RateLimit::identity(&headers, None), "ip:3.3.3.3");
+
+        // Unverified credentials do not change the identity
+        headers.insert("authorization", "secret".parse().unwrap());
+        headers.insert("x-auth-request-email", "a@example.com".parse().unwrap());
+        headers.insert("x-auth-request-user", "a".parse().unwrap());
+        assert_eq!(RateLimit::identity(&headers, Some(0)), "ip:2.2.2.2");
+    }
+}
@@ -0,0 +1,31 @@
+//! # Rate Limit Postgres
+//!
+//! Stores token buckets in postgres, limits are shared by replicas
+use super::{RateLimitBackend, RateLimitPolicyConfig};
+use crate::internal::*;
+use std::time::Duration;
+
+/// Postgres rate limit backend
+#[derive(Debug)]
+pub struct PostgresRateLimit {
+    postgres: Arc<PostgresPool>,
+}
+
+impl PostgresRateLimit {
+    pub fn new(postgres: Arc<PostgresPool>) -> Self {
+        Self { postgres }
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimitBackend for PostgresRateLimit {
+    async fn take(&self, key: &str, policy: &RateLimitPolicyConfig) -> Result<(f64, bool), XErr> {
+        self.postgres
+            .rate_limit_take(key, policy.requests_per_second, f64::from(policy.burst))
+            .await
+    }
+
+    async fn prune(&self, refill: Duration) -> Result<u64, XErr> {
+        self.postgres.rate_limit_delete_refilled(refill).await
+    }
+}
@@ -0,0 +1,68 @@
+//! # Rate Limit Service
+//!
+use crate::internal::*;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to rate limit requests
+#[derive(Debug, Clone)]
+pub struct RateLimitService<S> {
+    rate_limit: Arc<RateLimit>,
+    inner: S,
+}
+
+impl<S> RateLimitService<S> {
+    pub fn wrap(rate_limit: Arc<RateLimit>, api: S) -> Self {
+        Self {
+            rate_limit,
+            inner: api,
+        }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for RateLimitService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let rate_limit = self.rate_limit.clone();
+
+        Box::pin(async move {
+            let decision = rate_limit
+                .service_request_handler(req.uri().path(), req.headers())
+                .await;
+
+            let mut res = match decision.as_ref() {
+                Some(decision) if !decision.allowed => {
+                    rate_limit.service_status(decision).to_http()
+                }
+                _ => svc.call(req).await?,
+            };
+
+            if let Some(decision) = decision {
+                decision.headers(res.headers_mut());
+            }
+            Ok(res)
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for RateLimitService<S> {
+    const NAME: &'static str = S::NAME;
+}
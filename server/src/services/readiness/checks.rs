@@ -0,0 +1,92 @@
+//! # Readiness Checks
+//!
+use crate::internal::*;
+
+/// Checks schema migrations have been applied
+#[derive(Debug)]
+pub struct MigrationsCheck {
+    postgres: Arc<PostgresPool>,
+}
+
+impl MigrationsCheck {
+    pub fn new(postgres: Arc<PostgresPool>) -> Self {
+        Self { postgres }
+    }
+}
+
+#[tonic::async_trait]
+impl ReadinessCheck for PostgresPool {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn check(&self) -> Result<(), XErr> {
+        self.readiness().await
+    }
+}
+
+#[tonic::async_trait]
+impl ReadinessCheck for MigrationsCheck {
+    fn name(&self) -> &'static str {
+        "migrations"
+    }
+
+    async fn check(&self) -> Result<(), XErr> {
+        let pending = self.postgres.migrations_pending().await?;
+        if pending.is_empty() {
+            Ok(())
+        } else {
+            let pending: Vec<String> = pending.iter().map(|x| x.to_string()).collect();
+            Err(XErr::readiness(&format!(
+                "migrations pending: {}",
+                pending.join(", ")
+            )))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ReadinessCheck for Clients {
+    fn name(&self) -> &'static str {
+        "clients"
+    }
+
+    fn criticality(&self) -> ReadinessCriticality {
+        ReadinessCriticality::Degraded
+    }
+
+    async fn check(&self) -> Result<(), XErr> {
+        let hosts = self.hosts_unavailable();
+        if hosts.is_empty() {
+            Ok(())
+        } else {
+            Err(XErr::readiness(&format!(
+                "circuit open: {}",
+                hosts.join(", ")
+            )))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ReadinessCheck for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn criticality(&self) -> ReadinessCriticality {
+        ReadinessCriticality::Degraded
+    }
+
+    async fn check(&self) -> Result<(), XErr> {
+        let (backlog, backlog_max) = self.backlog().await?;
+        if backlog <= backlog_max {
+            Ok(())
+        } else {
+            Err(XErr::readiness(&format!(
+                "backlog of {} pending deliveries exceeds {}",
+                backlog, backlog_max
+            )))
+        }
+    }
+}
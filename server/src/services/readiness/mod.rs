@@ -0,0 +1,245 @@
+//! # Readiness
+//!
+//! Readiness of the API server is a composite of checks of its dependencies, each
+//! check has a timeout and a criticality:
+//!
+//! - `critical` checks must pass for the server to be ready
+//! - `degraded` checks report the server as degraded but still ready
+//!
+//! Checks are run concurrently, implement `ReadinessCheck` and add it in `Api` to
+//! check other dependencies. The startup probe passes once the server has been
+//! ready, and then always passes so slow dependencies do not restart the server
+//!
+//! <https://kubernetes.io/docs/tasks/configure-pod-container/configure-liveness-readiness-startup-probes/>
+use crate::internal::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+pub use checks::MigrationsCheck;
+
+mod checks;
+
+/// Readiness Check Criticality
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessCriticality {
+    Critical,
+    Degraded,
+}
+
+/// Readiness Check Configuration, undefined values use check defaults
+#[derive(Debug, Clone, Default)]
+pub struct ReadinessCheckConfig {
+    pub criticality: Option<ReadinessCriticality>,
+    pub timeout_millis: Option<u64>,
+}
+
+/// Readiness Configuration
+#[derive(Debug, Clone)]
+pub struct ReadinessConfig {
+    pub timeout_millis: u64,
+    pub checks: HashMap<String, ReadinessCheckConfig>,
+}
+
+/// Readiness Check
+#[tonic::async_trait]
+pub trait ReadinessCheck: Send + Sync {
+    /// Name of check used in configuration and reports
+    fn name(&self) -> &'static str;
+
+    /// Criticality of check if not configured
+    fn criticality(&self) -> ReadinessCriticality {
+        ReadinessCriticality::Critical
+    }
+
+    /// Returns an error if dependency is not ready
+    async fn check(&self) -> Result<(), XErr>;
+}
+
+/// Readiness
+pub struct Readiness {
+    config: ReadinessConfig,
+    metrics: Arc<Metrics>,
+    checks: Vec<Arc<dyn ReadinessCheck>>,
+    started: AtomicBool,
+}
+
+/// Readiness status of server or check
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessStatus {
+    Ready,
+    Degraded,
+    Unready,
+}
+
+/// Readiness report of check
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessCheckReport {
+    pub name: &'static str,
+    pub criticality: ReadinessCriticality,
+    pub status: ReadinessStatus,
+    pub latency_millis: u64,
+    pub error: Option<String>,
+}
+
+/// Readiness report of server
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    pub status: ReadinessStatus,
+    pub checks: Vec<ReadinessCheckReport>,
+}
+
+impl Readiness {
+    pub fn from_config(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        checks: Vec<Arc<dyn ReadinessCheck>>,
+    ) -> Self {
+        for name in config.readiness.checks.keys() {
+            if !checks.iter().any(|x| x.name() == name) {
+                warn!("readiness check {} is configured but does not exist", name);
+            }
+        }
+        Self {
+            config: config.readiness.clone(),
+            metrics,
+            checks,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Run checks and return report, records ready metric
+    pub async fn report(&self) -> ReadinessReport {
+        let checks = self.checks.iter().map(|check| self.check(check.as_ref()));
+        let checks = futures::future::join_all(checks).await;
+
+        let status = readiness_status(&checks);
+        let ready = status != ReadinessStatus::Unready;
+        self.metrics.api_ready(ready);
+        if ready {
+            self.started.store(true, Ordering::SeqCst);
+        }
+        ReadinessReport { status, checks }
+    }
+
+    /// Returns an error if server is not ready
+    pub async fn readiness(&self) -> Result<ReadinessReport, XErr> {
+        let report = self.report().await;
+        match report.status {
+            ReadinessStatus::Unready => Err(XErr::readiness(&report.failed().join(", "))),
+            _ => Ok(report),
+        }
+    }
+
+    /// Returns an error if server has not been ready since it started
+    pub async fn startup(&self) -> Result<(), XErr> {
+        if self.started.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.readiness().await?;
+        Ok(())
+    }
+
+    async fn check(&self, check: &dyn ReadinessCheck) -> ReadinessCheckReport {
+        let config = self.config.checks.get(check.name());
+        let criticality = config
+            .and_then(|x| x.criticality)
+            .unwrap_or_else(|| check.criticality());
+        let timeout = config
+            .and_then(|x| x.timeout_millis)
+            .unwrap_or(self.config.timeout_millis);
+
+        let start = Instant::now();
+        let result = tokio::time::timeout(Duration::from_millis(timeout), check.check()).await;
+        let latency_millis = start.elapsed().as_millis() as u64;
+
+        let error = match result {
+            Ok(Ok(_)) => None,
+            Ok(Err(err)) => {
+                let err: Error = err.into();
+                Some(format!("{:#}", err))
+            }
+            Err(_) => Some(format!("timeout after {}ms", timeout)),
+        };
+        let status = match (error.is_some(), criticality) {
+            (false, _) => ReadinessStatus::Ready,
+            (true, ReadinessCriticality::Critical) => ReadinessStatus::Unready,
+            (true, ReadinessCriticality::Degraded) => ReadinessStatus::Degraded,
+        };
+        if let Some(error) = error.as_ref() {
+            warn!("readiness check {} failed: {}", check.name(), error);
+        }
+        ReadinessCheckReport {
+            name: check.name(),
+            criticality,
+            status,
+            latency_millis,
+            error,
+        }
+    }
+}
+
+impl ReadinessReport {
+    /// Returns names of critical checks which failed, degraded checks are excluded
+    pub fn failed(&self) -> Vec<&'static str> {
+        self.checks
+            .iter()
+            .filter(|x| x.status == ReadinessStatus::Unready)
+            .map(|x| x.name)
+            .collect()
+    }
+}
+
+/// Returns status of server from status of checks
+fn readiness_status(checks: &[ReadinessCheckReport]) -> ReadinessStatus {
+    if checks.iter().any(|x| x.status == ReadinessStatus::Unready) {
+        ReadinessStatus::Unready
+    } else if checks.iter().any(|x| x.status == ReadinessStatus::Degraded) {
+        ReadinessStatus::Degraded
+    } else {
+        ReadinessStatus::Ready
+    }
+}
+
+impl fmt::Debug for Readiness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Readiness").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(name: &'static str, status: ReadinessStatus) -> ReadinessCheckReport {
+        ReadinessCheckReport {
+            name,
+            criticality: ReadinessCriticality::Critical,
+            status,
+            latency_millis: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn readiness_status_test() {
+        let mut checks = vec![report("postgres", ReadinessStatus::Ready)];
+        assert_eq!(readiness_status(&checks), ReadinessStatus::Ready);
+        checks.push(report("clients", ReadinessStatus::Degraded));
+        assert_eq!(readiness_status(&checks), ReadinessStatus::Degraded);
+        checks.push(report("migrations", ReadinessStatus::Unready));
+        assert_eq!(readiness_status(&checks), ReadinessStatus::Unready);
+
+        let report = ReadinessReport {
+            status: readiness_status(&checks),
+            checks,
+        };
+        assert_eq!(report.failed(), vec!["migrations"]);
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["status"], "unready");
+        assert_eq!(value["checks"][1]["status"], "degraded");
+    }
+}
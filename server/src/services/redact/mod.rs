@@ -0,0 +1,244 @@
+//! # Redact
+//!
+//! Redaction of sensitive data in tracing output before it is written to stderr
+//!
+//! - Values of configured field names are masked, this applies to fields of
+//!   structs in `Debug` output (e.g. requests logged by `tracing::instrument`)
+//!   and to JSON object keys
+//! - Values of configured metadata keys are masked (e.g. `authorization`)
+//! - Matches of configured regex patterns are masked (e.g. emails, tokens)
+//!
+//! Only string values are masked by field names and metadata keys, escaped quotes in
+//! values are masked up to the closing quote, including values of `Debug` output
+//! nested in JSON output where quotes are escaped twice
+//!
+//! Request bodies should be skipped in `tracing::instrument` attributes instead,
+//! bytes fields (e.g. `HttpBody.data`) are written as arrays which are not masked
+//!
+//! <https://cheatsheetseries.owasp.org/cheatsheets/Logging_Cheat_Sheet.html#data-to-exclude>
+use crate::internal::*;
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Value of redacted data
+const REDACTED: &str = "[redacted]";
+
+/// Redact Configuration
+#[derive(Debug, Clone)]
+pub struct RedactConfig {
+    pub fields: Vec<String>,
+    pub metadata_keys: Vec<String>,
+    pub patterns: Vec<String>,
+}
+
+/// Redact
+#[derive(Debug)]
+pub struct Redact {
+    keys: Vec<Regex>,
+    patterns: Vec<Regex>,
+}
+
+/// Tracing writer which redacts output before writing it to stderr
+#[derive(Debug, Clone)]
+pub struct RedactWriter<M = fn() -> io::Stderr> {
+    redact: Arc<Redact>,
+    make_writer: M,
+}
+
+/// Buffer of a tracing event, redacted and written to stderr when dropped
+#[derive(Debug)]
+pub struct RedactBuffer<W: Write> {
+    redact: Arc<Redact>,
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl Redact {
+    /// Returns an error if a pattern is not a valid regex
+    pub fn from_config(config: &RedactConfig) -> Result<Self, regex::Error> {
+        let keys: Vec<String> = config
+            .fields
+            .iter()
+            .chain(config.metadata_keys.iter())
+            .map(|x| regex::escape(x))
+            .collect();
+        // Key in `Debug` or JSON output, followed by a separator and quoted string
+        // which may be an option, quotes are escaped if nested in JSON output so
+        // the nested value has escaped backslashes before its escaped characters
+        let keys = if keys.is_empty() {
+            vec![]
+        } else {
+            let key = format!(r#"\b(?:{})\\?"?\s*[:=]\s*(?:Some\()?"#, keys.join("|"));
+            vec![
+                Regex::new(&format!(
+                    r#"(?i)(?P<key>{}")(?:[^"\\]|\\.)*(?P<end>")"#,
+                    key
+                ))?,
+                Regex::new(&format!(
+                    r#"(?i)(?P<key>{}\\")(?:[^"\\]|\\\\(?:\\\\|\\"|[^"\\]))*(?P<end>\\")"#,
+                    key
+                ))?,
+            ]
+        };
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|x| Regex::new(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { keys, patterns })
+    }
+
+    /// Returns text with sensitive data redacted
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for keys in self.keys.iter() {
+            if let Cow::Owned(x) = keys.replace_all(&text, format!("${{key}}{}${{end}}", REDACTED))
+            {
+                text = Cow::Owned(x);
+            }
+        }
+        for pattern in self.patterns.iter() {
+            if let Cow::Owned(x) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(x);
+            }
+        }
+        text
+    }
+}
+
+impl RedactWriter {
+    pub fn new(redact: Arc<Redact>) -> Self {
+        Self {
+            redact,
+            make_writer: io::stderr,
+        }
+    }
+}
+
+impl<M: MakeWriter> MakeWriter for RedactWriter<M> {
+    type Writer = RedactBuffer<M::Writer>;
+
+    fn make_writer(&self) -> Self::Writer {
+        RedactBuffer {
+            redact: self.redact.clone(),
+            buffer: Vec::new(),
+            writer: self.make_writer.make_writer(),
+        }
+    }
+}
+
+impl<W: Write> Write for RedactBuffer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for RedactBuffer<W> {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.buffer);
+        let text = self.redact.redact(&text);
+        self.writer.write_all(text.as_bytes()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn redact() -> Redact {
+        Redact::from_config(&RedactConfig {
+            fields: vec!["email".to_string(), "password".to_string()],
+            metadata_keys: vec!["authorization".to_string(), "x-xsrf-token".to_string()],
+            patterns: vec![r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string()],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn redact_test() {
+        let redact = redact();
+
+        assert_eq!(
+            redact.redact(r#"User { id: 1, email: "a@b", password: Some("x\"y"), name: "rex" }"#),
+            r#"User { id: 1, email: "[redacted]", password: Some("[redacted]"), name: "rex" }"#
+        );
+        assert_eq!(
+            redact.redact(r#"headers: {"Authorization": "Bearer abc", "x-xsrf-token": "xyz"}"#),
+            r#"headers: {"Authorization": "[redacted]", "x-xsrf-token": "[redacted]"}"#
+        );
+        assert_eq!(
+            redact.redact(r#"{"fields":{"request":"{\"authorization\": \"Bearer abc\"}"}}"#),
+            r#"{"fields":{"request":"{\"authorization\": \"[redacted]\"}"}}"#
+        );
+        assert_eq!(
+            redact.redact(
+                r#"{"fields":{"request":"User { password: Some(\"x\\\"y\"), name: \"rex\" }"}}"#
+            ),
+            r#"{"fields":{"request":"User { password: Some(\"[redacted]\"), name: \"rex\" }"}}"#
+        );
+        assert_eq!(
+            redact.redact(r#"{"email":"a@b","user_email":"a@example.com","n":1}"#),
+            r#"{"email":"[redacted]","user_email":"[redacted]","n":1}"#
+        );
+        assert_eq!(
+            redact.redact("email_verified: true"),
+            "email_verified: true"
+        );
+    }
+
+    /// Output written by tracing is redacted when each event is written
+    #[test]
+    fn redact_writer_test() {
+        #[derive(Debug, Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let make_writer = {
+            let output = output.clone();
+            move || output.clone()
+        };
+        let writer = RedactWriter {
+            redact: Arc::new(redact()),
+            make_writer,
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(writer)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            info!(request = ?("authorization", r#"{"password": "x\"y"}"#), "sent to a@b.io");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("sent to [redacted]"), "{}", output);
+        assert!(
+            output.contains(r#"\"password\": \"[redacted]\""#),
+            "{}",
+            output
+        );
+        assert!(
+            !output.contains("a@b.io") && !output.contains("x\\"),
+            "{}",
+            output
+        );
+    }
+}
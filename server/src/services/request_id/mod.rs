@@ -0,0 +1,100 @@
+//! # Request Id
+//!
+//! Request ids correlate log lines of a request with its response, so support
+//! tickets which include an id can be traced
+//!
+//! - A valid incoming `x-request-id` header (e.g. set by envoy) is used, otherwise
+//!   an id is generated
+//! - Id is added to the tracing span of the request and echoed in response metadata
+//! - Id is added to error status details as `google.rpc.RequestInfo`
+//! - Id is available to the request task, it is included in JSON panic output and
+//!   forwarded on outbound requests sent by `Clients`
+//! - Peer address of the connection is passed to inner services in the
+//!   `x-petshop-peer-addr` header. Tonic only exposes it on its own request type and
+//!   can't convert that back, so the request extensions are kept in a `RequestConnection`
+//!   extension which inner tower services can read, `tonic::Request::remote_addr` is
+//!   not available to handlers
+//!
+//! <https://www.envoyproxy.io/docs/envoy/latest/configuration/http/http_conn_man/headers#x-request-id>
+use crate::internal::*;
+
+pub use service::RequestIdService;
+
+mod service;
+
+/// Request id header
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Peer address header, set by the service and removed from incoming requests
+pub const X_PEER_ADDR: &str = "x-petshop-peer-addr";
+
+/// Extensions of incoming request which include the tonic connection info
+#[derive(Debug)]
+pub struct RequestConnection(tonic::Request<()>);
+
+/// Maximum length of incoming request ids
+const REQUEST_ID_MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Returns id of the current request, or none outside of a request task
+pub fn request_id_current() -> Option<String> {
+    REQUEST_ID.try_with(|x| x.clone()).ok()
+}
+
+impl RequestConnection {
+    /// Moves extensions of request into connection
+    fn take<B>(req: &mut http::Request<B>) -> Self {
+        let mut conn = http::Request::new(());
+        *conn.extensions_mut() = std::mem::take(req.extensions_mut());
+        Self(tonic::Request::from_http(conn))
+    }
+
+    /// Returns remote address of connection
+    pub fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        self.0.remote_addr()
+    }
+}
+
+/// Returns incoming request id if valid, or a generated id
+pub fn request_id_or_generate(headers: &HttpHeaders) -> String {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| request_id_valid(x))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// Returns true if id is not empty, not too long and only contains characters
+/// which are safe to include in logs and headers
+fn request_id_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= REQUEST_ID_MAX_LEN
+        && id
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '_' | '.' | ':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_id_or_generate_test() {
+        let mut headers = HttpHeaders::new();
+        let id = request_id_or_generate(&headers);
+        assert_eq!(id.len(), 36);
+        assert_ne!(id, request_id_or_generate(&headers));
+
+        headers.insert(X_REQUEST_ID, "abc-123_x.y:z".parse().unwrap());
+        assert_eq!(request_id_or_generate(&headers), "abc-123_x.y:z");
+
+        headers.insert(X_REQUEST_ID, "abc 123".parse().unwrap());
+        assert_ne!(request_id_or_generate(&headers), "abc 123");
+        headers.insert(X_REQUEST_ID, "a".repeat(129).parse().unwrap());
+        assert_eq!(request_id_or_generate(&headers).len(), 36);
+    }
+}
@@ -0,0 +1,74 @@
+//! # Request Id Service
+//!
+use super::{request_id_or_generate, RequestConnection, REQUEST_ID, X_PEER_ADDR, X_REQUEST_ID};
+use crate::services::errors_request_info;
+use http::header::HeaderValue;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+use tracing_futures::Instrument;
+
+/// Service interceptor to accept or generate request ids, the request is handled
+/// in a span and task with the id which is added to the response
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S> RequestIdService<S> {
+    pub fn wrap(api: S) -> Self {
+        Self { inner: api }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for RequestIdService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let request_id = request_id_or_generate(req.headers());
+        // Incoming ids are validated and generated ids are uuids
+        let header = HeaderValue::from_str(&request_id).expect("request id header failed");
+        req.headers_mut().insert(X_REQUEST_ID, header.clone());
+        // Extensions are moved to read the peer address with tonic, then kept in
+        // the connection extension so they are passed to inner services
+        let conn = RequestConnection::take(&mut req);
+        req.headers_mut().remove(X_PEER_ADDR);
+        if let Some(addr) = conn.remote_addr() {
+            let addr = HeaderValue::from_str(&addr.ip().to_string()).expect("peer addr failed");
+            req.headers_mut().insert(X_PEER_ADDR, addr);
+        }
+        req.extensions_mut().insert(conn);
+        let span = tracing::info_span!("request", request_id = %request_id);
+        let scope = request_id.clone();
+
+        let fut = async move {
+            let mut res = svc.call(req).await?;
+
+            errors_request_info(&request_id, res.headers_mut());
+            res.headers_mut().insert(X_REQUEST_ID, header);
+
+            Ok(res)
+        };
+        Box::pin(REQUEST_ID.scope(scope, fut.instrument(span)))
+    }
+}
+
+impl<S: NamedService> NamedService for RequestIdService<S> {
+    const NAME: &'static str = S::NAME;
+}
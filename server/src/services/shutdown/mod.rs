@@ -0,0 +1,223 @@
+//! # Shutdown
+//!
+//! Graceful shutdown of the server in phases, started by a signal or `Api::_shutdown`:
+//!
+//! 1. `draining` - readiness and gRPC health report not serving so load balancers
+//!    deregister the server, requests are still accepted for the pre-stop delay
+//! 2. `closing` - new connections are refused and HTTP/2 clients are sent GOAWAY,
+//!    in-flight requests and background tasks have the grace period to finish
+//! 3. `terminated` - remaining requests and tasks are dropped, internal server stops
+//!
+//! Background tasks (e.g. webhook deliveries) are spawned with `Shutdown::spawn` so
+//! they are drained, and can wait for the `closing` phase to stop early
+use crate::internal::*;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, Notify};
+
+/// Shutdown Configuration
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    pub pre_stop_delay_seconds: u64,
+    pub grace_period_seconds: u64,
+}
+
+/// Shutdown Phase
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ShutdownPhase {
+    Running,
+    Draining,
+    Closing,
+    Terminated,
+}
+
+/// Shutdown
+pub struct Shutdown {
+    config: ShutdownConfig,
+    metrics: Arc<Metrics>,
+    signal: broadcast::Sender<bool>,
+    phase: watch::Sender<ShutdownPhase>,
+    phase_rx: watch::Receiver<ShutdownPhase>,
+    phase_at: Mutex<Instant>,
+    tasks: AtomicUsize,
+    tasks_notify: Notify,
+}
+
+/// Decrements tasks when dropped, including if the task panics
+struct TaskGuard(Arc<Shutdown>);
+
+impl ShutdownPhase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Draining => "draining",
+            Self::Closing => "closing",
+            Self::Terminated => "terminated",
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let (signal, _) = broadcast::channel(8);
+        let (phase, phase_rx) = watch::channel(ShutdownPhase::Running);
+        Self {
+            config: config.shutdown.clone(),
+            metrics,
+            signal,
+            phase,
+            phase_rx,
+            phase_at: Mutex::new(Instant::now()),
+            tasks: AtomicUsize::new(0),
+            tasks_notify: Notify::new(),
+        }
+    }
+
+    /// Send shutdown signal, used by `Api::_shutdown` and if the internal server fails
+    pub fn signal(&self) {
+        // Error is returned if there are no receivers, shutdown has already started
+        self.signal.send(true).ok();
+    }
+
+    /// Returns receiver of shutdown signal
+    pub fn subscribe(&self) -> broadcast::Receiver<bool> {
+        self.signal.subscribe()
+    }
+
+    /// Returns current phase
+    pub fn phase(&self) -> ShutdownPhase {
+        *self.phase_rx.borrow()
+    }
+
+    /// Wait until shutdown has reached phase
+    pub async fn wait(&self, phase: ShutdownPhase) {
+        let mut rx = self.phase_rx.clone();
+        while *rx.borrow() < phase {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wait until shutdown has reached phase, owned future for servers and tasks
+    pub async fn wait_owned(self: Arc<Self>, phase: ShutdownPhase) {
+        self.wait(phase).await
+    }
+
+    /// Drain server after shutdown signal, moves to closing phase after pre-stop delay
+    pub async fn drain(&self) {
+        let pre_stop_delay = Duration::from_secs(self.config.pre_stop_delay_seconds);
+        info!("shutdown draining, pre-stop delay {:?}", pre_stop_delay);
+        self.phase_set(ShutdownPhase::Draining);
+        tokio::time::sleep(pre_stop_delay).await;
+
+        let grace_period = Duration::from_secs(self.config.grace_period_seconds);
+        info!(
+            "shutdown closing, grace period {:?} ({} tasks)",
+            grace_period,
+            self.tasks.load(Ordering::SeqCst)
+        );
+        self.phase_set(ShutdownPhase::Closing);
+    }
+
+    /// Await future until it completes or the grace period has expired, returns none
+    /// if the grace period expired and the future was dropped
+    pub async fn deadline<F: futures::Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.grace_period_expired() => None,
+        }
+    }
+
+    /// Wait for background tasks to finish or the grace period to expire, then
+    /// move to terminated phase
+    pub async fn terminate(&self) {
+        let drained = self.deadline(self.tasks_wait()).await.is_some();
+        if !drained {
+            warn!(
+                "shutdown grace period expired, dropping {} tasks",
+                self.tasks.load(Ordering::SeqCst)
+            );
+        }
+        self.phase_set(ShutdownPhase::Terminated);
+        info!("shutdown terminated");
+    }
+
+    /// Spawn background task which is drained on shutdown
+    pub fn spawn<F>(self: &Arc<Self>, future: F)
+    where
+        F: futures::Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.fetch_add(1, Ordering::SeqCst);
+        let guard = TaskGuard(self.clone());
+        tokio::spawn(async move {
+            future.await;
+            drop(guard);
+        });
+    }
+
+    async fn tasks_wait(&self) {
+        while self.tasks.load(Ordering::SeqCst) > 0 {
+            self.tasks_notify.notified().await;
+        }
+    }
+
+    async fn grace_period_expired(&self) {
+        self.wait(ShutdownPhase::Closing).await;
+        let closing_at = *self.phase_at.lock().expect("shutdown lock failed");
+        let grace_period = Duration::from_secs(self.config.grace_period_seconds);
+        tokio::time::sleep_until((closing_at + grace_period).into()).await;
+    }
+
+    /// Move to phase and record duration of previous phase
+    fn phase_set(&self, phase: ShutdownPhase) {
+        let previous = self.phase();
+        if phase <= previous {
+            return;
+        }
+        let now = Instant::now();
+        let elapsed = {
+            let mut phase_at = self.phase_at.lock().expect("shutdown lock failed");
+            let elapsed = now - *phase_at;
+            *phase_at = now;
+            elapsed
+        };
+        if previous != ShutdownPhase::Running {
+            info!("shutdown {} phase took {:?}", previous.name(), elapsed);
+            self.metrics
+                .shutdown_phase_duration(previous.name(), elapsed);
+        }
+        self.metrics.shutdown_phase(phase as u64);
+        self.phase.send(phase).ok();
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.tasks.fetch_sub(1, Ordering::SeqCst);
+        // Stores permit if drain is not waiting yet so the wake up is not lost
+        self.0.tasks_notify.notify_one();
+    }
+}
+
+#[tonic::async_trait]
+impl ReadinessCheck for Shutdown {
+    fn name(&self) -> &'static str {
+        "shutdown"
+    }
+
+    async fn check(&self) -> Result<(), XErr> {
+        match self.phase() {
+            ShutdownPhase::Running => Ok(()),
+            phase => Err(XErr::readiness(&format!("shutdown {}", phase.name()))),
+        }
+    }
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shutdown").finish()
+    }
+}
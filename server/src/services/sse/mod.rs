@@ -0,0 +1,188 @@
+//! # Server-Sent Events
+//!
+//! Server streaming RPCs are also served as `text/event-stream` for browser clients
+//! which can not use grpc-web, by `GET` requests to the RPC path with an
+//! `accept: text/event-stream` header (e.g. `new EventSource("/api.Petshop/WatchPets")`)
+//!
+//! - Request messages are parsed from the query string by the API
+//! - Stream messages are sent as JSON `message` events with an id, clients which
+//!   reconnect with the `Last-Event-ID` header resume after it
+//! - Heartbeat comments are sent while the stream is idle so proxies and load
+//!   balancers do not close the connection
+//! - Tonic only serves HTTP/2, so browsers connect through envoy, which must disable
+//!   the route timeout for streaming RPC paths (see the example envoy configurations)
+//! - Errors before the stream starts are returned as JSON with the catalogue HTTP
+//!   status, errors during the stream are sent as an `error` event which ends it
+//! - Requests pass through the same service wrappers and request handlers as gRPC
+//!   requests, so authentication, CSRF and rate limit checks are the same
+//!
+//! <https://html.spec.whatwg.org/multipage/server-sent-events.html>
+use crate::internal::*;
+use crate::services::errors_info;
+use futures::{Stream, StreamExt};
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use petshop_proto::google::rpc;
+use prost::Message;
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::Status;
+
+pub use service::SseService;
+
+mod service;
+
+/// Heartbeat comment sent while stream is idle
+const SSE_HEARTBEAT: &str = ": heartbeat\n\n";
+
+const TEXT_EVENT_STREAM: &str = "text/event-stream";
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Server-Sent Events Configuration
+#[derive(Debug, Clone)]
+pub struct SseConfig {
+    pub heartbeat_seconds: u64,
+    pub retry_millis: u64,
+}
+
+/// Server-Sent Events
+#[derive(Debug)]
+pub struct Sse {
+    config: SseConfig,
+}
+
+/// Server-Sent Event, sent as a `message` event with JSON data
+#[derive(Debug, Clone)]
+pub struct SseEvent {
+    pub id: String,
+    pub data: serde_json::Value,
+}
+
+impl Sse {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            config: config.sse.clone(),
+        }
+    }
+
+    /// Returns true if request is a `GET` which accepts an event stream
+    pub fn is_request(&self, req: &HyperRequest<Body>) -> bool {
+        req.method() == http::Method::GET
+            && req
+                .headers()
+                .get_all(http::header::ACCEPT)
+                .iter()
+                .filter_map(|x| x.to_str().ok())
+                .any(|x| x.contains(TEXT_EVENT_STREAM))
+    }
+
+    /// Returns `Last-Event-ID` header sent by reconnecting clients
+    pub fn last_event_id(&self, headers: &HttpHeaders) -> Option<String> {
+        headers
+            .get(LAST_EVENT_ID)
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+    }
+
+    /// Returns event stream response which sends events until the stream ends
+    /// or returns an error, with heartbeats while idle
+    pub fn response<S>(&self, locale: &'static str, stream: S) -> HyperResponse<BoxBody>
+    where
+        S: Stream<Item = Result<SseEvent, Status>> + Send + 'static,
+    {
+        let heartbeat = Duration::from_secs(self.config.heartbeat_seconds);
+        let interval = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat, heartbeat);
+        let retry = format!("retry: {}\n\n", self.config.retry_millis);
+
+        let events = futures::stream::unfold(
+            Some((Box::pin(stream), interval)),
+            move |state| async move {
+                let (mut stream, mut interval) = state?;
+                tokio::select! {
+                    item = stream.next() => match item {
+                        Some(Ok(event)) => Some((sse_event(&event), Some((stream, interval)))),
+                        Some(Err(status)) => Some((sse_error(locale, &status), None)),
+                        None => None,
+                    },
+                    _ = interval.tick() => Some((SSE_HEARTBEAT.to_string(), Some((stream, interval)))),
+                }
+            },
+        );
+        let body = futures::stream::once(async move { retry })
+            .chain(events)
+            .map(|x| Ok::<_, std::convert::Infallible>(hyper::body::Bytes::from(x)));
+
+        HyperResponse::builder()
+            .status(HttpStatus::OK)
+            .header(http::header::CONTENT_TYPE, TEXT_EVENT_STREAM)
+            .header(http::header::CACHE_CONTROL, "no-cache")
+            .header("x-accel-buffering", "no")
+            .body(BoxBody::map_from(Body::wrap_stream(body)))
+            .expect("sse response build failed")
+    }
+
+    /// Returns JSON error response with HTTP status of error in catalogue
+    pub fn error_response(&self, locale: &'static str, status: &Status) -> HyperResponse<BoxBody> {
+        let (http_status, error) = sse_status_json(locale, status);
+        HyperResponse::builder()
+            .status(http_status)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::CONTENT_LANGUAGE, locale)
+            .body(BoxBody::map_from(Body::from(error.to_string())))
+            .expect("sse response build failed")
+    }
+}
+
+/// Returns event with data on a single line, JSON strings escape line breaks
+fn sse_event(event: &SseEvent) -> String {
+    format!("id: {}\nevent: message\ndata: {}\n\n", event.id, event.data)
+}
+
+fn sse_error(locale: &'static str, status: &Status) -> String {
+    let (_, error) = sse_status_json(locale, status);
+    format!("event: error\ndata: {}\n\n", error)
+}
+
+/// Returns HTTP status and JSON of status using the error catalogue, errors
+/// which are not in the catalogue are returned as generic errors
+fn sse_status_json(locale: &'static str, status: &Status) -> (HttpStatus, serde_json::Value) {
+    let info = rpc::Status::decode(status.details())
+        .ok()
+        .and_then(|x| errors_info(&x));
+    let (error, metadata) = match info {
+        Some((error, error_info)) => (error, error_info.metadata),
+        None => (&ERROR_GENERIC, Default::default()),
+    };
+    let error_json = json!({
+        "code": error.code,
+        "message": error.render(locale, &metadata),
+        "metadata": metadata,
+    });
+    (error.http_status, error_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_event_test() {
+        let event = SseEvent {
+            id: "42".to_string(),
+            data: json!({ "message": "line 1\nline 2" }),
+        };
+        assert_eq!(
+            sse_event(&event),
+            "id: 42\nevent: message\ndata: {\"message\":\"line 1\\nline 2\"}\n\n"
+        );
+
+        let status: Status = XErr::not_found("pet").into();
+        let (http_status, error) = sse_status_json("en", &status);
+        assert_eq!(http_status, HttpStatus::NOT_FOUND);
+        assert_eq!(error["code"], "NOT_FOUND");
+        assert_eq!(error["message"], "The requested pet was not found");
+        let (http_status, error) = sse_status_json("en", &Status::internal("error"));
+        assert_eq!(http_status, HttpStatus::INTERNAL_SERVER_ERROR);
+        assert_eq!(error["code"], "INTERNAL");
+    }
+}
@@ -0,0 +1,54 @@
+//! # Server-Sent Events Service
+//!
+use crate::internal::*;
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse};
+use std::task::{Context, Poll};
+use tonic::{body::BoxBody, transport::NamedService};
+use tower::Service;
+
+/// Service interceptor to serve server streaming RPCs as server-sent events
+#[derive(Debug, Clone)]
+pub struct SseService<S> {
+    api: Api,
+    inner: S,
+}
+
+impl<S> SseService<S> {
+    pub fn wrap(api: Api, inner: S) -> Self {
+        Self { api, inner }
+    }
+}
+
+impl<S> Service<HyperRequest<Body>> for SseService<S>
+where
+    S: Service<HyperRequest<Body>, Response = HyperResponse<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HyperRequest<Body>) -> Self::Future {
+        let mut svc = self.inner.clone();
+        let api = self.api.clone();
+
+        Box::pin(async move {
+            if api.sse.is_request(&req) && Api::sse_route(req.uri().path()) {
+                return Ok(api.sse_request_handler(req).await);
+            }
+            svc.call(req).await
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for SseService<S> {
+    const NAME: &'static str = S::NAME;
+}
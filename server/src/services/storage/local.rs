@@ -0,0 +1,93 @@
+//! # Storage Local
+//!
+//! Stores objects as files in a directory on the local filesystem
+use super::StorageBackend;
+use crate::internal::*;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// Local filesystem storage backend
+#[derive(Debug)]
+pub struct LocalStorage {
+    path: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns path of object key, keys are generated by the server but are still
+    /// checked to prevent path traversal outside of storage directory
+    fn key_path(&self, key: &str) -> Result<PathBuf, XErr> {
+        let key_path = Path::new(key);
+        let valid = !key.is_empty()
+            && key_path
+                .components()
+                .all(|x| matches!(x, Component::Normal(_)));
+        if !valid {
+            return Err(XErr::storage_key(key));
+        }
+        Ok(self.path.join(key_path))
+    }
+}
+
+#[tonic::async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), XErr> {
+        let path = self.key_path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to temporary file and rename so readers never see a partial object
+        let tmp_path = path.with_extension(format!("tmp-{}", random_string(8)));
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, XErr> {
+        let path = self.key_path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), XErr> {
+        let path = self.key_path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_storage_test() {
+        let path = std::env::temp_dir().join(format!("petshop-storage-{}", random_string(8)));
+        let storage = LocalStorage::new(&path);
+
+        storage.put("pets/1/a", b"abc".to_vec()).await.unwrap();
+        assert_eq!(
+            storage.get("pets/1/a").await.unwrap(),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(storage.get("pets/1/b").await.unwrap(), None);
+
+        storage.delete("pets/1/a").await.unwrap();
+        assert_eq!(storage.get("pets/1/a").await.unwrap(), None);
+        storage.delete("pets/1/a").await.unwrap();
+
+        for key in &["", "../a", "/etc/passwd", "pets/../../a"] {
+            assert!(storage.get(key).await.is_err());
+            assert!(storage.delete(key).await.is_err());
+        }
+        tokio::fs::remove_dir_all(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+//! # Storage
+//!
+//! Object storage for uploaded files using a pluggable backend:
+//!
+//! - `local` stores objects as files in a directory
+//!
+//! Pet photos are validated before they are stored, see `photo` module, and are served
+//! by the `PetPhotoGet` endpoint rather than directly from the backend
+//!
+use crate::internal::*;
+use std::fmt;
+use tonic::Status;
+
+pub use local::LocalStorage;
+pub use photo::{photo_validate, PhotoError};
+
+mod local;
+mod photo;
+
+/// Length of generated photo ids
+const PHOTO_ID_LENGTH: usize = 24;
+
+/// Storage Backend Configuration
+#[derive(Debug, Clone)]
+pub enum StorageBackendConfig {
+    Local { path: String },
+}
+
+/// Storage Configuration
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub backend: StorageBackendConfig,
+    pub public_url: String,
+    pub max_photo_bytes: usize,
+}
+
+/// Storage Backend
+///
+/// Implement this trait to add other backends (e.g. S3 compatible)
+#[tonic::async_trait]
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Store object data with key, replaces existing object
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), XErr>;
+
+    /// Returns object data with key, or none if it does not exist
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, XErr>;
+
+    /// Delete object with key, objects which do not exist are ignored
+    async fn delete(&self, key: &str) -> Result<(), XErr>;
+}
+
+/// Storage
+pub struct Storage {
+    config: StorageConfig,
+    metrics: Arc<Metrics>,
+    backend: Box<dyn StorageBackend>,
+}
+
+/// Stored photo details
+#[derive(Debug, Clone)]
+pub struct StoragePhoto {
+    pub id: String,
+    pub key: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub url: String,
+}
+
+impl Storage {
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let backend: Box<dyn StorageBackend> = match &config.storage.backend {
+            StorageBackendConfig::Local { path } => Box::new(LocalStorage::new(path)),
+        };
+        Self {
+            config: config.storage.clone(),
+            metrics,
+            backend,
+        }
+    }
+
+    /// Maximum size of photos in bytes
+    pub fn max_photo_bytes(&self) -> usize {
+        self.config.max_photo_bytes
+    }
+
+    /// Validates and stores pet photo, returns stored photo details
+    pub async fn photo_put(
+        &self,
+        pet_id: i64,
+        content_type: &str,
+        data: Vec<u8>,
+    ) -> Result<StoragePhoto, Status> {
+        let (content_type, extension) =
+            match photo_validate(content_type, &data, self.config.max_photo_bytes) {
+                Ok(x) => x,
+                Err(err) => return Err(self.photo_error(err)),
+            };
+
+        let id = format!("{}.{}", random_string(PHOTO_ID_LENGTH), extension);
+        let key = format!("pets/{}/{}", pet_id, id);
+        let size_bytes = data.len() as i64;
+        self.backend.put(&key, data).await?;
+
+        Ok(StoragePhoto {
+            url: format!(
+                "{}/api.Petshop/PetPhotoGet/{}/{}",
+                self.config.public_url, pet_id, id
+            ),
+            id,
+            key,
+            content_type: content_type.to_string(),
+            size_bytes,
+        })
+    }
+
+    /// Delete stored photo which was not added to its pet, errors are logged
+    pub async fn photo_delete(&self, photo: &StoragePhoto) {
+        if let Err(err) = self.backend.delete(&photo.key).await {
+            let err: Error = err.into();
+            warn!("photo delete error: {}: {:#}", photo.key, err);
+        }
+    }
+
+    /// Returns stored object data
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, XErr> {
+        self.backend.get(key).await
+    }
+
+    /// Logs photo error and returns status with details
+    pub fn photo_error(&self, err: PhotoError) -> Status {
+        self.metrics.validate_error_counter_inc();
+        warn!("photo error: {}", err);
+        tonic_status_bad_request(&err.validation_errors())
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Storage").finish()
+    }
+}
@@ -0,0 +1,148 @@
+//! # Storage Photo
+//!
+//! Validation of uploaded photos, the declared content type must be an allowed
+//! image type and match the type detected from the leading (magic) bytes
+//!
+//! <https://cheatsheetseries.owasp.org/cheatsheets/File_Upload_Cheat_Sheet.html>
+use std::borrow::Cow;
+use validator::{ValidationError, ValidationErrors};
+
+/// Allowed image content types and file extensions
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+/// Photo Errors
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PhotoError {
+    #[error("content type `{0}` is not allowed")]
+    ContentType(String),
+
+    #[error("photo is empty")]
+    Empty,
+
+    #[error("photo exceeds {0} bytes")]
+    TooLarge(usize),
+
+    #[error("photo data does not match content type `{0}`")]
+    Mismatch(String),
+
+    #[error("pet exceeds {0} photos")]
+    TooMany(usize),
+}
+
+impl PhotoError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ContentType(_) => "photo_content_type",
+            Self::Empty => "photo_empty",
+            Self::TooLarge(_) => "photo_too_large",
+            Self::Mismatch(_) => "photo_mismatch",
+            Self::TooMany(_) => "photo_too_many",
+        }
+    }
+
+    /// Returns errors in the same format as derived validation
+    pub fn validation_errors(&self) -> ValidationErrors {
+        let mut err = ValidationError::new(self.code());
+        err.message = Some(Cow::Owned(self.to_string()));
+        match self {
+            Self::ContentType(content_type) | Self::Mismatch(content_type) => {
+                err.add_param(Cow::Borrowed("content_type"), content_type);
+            }
+            Self::TooLarge(max) | Self::TooMany(max) => err.add_param(Cow::Borrowed("max"), max),
+            Self::Empty => {}
+        }
+        let mut errors = ValidationErrors::new();
+        errors.add("photo", err);
+        errors
+    }
+}
+
+/// Validates photo data, returns normalised content type and file extension
+pub fn photo_validate(
+    content_type: &str,
+    data: &[u8],
+    max_bytes: usize,
+) -> Result<(&'static str, &'static str), PhotoError> {
+    let declared = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    let (content_type, extension) = IMAGE_TYPES
+        .iter()
+        .find(|(x, _)| *x == declared)
+        .copied()
+        .ok_or(PhotoError::ContentType(declared))?;
+
+    if data.is_empty() {
+        return Err(PhotoError::Empty);
+    }
+    if data.len() > max_bytes {
+        return Err(PhotoError::TooLarge(max_bytes));
+    }
+    if image_content_type(data) != Some(content_type) {
+        return Err(PhotoError::Mismatch(content_type.to_string()));
+    }
+    Ok((content_type, extension))
+}
+
+/// Returns image content type detected from magic bytes
+fn image_content_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn photo_validate_test() {
+        assert_eq!(
+            photo_validate("image/png", PNG, 1024).unwrap(),
+            ("image/png", "png")
+        );
+        assert_eq!(
+            photo_validate("Image/JPEG; charset=binary", b"\xff\xd8\xff\xe0", 1024).unwrap(),
+            ("image/jpeg", "jpg")
+        );
+        assert_eq!(
+            photo_validate("image/webp", b"RIFF\0\0\0\0WEBPVP8 ", 1024).unwrap(),
+            ("image/webp", "webp")
+        );
+
+        assert_eq!(
+            photo_validate("image/svg+xml", b"<svg></svg>", 1024).unwrap_err(),
+            PhotoError::ContentType("image/svg+xml".to_string())
+        );
+        assert_eq!(
+            photo_validate("image/png", b"", 1024).unwrap_err(),
+            PhotoError::Empty
+        );
+        assert_eq!(
+            photo_validate("image/png", PNG, 8).unwrap_err(),
+            PhotoError::TooLarge(8)
+        );
+        assert_eq!(
+            photo_validate("image/gif", PNG, 1024).unwrap_err(),
+            PhotoError::Mismatch("image/gif".to_string())
+        );
+    }
+}
@@ -0,0 +1,498 @@
+//! # Webhooks
+//!
+//! Outbound webhook delivery to subscribers registered in postgres
+//!
+//! - Deliveries are created for each active subscriber of an event type
+//! - Payloads are sent as JSON using the Clients service module
+//! - Requests are signed with HMAC-SHA256 of the timestamp and body using the subscriber secret
+//! - Failed deliveries are retried with backoff, each attempt is logged in postgres
+//! - Deliveries waiting to retry when the server shuts down are released, so they
+//!   are resumed by another server or after a restart
+//! - Pending deliveries are leased in postgres while they are sent, deliveries with
+//!   expired leases (e.g. after a crash) are resumed by a background task
+//! - Destinations are resolved before each attempt, and private, loopback or link-local
+//!   addresses are rejected unless `allow_private_destinations` is configured,
+//!   redirects are not followed
+//!
+//! Destinations are resolved again when requests are sent, so a subscriber controlling
+//! DNS could still return a different address between the check and the request,
+//! restrict egress from the server at the network level where that matters
+//!
+//! Subscribers can verify requests by computing the signature of the string
+//! `{timestamp}.{body}` and comparing it with the signature header in constant time,
+//! and should reject requests with timestamps that are too old to prevent replays
+use crate::internal::*;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{Method, RequestBuilder};
+use sha2::Sha256;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+pub const EVENT_PET_CREATED: &str = "pet.created";
+pub const EVENT_PET_UPDATED: &str = "pet.updated";
+
+pub const X_PETSHOP_EVENT: &str = "x-petshop-event";
+pub const X_PETSHOP_DELIVERY: &str = "x-petshop-delivery";
+pub const X_PETSHOP_TIMESTAMP: &str = "x-petshop-timestamp";
+pub const X_PETSHOP_SIGNATURE_256: &str = "x-petshop-signature-256";
+
+/// Interval between checks for deliveries with expired leases
+const WEBHOOKS_RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of deliveries resumed on each check
+const WEBHOOKS_RESUME_LIMIT: i64 = 100;
+
+/// Webhooks Configuration
+#[derive(Debug, Clone)]
+pub struct WebhooksConfig {
+    pub max_attempts: u32,
+    pub backoff_base_seconds: u64,
+    pub backoff_max_seconds: u64,
+    pub backlog_max: i64,
+    pub delivery_timeout_seconds: u64,
+    pub allow_private_destinations: bool,
+}
+
+/// Webhook delivery with subscriber details required to send it
+#[derive(Debug, Clone)]
+pub struct WebhookDeliveryJob {
+    pub id: i64,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub created_at: chrono::DateTime<Utc>,
+    pub url: String,
+    pub secret: String,
+    pub attempts: i32,
+}
+
+/// Webhooks
+#[derive(Clone)]
+pub struct Webhooks {
+    config: WebhooksConfig,
+    metrics: Arc<Metrics>,
+    postgres: Arc<PostgresPool>,
+    clients: Arc<Clients>,
+    shutdown: Arc<Shutdown>,
+}
+
+impl Webhooks {
+    pub fn from_config(
+        config: &Config,
+        metrics: Arc<Metrics>,
+        postgres: Arc<PostgresPool>,
+        clients: Arc<Clients>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        Self {
+            config: config.webhooks.clone(),
+            metrics,
+            postgres,
+            clients,
+            shutdown,
+        }
+    }
+
+    /// Create deliveries of event for subscribers and send them in background tasks
+    pub async fn publish(&self, event_type: &str, payload: serde_json::Value) -> Result<(), XErr> {
+        let jobs = self
+            .postgres
+            .webhook_delivery_insert(event_type, &payload, self.locked_until())
+            .await?;
+
+        for job in jobs {
+            self.spawn(job, self.config.max_attempts);
+        }
+        Ok(())
+    }
+
+    /// Reset delivery to pending and send it in a background task
+    pub async fn redeliver(&self, id: i64) -> Result<Option<i64>, XErr> {
+        let job = self
+            .postgres
+            .webhook_delivery_redeliver(id, self.locked_until())
+            .await?;
+        Ok(job.map(|job| {
+            let id = job.id;
+            self.spawn(job, self.config.max_attempts);
+            id
+        }))
+    }
+
+    /// Resume pending deliveries with expired leases until the server is shutting down
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(WEBHOOKS_RESUME_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.shutdown.wait(ShutdownPhase::Closing) => break,
+            }
+            if let Err(err) = self.resume().await {
+                let err: Error = err.into();
+                warn!("webhooks resume error: {:#}", err);
+            }
+        }
+        info!("webhooks resume stopped");
+    }
+
+    async fn resume(&self) -> Result<(), XErr> {
+        let jobs = self
+            .postgres
+            .webhook_delivery_resume(self.locked_until(), WEBHOOKS_RESUME_LIMIT)
+            .await?;
+
+        for job in jobs {
+            info!("webhook delivery {} resumed", job.id);
+            // Attempts already made since delivery was created or redelivered, a delivery
+            // interrupted after its last attempt is failed without sending it again
+            let attempted = job.attempts as u32 % self.config.max_attempts;
+            if job.attempts > 0 && attempted == 0 {
+                self.metrics.webhooks_failure_counter_inc();
+                self.postgres.webhook_delivery_failed(job.id).await?;
+            } else {
+                self.spawn(job, self.config.max_attempts - attempted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an error if url does not resolve to addresses allowed as destinations
+    pub async fn destination_check(&self, url: &str) -> Result<(), XErr> {
+        if self.config.allow_private_destinations {
+            return Ok(());
+        }
+        let url = reqwest::Url::parse(url).map_err(|_| XErr::webhook_destination("url"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| XErr::webhook_destination("port"))?;
+        let addrs: Vec<IpAddr> = match url.host() {
+            Some(url::Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+            Some(url::Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+            Some(url::Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+                .await
+                .map_err(|_| XErr::webhook_destination("host can not be resolved"))?
+                .map(|addr| addr.ip())
+                .collect(),
+            None => vec![],
+        };
+        if addrs.is_empty() {
+            return Err(XErr::webhook_destination("host"));
+        }
+        match addrs.into_iter().find(|ip| !ip_is_public(ip)) {
+            Some(ip) => Err(XErr::webhook_destination(&format!(
+                "address {} is not allowed",
+                ip
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns time until which deliveries sent now are leased
+    fn locked_until(&self) -> chrono::DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(self.config.delivery_timeout_seconds as i64)
+    }
+
+    /// Returns number of pending deliveries and configured maximum
+    pub async fn backlog(&self) -> Result<(i64, i64), XErr> {
+        let backlog = self.postgres.webhook_delivery_pending_count().await?;
+        Ok((backlog, self.config.backlog_max))
+    }
+
+    fn spawn(&self, job: WebhookDeliveryJob, max_attempts: u32) {
+        let webhooks = self.clone();
+        self.shutdown.spawn(async move {
+            let id = job.id;
+            if let Err(err) = webhooks.deliver(job, max_attempts).await {
+                let err: Error = err.into();
+                warn!("webhook delivery {} error: {:#}", id, err);
+            }
+        });
+    }
+
+    /// Send delivery until successful or max attempts are reached, recording each attempt
+    #[tracing::instrument(skip(self, job), fields(id = job.id, event_type = %job.event_type))]
+    async fn deliver(&self, job: WebhookDeliveryJob, max_attempts: u32) -> Result<(), XErr> {
+        let body = serde_json::to_vec(&json!({
+            "id": job.id,
+            "event": job.event_type,
+            "created_at": job.created_at.to_rfc3339(),
+            "data": job.payload,
+        }))?;
+
+        for i in 0..max_attempts {
+            let attempt = job.attempts + i as i32 + 1;
+            // Lease covers the backoff and request, it is longer than the maximum backoff
+            self.postgres
+                .webhook_delivery_lease(job.id, self.locked_until())
+                .await?;
+            if i > 0 {
+                // Lease is released if server is shutting down, delivery is resumed
+                // with its remaining attempts
+                tokio::select! {
+                    _ = tokio::time::sleep(self.backoff(i)) => {}
+                    _ = self.shutdown.wait(ShutdownPhase::Closing) => {
+                        warn!("webhook delivery interrupted by shutdown");
+                        return self.postgres.webhook_delivery_lease(job.id, Utc::now()).await;
+                    }
+                }
+            }
+
+            let start = Instant::now();
+            let res = match self.destination_check(&job.url).await {
+                Ok(()) => {
+                    let builder = self.clients.request(Method::POST, &job.url);
+                    let req = signed_request(
+                        builder,
+                        &job.secret,
+                        &job.event_type,
+                        job.id,
+                        Utc::now().timestamp(),
+                        body.clone(),
+                    )
+                    .build()?;
+                    self.clients.send_without_redirects(req).await
+                }
+                Err(err) => Err(err),
+            };
+            let duration_millis = start.elapsed().as_millis() as i64;
+            self.metrics.webhooks_attempt_counter_inc();
+
+            let (status_code, error) = match res {
+                Ok(res) if res.status().is_success() => (Some(res.status().as_u16() as i32), None),
+                Ok(res) => (
+                    Some(res.status().as_u16() as i32),
+                    Some(format!("unexpected status {}", res.status())),
+                ),
+                Err(err) => {
+                    let err: Error = err.into();
+                    (None, Some(format!("{:#}", err)))
+                }
+            };
+            let delivered = error.is_none();
+            self.postgres
+                .webhook_delivery_attempt_insert(
+                    job.id,
+                    attempt,
+                    status_code,
+                    error,
+                    duration_millis,
+                    delivered,
+                )
+                .await?;
+
+            if delivered {
+                info!("webhook delivered on attempt {}", attempt);
+                return Ok(());
+            }
+        }
+
+        warn!("webhook delivery failed");
+        self.metrics.webhooks_failure_counter_inc();
+        self.postgres.webhook_delivery_failed(job.id).await
+    }
+
+    /// Returns jittered exponential backoff before retry (starting at 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        use rand::Rng;
+        let exponent = retry.saturating_sub(1).min(32);
+        let cap = self
+            .config
+            .backoff_base_seconds
+            .saturating_mul(1 << exponent)
+            .min(self.config.backoff_max_seconds)
+            .saturating_mul(1000);
+        let millis = rand::thread_rng().gen_range(cap / 2..=cap);
+        Duration::from_millis(millis)
+    }
+}
+
+impl fmt::Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhooks").finish()
+    }
+}
+
+/// Returns hex encoded HMAC-SHA256 signature of timestamp and body
+pub fn webhook_signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Returns true if address is publicly routable, rejects private, loopback, link-local,
+/// shared, documentation, multicast and reserved ranges
+fn ip_is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ipv4_is_public(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            // IPv4 mapped and compatible addresses (excluding `::` and `::1`)
+            Some(v4) if !ip.is_unspecified() && !ip.is_loopback() => ipv4_is_public(&v4),
+            _ => ipv6_is_public(ip),
+        },
+    }
+}
+
+fn ipv4_is_public(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || a == 0
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (b & 0xc0) == 64)
+        // 192.0.0.0/24 protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+fn ipv6_is_public(ip: &Ipv6Addr) -> bool {
+    let segment = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (segment & 0xfe00) == 0xfc00
+        // fe80::/10 link-local
+        || (segment & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (segment == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Add JSON body and signature headers to request
+fn signed_request(
+    builder: RequestBuilder,
+    secret: &str,
+    event_type: &str,
+    id: i64,
+    timestamp: i64,
+    body: Vec<u8>,
+) -> RequestBuilder {
+    let signature = webhook_signature(secret, timestamp, &body);
+    builder
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(X_PETSHOP_EVENT, event_type)
+        .header(X_PETSHOP_DELIVERY, id.to_string())
+        .header(X_PETSHOP_TIMESTAMP, timestamp.to_string())
+        .header(X_PETSHOP_SIGNATURE_256, format!("sha256={}", signature))
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+
+    #[test]
+    fn webhook_signature_test() {
+        // echo -n '1600000000.{"id":1}' | openssl dgst -sha256 -hmac "secret"
+        assert_eq!(
+            webhook_signature("secret", 1600000000, b"{\"id\":1}"),
+            "49847f6653f3434dc0d5563850815d91e18471282eeccadbf48380236b3ed25f"
+        );
+        assert_ne!(
+            webhook_signature("secret", 1600000000, b"{\"id\":1}"),
+            webhook_signature("secret", 1600000001, b"{\"id\":1}")
+        );
+        assert_ne!(
+            webhook_signature("secret", 1600000000, b"{\"id\":1}"),
+            webhook_signature("other", 1600000000, b"{\"id\":1}")
+        );
+    }
+
+    #[test]
+    fn ip_is_public_test() {
+        for ip in &[
+            "93.184.216.34",
+            "8.8.8.8",
+            "2606:2800:220:1:248:1893:25c8:1946",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(ip_is_public(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!ip_is_public(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    /// Local HTTP server verifies signature headers of delivered request
+    #[tokio::test]
+    async fn signed_request_test() {
+        async fn handler(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .and_then(|x| x.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let event = header(X_PETSHOP_EVENT);
+            let timestamp: i64 = header(X_PETSHOP_TIMESTAMP).parse().unwrap_or_default();
+            let signature = header(X_PETSHOP_SIGNATURE_256);
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+
+            let expected = format!("sha256={}", webhook_signature("secret", timestamp, &body));
+            let status = if event == EVENT_PET_CREATED && signature == expected {
+                StatusCode::OK
+            } else {
+                StatusCode::UNAUTHORIZED
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap())
+        }
+
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(service_fn(handler))
+            }));
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let body = b"{\"id\":1}".to_vec();
+        for (secret, expected) in &[
+            ("secret", StatusCode::OK),
+            ("wrong", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = signed_request(
+                client.post(&url),
+                secret,
+                EVENT_PET_CREATED,
+                1,
+                Utc::now().timestamp(),
+                body.clone(),
+            );
+            let res = req.send().await.unwrap();
+            assert_eq!(res.status(), *expected);
+        }
+    }
+}